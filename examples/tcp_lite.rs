@@ -0,0 +1,104 @@
+//! A simplified TCP connection: the three-way handshake, data transfer, and an orderly close.
+//! Segments that make no sense in the current State are protocol errors, so the State Machine
+//! uses [UnhandledEventPolicy::Error], and the definition is checked with
+//! [StateMachineFactory::validate] before any connection is built.
+//!
+//! Run with `cargo run --example tcp_lite`.
+
+use std::sync::Mutex;
+use statement::{StateMachineError, StateMachineFactory, UnhandledEventPolicy};
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+enum Segment {
+    Syn,
+    SynAck,
+    Ack,
+    Data(Vec<u8>),
+    Fin,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Tcp {
+    Listen,
+    SynReceived,
+    Established,
+    CloseWait,
+    LastAck,
+    Closed,
+}
+
+const STATES: [Tcp; 6] = [Tcp::Listen, Tcp::SynReceived, Tcp::Established, Tcp::CloseWait, Tcp::LastAck, Tcp::Closed];
+
+#[derive(Default)]
+struct Connection {
+    sent: Mutex<Vec<Segment>>,
+    received: Mutex<Vec<u8>>,
+}
+
+impl Connection {
+    fn send(&self, segment: Segment) {
+        self.sent.lock().unwrap().push(segment);
+    }
+}
+
+fn factory<'a>() -> StateMachineFactory<'a, Segment, Tcp, Connection> {
+    StateMachineFactory::<Segment, Tcp, Connection>::new()
+        .with_named_event_transition_effect("accept", &Segment::Syn, Tcp::Listen, Tcp::SynReceived, |d| {
+            d.data.send(Segment::SynAck);
+            Ok(())
+        })
+        .with_named_event_transition("established", &Segment::Ack, Tcp::SynReceived, Tcp::Established)
+        .with_named_event_kind_transition_effect("receive", &Segment::Data(Vec::new()), Tcp::Established, Tcp::Established, |d| {
+            if let Segment::Data(bytes) = d.event {
+                d.data.received.lock().unwrap().extend(bytes);
+                d.data.send(Segment::Ack);
+            }
+            Ok(())
+        })
+        // The peer closes: acknowledge, then send our own FIN once the application is done
+        .with_named_event_transition_effect("peer close", &Segment::Fin, Tcp::Established, Tcp::CloseWait, |d| {
+            d.data.send(Segment::Ack);
+            Ok(())
+        })
+        .with_named_auto_transition("close", Tcp::CloseWait, Tcp::LastAck)
+        .with_named_event_transition("closed", &Segment::Ack, Tcp::LastAck, Tcp::Closed)
+        .unhandled_event_policy(UnhandledEventPolicy::Error)
+        .with_final_states([Tcp::Closed])
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let report = factory().validate(&Tcp::Listen, &STATES);
+    assert!(report.is_empty(), "invalid definition: {:?}", report);
+    let factory = factory().cycle(true).lock();
+
+    let mut connection = factory.build(Tcp::Listen, Connection::default());
+    for segment in [Segment::Syn, Segment::Ack, Segment::Data(b"hello".to_vec()), Segment::Fin] {
+        connection.handle_event(segment)?;
+    }
+    // Cycle mode lets the automatic "close" Transition follow "peer close" straight away
+    assert_eq!(Tcp::LastAck, connection.state);
+    connection.handle_event(Segment::Ack)?;
+    assert!(connection.is_complete());
+    assert_eq!(b"hello".to_vec(), *connection.data.received.lock().unwrap());
+    assert_eq!(vec![Segment::SynAck, Segment::Ack, Segment::Ack], *connection.data.sent.lock().unwrap());
+
+    // Data before the handshake completes is rejected rather than silently ignored
+    let mut rogue = factory.build(Tcp::Listen, Connection::default());
+    rogue.handle_event(Segment::Syn)?;
+    match rogue.handle_event(Segment::Data(b"too early".to_vec())) {
+        Err(StateMachineError::UnhandledEvent(Tcp::SynReceived)) => {}
+        other => panic!("expected a protocol error, got {:?}", other.copied()),
+    }
+
+    println!("received {:?}", String::from_utf8_lossy(&connection.data.received.lock().unwrap()));
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    run()
+}
+
+#[test]
+fn tcp_lite() {
+    run().expect("unexpected error");
+}
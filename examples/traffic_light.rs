@@ -0,0 +1,82 @@
+//! A traffic light driven by timeout Transitions, with a pedestrian button that shortens the
+//! green phase. Instead of sleeping, the example uses [StateMachine::fast_forward] to jump to each
+//! deadline, so the whole cycle runs instantly.
+//!
+//! Run with `cargo run --example traffic_light`.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use statement::{StateMachine, StateMachineFactory};
+
+#[derive(Eq, PartialEq, Debug)]
+enum Event {
+    PedestrianButton,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Light {
+    Red,
+    RedAmber,
+    Green,
+    Amber,
+}
+
+#[derive(Default)]
+struct Crossing {
+    walk_requested: AtomicBool,
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let factory = StateMachineFactory::<Event, Light, Crossing>::new()
+        .with_timeout_transition(Light::Red, Duration::from_secs(30), Light::RedAmber)
+        .with_timeout_transition(Light::RedAmber, Duration::from_secs(2), Light::Green)
+        .with_timeout_transition(Light::Green, Duration::from_secs(60), Light::Amber)
+        .with_timeout_transition_effect(Light::Amber, Duration::from_secs(3), Light::Red, |d| {
+            d.data.walk_requested.store(false, Ordering::SeqCst);
+            Ok(())
+        })
+        // Pressing the button while the light is green ends the green phase early
+        .with_named_event_transition_effect("walk", &Event::PedestrianButton, Light::Green, Light::Amber, |d| {
+            d.data.walk_requested.store(true, Ordering::SeqCst);
+            Ok(())
+        })
+        .with_history(16)
+        .lock();
+
+    let mut sm: StateMachine<Event, Light, Crossing> = factory.build(Light::Red, Crossing::default());
+    let first_change = sm.next_deadline().expect("red has a timeout");
+
+    // One full cycle without pedestrians
+    let mut lights = vec![sm.state];
+    while sm.state != Light::Red || lights.len() == 1 {
+        sm.fast_forward()?;
+        lights.push(sm.state);
+    }
+    assert_eq!(vec![Light::Red, Light::RedAmber, Light::Green, Light::Amber, Light::Red], lights);
+    assert_eq!(Duration::from_secs(65), sm.now() - first_change);
+
+    // A pedestrian arrives during the next green phase
+    sm.fast_forward()?;
+    sm.fast_forward()?;
+    assert_eq!(Light::Green, sm.state);
+    sm.handle_event(Event::PedestrianButton)?;
+    assert_eq!(Light::Amber, sm.state);
+    assert!(sm.data.walk_requested.load(Ordering::SeqCst));
+    sm.fast_forward()?;
+    assert_eq!(Light::Red, sm.state);
+    assert!(!sm.data.walk_requested.load(Ordering::SeqCst));
+
+    for record in sm.history() {
+        println!("{:?} -> {:?} ({})", record.from, record.to, record.event_debug);
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    run()
+}
+
+#[test]
+fn traffic_light() {
+    run().expect("unexpected error");
+}
@@ -0,0 +1,109 @@
+//! A vending machine that takes coins, dispenses a product once enough credit has been inserted,
+//! and returns change. A service mode can be entered from any State and returns to the State the
+//! machine was in, using `ToState::Push` and `ToState::Pop`.
+//!
+//! Run with `cargo run --example vending_machine`.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use statement::{FromState, StateMachineFactory};
+use statement::ToState::{Pop, Push};
+
+const PRICE: u32 = 150;
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+enum Event {
+    Coin(u32),
+    Select,
+    Dispensed,
+    Refund,
+    Service,
+    ServiceDone,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum State {
+    Idle,
+    Collecting,
+    Dispensing,
+    Service,
+}
+
+#[derive(Default)]
+struct Machine {
+    credit: AtomicU32,
+    change: AtomicU32,
+    dispensed: AtomicU32,
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let factory = StateMachineFactory::<Event, State, Machine, String>::new()
+        .with_named_event_kind_transition_effect("insert coin", &Event::Coin(0), FromState::AnyOf(vec![State::Idle, State::Collecting]), State::Collecting, |d| {
+            if let Event::Coin(value) = d.event {
+                d.data.credit.fetch_add(*value, Ordering::SeqCst);
+            }
+            Ok(())
+        })
+        .with_named_predicated_transition_effect("vend", State::Collecting, State::Dispensing, |d| {
+            *d.event == Event::Select && d.data.credit.load(Ordering::SeqCst) >= PRICE
+        }, |d| {
+            let change = d.data.credit.swap(0, Ordering::SeqCst) - PRICE;
+            d.data.change.fetch_add(change, Ordering::SeqCst);
+            // The product drops straight away, so report it without waiting for a sensor
+            d.emit(Event::Dispensed);
+            Ok(())
+        })
+        .with_named_event_transition_effect("finish", &Event::Dispensed, State::Dispensing, State::Idle, |d| {
+            d.data.dispensed.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        })
+        .with_named_event_transition_effect("refund", &Event::Refund, State::Collecting, State::Idle, |d| {
+            let credit = d.data.credit.swap(0, Ordering::SeqCst);
+            d.data.change.fetch_add(credit, Ordering::SeqCst);
+            Ok(())
+        })
+        .with_named_event_transition("enter service", &Event::Service, FromState::NoneOf(vec![State::Service]), Push(State::Service))
+        .with_named_event_transition("leave service", &Event::ServiceDone, State::Service, Pop)
+        .with_state_description(State::Idle, "Insert coins")
+        .with_state_description(State::Collecting, "Press select once enough credit is inserted")
+        .lock();
+
+    let mut sm = factory.build(State::Idle, Machine::default());
+
+    // Not enough credit yet, so selecting does nothing
+    sm.handle_event(Event::Coin(100))?;
+    sm.handle_event(Event::Select)?;
+    assert_eq!(State::Collecting, sm.state);
+
+    // A technician opens the machine part way through, and the credit is kept
+    sm.handle_event(Event::Service)?;
+    assert_eq!(State::Service, sm.state);
+    sm.handle_event(Event::ServiceDone)?;
+    assert_eq!(State::Collecting, sm.state);
+
+    sm.handle_event(Event::Coin(100))?;
+    sm.handle_event(Event::Select)?;
+    assert_eq!(State::Idle, sm.state);
+    assert_eq!(vec![Some("vend"), Some("finish")], sm.fired_transitions().collect::<Vec<_>>());
+    assert_eq!(1, sm.data.dispensed.load(Ordering::SeqCst));
+    assert_eq!(50, sm.data.change.load(Ordering::SeqCst));
+
+    // Changing your mind returns the credit
+    sm.handle_event(Event::Coin(20))?;
+    sm.handle_event(Event::Refund)?;
+    assert_eq!(70, sm.data.change.load(Ordering::SeqCst));
+
+    println!("{}: dispensed {}, returned {} in change",
+        sm.state_description(&sm.state, None).unwrap_or("?"),
+        sm.data.dispensed.load(Ordering::SeqCst),
+        sm.data.change.load(Ordering::SeqCst));
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    run()
+}
+
+#[test]
+fn vending_machine() {
+    run().expect("unexpected error");
+}
@@ -0,0 +1,332 @@
+//! Graph metrics and ownership maps for State Machine definitions.
+//!
+//! [StateMachineFactory::analyze] treats the known States as the nodes of a graph and every way a
+//! Transition can move between two different States as an edge, then reports metrics that are
+//! useful for keeping large State Machines maintainable, e.g. by failing a CI build when a State
+//! has too many outgoing Transitions.
+//!
+//! ```
+//! use statement::StateMachineFactory;
+//!
+//! #[derive(Eq, PartialEq)]
+//! enum Event { Next, Back }
+//!
+//! let report = StateMachineFactory::<Event, u32, ()>::new()
+//!     .with_event_transition(&Event::Next, 1, 2)
+//!     .with_event_transition(&Event::Next, 2, 3)
+//!     .with_event_transition(&Event::Back, 3, 2)
+//!     .analyze(&[1, 2, 3]);
+//!
+//! assert!(report.max_fan_out() <= 8);
+//! assert_eq!(vec![vec![2, 3]], report.strongly_connected_components);
+//! assert_eq!(1, report.longest_path);
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use crate::{StateMachineFactory, ToState};
+use crate::validation::TransitionId;
+
+/// Metrics for a single State, see [AnalysisReport]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StateMetrics<TState> {
+    /// The State these metrics describe.
+    pub state: TState,
+    /// The number of ways a Transition can move into this State from a different State.
+    pub fan_in: usize,
+    /// The number of ways a Transition can move out of this State into a different State.
+    pub fan_out: usize,
+}
+
+/// Graph metrics produced by [StateMachineFactory::analyze]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AnalysisReport<TState> {
+    /// Metrics for each known State, in the order the States were provided.
+    pub states: Vec<StateMetrics<TState>>,
+    /// The cyclomatic complexity `E - N + 2P` of the graph, where E is the number of edges, N the
+    /// number of States, and P the number of connected parts of the graph.
+    pub complexity: usize,
+    /// The number of Transitions on the longest path through the graph, counting each strongly
+    /// connected component (cycle) as a single step. For graphs without cycles, this is the
+    /// exact longest path.
+    pub longest_path: usize,
+    /// Groups of States that can all reach each other, in the order the States were provided.
+    /// Only groups of more than one State are included.
+    pub strongly_connected_components: Vec<Vec<TState>>,
+}
+
+impl <TState> AnalysisReport<TState> {
+    /// Returns the highest fan-out of any State.
+    pub fn max_fan_out(&self) -> usize {
+        self.states.iter().map(|metrics| metrics.fan_out).max().unwrap_or(0)
+    }
+
+    /// Returns the highest fan-in of any State.
+    pub fn max_fan_in(&self) -> usize {
+        self.states.iter().map(|metrics| metrics.fan_in).max().unwrap_or(0)
+    }
+}
+
+/// Transitions grouped by their `owner` metadata, produced by
+/// [StateMachineFactory::ownership_map]. Its `Display` rendering lists one owned Transition per
+/// line in a CODEOWNERS-like format (`<transition> <owner>`), where unnamed Transitions are
+/// written as `#<index>`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct OwnershipMap {
+    /// The Transitions owned by each owner, in definition order.
+    pub owners: BTreeMap<String, Vec<TransitionId>>,
+    /// The Transitions without an owner, in definition order.
+    pub unowned: Vec<TransitionId>,
+}
+
+impl OwnershipMap {
+    /// Returns the owner of the Transition with the given name, if it has one.
+    pub fn owner_of(&self, transition_name: &str) -> Option<&str> {
+        self.owners.iter()
+            .find(|(_, transitions)| transitions.iter().any(|id| id.name.as_deref() == Some(transition_name)))
+            .map(|(owner, _)| owner.as_str())
+    }
+}
+
+impl Display for OwnershipMap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut lines: Vec<_> = self.owners.iter()
+            .flat_map(|(owner, transitions)| transitions.iter().map(move |id| (id, owner)))
+            .collect();
+        lines.sort_by_key(|(id, _)| id.index);
+        for (id, owner) in lines {
+            match &id.name {
+                Some(name) => writeln!(f, "{} {}", name, owner)?,
+                None => writeln!(f, "#{} {}", id.index, owner)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + PartialEq + 'a, TData, TErr> StateMachineFactory<'a, TEvent, TState, TData, TErr> {
+    /// Groups the Transitions defined so far by their `owner` metadata (see
+    /// [StateMachineFactory::with_metadata]), so that State Machines maintained by several teams
+    /// can generate ownership maps and route alerts for failing Effects to the right team.
+    pub fn ownership_map(&self) -> OwnershipMap {
+        let mut map = OwnershipMap::default();
+        for (index, transition) in self.transitions.iter().enumerate() {
+            let id = TransitionId { index, name: transition.name.clone() };
+            match transition.metadata("owner") {
+                Some(owner) => map.owners.entry(owner.to_string()).or_default().push(id),
+                None => map.unowned.push(id),
+            }
+        }
+        map
+    }
+
+    /// Computes graph metrics over the known States for the Transitions defined so far. Predicates
+    /// are never run, so any Transition is assumed to be able to execute, and Transitions whose
+    /// to_state is calculated ([ToState::Calc]) or popped ([ToState::Pop]) are assumed to be able
+    /// to reach every known State. See the [analysis](crate::analysis) module.
+    pub fn analyze(&self, states: &[TState]) -> AnalysisReport<TState> {
+        let edges = self.edges(states);
+
+        let mut metrics: Vec<_> = states.iter()
+            .map(|state| StateMetrics { state: state.clone(), fan_in: 0, fan_out: 0 })
+            .collect();
+        for (from, to) in &edges {
+            metrics[*from].fan_out += 1;
+            metrics[*to].fan_in += 1;
+        }
+
+        let components = strongly_connected_components(states.len(), &edges);
+        let longest_path = longest_path(states.len(), &edges, &components);
+        let complexity = edges.len() + 2 * connected_parts(states.len(), &edges) - states.len();
+
+        let mut strongly_connected_components: Vec<Vec<TState>> = components.into_iter()
+            .filter(|component| component.len() > 1)
+            .map(|mut component| {
+                component.sort();
+                component.into_iter().map(|index| states[index].clone()).collect()
+            })
+            .collect();
+        strongly_connected_components.sort_by_key(|component| states.iter().position(|state| state == &component[0]));
+
+        AnalysisReport {
+            states: metrics,
+            complexity,
+            longest_path,
+            strongly_connected_components,
+        }
+    }
+
+    /// Lists every way a Transition can move between two different known States, as pairs of
+    /// indexes into `states`
+    fn edges(&self, states: &[TState]) -> Vec<(usize, usize)> {
+        let index_of = |state: &TState| states.iter().position(|known| known == state);
+        let mut edges = Vec::new();
+        for (from, state) in states.iter().enumerate() {
+            for transition in self.transitions.iter().filter(|transition| transition.from_state.matches(state)) {
+                match &transition.get_to_state {
+                    ToState::To(to_state) | ToState::Push(to_state) => edges.extend(index_of(to_state).map(|to| (from, to))),
+                    ToState::Calc(_) | ToState::Pop => edges.extend((0..states.len()).map(|to| (from, to))),
+                    ToState::History(history_states) => edges.extend(states.iter().enumerate()
+                        .filter(|(_, to_state)| history_states.matches(to_state))
+                        .map(|(to, _)| (from, to))),
+                    ToState::Same | ToState::SelfExternal => {}
+                }
+            }
+            for timeout in self.timeouts.iter().filter(|timeout| timeout.from_state.matches(state)) {
+                edges.extend(index_of(&timeout.to_state).map(|to| (from, to)));
+            }
+        }
+        edges.retain(|(from, to)| from != to);
+        edges
+    }
+}
+
+/// Finds the strongly connected components of a graph with Tarjan's algorithm. Components are
+/// returned in reverse topological order: every component appears after the components it leads to.
+fn strongly_connected_components(nodes: usize, edges: &[(usize, usize)]) -> Vec<Vec<usize>> {
+    struct Tarjan<'e> {
+        edges: &'e [(usize, usize)],
+        index: Vec<Option<usize>>,
+        low_link: Vec<usize>,
+        on_stack: Vec<bool>,
+        stack: Vec<usize>,
+        next_index: usize,
+        components: Vec<Vec<usize>>,
+    }
+
+    impl Tarjan<'_> {
+        fn visit(&mut self, node: usize) {
+            self.index[node] = Some(self.next_index);
+            self.low_link[node] = self.next_index;
+            self.next_index += 1;
+            self.stack.push(node);
+            self.on_stack[node] = true;
+
+            for (_, next) in self.edges.iter().filter(|(from, _)| *from == node) {
+                match self.index[*next] {
+                    None => {
+                        self.visit(*next);
+                        self.low_link[node] = self.low_link[node].min(self.low_link[*next]);
+                    }
+                    Some(index) if self.on_stack[*next] => self.low_link[node] = self.low_link[node].min(index),
+                    Some(_) => {}
+                }
+            }
+
+            if Some(self.low_link[node]) == self.index[node] {
+                let mut component = Vec::new();
+                while let Some(member) = self.stack.pop() {
+                    self.on_stack[member] = false;
+                    component.push(member);
+                    if member == node {
+                        break;
+                    }
+                }
+                self.components.push(component);
+            }
+        }
+    }
+
+    let mut tarjan = Tarjan {
+        edges,
+        index: vec![None; nodes],
+        low_link: vec![0; nodes],
+        on_stack: vec![false; nodes],
+        stack: Vec::new(),
+        next_index: 0,
+        components: Vec::new(),
+    };
+    for node in 0..nodes {
+        if tarjan.index[node].is_none() {
+            tarjan.visit(node);
+        }
+    }
+    tarjan.components
+}
+
+/// Finds the longest path through the graph of strongly connected components
+fn longest_path(nodes: usize, edges: &[(usize, usize)], components: &[Vec<usize>]) -> usize {
+    let mut component_of = vec![0; nodes];
+    for (index, component) in components.iter().enumerate() {
+        for node in component {
+            component_of[*node] = index;
+        }
+    }
+
+    // Components lead only to components found before them, so those are already measured
+    let mut longest = vec![0; components.len()];
+    for (index, component) in components.iter().enumerate() {
+        longest[index] = edges.iter()
+            .filter(|(from, to)| component.contains(from) && component_of[*to] != index)
+            .map(|(_, to)| longest[component_of[*to]] + 1)
+            .max()
+            .unwrap_or(0);
+    }
+    longest.into_iter().max().unwrap_or(0)
+}
+
+/// Counts the parts of a graph that are connected when edge directions are ignored
+fn connected_parts(nodes: usize, edges: &[(usize, usize)]) -> usize {
+    let mut part: Vec<usize> = (0..nodes).collect();
+    fn root(part: &mut [usize], mut node: usize) -> usize {
+        while part[node] != node {
+            part[node] = part[part[node]];
+            node = part[node];
+        }
+        node
+    }
+    for (from, to) in edges {
+        let (from, to) = (root(&mut part, *from), root(&mut part, *to));
+        part[from] = to;
+    }
+    (0..nodes).filter(|node| root(&mut part, *node) == *node).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::StateMachineFactory;
+    use crate::analysis::StateMetrics;
+    use crate::validation::TransitionId;
+    use crate::FromState::Any;
+
+    #[derive(Eq, PartialEq)]
+    enum Events {
+        Next,
+        Reset
+    }
+
+    #[test]
+    fn test_analysis() {
+        let report = StateMachineFactory::<Events, u32, ()>::new()
+            .with_event_transition(&Events::Next, 1, 2)
+            .with_event_transition(&Events::Next, 2, 3)
+            .with_event_transition(&Events::Next, 3, 4)
+            .with_event_transition(&Events::Reset, Any, 1)
+            .analyze(&[1, 2, 3, 4, 5]);
+
+        assert_eq!(StateMetrics { state: 1, fan_in: 4, fan_out: 1 }, report.states[0]);
+        assert_eq!(StateMetrics { state: 4, fan_in: 1, fan_out: 1 }, report.states[3]);
+        assert_eq!(2, report.max_fan_out());
+        assert_eq!(vec![vec![1, 2, 3, 4]], report.strongly_connected_components);
+        // State 5 only leads into the cycle
+        assert_eq!(1, report.longest_path);
+        // 7 edges, 5 states, 1 connected part
+        assert_eq!(4, report.complexity);
+    }
+
+    #[test]
+    fn test_ownership_map() {
+        let map = StateMachineFactory::<Events, u32, ()>::new()
+            .with_named_event_transition("advance", &Events::Next, 1, 2)
+            .with_metadata("owner", "@checkout-team")
+            .with_event_transition(&Events::Next, 2, 3)
+            .with_metadata("owner", "@payments-team")
+            .with_named_event_transition("reset", &Events::Reset, Any, 1)
+            .ownership_map();
+
+        assert_eq!(Some("@checkout-team"), map.owner_of("advance"));
+        assert_eq!(None, map.owner_of("reset"));
+        assert_eq!(vec![TransitionId { index: 2, name: Some("reset".into()) }], map.unowned);
+        assert_eq!("advance @checkout-team\n#1 @payments-team\n", map.to_string());
+    }
+}
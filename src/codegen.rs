@@ -0,0 +1,384 @@
+//! Generation of typestate wrapper types from State Machine definitions.
+//!
+//! [StateMachineFactory::typestate_source] renders Rust source for a zero-sized wrapper type with
+//! one marker type per State, and one method per named Transition that consumes the wrapper in its
+//! from_state and returns it in its to_state. Calling a Transition that is not valid from the
+//! current State is then a compile error, while the runtime State Machine and the compile-time API
+//! are both derived from the same factory.
+//!
+//! The source is usually written from a build script and included in the crate:
+//!
+//! ```ignore
+//! // build.rs
+//! let source = order_factory().typestate_source("Order", &State::Pending, &STATES)?;
+//! std::fs::write(Path::new(&std::env::var("OUT_DIR")?).join("order.rs"), source)?;
+//!
+//! // lib.rs
+//! include!(concat!(env!("OUT_DIR"), "/order.rs"));
+//! ```
+//!
+//! ```
+//! use statement::StateMachineFactory;
+//!
+//! #[derive(Eq, PartialEq)]
+//! enum Event { Start, Stop }
+//!
+//! #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+//! enum State { Idle, Running }
+//!
+//! let source = StateMachineFactory::<Event, State, ()>::new()
+//!     .with_named_event_transition("start", &Event::Start, State::Idle, State::Running)
+//!     .with_named_event_transition("stop", &Event::Stop, State::Running, State::Idle)
+//!     .typestate_source("Machine", &State::Idle, &[State::Idle, State::Running])
+//!     .unwrap();
+//!
+//! assert!(source.contains("impl Machine<Idle> {"));
+//! assert!(source.contains("pub fn start(self) -> Machine<Running> {"));
+//! ```
+
+use std::fmt::{Debug, Write};
+use thiserror::Error;
+use crate::{StateMachineFactory, StateMachineTransition, ToState};
+
+/// An error returned by [StateMachineFactory::typestate_source] when the generated source would
+/// not compile
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum CodegenError {
+    /// Two States, or a State and the wrapper, render to the same type name
+    #[error("{first} and {second} both generate the type name {name}")]
+    DuplicateTypeName {
+        /// The generated type name
+        name: String,
+        /// The `Debug` rendering of the first State, or the name of the wrapper
+        first: String,
+        /// The `Debug` rendering of the second State
+        second: String,
+    },
+    /// Two Transitions from the same State, or a Transition from the initial State and the
+    /// constructor, render to the same method name
+    #[error("{first} and {second} both generate the method name {name} in State {state}")]
+    DuplicateMethodName {
+        /// The `Debug` rendering of the State
+        state: String,
+        /// The generated method name
+        name: String,
+        /// The name of the first Transition, or `new` for the constructor
+        first: String,
+        /// The name of the second Transition
+        second: String,
+    },
+}
+
+impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + PartialEq + 'a, TData, TErr> StateMachineFactory<'a, TEvent, TState, TData, TErr>
+where TState: Debug
+{
+    /// Renders Rust source for typestate wrapper types over the known States, see the
+    /// [codegen](crate::codegen) module. The wrapper is named `machine` and can only be created in
+    /// `initial_state`. Marker types are named after the `Debug` rendering of each State, so States
+    /// should render as identifiers (as unit enum variants do); any other characters are dropped.
+    ///
+    /// Descriptions attached with [StateMachineFactory::with_state_description] and
+    /// [StateMachineFactory::with_description] become the doc comments of the generated types and
+    /// methods, followed by any documentation attached with [StateMachineFactory::with_doc]. Only
+    /// named Transitions become methods, named after the Transition. Transitions whose to_state is
+    /// calculated ([ToState::Calc]) or popped ([ToState::Pop]) cannot be expressed as a single
+    /// return type and are skipped, as is any Transition whose name is already used by an earlier
+    /// Transition from the same State.
+    ///
+    /// Names that are Rust keywords are generated as raw identifiers (`r#move`). Fails with a
+    /// [CodegenError] if two different States or Transitions would generate the same name.
+    pub fn typestate_source(&self, machine: &str, initial_state: &TState, states: &[TState]) -> Result<String, CodegenError> {
+        let mut type_names = vec![(identifier(machine.to_string()), machine.to_string())];
+        for (index, state) in states.iter().enumerate() {
+            if states[..index].contains(state) {
+                continue;
+            }
+            let name = type_name(state);
+            if let Some((_, first)) = type_names.iter().find(|(existing, _)| *existing == name) {
+                return Err(CodegenError::DuplicateTypeName { name, first: first.clone(), second: format!("{:?}", state) });
+            }
+            type_names.push((name, format!("{:?}", state)));
+        }
+        for state in states {
+            // The constructor shares the impl block of the Transitions from the initial State
+            let mut method_names = Vec::new();
+            if state == initial_state {
+                method_names.push(("new".to_string(), "new"));
+            }
+            for (name, _, _) in self.typestate_methods(state) {
+                let method = method_name(name);
+                if let Some((_, first)) = method_names.iter().find(|(existing, _)| *existing == method) {
+                    return Err(CodegenError::DuplicateMethodName { state: format!("{:?}", state), name: method, first: first.to_string(), second: name.to_string() });
+                }
+                method_names.push((method, name));
+            }
+        }
+
+        let mut source = String::new();
+        // Writing to a String cannot fail
+        let _ = self.write_typestate_source(&mut source, machine, initial_state, states);
+        Ok(source)
+    }
+
+    /// Returns the name, to_state and Transition of every method generated for a State
+    fn typestate_methods<'s>(&'s self, state: &'s TState) -> impl Iterator<Item = (&'s str, &'s TState, &'s StateMachineTransition<'a, TEvent, TState, TData, TErr>)> + 's {
+        let mut names: Vec<&str> = Vec::new();
+        self.transitions.iter()
+            .filter(move |transition| transition.from_state.matches(state))
+            .filter_map(move |transition| {
+                let name = transition.name.as_deref()?;
+                let to_state = match &transition.get_to_state {
+                    ToState::To(to_state) | ToState::Push(to_state) => to_state,
+                    ToState::Same | ToState::SelfExternal => state,
+                    ToState::Calc(_) | ToState::Pop | ToState::History(_) => return None
+                };
+                if names.contains(&name) {
+                    return None;
+                }
+                names.push(name);
+                Some((name, to_state, transition))
+            })
+    }
+
+    fn write_typestate_source(&self, source: &mut String, machine: &str, initial_state: &TState, states: &[TState]) -> std::fmt::Result {
+        writeln!(source, "// Generated by statement::codegen, do not edit.")?;
+        writeln!(source)?;
+        writeln!(source, "/// Typestate wrapper for the `{}` State Machine", machine)?;
+        writeln!(source, "pub struct {}<S> {{", identifier(machine.to_string()))?;
+        writeln!(source, "    _state: ::std::marker::PhantomData<S>,")?;
+        writeln!(source, "}}")?;
+        for (index, state) in states.iter().enumerate() {
+            if states[..index].contains(state) {
+                continue;
+            }
+            writeln!(source)?;
+            match self.state_description(state, None) {
+                Some(description) => writeln!(source, "/// {}", description)?,
+                None => writeln!(source, "/// The `{:?}` State", state)?
+            }
+            writeln!(source, "pub struct {};", type_name(state))?;
+        }
+
+        let machine = identifier(machine.to_string());
+        writeln!(source)?;
+        writeln!(source, "impl {}<{}> {{", machine, type_name(initial_state))?;
+        writeln!(source, "    /// Creates the wrapper in the initial State")?;
+        writeln!(source, "    #[allow(clippy::new_without_default)]")?;
+        writeln!(source, "    pub fn new() -> Self {{")?;
+        writeln!(source, "        Self {{ _state: ::std::marker::PhantomData }}")?;
+        writeln!(source, "    }}")?;
+        writeln!(source, "}}")?;
+
+        for (index, state) in states.iter().enumerate() {
+            if states[..index].contains(state) {
+                continue;
+            }
+            let mut body = String::new();
+            for (name, to_state, transition) in self.typestate_methods(state) {
+                if !body.is_empty() {
+                    writeln!(body)?;
+                }
+                match transition.description(None) {
+                    Some(description) => writeln!(body, "    /// {}", description)?,
+                    None => writeln!(body, "    /// Executes the `{}` Transition", name)?
+                }
+                if let Some(doc) = transition.doc() {
+                    writeln!(body, "    ///")?;
+                    for line in doc.lines() {
+                        writeln!(body, "    /// {}", line)?;
+                    }
+                }
+                writeln!(body, "    pub fn {}(self) -> {}<{}> {{", method_name(name), machine, type_name(to_state))?;
+                writeln!(body, "        {} {{ _state: ::std::marker::PhantomData }}", machine)?;
+                writeln!(body, "    }}")?;
+            }
+            if !body.is_empty() {
+                writeln!(source)?;
+                writeln!(source, "impl {}<{}> {{", machine, type_name(state))?;
+                write!(source, "{}", body)?;
+                writeln!(source, "}}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Derives the name of a marker type from the `Debug` rendering of a State
+fn type_name<TState: Debug>(state: &TState) -> String {
+    let name: String = format!("{:?}", state).chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+        .collect();
+    match name.chars().next() {
+        Some(c) if !c.is_ascii_digit() => identifier(name),
+        _ => format!("State{}", name)
+    }
+}
+
+/// Derives a method name from the name of a Transition
+fn method_name(name: &str) -> String {
+    let name: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+    match name.chars().next() {
+        Some(c) if !c.is_ascii_digit() && name != "_" => identifier(name),
+        _ => format!("_{}", name)
+    }
+}
+
+/// Rust keywords, which are only valid identifiers in raw form
+const KEYWORDS: [&str; 52] = [
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "gen",
+    "if", "impl", "in", "let", "loop", "macro", "match", "mod", "move", "mut",
+    "override", "priv", "pub", "ref", "return", "self", "Self", "static", "struct", "super",
+    "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use", "virtual", "where",
+    "while", "yield",
+];
+
+/// Turns a name into a valid identifier, using the raw form for keywords. `self`, `Self`, `super`
+/// and `crate` cannot be raw identifiers and get a trailing underscore instead.
+fn identifier(name: String) -> String {
+    match name.as_str() {
+        "self" | "Self" | "super" | "crate" => format!("{}_", name),
+        _ if KEYWORDS.contains(&name.as_str()) => format!("r#{}", name),
+        _ => name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{FromState, StateMachineFactory};
+    use crate::codegen::CodegenError;
+    use crate::ToState::Calc;
+
+    #[derive(Eq, PartialEq)]
+    enum Events {
+        Start,
+        Stop,
+        Reset
+    }
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum States {
+        Idle,
+        Running,
+        Stopped
+    }
+
+    #[test]
+    fn test_typestate_source() {
+        let source = StateMachineFactory::<Events, States, ()>::new()
+            .with_named_event_transition("start", &Events::Start, States::Idle, States::Running)
+            .with_named_event_transition("stop", &Events::Stop, States::Running, States::Stopped)
+            .with_description("Stops the machine")
+            .with_state_description(States::Stopped, "The machine has stopped")
+            .with_named_event_transition("reset", &Events::Reset, FromState::AnyOf(vec![States::Running, States::Stopped]), States::Idle)
+            .with_doc("Operators reset jammed machines remotely")
+            .with_named_event_transition("Reset Twice", &Events::Reset, States::Stopped, States::Running)
+            .with_event_transition(&Events::Start, States::Stopped, States::Running)
+            .with_named_event_transition("restart", &Events::Start, States::Stopped, Calc(Box::new(|_| States::Running)))
+            .typestate_source("Machine", &States::Idle, &[States::Idle, States::Running, States::Stopped])
+            .expect("unexpected error");
+
+        assert_eq!(r#"// Generated by statement::codegen, do not edit.
+
+/// Typestate wrapper for the `Machine` State Machine
+pub struct Machine<S> {
+    _state: ::std::marker::PhantomData<S>,
+}
+
+/// The `Idle` State
+pub struct Idle;
+
+/// The `Running` State
+pub struct Running;
+
+/// The machine has stopped
+pub struct Stopped;
+
+impl Machine<Idle> {
+    /// Creates the wrapper in the initial State
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self { _state: ::std::marker::PhantomData }
+    }
+}
+
+impl Machine<Idle> {
+    /// Executes the `start` Transition
+    pub fn start(self) -> Machine<Running> {
+        Machine { _state: ::std::marker::PhantomData }
+    }
+}
+
+impl Machine<Running> {
+    /// Stops the machine
+    pub fn stop(self) -> Machine<Stopped> {
+        Machine { _state: ::std::marker::PhantomData }
+    }
+
+    /// Executes the `reset` Transition
+    ///
+    /// Operators reset jammed machines remotely
+    pub fn reset(self) -> Machine<Idle> {
+        Machine { _state: ::std::marker::PhantomData }
+    }
+}
+
+impl Machine<Stopped> {
+    /// Executes the `reset` Transition
+    ///
+    /// Operators reset jammed machines remotely
+    pub fn reset(self) -> Machine<Idle> {
+        Machine { _state: ::std::marker::PhantomData }
+    }
+
+    /// Executes the `Reset Twice` Transition
+    pub fn reset_twice(self) -> Machine<Running> {
+        Machine { _state: ::std::marker::PhantomData }
+    }
+}
+"#, source);
+    }
+
+    #[test]
+    fn test_typestate_keywords() {
+        // States render through Debug as their quoted text, generating the type names loop, Self and Done
+        let source = StateMachineFactory::<Events, &str, ()>::new()
+            .with_named_event_transition("move", &Events::Start, "loop", "Self")
+            .with_named_event_transition("type", &Events::Stop, "loop", "Done")
+            .with_named_event_transition("self", &Events::Reset, "Self", "loop")
+            .typestate_source("Machine", &"loop", &["loop", "Self", "Done"])
+            .expect("unexpected error");
+
+        assert!(source.contains("pub struct r#loop;"));
+        assert!(source.contains("pub struct Self_;"));
+        assert!(source.contains("pub fn r#move(self) -> Machine<Self_> {"));
+        assert!(source.contains("pub fn r#type(self) -> Machine<Done> {"));
+        assert!(source.contains("pub fn self_(self) -> Machine<r#loop> {"));
+    }
+
+    #[test]
+    fn test_typestate_collisions() {
+        #[derive(Clone, Eq, PartialEq, Debug)]
+        enum Codes {
+            A(u8),
+            A1,
+            Machine
+        }
+
+        let factory = StateMachineFactory::<Events, Codes, ()>::new()
+            .with_named_event_transition("Reset Twice", &Events::Reset, Codes::A1, Codes::A1)
+            .with_named_event_transition("reset_twice", &Events::Reset, Codes::A1, Codes::A(1))
+            .with_named_event_transition("new", &Events::Start, Codes::A(1), Codes::A1);
+
+        assert_eq!(Err(CodegenError::DuplicateTypeName { name: "A1".to_string(), first: "A(1)".to_string(), second: "A1".to_string() }),
+            factory.typestate_source("Machine", &Codes::A(1), &[Codes::A(1), Codes::A1]));
+        assert_eq!(Err(CodegenError::DuplicateTypeName { name: "Machine".to_string(), first: "Machine".to_string(), second: "Machine".to_string() }),
+            factory.typestate_source("Machine", &Codes::A1, &[Codes::A1, Codes::Machine]));
+        assert_eq!(Err(CodegenError::DuplicateMethodName { state: "A1".to_string(), name: "reset_twice".to_string(), first: "Reset Twice".to_string(), second: "reset_twice".to_string() }),
+            factory.typestate_source("Wrapper", &Codes::Machine, &[Codes::A1, Codes::Machine]));
+        assert_eq!(Err(CodegenError::DuplicateMethodName { state: "A(1)".to_string(), name: "new".to_string(), first: "new".to_string(), second: "new".to_string() }),
+            factory.typestate_source("Wrapper", &Codes::A(1), &[Codes::A(1), Codes::Machine]));
+    }
+}
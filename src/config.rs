@@ -0,0 +1,656 @@
+//! Declarative State Machine definitions, loaded from JSON, TOML, or YAML.
+//!
+//! A [MachineDefinition] describes Transitions using names for States, Events, Predicates, and
+//! Effects. A [HandlerRegistry] maps those names to the values and code they stand for, and turns
+//! a definition into a [StateMachineFactory] or [LockedStateMachineFactory]. This allows the
+//! Transitions of a State Machine to be changed without recompiling, while Effects and Predicates
+//! remain ordinary Rust code.
+//!
+//! Definitions can also be rendered for clients written in other languages:
+//! [MachineDefinition::to_typescript] declares TypeScript types for the State and Event names, and
+//! [MachineDefinition::to_xstate] renders a skeleton XState machine.
+//!
+//! ```
+//! use statement::config::{HandlerRegistry, MachineDefinition};
+//!
+//! #[derive(Clone, Eq, PartialEq, Debug)]
+//! enum State { Pending, Paid }
+//!
+//! #[derive(Clone, Eq, PartialEq)]
+//! enum Event { Pay }
+//!
+//! let definition = MachineDefinition::from_toml(r#"
+//!     [[transitions]]
+//!     name = "pay"
+//!     from = "Pending"
+//!     to = "Paid"
+//!     event = "Pay"
+//!     effect = "charge_card"
+//! "#).unwrap();
+//!
+//! let registry = HandlerRegistry::<Event, State, ()>::new()
+//!     .with_state("Pending", State::Pending)
+//!     .with_state("Paid", State::Paid)
+//!     .with_event("Pay", Event::Pay)
+//!     .with_effect("charge_card", |_| Ok(()));
+//!
+//! let mut sm = registry.locked_factory(&definition).unwrap().build(State::Pending, ());
+//! sm.handle_event(Event::Pay).unwrap();
+//! assert_eq!(State::Paid, sm.state);
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::{plain_effect, FromState, LockedStateMachineFactory, StateMachineFactory, StateMachineTransition, StateTransitionEffectData, ToState, TransitionPredicate};
+use crate::validation::GuardExampleFailure;
+
+/// Serializable description of the Transitions of a State Machine, in which States, Events,
+/// Predicates, and Effects are referred to by name.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MachineDefinition {
+    /// Whether State Machines built from this definition cycle, see [StateMachineFactory::cycle].
+    #[serde(default)]
+    pub cycle: bool,
+    /// The Transitions of the State Machine, in definition order.
+    #[serde(default)]
+    pub transitions: Vec<TransitionDefinition>,
+    /// Display details of States, keyed by State name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub states: BTreeMap<String, StateDefinition>,
+}
+
+/// Serializable display details of a single State.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StateDefinition {
+    /// The description of the State, see [StateMachineFactory::with_state_description].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Descriptions of the State keyed by locale, see
+    /// [StateMachineFactory::with_localized_state_description].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub descriptions: BTreeMap<String, String>,
+}
+
+/// Serializable description of a single Transition.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TransitionDefinition {
+    /// The name of the Transition, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The State or States the Transition is valid from.
+    pub from: FromDefinition,
+    /// The State the Transition moves to, or None to stay in the same State.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    /// The name of an Event the incoming Event must be equal to, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event: Option<String>,
+    /// The name of a registered Predicate that must also pass, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub predicate: Option<String>,
+    /// The name of a registered Effect to execute, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effect: Option<String>,
+    /// The priority of the Transition, see [StateMachineFactory::with_priority].
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub priority: i32,
+    /// Free-form metadata about the Transition, see [StateMachineFactory::with_metadata].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+    /// The description of the Transition, see [StateMachineFactory::with_description].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Descriptions of the Transition keyed by locale, see
+    /// [StateMachineFactory::with_localized_description].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub descriptions: BTreeMap<String, String>,
+    /// Documentation explaining why the Transition exists, see [StateMachineFactory::with_doc].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub doc: Option<String>,
+    /// Examples for the Predicate of the Transition, see
+    /// [StateMachineFactory::with_guard_example].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub examples: Vec<ExampleDefinition>,
+}
+
+/// Serializable example for the Predicate of a Transition, in which the State, Event, and Data
+/// are referred to by name.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ExampleDefinition {
+    /// The State the State Machine is in.
+    pub from: String,
+    /// The Event received.
+    pub event: String,
+    /// The name of the Data, registered with [HandlerRegistry::with_example_data].
+    pub data: String,
+    /// Whether the Predicate should pass.
+    pub expected: bool,
+}
+
+fn is_zero(priority: &i32) -> bool {
+    *priority == 0
+}
+
+/// Serializable description of the States a Transition is valid from. A single State named `*`
+/// stands for [FromState::Any].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FromDefinition {
+    /// A single named State, or `*` for any State.
+    One(String),
+    /// Any of the named States.
+    Many(Vec<String>),
+    /// Any State except the named States, written as `{ none_of = [...] }`.
+    NoneOf {
+        /// The States the Transition is not valid from.
+        none_of: Vec<String>
+    },
+}
+
+impl MachineDefinition {
+    /// Parses a definition from JSON.
+    pub fn from_json(json: &str) -> Result<Self, ConfigError> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Parses a definition from TOML.
+    pub fn from_toml(toml: &str) -> Result<Self, ConfigError> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    /// Parses a definition from YAML.
+    pub fn from_yaml(yaml: &str) -> Result<Self, ConfigError> {
+        Ok(serde_yaml::from_str(yaml)?)
+    }
+
+    /// Lists the names of the States the definition refers to, in order of first appearance in
+    /// the Transitions, followed by any other States with display details.
+    pub fn state_names(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        for transition in &self.transitions {
+            let from = match &transition.from {
+                FromDefinition::One(name) if name == "*" => &[][..],
+                FromDefinition::One(name) => std::slice::from_ref(name),
+                FromDefinition::Many(names) => names.as_slice(),
+                FromDefinition::NoneOf { none_of } => none_of.as_slice(),
+            };
+            for name in from.iter().chain(&transition.to) {
+                if !names.contains(&name.as_str()) {
+                    names.push(name.as_str());
+                }
+            }
+        }
+        for name in self.states.keys() {
+            if !names.contains(&name.as_str()) {
+                names.push(name.as_str());
+            }
+        }
+        names
+    }
+
+    /// Lists the names of the Events the definition refers to, in order of first appearance.
+    pub fn event_names(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        for name in self.transitions.iter().filter_map(|transition| transition.event.as_deref()) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    /// Renders TypeScript declarations of the State and Event names of the definition, so that
+    /// clients of a State Machine (e.g. a frontend consuming its State) stay in sync with it. The
+    /// output declares `State` and `Event` string union types and `STATES` and `EVENTS` arrays.
+    pub fn to_typescript(&self) -> String {
+        let union = |names: &[&str]| match names.is_empty() {
+            true => "never".to_string(),
+            false => names.iter().map(|name| quote(name)).collect::<Vec<_>>().join(" | ")
+        };
+        let array = |names: &[&str]| names.iter().map(|name| quote(name)).collect::<Vec<_>>().join(", ");
+        let states = self.state_names();
+        let events = self.event_names();
+        format!("// Generated by statement, do not edit.\n\n\
+            export type State = {};\n\n\
+            export const STATES: readonly State[] = [{}];\n\n\
+            export type Event = {};\n\n\
+            export const EVENTS: readonly Event[] = [{}];\n",
+            union(&states), array(&states), union(&events), array(&events))
+    }
+
+    /// Renders a skeleton [XState](https://stately.ai/docs/xstate) machine with the States and
+    /// Transitions of the definition, starting in the `initial` State, including the default
+    /// descriptions of States and Transitions. Predicates and Effects are referred to by name as
+    /// guards and actions, and must be provided on the client. Transitions without an Event become
+    /// eventless (`always`) Transitions, and Transitions from any State are declared on the machine
+    /// itself.
+    pub fn to_xstate(&self, id: &str, initial: &str) -> String {
+        let states = self.state_names();
+        let mut machine_on = serde_json::Map::new();
+        let mut state_configs: Vec<serde_json::Map<String, serde_json::Value>> = vec![serde_json::Map::new(); states.len()];
+        for transition in &self.transitions {
+            let mut config = serde_json::Map::new();
+            if let Some(to) = &transition.to {
+                config.insert("target".into(), to.as_str().into());
+            }
+            if let Some(predicate) = &transition.predicate {
+                config.insert("guard".into(), predicate.as_str().into());
+            }
+            if let Some(effect) = &transition.effect {
+                config.insert("actions".into(), vec![effect.as_str()].into());
+            }
+            if let Some(description) = &transition.description {
+                config.insert("description".into(), description.as_str().into());
+            }
+            if let Some(doc) = &transition.doc {
+                config.insert("meta".into(), serde_json::json!({ "doc": doc }));
+            }
+            match (&transition.from, &transition.event) {
+                (FromDefinition::One(name), Some(event)) if name == "*" => push_transition(&mut machine_on, event, config),
+                (from, event) => {
+                    let matching = state_configs.iter_mut().zip(&states).filter(|(_, state)| match from {
+                        FromDefinition::One(name) => name == "*" || name == *state,
+                        FromDefinition::Many(names) => names.iter().any(|name| name == *state),
+                        FromDefinition::NoneOf { none_of } => !none_of.iter().any(|name| name == *state),
+                    });
+                    for (state_config, _) in matching {
+                        match event {
+                            Some(event) => {
+                                let on = state_config.entry("on").or_insert_with(|| serde_json::Map::new().into());
+                                push_transition(on.as_object_mut().expect("on is an object"), event, config.clone());
+                            }
+                            None => push_transition(state_config, "always", config.clone())
+                        }
+                    }
+                }
+            }
+        }
+
+        for (state_config, state) in state_configs.iter_mut().zip(&states) {
+            if let Some(description) = self.states.get(*state).and_then(|state| state.description.as_ref()) {
+                state_config.insert("description".into(), description.as_str().into());
+            }
+        }
+
+        let mut machine = serde_json::Map::new();
+        machine.insert("id".into(), id.into());
+        machine.insert("initial".into(), initial.into());
+        if !machine_on.is_empty() {
+            machine.insert("on".into(), machine_on.into());
+        }
+        machine.insert("states".into(), states.iter().map(|state| state.to_string()).zip(state_configs.into_iter().map(Into::into)).collect::<serde_json::Map<_, _>>().into());
+        format!("// Generated by statement, do not edit.\n\n\
+            import {{ createMachine }} from \"xstate\";\n\n\
+            export const machine = createMachine({});\n",
+            serde_json::to_string_pretty(&serde_json::Value::Object(machine)).expect("JSON values always serialize"))
+    }
+}
+
+/// Adds a Transition to the list of Transitions for a key of an XState configuration object
+fn push_transition(object: &mut serde_json::Map<String, serde_json::Value>, key: &str, config: serde_json::Map<String, serde_json::Value>) {
+    let transitions = object.entry(key).or_insert_with(|| Vec::<serde_json::Value>::new().into());
+    transitions.as_array_mut().expect("transitions are declared in arrays").push(config.into());
+}
+
+/// Quotes a name as a string literal
+fn quote(name: &str) -> String {
+    serde_json::Value::from(name).to_string()
+}
+
+/// Error type for loading a [MachineDefinition]
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    /// The definition is not valid JSON
+    #[error("invalid JSON definition: {0}")]
+    Json(#[from] serde_json::Error),
+    /// The definition is not valid TOML
+    #[error("invalid TOML definition: {0}")]
+    Toml(#[from] toml::de::Error),
+    /// The definition is not valid YAML
+    #[error("invalid YAML definition: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    /// The definition refers to a State that is not registered
+    #[error("unknown state {0:?}")]
+    UnknownState(String),
+    /// The definition refers to an Event that is not registered
+    #[error("unknown event {0:?}")]
+    UnknownEvent(String),
+    /// The definition refers to a Predicate that is not registered
+    #[error("unknown predicate {0:?}")]
+    UnknownPredicate(String),
+    /// The definition refers to an Effect that is not registered
+    #[error("unknown effect {0:?}")]
+    UnknownEffect(String),
+    /// The definition refers to example Data that is not registered
+    #[error("unknown example data {0:?}")]
+    UnknownExampleData(String),
+    /// Predicates disagreed with the examples in the definition, see
+    /// [StateMachineFactory::lock_validated]
+    #[error("predicates failed their examples: {0:?}")]
+    GuardExamplesFailed(Vec<GuardExampleFailure>),
+}
+
+/// Shared Predicate registered with a [HandlerRegistry]
+type RegisteredPredicate<'a, TEvent, TState, TData> = Arc<dyn Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> bool + 'a>;
+
+/// Shared Effect registered with a [HandlerRegistry]
+type RegisteredEffect<'a, TEvent, TState, TData, TErr> = Arc<dyn Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + 'a>;
+
+/// Example Data registered with a [HandlerRegistry], copied for every example that uses it
+type RegisteredData<'a, TData> = Box<dyn Fn() -> TData + 'a>;
+
+/// Maps the names used in a [MachineDefinition] to States, Events, Predicates, and Effects.
+pub struct HandlerRegistry<'a, TEvent, TState, TData, TErr = Box<dyn std::error::Error>> {
+    states: HashMap<String, TState>,
+    events: HashMap<String, TEvent>,
+    predicates: HashMap<String, RegisteredPredicate<'a, TEvent, TState, TData>>,
+    effects: HashMap<String, RegisteredEffect<'a, TEvent, TState, TData, TErr>>,
+    example_data: HashMap<String, RegisteredData<'a, TData>>,
+}
+
+impl <TEvent, TState, TData, TErr> Default for HandlerRegistry<'_, TEvent, TState, TData, TErr> {
+    fn default() -> Self {
+        Self {
+            states: HashMap::new(),
+            events: HashMap::new(),
+            predicates: HashMap::new(),
+            effects: HashMap::new(),
+            example_data: HashMap::new(),
+        }
+    }
+}
+
+impl <'a, TEvent, TState, TData, TErr> HandlerRegistry<'a, TEvent, TState, TData, TErr>
+where
+    TEvent: PartialEq + Clone + Send + 'a,
+    TState: PartialEq<TState> + Clone + Send + Eq + 'a,
+    TData: 'a,
+    TErr: 'a,
+{
+    /// Creates an empty `HandlerRegistry`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a State under the given name.
+    pub fn with_state(mut self, name: impl Into<String>, state: TState) -> Self {
+        self.states.insert(name.into(), state);
+        self
+    }
+
+    /// Registers an Event under the given name. Transitions referring to this name match incoming
+    /// Events that are equal to it.
+    pub fn with_event(mut self, name: impl Into<String>, event: TEvent) -> Self {
+        self.events.insert(name.into(), event);
+        self
+    }
+
+    /// Registers a Predicate under the given name.
+    pub fn with_predicate(mut self, name: impl Into<String>, predicate: impl Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> bool + 'a) -> Self {
+        self.predicates.insert(name.into(), Arc::new(predicate));
+        self
+    }
+
+    /// Registers an Effect under the given name.
+    pub fn with_effect(mut self, name: impl Into<String>, effect: impl Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + 'a) -> Self {
+        self.effects.insert(name.into(), Arc::new(effect));
+        self
+    }
+
+    /// Registers Data under the given name, for use by the examples in a definition.
+    pub fn with_example_data(mut self, name: impl Into<String>, data: TData) -> Self
+    where TData: Clone
+    {
+        self.example_data.insert(name.into(), Box::new(move || data.clone()));
+        self
+    }
+
+    /// Creates a [StateMachineFactory] with the Transitions of the given definition. Further
+    /// Transitions can be added to the factory in code before it is locked.
+    pub fn factory(&self, definition: &MachineDefinition) -> Result<StateMachineFactory<'a, TEvent, TState, TData, TErr>, ConfigError> {
+        let mut factory = StateMachineFactory::new().cycle(definition.cycle);
+        for transition in &definition.transitions {
+            factory = factory
+                .with_custom_transition(self.transition(transition)?)
+                .with_priority(transition.priority);
+            for (key, value) in &transition.metadata {
+                factory = factory.with_metadata(key.clone(), value.clone());
+            }
+            if let Some(description) = &transition.description {
+                factory = factory.with_description(description.clone());
+            }
+            for (locale, description) in &transition.descriptions {
+                factory = factory.with_localized_description(locale.clone(), description.clone());
+            }
+            if let Some(doc) = &transition.doc {
+                factory = factory.with_doc(doc.clone());
+            }
+            for example in &transition.examples {
+                let data = self.example_data.get(&example.data).ok_or_else(|| ConfigError::UnknownExampleData(example.data.clone()))?;
+                factory = factory.with_guard_example(self.state(&example.from)?, self.event(&example.event)?, data(), example.expected);
+            }
+        }
+        for (name, state) in &definition.states {
+            if let Some(description) = &state.description {
+                factory = factory.with_state_description(self.state(name)?, description.clone());
+            }
+            for (locale, description) in &state.descriptions {
+                factory = factory.with_localized_state_description(self.state(name)?, locale.clone(), description.clone());
+            }
+        }
+        Ok(factory)
+    }
+
+    /// Creates a [LockedStateMachineFactory] with the Transitions of the given definition, after
+    /// checking the examples in the definition with [StateMachineFactory::lock_validated].
+    pub fn locked_factory(&self, definition: &MachineDefinition) -> Result<LockedStateMachineFactory<'a, TEvent, TState, TData, TErr>, ConfigError> {
+        self.factory(definition)?.lock_validated().map_err(ConfigError::GuardExamplesFailed)
+    }
+
+    fn transition(&self, definition: &TransitionDefinition) -> Result<StateMachineTransition<'a, TEvent, TState, TData, TErr>, ConfigError> {
+        let from_state = match &definition.from {
+            FromDefinition::One(name) if name == "*" => FromState::Any,
+            FromDefinition::One(name) => FromState::From(self.state(name)?),
+            FromDefinition::Many(names) => FromState::AnyOf(names.iter().map(|name| self.state(name)).collect::<Result<_, _>>()?),
+            FromDefinition::NoneOf { none_of } => FromState::NoneOf(none_of.iter().map(|name| self.state(name)).collect::<Result<_, _>>()?),
+        };
+        let to_state = match &definition.to {
+            Some(name) => ToState::To(self.state(name)?),
+            None => ToState::Same,
+        };
+        let event = match &definition.event {
+            Some(name) => Some(self.event(name)?),
+            None => None,
+        };
+        let predicate = match &definition.predicate {
+            Some(name) => Some(self.predicates.get(name).cloned().ok_or_else(|| ConfigError::UnknownPredicate(name.clone()))?),
+            None => None,
+        };
+        let effect = match &definition.effect {
+            Some(name) => Some(self.effects.get(name).cloned().ok_or_else(|| ConfigError::UnknownEffect(name.clone()))?),
+            None => None,
+        };
+
+        // The event, if any, and the predicate, if any, must both match
+        let event_predicate = match (event, predicate) {
+            (None, None) => None,
+            (event, predicate) => Some(TransitionPredicate::Infallible(Box::new(move |d: &StateTransitionEffectData<TEvent, TState, TData>| {
+                event.as_ref().is_none_or(|event| event == d.event) && predicate.as_ref().is_none_or(|predicate| predicate(d))
+            }) as _)),
+        };
+
+        Ok(StateMachineTransition::new(
+            definition.name.clone(),
+            event_predicate,
+            from_state,
+            to_state,
+            effect.map(|effect| plain_effect(move |d: StateTransitionEffectData<TEvent, TState, TData>| effect(d))),
+        ))
+    }
+
+    pub(crate) fn state(&self, name: &str) -> Result<TState, ConfigError> {
+        self.states.get(name).cloned().ok_or_else(|| ConfigError::UnknownState(name.to_string()))
+    }
+
+    fn event(&self, name: &str) -> Result<TEvent, ConfigError> {
+        self.events.get(name).cloned().ok_or_else(|| ConfigError::UnknownEvent(name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{ConfigError, HandlerRegistry, MachineDefinition};
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum States {
+        Idle,
+        Running,
+        Stopped
+    }
+
+    #[derive(Clone, Eq, PartialEq, Debug)]
+    enum Events {
+        Start,
+        Stop { force: bool }
+    }
+
+    fn registry<'a>() -> HandlerRegistry<'a, Events, States, ()> {
+        HandlerRegistry::new()
+            .with_state("Idle", States::Idle)
+            .with_state("Running", States::Running)
+            .with_state("Stopped", States::Stopped)
+            .with_event("Start", Events::Start)
+            .with_predicate("is_forced_stop", |d| matches!(d.event, Events::Stop { force: true }))
+            .with_effect("fail", |_| Err("effect failed".into()))
+            .with_example_data("none", ())
+    }
+
+    #[test]
+    fn test_json_definition() {
+        let definition = MachineDefinition::from_json(r#"{
+            "transitions": [
+                { "from": "Idle", "to": "Running", "event": "Start" },
+                { "name": "force_stop", "from": ["Idle", "Running"], "to": "Stopped", "predicate": "is_forced_stop" },
+                { "from": { "none_of": ["Idle", "Running"] }, "to": "Idle", "event": "Start" }
+            ]
+        }"#).expect("invalid definition");
+
+        let mut sm = registry().locked_factory(&definition).expect("invalid definition").build(States::Idle, ());
+        assert_eq!(&States::Running, sm.handle_event(Events::Start).expect("unexpected error"));
+        assert_eq!(&States::Running, sm.handle_event(Events::Stop { force: false }).expect("unexpected error"));
+        assert_eq!(&States::Stopped, sm.handle_event(Events::Stop { force: true }).expect("unexpected error"));
+        assert_eq!(&States::Idle, sm.handle_event(Events::Start).expect("unexpected error"));
+    }
+
+    #[test]
+    fn test_yaml_definition_with_effect() {
+        let definition = MachineDefinition::from_yaml("
+transitions:
+  - from: '*'
+    event: Start
+    effect: fail
+").expect("invalid definition");
+
+        let mut sm = registry().locked_factory(&definition).expect("invalid definition").build(States::Running, ());
+        assert!(sm.handle_event(Events::Start).is_err());
+        assert_eq!(States::Running, sm.state);
+    }
+
+    #[test]
+    fn test_descriptions() {
+        let definition = MachineDefinition::from_toml(r#"
+            [[transitions]]
+            name = "start"
+            from = "Idle"
+            to = "Running"
+            event = "Start"
+            description = "Start the machine"
+            descriptions = { fr = "Démarrer la machine" }
+            doc = "Idle machines start on demand"
+
+            [states.Running]
+            description = "Running"
+            descriptions = { fr = "En marche" }
+        "#).expect("invalid definition");
+
+        let sm = registry().locked_factory(&definition).expect("invalid definition").build(States::Idle, ());
+        assert_eq!(Some("En marche"), sm.state_description(&States::Running, Some("fr-CA")));
+        assert_eq!(Some("Start the machine"), sm.transition_description("start", Some("de")));
+        assert_eq!(Some("Idle machines start on demand"), sm.transition_doc("start"));
+        let xstate = definition.to_xstate("machine", "Idle");
+        assert!(xstate.contains(r#""description": "Start the machine""#));
+        assert!(xstate.contains(r#""doc": "Idle machines start on demand""#));
+    }
+
+    #[test]
+    fn test_typescript() {
+        let definition = MachineDefinition::from_json(r#"{
+            "transitions": [
+                { "from": "Idle", "to": "Running", "event": "Start", "effect": "notify" },
+                { "from": "Running", "to": "Stopped", "predicate": "is_forced_stop" },
+                { "from": "*", "to": "Idle", "event": "Reset" }
+            ]
+        }"#).expect("invalid definition");
+
+        assert_eq!(r#"// Generated by statement, do not edit.
+
+export type State = "Idle" | "Running" | "Stopped";
+
+export const STATES: readonly State[] = ["Idle", "Running", "Stopped"];
+
+export type Event = "Start" | "Reset";
+
+export const EVENTS: readonly Event[] = ["Start", "Reset"];
+"#, definition.to_typescript());
+
+        let xstate = definition.to_xstate("machine", "Idle");
+        let json = xstate.split_once("createMachine(").and_then(|(_, rest)| rest.strip_suffix(");\n")).expect("unexpected output");
+        assert_eq!(serde_json::json!({
+            "id": "machine",
+            "initial": "Idle",
+            "on": { "Reset": [{ "target": "Idle" }] },
+            "states": {
+                "Idle": { "on": { "Start": [{ "target": "Running", "actions": ["notify"] }] } },
+                "Running": { "always": [{ "target": "Stopped", "guard": "is_forced_stop" }] },
+                "Stopped": {}
+            }
+        }), serde_json::from_str::<serde_json::Value>(json).expect("invalid JSON"));
+    }
+
+    #[test]
+    fn test_guard_examples() {
+        let definition = MachineDefinition::from_yaml("
+transitions:
+  - name: force_stop
+    from: Running
+    to: Stopped
+    predicate: is_forced_stop
+    examples:
+      - { from: Running, event: Start, data: none, expected: true }
+").expect("invalid definition");
+
+        match registry().locked_factory(&definition) {
+            Err(ConfigError::GuardExamplesFailed(failures)) => {
+                assert_eq!(Some("force_stop"), failures[0].transition.name.as_deref());
+                assert_eq!(Ok(false), failures[0].actual);
+            },
+            _ => panic!("expected the example to fail")
+        }
+    }
+
+    #[test]
+    fn test_unknown_names() {
+        let definition = MachineDefinition::from_toml(r#"
+            [[transitions]]
+            from = "Idle"
+            to = "Paused"
+        "#).expect("invalid definition");
+
+        match registry().locked_factory(&definition) {
+            Err(ConfigError::UnknownState(name)) => assert_eq!("Paused", name),
+            _ => panic!("expected an unknown state error")
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Many instances of State Machines, keyed by an ID, with Events routed to them.
+//!
+//! An [Ensemble] owns one [StateMachine] per key (one per connection, or per order, for example)
+//! and routes each `(key, event)` pair to the instance for that key, building the instance the
+//! first time an Event arrives for it. Instances are built by a function of the key, so they may
+//! come from different locked factories or start in different States. Events can also be
+//! broadcast to every instance.
+//!
+//! ```
+//! use statement::StateMachineFactory;
+//! use statement::ensemble::Ensemble;
+//!
+//! #[derive(Clone, Eq, PartialEq)]
+//! enum Event { Open, Close }
+//!
+//! #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+//! enum State { Idle, Open, Closed }
+//!
+//! let factory = StateMachineFactory::<Event, State, ()>::new()
+//!     .with_event_transition(&Event::Open, State::Idle, State::Open)
+//!     .with_event_transition(&Event::Close, State::Open, State::Closed)
+//!     .lock();
+//!
+//! let mut connections = Ensemble::new(|_: &u32| factory.build(State::Idle, ()));
+//! connections.handle_event(1, Event::Open).unwrap();
+//! connections.handle_event(2, Event::Open).unwrap();
+//! connections.broadcast(Event::Close).unwrap();
+//! assert_eq!(Some(&State::Closed), connections.state(&1));
+//! assert_eq!(None, connections.state(&3));
+//! ```
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use thiserror::Error;
+use crate::{StateMachine, StateMachineError};
+
+/// Builds the instance of an [Ensemble] for a key
+type InstanceBuilder<'a, TKey, TEvent, TState, TData, TErr> = Box<dyn Fn(&TKey) -> StateMachine<'a, TEvent, TState, TData, TErr> + 'a>;
+
+/// State Machine instances keyed by an ID, see the [ensemble](crate::ensemble) module.
+pub struct Ensemble<'a, TKey, TEvent, TState: PartialEq<TState> + Clone + Send + 'a, TData, TErr = Box<dyn std::error::Error>> {
+    instances: HashMap<TKey, StateMachine<'a, TEvent, TState, TData, TErr>>,
+    build: InstanceBuilder<'a, TKey, TEvent, TState, TData, TErr>,
+}
+
+/// An error returned by one instance of an [Ensemble]
+#[derive(Error, Debug)]
+#[error("error in instance {key:?}: {error:?}")]
+pub struct InstanceError<TKey, TState: Send + Clone + Eq + PartialEq, TErr = Box<dyn std::error::Error>> {
+    /// The key of the instance
+    pub key: TKey,
+    /// The error returned by the instance
+    pub error: StateMachineError<TState, TErr>,
+}
+
+impl <'a, TKey: Hash + Eq, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + PartialEq + 'a, TData, TErr> Ensemble<'a, TKey, TEvent, TState, TData, TErr> {
+    /// Creates an `Ensemble` with no instances, which builds the instance for a key with `build`
+    /// when the first Event for that key arrives.
+    pub fn new(build: impl Fn(&TKey) -> StateMachine<'a, TEvent, TState, TData, TErr> + 'a) -> Self {
+        Self {
+            instances: HashMap::new(),
+            build: Box::new(build),
+        }
+    }
+
+    /// Handles an Event in the instance for `key`, as [StateMachine::handle_event] would, building
+    /// the instance first if there is none yet. Returns the new State of the instance.
+    pub fn handle_event(&mut self, key: TKey, event: TEvent) -> Result<&TState, StateMachineError<TState, TErr>> {
+        let build = &self.build;
+        self.instances.entry(key)
+            .or_insert_with_key(|key| build(key))
+            .handle_event(event)
+    }
+
+    /// Handles an Event in every existing instance, in no particular order; no instances are
+    /// built. An instance that fails does not stop the Event from reaching the remaining
+    /// instances; the errors of all failing instances are returned together.
+    pub fn broadcast(&mut self, event: TEvent) -> Result<(), Vec<InstanceError<TKey, TState, TErr>>>
+    where TEvent: Clone, TKey: Clone
+    {
+        let errors: Vec<_> = self.instances.iter_mut()
+            .filter_map(|(key, sm)| sm.handle_event(event.clone()).err().map(|error| InstanceError { key: key.clone(), error }))
+            .collect();
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors)
+        }
+    }
+
+    /// Returns the current State of the instance for `key`, or None if there is no such instance.
+    pub fn state(&self, key: &TKey) -> Option<&TState> {
+        self.instances.get(key).map(|sm| &sm.state)
+    }
+
+    /// Returns the instance for `key`, or None if there is no such instance.
+    pub fn get(&self, key: &TKey) -> Option<&StateMachine<'a, TEvent, TState, TData, TErr>> {
+        self.instances.get(key)
+    }
+
+    /// Returns the instance for `key` mutably, or None if there is no such instance.
+    pub fn get_mut(&mut self, key: &TKey) -> Option<&mut StateMachine<'a, TEvent, TState, TData, TErr>> {
+        self.instances.get_mut(key)
+    }
+
+    /// Adds an instance built elsewhere (restored from a snapshot, for example), returning the
+    /// instance it replaces, if any.
+    pub fn insert(&mut self, key: TKey, sm: StateMachine<'a, TEvent, TState, TData, TErr>) -> Option<StateMachine<'a, TEvent, TState, TData, TErr>> {
+        self.instances.insert(key, sm)
+    }
+
+    /// Removes the instance for `key` (when its connection closes, for example) and returns it.
+    /// A later Event for `key` builds a new instance.
+    pub fn remove(&mut self, key: &TKey) -> Option<StateMachine<'a, TEvent, TState, TData, TErr>> {
+        self.instances.remove(key)
+    }
+
+    /// Returns the key and current State of every instance, in no particular order.
+    pub fn states(&self) -> impl Iterator<Item = (&TKey, &TState)> + '_ {
+        self.instances.iter().map(|(key, sm)| (key, &sm.state))
+    }
+
+    /// Returns the number of instances.
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    /// Returns true if there are no instances.
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{StateMachineError, StateMachineFactory, UnhandledEventPolicy};
+    use crate::ensemble::Ensemble;
+
+    #[derive(Clone, Eq, PartialEq, Debug)]
+    enum Events {
+        Connect,
+        Disconnect,
+        Shutdown
+    }
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum States {
+        Waiting,
+        Connected,
+        Closed
+    }
+
+    #[test]
+    fn test_ensemble() {
+        let clients = StateMachineFactory::<Events, States, u32>::new()
+            .with_event_transition(&Events::Connect, States::Waiting, States::Connected)
+            .with_event_transition(&Events::Disconnect, States::Connected, States::Waiting)
+            .with_event_transition(&Events::Shutdown, States::Connected, States::Closed)
+            .lock();
+        let admins = StateMachineFactory::<Events, States, u32>::new()
+            .with_event_transition(&Events::Connect, States::Waiting, States::Connected)
+            .unhandled_event_policy(UnhandledEventPolicy::Error)
+            .lock();
+
+        // Keys below 100 are administrators
+        let mut ensemble = Ensemble::new(|key: &u32| match *key {
+            key if key < 100 => admins.build(States::Waiting, key),
+            key => clients.build(States::Waiting, key)
+        });
+        assert!(ensemble.is_empty());
+
+        assert_eq!(&States::Connected, ensemble.handle_event(1, Events::Connect).expect("unexpected error"));
+        assert_eq!(&States::Connected, ensemble.handle_event(200, Events::Connect).expect("unexpected error"));
+        assert_eq!(&States::Connected, ensemble.handle_event(300, Events::Connect).expect("unexpected error"));
+        assert_eq!(&States::Waiting, ensemble.handle_event(300, Events::Disconnect).expect("unexpected error"));
+        assert_eq!(3, ensemble.len());
+        assert_eq!(Some(200), ensemble.get(&200).map(|sm| sm.data));
+
+        // Every instance sees a broadcast, and failures are collected
+        let errors = ensemble.broadcast(Events::Shutdown).expect_err("expected the administrator to fail");
+        assert_eq!(1, errors.len());
+        assert_eq!(1, errors[0].key);
+        assert!(matches!(errors[0].error, StateMachineError::UnhandledEvent(States::Connected)));
+        assert_eq!(Some(&States::Closed), ensemble.state(&200));
+        assert_eq!(Some(&States::Waiting), ensemble.state(&300));
+
+        // Removed instances are built again by the next Event
+        assert!(ensemble.remove(&200).is_some());
+        assert_eq!(None, ensemble.state(&200));
+        assert_eq!(&States::Waiting, ensemble.handle_event(200, Events::Disconnect).expect("unexpected error"));
+        let mut states: Vec<_> = ensemble.states().map(|(key, state)| (*key, *state)).collect();
+        states.sort_by_key(|(key, _)| *key);
+        assert_eq!(vec![(1, States::Connected), (200, States::Waiting), (300, States::Waiting)], states);
+    }
+}
@@ -0,0 +1,119 @@
+//! Combinators for building Predicates.
+//!
+//! Predicates passed to [StateMachineFactory::with_predicated_transition](crate::StateMachineFactory::with_predicated_transition)
+//! and its variants are closures over [StateTransitionEffectData]. The functions in this module
+//! build common checks, and the [Guard] trait combines any Predicates (including plain closures)
+//! with [Guard::and], [Guard::or] and [Guard::not], so a check used by several Transitions can be
+//! written once and composed rather than repeated inline.
+//!
+//! ```
+//! use statement::StateMachineFactory;
+//! use statement::guards::{self, Guard};
+//!
+//! #[derive(Clone, Eq, PartialEq)]
+//! enum Event { Withdraw, Deposit }
+//!
+//! #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+//! enum State { Open, Overdrawn }
+//!
+//! let overdrawing = guards::event_is(Event::Withdraw).and(guards::data(|balance: &i32| *balance < 0));
+//! let mut sm = StateMachineFactory::<Event, State, i32>::new()
+//!     .with_predicated_transition(State::Open, State::Overdrawn, overdrawing)
+//!     .with_predicated_transition(State::Overdrawn, State::Open, guards::event_is(Event::Deposit).and(guards::data(|balance: &i32| *balance < 0).not()))
+//!     .lock()
+//!     .build(State::Open, -5);
+//!
+//! sm.handle_event(Event::Deposit).unwrap();
+//! assert_eq!(State::Open, sm.state);
+//! sm.handle_event(Event::Withdraw).unwrap();
+//! assert_eq!(State::Overdrawn, sm.state);
+//! ```
+
+use crate::StateTransitionEffectData;
+
+/// A Predicate over [StateTransitionEffectData], see the [guards](crate::guards) module. Every
+/// closure with the signature of a Predicate is a `Guard`.
+pub trait Guard<TEvent, TState, TData>: Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> bool + Sized {
+    /// Returns a Guard that passes when both this Guard and `other` pass. `other` is only run if
+    /// this Guard passes.
+    fn and(self, other: impl Guard<TEvent, TState, TData>) -> impl Guard<TEvent, TState, TData> {
+        move |d: &StateTransitionEffectData<TEvent, TState, TData>| self(d) && other(d)
+    }
+
+    /// Returns a Guard that passes when this Guard or `other` passes. `other` is only run if this
+    /// Guard fails.
+    fn or(self, other: impl Guard<TEvent, TState, TData>) -> impl Guard<TEvent, TState, TData> {
+        move |d: &StateTransitionEffectData<TEvent, TState, TData>| self(d) || other(d)
+    }
+
+    /// Returns a Guard that passes when this Guard fails.
+    fn not(self) -> impl Guard<TEvent, TState, TData> {
+        move |d: &StateTransitionEffectData<TEvent, TState, TData>| !self(d)
+    }
+}
+
+impl <TEvent, TState, TData, F> Guard<TEvent, TState, TData> for F
+where F: Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> bool
+{
+}
+
+/// Returns a Guard that passes when the Event equals `event`.
+pub fn event_is<TEvent: PartialEq<TEvent>, TState, TData>(event: TEvent) -> impl Guard<TEvent, TState, TData> {
+    move |d: &StateTransitionEffectData<TEvent, TState, TData>| d.event == &event
+}
+
+/// Returns a Guard that passes when `check` passes for the Event.
+pub fn event<TEvent, TState, TData>(check: impl Fn(&TEvent) -> bool) -> impl Guard<TEvent, TState, TData> {
+    move |d: &StateTransitionEffectData<TEvent, TState, TData>| check(d.event)
+}
+
+/// Returns a Guard that passes when `check` passes for the Data of the State Machine.
+pub fn data<TEvent, TState, TData>(check: impl Fn(&TData) -> bool) -> impl Guard<TEvent, TState, TData> {
+    move |d: &StateTransitionEffectData<TEvent, TState, TData>| check(d.data)
+}
+
+/// Returns a Guard that passes for every Transition, as a starting point for folding a list of
+/// Guards together with [Guard::and].
+pub fn always<TEvent, TState, TData>() -> impl Guard<TEvent, TState, TData> {
+    |_: &StateTransitionEffectData<TEvent, TState, TData>| true
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::StateMachineFactory;
+    use crate::guards::{self, Guard};
+
+    #[derive(Clone, Eq, PartialEq, Debug)]
+    enum Events {
+        Ping,
+        Data(u32)
+    }
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum States {
+        Idle,
+        Busy
+    }
+
+    #[test]
+    fn test_guards() {
+        let large = || guards::event(|e: &Events| matches!(e, Events::Data(n) if *n > 10));
+        let enabled = || guards::data(|enabled: &bool| *enabled);
+        let factory = StateMachineFactory::<Events, States, bool>::new()
+            .with_predicated_transition(States::Idle, States::Busy, large().and(enabled()))
+            .with_predicated_transition(States::Busy, States::Idle, guards::event_is(Events::Ping).or(enabled().not()))
+            .with_predicated_transition(States::Idle, States::Idle, guards::always().and(guards::event_is(Events::Ping)))
+            .lock();
+
+        let mut sm = factory.build(States::Idle, true);
+        assert_eq!(&States::Idle, sm.handle_event(Events::Data(5)).expect("unexpected error"));
+        assert_eq!(&States::Busy, sm.handle_event(Events::Data(11)).expect("unexpected error"));
+        assert_eq!(&States::Busy, sm.handle_event(Events::Data(11)).expect("unexpected error"));
+        assert_eq!(&States::Idle, sm.handle_event(Events::Ping).expect("unexpected error"));
+
+        // Disabled machines drop straight back to Idle
+        let mut sm = factory.build(States::Busy, false);
+        assert_eq!(&States::Idle, sm.handle_event(Events::Data(11)).expect("unexpected error"));
+        assert_eq!(&States::Idle, sm.handle_event(Events::Data(11)).expect("unexpected error"));
+    }
+}
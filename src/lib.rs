@@ -21,21 +21,32 @@
 //! 2. Add transitions using one or more of:
 //!     - [StateMachineFactory::with_predicated_transition]
 //!     - [StateMachineFactory::with_predicated_transition_effect]
+//!     - [StateMachineFactory::with_fallible_predicated_transition]
+//!     - [StateMachineFactory::with_fallible_predicated_transition_effect]
 //!     - [StateMachineFactory::with_event_transition]
 //!     - [StateMachineFactory::with_event_transition_effect]
+//!     - [StateMachineFactory::with_event_kind_transition]
+//!     - [StateMachineFactory::with_event_kind_transition_effect]
+//!     - [StateMachineFactory::with_extracted_event_transition]
 //!     - [StateMachineFactory::with_auto_transition]
 //!     - [StateMachineFactory::with_custom_transition]
 //! 3. Lock your factory into a [LockedStateMachineFactory] by calling [StateMachineFactory::lock]
 //! 4. Create a state machine by calling [LockedStateMachineFactory::build]
 //!
+//! Predicates, Effects and other callbacks are not required to be `Send`, so a State Machine that
+//! lives on a single thread can use `Rc<RefCell<..>>` Data or capture handles that cannot leave
+//! their thread (such as GUI widgets). To run a State Machine on another thread, see the [runner]
+//! module.
+//!
 //! # Transitions
 //!
 //! Transitions (represented by the [StateMachineTransition] struct) must specify the State or set
 //! of initial states (as a [FromState]) that may trigger them:
 //! - [FromState::Any]: Any starting state - this Transition will be evaluated for all events.
 //! - [FromState::AnyOf]: Any starting state in the provided list.
+//! - [FromState::NoneOf]: Any starting state except those in the provided list.
 //! - [FromState::From]: The specific provided started state. FromState implements [From] for this
-//! variant, so the variant can be elided for the common case.
+//!   variant, so the variant can be elided for the common case.
 //!
 //! Transitions may also optionally provide a predicate to apply custom logic to decide whether the
 //! Transition is applied. Transitions may also be triggered from any ([FromState::Any]) state,
@@ -44,40 +55,257 @@
 //! Transitions must also describe the state that they transition the State Machine into. The to_state
 //! of a transition can be represented as one of the following:
 //! - [To]: A specific, pre-defined state. ToState implements [From] for this variant, so the variant
-//! can be elided for the common case.
+//!   can be elided for the common case.
 //! - [Same]: Whatever state the transition started from; this makes the transition a no-op for the
-//! state machine, but side effects may still be executed. This is useful in some cases, such as in
-//! transition loggers.
+//!   state machine, but side effects may still be executed. This is useful in some cases, such as in
+//!   transition loggers.
+//! - [SelfExternal]: Leaves the state and enters it again, so that timeouts restart and state
+//!   contexts are recreated as on any change of state, while [Same] stays in the state without
+//!   leaving it.
+//! - [Push] and [Pop]: Enter a state while remembering the current one on a stack, and return to
+//!   the most recently remembered state. This gives pushdown automaton semantics, useful for
+//!   nested modes and parsing-style machines.
 //! - [Calc]: Allows for dynamic target state calculation, when a given transition may result in
-//! more than one target states. This is something of an antipattern; these should preferentially
-//! be represented as multiple transitions with different predicates.
+//!   more than one target states. This is something of an antipattern; these should preferentially
+//!   be represented as multiple transitions with different predicates.
 //!
 //! # Event Lifecycle
 //!
-//! 1. Handle event called.
-//! 2. For each defined transition:
+//! 1. Handle event called. Any event interceptors (see
+//!    [StateMachineFactory::with_event_interceptor]) run first, and may replace or swallow the
+//!    Event. If the Event fails a pre-condition (see
+//!    [StateMachineFactory::with_precondition]), it is refused and handling ends. If the Event is
+//!    deferred in the current state (see [StateMachineFactory::with_deferred_event]), it is queued
+//!    and handling ends. Otherwise, if an event enricher is set (see
+//!    [StateMachineFactory::with_event_enricher]), it is applied to the Event.
+//! 2. For each defined transition, in descending priority order (see
+//!    [StateMachineFactory::with_priority]) and then definition order:
+//!
+//!    2a. Determine if the from_state of the transition matches the current state, and that the
+//!    transition is not disabled (see [StateMachineFactory::with_circuit_breaker]).
+//!    If false, break and move on to the next transition.
 //!
-//!     2a. Determine if the from_state of the transition matches the current state.
-//!         If false, break and move on to the next transition.
+//!    2b. Run the transition's predicate, if any.
+//!    If false, break and move on to the next transition.
 //!
-//!     2b. Determine the to_state of the transition.
+//!    2c. Determine the to_state of the transition, calculating it if it is a [Calc].
 //!
-//!     2c. Run the transition's predicate, if any.
-//!         If false (or no predicate), break and move on to the next transition.
+//!    2d. Run the transition's effect, if any. If it fails, apply the [EffectErrorPolicy];
+//!    otherwise apply the [EffectOutcome] it returned (see
+//!    [StateMachineFactory::with_directed_effect]).
 //!
-//!     2d. Run the transition's effect, if any.
+//!    2e. Transition the state machine to the to_state determined in 2c above. If this is a final
+//!    state (see [StateMachineFactory::with_final_states]), stop handling the event and any
+//!    emitted events.
 //!
-//!     2e. Transition the state machine to the to_state determined in 2b above.
+//!    2f. If the [EvaluationStrategy] is FirstMatch, stop evaluating transitions for this pass.
 //!
 //! 3. If the State Machine has cycle set to true, return to 2.
 //!
+//! 4. If no transition matched the event in 2b, apply the [UnhandledEventPolicy].
+//!
+//! 5. If the state changed and Events are deferred, redeliver each of them in order, starting
+//!    again at 1.
+//!
+//! 6. If any Effects emitted Events with [StateTransitionEffectData::emit], handle each of them in
+//!    order, starting again at 1.
+//!
+//! 7. Check the resulting State and Data against the post-conditions (see
+//!    [StateMachineFactory::with_postcondition]).
+//!
+//! # Reusable Transition Fragments
+//!
+//! Effects and Predicates only see `TData` through the bounds placed on it, so Transitions can be
+//! written against capability traits rather than a concrete Data type. A function that adds such
+//! Transitions to a factory (a *fragment*) can then be applied with
+//! [StateMachineFactory::with_fragment] to any State Machine whose Data implements those traits:
+//!
+//! ```
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//! use statement::StateMachineFactory;
+//!
+//! #[derive(Eq, PartialEq)]
+//! enum Event { AddItem, Checkout }
+//!
+//! #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+//! enum State { Shopping, CheckedOut }
+//!
+//! trait HasCart { fn items(&self) -> &AtomicUsize; }
+//! trait HasUser { fn user_name(&self) -> &str; }
+//!
+//! // This fragment works with any Data that has a cart and a user
+//! fn cart_transitions<'a, TData: HasCart + HasUser>(
+//!     factory: StateMachineFactory<'a, Event, State, TData>
+//! ) -> StateMachineFactory<'a, Event, State, TData> {
+//!     factory
+//!         .with_event_transition_effect(&Event::AddItem, State::Shopping, State::Shopping, |d| {
+//!             d.data.items().fetch_add(1, Ordering::SeqCst);
+//!             Ok(())
+//!         })
+//!         .with_predicated_transition(State::Shopping, State::CheckedOut, |d| {
+//!             *d.event == Event::Checkout && d.data.items().load(Ordering::SeqCst) > 0
+//!         })
+//! }
+//!
+//! struct WebSession { items: AtomicUsize, login: String }
+//! impl HasCart for WebSession { fn items(&self) -> &AtomicUsize { &self.items } }
+//! impl HasUser for WebSession { fn user_name(&self) -> &str { &self.login } }
+//!
+//! let mut sm = StateMachineFactory::new()
+//!     .with_fragment(cart_transitions)
+//!     .lock()
+//!     .build(State::Shopping, WebSession { items: AtomicUsize::new(0), login: "ada".into() });
+//!
+//! sm.handle_event(Event::AddItem).unwrap();
+//! sm.handle_event(Event::Checkout).unwrap();
+//! assert_eq!(State::CheckedOut, sm.state);
+//! ```
+//!
+//! Fragments can also be added to a State Machine that is already running with
+//! [StateMachine::add_transitions], keeping its State and Data, and named Transitions can be
+//! switched off and on with [StateMachine::disable_transition] and
+//! [StateMachine::enable_transition].
+//!
+//! # Guards
+//!
+//! The [guards] module builds Predicates from reusable parts, such as [guards::event_is] and
+//! [guards::data], combined with [guards::Guard::and], [guards::Guard::or] and
+//! [guards::Guard::not].
+//!
+//! # Declarative Definitions
+//!
+//! The [state_machine!] macro builds a factory from a compact table of Transitions, with every
+//! State and Event checked by the compiler.
+//!
+//! With the `config` feature enabled, the [config] module can load Transitions from JSON, TOML, or
+//! YAML definitions that refer to States, Events, Predicates, and Effects by name. With the
+//! `scxml` feature enabled, the [scxml] module reads definitions from a subset of SCXML, and
+//! renders factories back to SCXML.
+//!
+//! # Snapshots
+//!
+//! The State and Data of a State Machine can be captured with [StateMachine::snapshot] and later
+//! turned back into a State Machine with [LockedStateMachineFactory::restore]. Enable the `serde`
+//! feature to serialize [Snapshot]s.
+//!
+//! # Validation
+//!
+//! [StateMachineFactory::validate] checks a definition for unreachable States, dead ends,
+//! Transitions that can never execute, ambiguous Transitions, and references to States outside
+//! the complete set declared with [StateMachineFactory::with_states], without running any
+//! Predicates or Effects. See the [validation] module. [StateMachineFactory::lock_validated] also
+//! runs the examples attached to Predicates with [StateMachineFactory::with_guard_example].
+//!
+//! # Analysis
+//!
+//! [StateMachineFactory::analyze] reports graph metrics (fan-in and fan-out per State, cyclomatic
+//! complexity, longest path, and strongly connected components) that can be checked in CI. See
+//! the [analysis] module.
+//!
+//! # Typestate Code Generation
+//!
+//! [StateMachineFactory::typestate_source] renders Rust source for zero-sized typestate wrapper
+//! types (`Machine<Idle>::start() -> Machine<Running>`) from a factory, typically from a build
+//! script, so compile-time-checked APIs share their definition with the runtime State Machine. See
+//! the [codegen] module.
+//!
+//! # Orthogonal Regions
+//!
+//! The [regions] module provides [regions::ParallelStateMachine], which runs several independent
+//! regions, each with its own State, over one shared set of Data and dispatches every Event to
+//! all of them.
+//!
+//! # Ensembles
+//!
+//! The [ensemble] module provides [ensemble::Ensemble], which owns one State Machine instance per
+//! key, builds instances as Events first arrive for them, and routes or broadcasts Events to them.
+//!
+//! # Running on a Thread
+//!
+//! The [runner] module provides [runner::StateMachineRunner], which owns a State Machine on a
+//! dedicated thread, handles Events sent to it over a channel, and publishes its State to
+//! watchers.
+//!
+//! # Persistence
+//!
+//! The [persist] module stores the Events handled by a State Machine in a [persist::EventStore]
+//! rather than the State Machine itself. [LockedStateMachineFactory::rehydrate] rebuilds the State
+//! Machine by replaying them, optionally without running Effects a second time.
+//!
+//! # Testing
+//!
+//! The [testing] module provides [testing::scenario], a small DSL for driving a State Machine
+//! through a sequence of Events and checking the States and Transitions that result, and
+//! [testing::soak], which runs a State Machine for many random Events and checks that its
+//! bookkeeping stays bounded, and [testing::replay_diff], which replays an Event log against two
+//! versions of a definition and reports where they first disagree. A
+//! [testing::MachineTestHarness] drives a State Machine through scripted Event sequences and
+//! reports which Transitions were never exercised, and [testing::explore] and
+//! [testing::random_walk] search for reachable States, dead ends and panicking Effects.
+//!
+//! # Simulation
+//!
+//! Transitions given a weight with [StateMachineFactory::with_weight] compete: each pass, one of
+//! the matching weighted Transitions is chosen at random in proportion to its weight. Random
+//! numbers come from a [RandomSource] set with [StateMachineFactory::with_random], so Monte-Carlo
+//! simulations can be seeded and reproduced. The `rand` feature makes every `rand` random number
+//! generator a [RandomSource].
+//!
+//! # Timeouts
+//!
+//! Transitions added with [StateMachineFactory::with_timeout_transition] are taken when the State
+//! Machine has stayed in a State for too long, rather than in response to an Event. The State
+//! Machine does not run a timer of its own: call [StateMachine::tick] with the current time,
+//! either periodically or at the instant returned by [StateMachine::next_deadline].
+//! [StateMachineFactory::with_every_transition] runs an Effect periodically for as long as the
+//! State Machine stays in a State.
+//!
+//! State Machines read the current time from a [Clock], the system clock unless another is set
+//! with [StateMachineFactory::with_clock]. A [MockClock] is only advanced when told to, so tests
+//! can call [StateMachine::poll] after advancing it instead of waiting.
+//!
+//! # State Context
+//!
+//! [StateMachineFactory::with_state_context] attaches typed data to some States, such as the
+//! number of attempts made while connecting. It is created when the State Machine enters one of
+//! those States, read by Predicates and Effects while it stays there, and dropped when it leaves.
+//!
+//! # Tracing
+//!
+//! With the `tracing` feature enabled, [StateMachineFactory::with_tracing] opens a span for every
+//! Event handled and records an event for every Transition considered, including the result of its
+//! Predicate and Effect.
+//!
 #![deny(missing_docs)]
 
+pub mod analysis;
+pub mod codegen;
+#[cfg(feature = "config")]
+pub mod config;
+pub mod ensemble;
+pub mod guards;
+mod macros;
+pub mod persist;
+pub mod regions;
+pub mod runner;
+#[cfg(feature = "scxml")]
+pub mod scxml;
+pub mod testing;
+pub mod validation;
+
+use std::any::{Any, TypeId};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fmt::{Debug};
+use std::hash::Hash;
 use std::ops::Deref;
-use std::sync::Arc;
-use thiserror::Error;
-use crate::ToState::{Calc, Same, To};
+use std::rc::Rc;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant, SystemTime};
+use crate::ToState::{Calc, History, Pop, Push, Same, SelfExternal, To};
+use crate::validation::TransitionId;
 
 /// State Machine instance, usually created by calling create on a [LockedStateMachineFactory]
 #[derive(Default, Clone)]
@@ -88,6 +316,11 @@ pub struct StateMachine<'a, TEvent, TState: PartialEq<TState> + Clone + Send + '
     /// All of the transitions that are valid for this state machine. Note that this list may be
     /// shared with other state machine instances.
     pub transitions: Arc<Vec<StateMachineTransition<'a, TEvent, TState, TData, TErr>>>,
+    /// Transitions added with [StateMachine::add_transitions], evaluated after `transitions`. They
+    /// are owned by this instance rather than shared.
+    added_transitions: Vec<Arc<StateMachineTransition<'a, TEvent, TState, TData, TErr>>>,
+    /// Indices of the Transitions disabled with [StateMachine::disable_transition]
+    disabled_transitions: Vec<usize>,
     /// Data associated with this state machine instance. This may be used to track information that
     /// cannot be expressed conveniently in Events, or it may be data which Side Effects act on. In
     /// the latter case, `TData` may need to implement interior mutability.
@@ -95,6 +328,69 @@ pub struct StateMachine<'a, TEvent, TState: PartialEq<TState> + Clone + Send + '
     /// True if this state machine automatically re-runs evaluation after a transition, potentially
     /// executing multiple state transitions for one event.
     pub cycle: bool,
+    /// The maximum number of times evaluation may loop back for a single Event when `cycle` is
+    /// true, or None for no limit.
+    pub max_cycles: Option<usize>,
+    /// Determines what happens when an Event matches no Transition.
+    pub unhandled_event_policy: UnhandledEventPolicy<'a, TEvent, TState, TData>,
+    /// Optional enrichment step applied to every Event before any Transition is evaluated.
+    pub event_enricher: Option<EventEnricher<'a, TEvent, TData>>,
+    interceptors: Arc<Vec<EventInterceptor<'a, TEvent, TState, TData>>>,
+    /// Determines whether every matching Transition or only the first one executes per pass.
+    pub evaluation_strategy: EvaluationStrategy,
+    /// Determines what happens when an Effect fails, see [StateMachineFactory::effect_error_policy].
+    pub effect_error_policy: EffectErrorPolicy,
+    /// True if an Event for which more than one Transition would change the State is refused,
+    /// see [StateMachineFactory::strict].
+    pub strict: bool,
+    format_event: Option<fn(&TEvent) -> String>,
+    /// Optional custom equivalence used to match the current State against the from_state of
+    /// Transitions, instead of `PartialEq`.
+    pub state_equivalence: Option<StateEquivalence<'a, TState>>,
+    /// Named parameters declared on the factory (with any per-build overrides applied), available
+    /// to Predicates and Effects.
+    pub parameters: Arc<Parameters>,
+    /// States in which the State Machine is complete, see [StateMachineFactory::with_final_states].
+    pub final_states: Arc<Vec<TState>>,
+    state_descriptions: Arc<Vec<(TState, Description)>>,
+    computed_views: Arc<Vec<ComputedView<'a, TState, TData>>>,
+    timeouts: Arc<Vec<TimeoutTransition<'a, TState, TData, TErr>>>,
+    intervals: Arc<Vec<IntervalTransition<'a, TState, TData, TErr>>>,
+    /// How often each interval Transition has fired, and since which entry into the current State
+    interval_counts: Vec<(Instant, u32)>,
+    deferrals: Arc<Vec<Deferral<'a, TEvent, TState>>>,
+    preconditions: Arc<Vec<Precondition<'a, TEvent, TState, TData>>>,
+    postconditions: Arc<Vec<Postcondition<'a, TState, TData>>>,
+    context_definitions: Arc<Vec<StateContextDefinition<'a, TState, TData>>>,
+    contexts: Vec<StateContext>,
+    deferred: VecDeque<DeferredEvent<TEvent>>,
+    /// The number of Events deferred so far, which orders them by arrival
+    deferred_count: u64,
+    state_entered_at: EnteredAt,
+    epoch: Epoch,
+    clock: Option<SharedClock<'a>>,
+    random: Option<SharedRandom>,
+    last_tick: Option<Instant>,
+    stack: Vec<TState>,
+    history_states: Vec<Option<TState>>,
+    /// The number of external self-transitions executed, see [ToState::SelfExternal]
+    reentries: usize,
+    last_cause: Option<TEvent>,
+    fired: Vec<usize>,
+    history: Option<TransitionHistory<TEvent, TState>>,
+    cause_recorder: Option<CauseRecorder<TEvent>>,
+    #[cfg(feature = "tracing")]
+    tracer: Option<Tracer<TEvent, TState>>,
+    failure_tracker: Option<FailureTracker<'a, TState, TErr>>,
+    progress_observer: Option<ProgressObserver<'a, TState>>,
+    metrics: Option<Metrics<'a, TState>>,
+    transition_index: Option<Arc<dyn TransitionIndex<TState> + 'a>>,
+    event_index: Option<Arc<EventIndex<TEvent>>>,
+    failure_injector: FailureInjector<'a, TErr>,
+    /// True if Effects are skipped, as if they had succeeded, see [testing::replay_diff]
+    pure: bool,
+    state_publisher: StatePublisher<TState>,
+    extracted: ExtractedPayload,
 }
 
 impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + PartialEq + 'a, TData, TErr> StateMachine<'a, TEvent, TState, TData, TErr>
@@ -102,9 +398,57 @@ impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + PartialEq + 'a
     fn new(cycle: bool, initial_state: TState, initial_data: TData) -> Self {
         Self {
             cycle,
+            max_cycles: None,
             state: initial_state,
             data: initial_data,
             transitions: Arc::new(Vec::new()),
+            added_transitions: Vec::new(),
+            disabled_transitions: Vec::new(),
+            unhandled_event_policy: UnhandledEventPolicy::Ignore,
+            event_enricher: None,
+            interceptors: Arc::new(Vec::new()),
+            evaluation_strategy: EvaluationStrategy::AllMatches,
+            effect_error_policy: EffectErrorPolicy::AbortAndKeep,
+            strict: false,
+            format_event: None,
+            state_equivalence: None,
+            parameters: Arc::new(Parameters::new()),
+            final_states: Arc::new(Vec::new()),
+            state_descriptions: Arc::new(Vec::new()),
+            computed_views: Arc::new(Vec::new()),
+            timeouts: Arc::new(Vec::new()),
+            intervals: Arc::new(Vec::new()),
+            interval_counts: Vec::new(),
+            deferrals: Arc::new(Vec::new()),
+            preconditions: Arc::new(Vec::new()),
+            postconditions: Arc::new(Vec::new()),
+            context_definitions: Arc::new(Vec::new()),
+            contexts: Vec::new(),
+            deferred: VecDeque::new(),
+            deferred_count: 0,
+            state_entered_at: EnteredAt::default(),
+            epoch: Epoch::default(),
+            clock: None,
+            random: None,
+            last_tick: None,
+            stack: Vec::new(),
+            history_states: Vec::new(),
+            reentries: 0,
+            last_cause: None,
+            fired: Vec::new(),
+            history: None,
+            cause_recorder: None,
+            #[cfg(feature = "tracing")]
+            tracer: None,
+            failure_tracker: None,
+            progress_observer: None,
+            metrics: None,
+            transition_index: None,
+            event_index: None,
+            failure_injector: FailureInjector::new(Arc::new(Vec::new())),
+            pure: false,
+            state_publisher: StatePublisher::default(),
+            extracted: ExtractedPayload::default(),
         }
     }
 
@@ -114,530 +458,5809 @@ impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + PartialEq + 'a
         self
     }
 
-    /// Handles an Event, causing the state machine to execute one or more Transitions.
+    /// Handles an Event, causing the state machine to execute one or more Transitions. Any Events
+    /// emitted by Effects (see [StateTransitionEffectData::emit]) are handled in FIFO order before
+    /// this method returns.
+    ///
+    /// States are only cloned for Transitions that execute, so Transitions whose from_state or
+    /// Predicate does not match cost no clone even for States that are not `Copy`.
+    /// [ToState::Calc] callbacks only run once the Predicate has passed.
     pub fn handle_event(&mut self, event: TEvent) -> Result<&TState, StateMachineError<TState, TErr>> {
-        loop {
-            let mut transition_occurred = false;
-            for transition in self.transitions.deref() {
+        let started = self.metrics.is_some().then(Instant::now);
+        let result = self.process_event(event);
+        if let (Some(metrics), Some(started)) = (&self.metrics, started) {
+            metrics.event_handled(started.elapsed(), result.is_ok());
+        }
+        self.state_publisher.publish(&self.state);
+        result.map(|_| &self.state)
+    }
 
-                // Determine if the current state matches the from_state of the transition
-                let from_state_matches = match &transition.from_state {
-                    FromState::Any => true,
-                    FromState::AnyOf(states) => states.iter().any(|s|s == &self.state),
-                    FromState::From(state) => state == &self.state
-                };
+    /// Handles an Event for [StateMachine::handle_event], which reports the result to the metrics
+    /// and publishes the State however this returns
+    fn process_event(&mut self, event: TEvent) -> Result<(), StateMachineError<TState, TErr>> {
+        self.fired.clear();
+        if self.is_complete() {
+            return Err(StateMachineError::MachineCompleted(self.state.clone()));
+        }
+        let Some(event) = self.intercept(event) else {
+            return Ok(());
+        };
+        self.check_preconditions(&event)?;
+        let checkpoint = (self.effect_error_policy == EffectErrorPolicy::AbortAndRollback).then(|| self.checkpoint());
+        let mut effect_errors = Vec::new();
+        let mut redelivered = Vec::new();
+        let mut result = self.evaluate_events(event, &mut effect_errors, checkpoint.is_some().then_some(&mut redelivered));
+        if result.is_ok() && !effect_errors.is_empty() {
+            result = Err(StateMachineError::EffectErrors { state: self.state.clone(), errors: effect_errors });
+        }
+        if result.is_ok() {
+            if let Some(postcondition) = self.postconditions.iter().find(|postcondition| !(postcondition.check)(&self.state, &self.data)) {
+                result = Err(StateMachineError::PostconditionFailed { name: postcondition.name.clone(), state: self.state.clone() });
+            }
+        }
+        if let (Err(_), Some(checkpoint)) = (&result, checkpoint) {
+            self.roll_back(checkpoint, redelivered);
+        }
+        result
+    }
 
-                // If the from_state matches, we need to consider whether this transition should execute
-                if from_state_matches {
-
-                    // Determine the result state and whether we need to proceed after this transition
-                    // If proceed is true OR this transition changes the state, we will continue to
-                    // evaluate further transitions after executing this one.
-                    let to_state = match &transition.get_to_state {
-                        To(to_state) => to_state.clone(),
-                        Calc(get_to_state) => {
-                            let data = StateTransitionToStateData {
-                                data: &mut self.data,
-                                event: &event,
-                                from: &self.state,
-                            };
-                            get_to_state.deref()(data)
-                        },
-                        Same => self.state.clone()
-                    };
+    /// Captures what [EffectErrorPolicy::AbortAndRollback] restores if handling an Event fails
+    fn checkpoint(&self) -> Checkpoint<TEvent, TState> {
+        Checkpoint {
+            state: self.state.clone(),
+            stack: self.stack.clone(),
+            history_states: self.history_states.clone(),
+            state_entered_at: self.state_entered_at,
+            last_cause: self.clone_last_cause(),
+            contexts: self.contexts.clone(),
+            reentries: self.reentries,
+            history: self.history.as_ref().map(|history| history.records.clone()),
+            deferred_count: self.deferred_count,
+        }
+    }
 
-                    // This sets up a data item to pass to the Predicate method (if any) and the
-                    // Effect method (if any)
-                    let transition_effect_data = StateTransitionEffectData {
-                        name: &transition.name,
-                        data: &mut self.data,
-                        event: &event,
-                        from: &self.state,
-                        to: &to_state
-                    };
+    /// Undoes the changes made since a checkpoint was captured. Transitions executed since then
+    /// are no longer reported by [StateMachine::fired_transitions]. Deferred Events that were
+    /// redelivered since then are queued again, and Events deferred since then are dropped.
+    fn roll_back(&mut self, checkpoint: Checkpoint<TEvent, TState>, redelivered: Vec<DeferredEvent<TEvent>>) {
+        self.state = checkpoint.state;
+        self.stack = checkpoint.stack;
+        self.history_states = checkpoint.history_states;
+        self.state_entered_at = checkpoint.state_entered_at;
+        self.last_cause = checkpoint.last_cause;
+        self.contexts = checkpoint.contexts;
+        self.reentries = checkpoint.reentries;
+        if let (Some(history), Some(records)) = (&mut self.history, checkpoint.history) {
+            history.records = records;
+        }
+        self.fired.clear();
+        let mut deferred: Vec<_> = redelivered.into_iter().chain(self.deferred.drain(..))
+            .filter(|deferred| deferred.sequence < checkpoint.deferred_count)
+            .collect();
+        deferred.sort_by_key(|deferred| deferred.sequence);
+        self.deferred = deferred.into();
+    }
 
-                    // If there is a Predicate on this Transition, execute it and if it returns
-                    // false, skip to the next Transition
-                    if let Some(predicate) = &transition.event_predicate {
-                        if !predicate(&transition_effect_data) {
-                            continue;
-                        }
-                    }
+    /// Runs the interceptors over an Event, returning the Event to handle or None if it was
+    /// swallowed, see [StateMachineFactory::with_event_interceptor]
+    fn intercept(&mut self, mut event: TEvent) -> Option<TEvent> {
+        for interceptor in self.interceptors.iter() {
+            match interceptor(&event, &self.state, &mut self.data) {
+                EventAction::Pass => {}
+                EventAction::Transform(transformed) => event = transformed,
+                EventAction::Swallow => return None
+            }
+        }
+        Some(event)
+    }
+
+    /// Handles a sequence of Events in order, as [StateMachine::handle_event] would, stopping at
+    /// the first Event that fails. Returns the final State, or [StateMachineError::EventFailed]
+    /// identifying the failing Event and the State the State Machine was left in, which makes
+    /// replaying recorded Event logs a single call.
+    pub fn handle_events(&mut self, events: impl IntoIterator<Item = TEvent>) -> Result<&TState, StateMachineError<TState, TErr>> {
+        for (index, event) in events.into_iter().enumerate() {
+            if let Some(error) = self.handle_event(event).err() {
+                return Err(StateMachineError::EventFailed {
+                    index,
+                    state: self.state.clone(),
+                    error: Box::new(error)
+                });
+            }
+        }
+        Ok(&self.state)
+    }
+
+    /// Evaluates an Event as [StateMachine::handle_event] would, but without running any Effects or
+    /// changing the State Machine, and returns the Transitions that would execute and the State the
+    /// State Machine would end up in. This answers questions like "can the user do X right now?"
+    /// without cloning the State Machine and its Data.
+    ///
+    /// Calculated to_states and Predicates are run, so they should be free of side effects.
+    /// Event interceptors (see [StateMachineFactory::with_event_interceptor]) are not run, as they
+    /// may change the Data, so an Event that an interceptor would swallow or replace is previewed
+    /// as it was passed in. Events emitted by Effects, deferred Events, injected failures and
+    /// post-conditions are not considered, and the [UnhandledEventPolicy] is not applied: an Event
+    /// that no Transition matches returns no Transitions. Pre-conditions are checked.
+    pub fn peek_event(&self, event: TEvent) -> Result<EventPreview<TState>, StateMachineError<TState, TErr>> {
+        if self.is_complete() {
+            return Err(StateMachineError::MachineCompleted(self.state.clone()));
+        }
+        self.check_preconditions(&event)?;
+        if self.is_deferred(&event) {
+            return Ok(EventPreview { transitions: Vec::new(), state: self.state.clone() });
+        }
+        let event = match &self.event_enricher {
+            Some(enricher) => enricher(event, &self.data),
+            None => event
+        };
+        let emitted = RefCell::new(VecDeque::new());
+        let now = self.now();
+        let mut state = self.state.clone();
+        let mut stack = self.stack.clone();
+        let mut history_states = self.history_states.clone();
+        let mut transitions = Vec::new();
+        let mut cycles = 0;
+        loop {
+            if self.strict {
+                self.check_ambiguity(&state, &stack, &history_states, &event, &emitted)?;
+            }
+            let mut transition_occurred = false;
+            let mut position = 0;
+            while let Some(index) = self.next_candidate(&state, &event, position) {
+                position = index + 1;
+                let transition = transition_at(&self.transitions, &self.added_transitions, index);
+                if !self.is_candidate(index, &state, now) || transition.weight.is_some() {
+                    continue;
+                }
 
-                    // If there is an Effect on this Transition, execute it
-                    if let Some(effect) = &transition.effect {
-                        effect(transition_effect_data)
-                            .map_err(|e| StateMachineError::EffectError(self.state.clone(), to_state.clone(), e))?;
+                let target = provisional_to_state(transition, index, &state, &stack, &history_states);
+                if let Some(predicate) = &transition.event_predicate {
+                    match predicate.evaluate(&self.transition_effect_data(transition, &event, &state, target, &emitted)) {
+                        Ok(true) => {}
+                        Ok(false) => continue,
+                        Err(e) => return Err(StateMachineError::PredicateError(state.clone(), target.clone(), e))
                     }
+                }
+                let to_state = self.to_state(transition, &event, &state, target).into_owned();
 
-                    // If proceed is false or we changed state, mark transition_occurred as true so
-                    // that we evaluate all of the transitions again.
-                    if &self.state != &to_state {
-                        self.state = to_state;
-                        transition_occurred = true;
+                match &transition.get_to_state {
+                    Pop if stack.is_empty() => return Err(StateMachineError::EmptyStack(state)),
+                    Pop => { stack.pop(); },
+                    Push(_) => stack.push(state.clone()),
+                    _ => {}
+                }
+                transitions.push(TransitionId { index, name: transition.name.clone() });
+
+                if state != to_state || matches!(transition.get_to_state, SelfExternal) {
+                    record_departure(&self.transitions, &self.added_transitions, &mut history_states, &state);
+                    state = to_state;
+                    transition_occurred = true;
+                    if self.final_states.contains(&state) {
+                        return Ok(EventPreview { transitions, state });
                     }
                 }
+
+                if self.evaluation_strategy == EvaluationStrategy::FirstMatch {
+                    break;
+                }
             }
 
-            // If no transition occurred, we can end evaluation
             if !self.cycle || !transition_occurred {
                 break;
             }
+            if self.max_cycles.is_some_and(|max_cycles| cycles >= max_cycles) {
+                return Err(StateMachineError::CycleLimitExceeded { state, cycles });
+            }
+            cycles += 1;
         }
-        Ok(&self.state)
+        Ok(EventPreview { transitions, state })
     }
-}
-
-/// Locked Factory for StateMachines. This struct is created by calling .lock() on a
-/// StateMachineFactory, usually after defining all transitions needed.
-pub struct LockedStateMachineFactory<'a, TEvent, TState: PartialEq<TState> + Clone + Send + 'a, TData = (), TErr = Box<dyn std::error::Error>> {
-    transitions: Arc<Vec<StateMachineTransition<'a, TEvent, TState, TData, TErr>>>,
-    cycle: bool,
-}
 
-impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + PartialEq + 'a, TData, TErr> LockedStateMachineFactory<'a, TEvent, TState, TData, TErr> {
-    /// Builds a StateMachine with a specified initial state and initial data.
-    pub fn build(&self, initial_state: TState, initial_data: TData) -> StateMachine<'a, TEvent, TState, TData, TErr> {
-        StateMachine::new(self.cycle, initial_state, initial_data).with_transitions(self.transitions.clone())
+    /// Returns a [StateReader] that can read the State last committed by
+    /// [StateMachine::handle_event] from other threads, without waiting for an in-progress
+    /// [StateMachine::handle_event] call (and its Effects) to complete.
+    pub fn state_reader(&mut self) -> StateReader<TState> {
+        StateReader {
+            shared: self.state_publisher.get_or_init(&self.state)
+        }
     }
-}
 
-/// Factory for StateMachines. This struct can be used to define a series of Transitions that
-/// may be subsequently used to create multiple state machine instances with those same
-/// transitions.
-#[derive(Default)]
-pub struct StateMachineFactory<'a, TEvent, TState: PartialEq<TState> + Clone + Send + 'a, TData, TErr = Box<dyn std::error::Error>> {
-    cycle: bool,
-    transitions: Vec<StateMachineTransition<'a, TEvent, TState, TData, TErr>>,
-}
+    /// Subscribes to changes of State. Whenever [StateMachine::handle_event] or
+    /// [StateMachine::tick] leaves the State Machine in a different State than the one last
+    /// sent, a [StateChange] carrying the old and new States is sent to the returned receiver,
+    /// which may live on another thread. States passed through while handling a single Event, and
+    /// changes that are rolled back, are not sent. Dropping the receiver unsubscribes it.
+    pub fn subscribe(&mut self) -> Receiver<StateChange<TState>> {
+        self.state_publisher.subscribe(&self.state)
+    }
 
-impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + PartialEq + 'a, TData, TErr> StateMachineFactory<'a, TEvent, TState, TData, TErr> {
-    /// Creates a new `StateMachineFactory`
-    pub fn new() -> Self {
-        Self {
-            cycle: false,
-            transitions: Vec::new(),
+    /// Determines if a state (usually the current state) matches the from_state of a transition
+    fn matches_from_state(&self, current: &TState, from_state: &FromState<TState>) -> bool {
+        let matches = |state: &TState| match &self.state_equivalence {
+            Some(equivalence) => equivalence(state, current),
+            None => state == current
+        };
+        match from_state {
+            FromState::Any => true,
+            FromState::AnyOf(states) => states.iter().any(matches),
+            FromState::NoneOf(states) => !states.iter().any(matches),
+            FromState::From(state) => matches(state)
         }
     }
 
-    /// Controls whether a state machine loops back after a transition.
-    pub fn cycle(self, cycle: bool) -> Self {
-        Self {
-            cycle,
-            transitions: self.transitions
+    /// Finds the index of the next Transition at or after `position` that may match a State
+    /// (usually the current State) and an Event, using the indexes built by
+    /// [StateMachineFactory::lock_indexed] and [LockedStateMachineFactory::with_event_index] if
+    /// there are any.
+    fn next_candidate(&self, state: &TState, event: &TEvent, mut position: usize) -> Option<usize> {
+        let by_state = self.transition_index.as_ref().map(|transition_index| transition_index.candidates(state));
+        let by_event = self.event_index.as_ref().map(|event_index| event_index.candidates(event));
+        // Leapfrog between the two lists until they agree on a Transition
+        loop {
+            let state_candidate = self.next_indexed(by_state, position)?;
+            let event_candidate = self.next_indexed(by_event, state_candidate)?;
+            if event_candidate == state_candidate {
+                return Some(state_candidate);
+            }
+            position = event_candidate;
         }
     }
 
-    /// Creates a LockedStateMachineFactory which can be used to build StateMachine instances
-    /// with the Transitions defined in this StateMachineFactory.
-    pub fn lock(self) -> LockedStateMachineFactory<'a, TEvent, TState, TData, TErr> {
-        LockedStateMachineFactory {
-            cycle: self.cycle,
-            transitions: Arc::new(self.transitions)
+    /// Finds the index of the next Transition at or after `position` among indexed candidates, or
+    /// among every Transition if there is no index
+    fn next_indexed(&self, candidates: Option<&[usize]>, position: usize) -> Option<usize> {
+        match candidates {
+            Some(candidates) if position < self.transitions.len() => {
+                candidates.get(candidates.partition_point(|index| *index < position)).copied()
+                    // Transitions added after the State Machine was built are not indexed
+                    .or_else(|| self.next_indexed(None, self.transitions.len()))
+            }
+            _ => (position < self.transition_count()).then_some(position)
         }
     }
 
-    /// Adds an externally-created transition to this `StateMachineFactory`
-    pub fn with_custom_transition(mut self, transition: StateMachineTransition<'a, TEvent, TState, TData, TErr>) -> Self
-    {
-        self.transitions.push(transition);
-        self
-    }
+    /// Chooses the weighted Transition that may execute in the next pass, see
+    /// [StateMachineFactory::with_weight]. Returns None if no weighted Transition matches.
+    fn choose_weighted(&mut self, event: &TEvent, emitted: &RefCell<VecDeque<TEvent>>) -> Result<Option<usize>, StateMachineError<TState, TErr>> {
+        let now = self.now();
+        let mut candidates = Vec::new();
+        let mut position = 0;
+        while let Some(index) = self.next_candidate(&self.state, event, position) {
+            position = index + 1;
+            let transition = transition_at(&self.transitions, &self.added_transitions, index);
+            let Some(weight) = transition.weight.filter(|weight| *weight > 0) else {
+                continue;
+            };
+            if !self.is_candidate(index, &self.state, now) {
+                continue;
+            }
+            // The result State is only needed once the Transition is chosen to execute
+            let target = provisional_to_state(transition, index, &self.state, &self.stack, &self.history_states);
+            if let Some(predicate) = &transition.event_predicate {
+                match predicate.evaluate(&self.transition_effect_data(transition, event, &self.state, target, emitted)) {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => return Err(StateMachineError::PredicateError(self.state.clone(), target.clone(), e))
+                }
+            }
+            candidates.push((index, weight as u64));
+        }
+        if candidates.is_empty() {
+            return Ok(None);
+        }
 
-    /// Adds a named Transition to the State Machine definition with no predicate and no side
-    /// effects. If this State Machine has cycle enabled, this transition will execute
-    /// automatically, essentially skipping the From state. If Cycle is not enabled, the State
-    /// Machine will transition to the To state with any future event.
-    pub fn with_named_auto_transition(mut self, name: impl Into<String>, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>) -> Self
-    {
-        self.transitions.push(StateMachineTransition::new(Some(name.into()), None, from_state.into(), get_to_state.into(), None));
-        self
+        let total: u64 = candidates.iter().map(|(_, weight)| weight).sum();
+        let random = self.random.get_or_insert_with(|| Arc::new(Mutex::new(XorShiftRandom::default())));
+        let mut draw = random.lock().unwrap_or_else(|e| e.into_inner()).next_random() % total;
+        for (index, weight) in candidates {
+            if draw < weight {
+                return Ok(Some(index));
+            }
+            draw -= weight;
+        }
+        Ok(None)
     }
 
-    /// Adds a named Transition to the State Machine definition with a side effect and no predicate.
-    /// If this State Machine has cycle enabled, this transition will execute automatically,
-    /// essentially skipping the From state after executing the side effect. If Cycle is not
-    /// enabled, the State Machine will transition to the To state with any future event.
-    pub fn with_named_transition_effect(mut self, name: impl Into<String>, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, effect: impl Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + Send + 'a) -> Self
-    {
-        self.transitions.push(StateMachineTransition::new(Some(name.into()), None, from_state.into(), get_to_state.into(), Some(Box::new(effect))));
-        self
+    /// Evaluates an Event, followed by any Events emitted while evaluating it, collecting Effect
+    /// errors if the [EffectErrorPolicy] is ContinueCollectingErrors. Deferred Events that are
+    /// redelivered are kept in `redelivered`, if given, so that they can be queued again by a
+    /// rollback.
+    fn evaluate_events(&mut self, event: TEvent, effect_errors: &mut Vec<(TState, TState, TErr)>, redelivered: Option<&mut Vec<DeferredEvent<TEvent>>>) -> Result<(), StateMachineError<TState, TErr>> {
+        self.evaluate_queued_events(Some((event, None)), 0, effect_errors, redelivered)
     }
 
-    /// Adds a name Transition to the State Machine definition with a predicate and no Side Effect.
-    /// This transition will test the predicate for any event and move to the To state if the
-    /// Predicate returns true.
-    pub fn with_named_predicated_transition(mut self, name: impl Into<String>, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, event_predicate: impl Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> bool + Send + 'a) -> Self
-    {
-        self.transitions.push(StateMachineTransition::new(Some(name.into()), Some(Box::new(event_predicate)), from_state.into(), get_to_state.into(), None));
-        self
+    /// Redelivers every deferred Event, in the order they arrived, after the State changed
+    /// without an Event, as a timeout Transition does.
+    fn redeliver_deferred(&mut self, effect_errors: &mut Vec<(TState, TState, TErr)>) -> Result<(), StateMachineError<TState, TErr>> {
+        let redeliver = self.deferred.len().saturating_sub(1);
+        let next_event = self.deferred.pop_front().map(|deferred| (deferred.event, Some((deferred.sequence, deferred.enriched))));
+        self.evaluate_queued_events(next_event, redeliver, effect_errors, None)
     }
 
-    /// Adds a named Transition to the State Machine definition with a predicate and a Side Effect.
-    /// This transition will test the predicate for any event and execute the Side Effect then move
-    /// to the To state if the Predicate returns true.
-    pub fn with_named_predicated_transition_effect(mut self, name: impl Into<String>, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, event_predicate: impl Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> bool + Send + 'a, effect: impl Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + Send + 'a) -> Self
-    {
-        self.transitions.push(StateMachineTransition::new(Some(name.into()), Some(Box::new(event_predicate)), from_state.into(), get_to_state.into(), Some(Box::new(effect))));
-        self
+    /// Evaluates `next_event` and then the Events queued behind it, see
+    /// [StateMachine::evaluate_events]. `redeliver` deferred Events are redelivered first.
+    fn evaluate_queued_events(&mut self, mut next_event: Option<(TEvent, Option<(u64, bool)>)>, mut redeliver: usize, effect_errors: &mut Vec<(TState, TState, TErr)>, mut redelivered: Option<&mut Vec<DeferredEvent<TEvent>>>) -> Result<(), StateMachineError<TState, TErr>> {
+        let emitted = RefCell::new(VecDeque::new());
+        // A redelivered Event carries the place it was deferred in, and whether it was enriched
+        while let Some((event, queued)) = next_event {
+            if self.is_deferred(&event) {
+                // An Event deferred again keeps its place in the order of arrival
+                let (sequence, enriched) = queued.unwrap_or_else(|| {
+                    self.deferred_count += 1;
+                    (self.deferred_count - 1, false)
+                });
+                self.deferred.push_back(DeferredEvent { event, sequence, enriched });
+            } else {
+                let event = match &self.event_enricher {
+                    Some(enricher) if !queued.is_some_and(|(_, enriched)| enriched) => enricher(event, &self.data),
+                    _ => event
+                };
+                #[cfg(feature = "tracing")]
+                let _span = self.tracer.as_ref().map(|tracer| tracer.event_span(&event, &self.state).entered());
+                let previous_state = (!self.deferred.is_empty()).then(|| (self.state.clone(), self.reentries));
+                let result = self.evaluate_event(&event, &emitted, effect_errors);
+                if let (Some(redelivered), Some((sequence, _))) = (&mut redelivered, queued) {
+                    redelivered.push(DeferredEvent { event, sequence, enriched: true });
+                }
+                result?;
+                if previous_state.is_some_and(|previous_state| previous_state != (self.state.clone(), self.reentries)) {
+                    redeliver = self.deferred.len();
+                }
+            }
+            // Events emitted before the State Machine completed are not handled. Deferred Events
+            // are redelivered, in the order they arrived, before any Events emitted by Effects.
+            next_event = match self.is_complete() {
+                true => None,
+                false if redeliver > 0 => {
+                    redeliver -= 1;
+                    self.deferred.pop_front().map(|deferred| (deferred.event, Some((deferred.sequence, deferred.enriched))))
+                }
+                false => emitted.borrow_mut().pop_front().map(|event| (event, None))
+            };
+        }
+        Ok(())
     }
 
-    /// Adds an unnamed Transition to the State Machine definition with no predicate and no side
-    /// effects. If this State Machine has cycle enabled, this transition will execute
-    /// automatically, essentially skipping the From state. If Cycle is not enabled, the State
-    /// Machine will transition to the To state with any future event.
-    pub fn with_auto_transition(mut self, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>) -> Self
-    {
-        self.transitions.push(StateMachineTransition::new(None, None, from_state.into(), get_to_state.into(), None));
-        self
+    /// Checks an incoming Event against the pre-conditions, see
+    /// [StateMachineFactory::with_precondition]
+    fn check_preconditions(&self, event: &TEvent) -> Result<(), StateMachineError<TState, TErr>> {
+        match self.preconditions.iter().find(|precondition| !(precondition.check)(event, &self.state, &self.data)) {
+            Some(precondition) => Err(StateMachineError::PreconditionFailed { name: precondition.name.clone(), state: self.state.clone() }),
+            None => Ok(())
+        }
     }
 
-    /// Adds an unnamed Transition to the State Machine definition with a side effect and no
-    /// predicate. If this State Machine has cycle enabled, this transition will execute
-    /// automatically, essentially skipping the From state after executing the side effect. If
-    /// Cycle is not enabled, the State Machine will transition to the To state with any future
-    /// event.
-    pub fn with_transition_effect(mut self, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, effect: impl Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + Send + 'a) -> Self
-    {
-        self.transitions.push(StateMachineTransition::new(None, None, from_state.into(), get_to_state.into(), Some(Box::new(effect))));
-        self
+    /// Determines whether the Transition at `index` is considered in `state` at `now`: its
+    /// from_state matches, and it is not disabled by [StateMachine::disable_transition] or by the
+    /// circuit breaker. Weighted Transitions are filtered by the callers.
+    fn is_candidate(&self, index: usize, state: &TState, now: Instant) -> bool {
+        let transition = transition_at(&self.transitions, &self.added_transitions, index);
+        self.matches_from_state(state, &transition.from_state)
+            && !self.disabled_transitions.contains(&index)
+            && !self.failure_tracker.as_ref().is_some_and(|tracker| tracker.is_disabled_at(index, now))
     }
 
-    /// Adds an unnamed Transition to the State Machine definition with a predicate and no Side
-    /// Effect. This transition will test the predicate for any event and move to the To state if
-    /// the Predicate returns true.
-    pub fn with_predicated_transition(mut self, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, event_predicate: impl Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> bool + Send + 'a) -> Self
-    {
-        self.transitions.push(StateMachineTransition::new(None, Some(Box::new(event_predicate)), from_state.into(), get_to_state.into(), None));
-        self
+    /// Sets up the data passed to the Predicate and Effect of a Transition from `from`, which has
+    /// the contexts of the current State only if it is the current State
+    fn transition_effect_data<'s>(&'s self, transition: &'s StateMachineTransition<'a, TEvent, TState, TData, TErr>, event: &'s TEvent, from: &'s TState, to: &'s TState, emitted: &'s RefCell<VecDeque<TEvent>>) -> StateTransitionEffectData<'s, TEvent, TState, TData> {
+        StateTransitionEffectData {
+            name: &transition.name,
+            data: &self.data,
+            event,
+            from,
+            to,
+            parameters: &self.parameters,
+            emitted,
+            contexts: if from == &self.state { &self.contexts } else { &[] },
+            progress_observer: self.progress_observer.as_deref(),
+            extracted: &self.extracted
+        }
     }
 
-    /// Adds an unnamed Transition to the State Machine definition with a predicate and a Side
-    /// Effect. This transition will test the predicate for any event and execute the Side Effect
-    /// then move to the To state if the Predicate returns true.
-    pub fn with_predicated_transition_effect(mut self, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, event_predicate: impl Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> bool + Send + 'a, effect: impl Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + Send + 'a) -> Self
-    {
-        self.transitions.push(StateMachineTransition::new(None, Some(Box::new(event_predicate)), from_state.into(), get_to_state.into(), Some(Box::new(effect))));
-        self
+    /// Determines the State a Transition whose Predicate has passed moves to from `from`, given
+    /// its provisional result State (see [provisional_to_state]). Only a calculated State is
+    /// created; any other is borrowed.
+    fn to_state<'s>(&self, transition: &StateMachineTransition<'a, TEvent, TState, TData, TErr>, event: &TEvent, from: &TState, target: &'s TState) -> Cow<'s, TState> {
+        match &transition.get_to_state {
+            Calc(get_to_state) => Cow::Owned(get_to_state.deref()(StateTransitionToStateData {
+                data: &self.data,
+                event,
+                from,
+                parameters: &self.parameters,
+            })),
+            _ => Cow::Borrowed(target)
+        }
     }
-}
 
-impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + PartialEq + 'a, TData, TErr> StateMachineFactory<'a, TEvent, TState, TData, TErr>
-where TEvent: PartialEq<TEvent> + Sync
-{
-    /// Adds a named Transition to the State Machine definition whose predicate checks for equality with a
-    /// provided Event reference. This is syntactic sugar for `.with_predicated_transition(..)` with
-    /// an equality Predicate.
-    pub fn with_named_event_transition<'b>(mut self, name: impl Into<String>, event: &'a TEvent, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>) -> Self
-    {
-        self.transitions.push(
-            StateMachineTransition::new(
-                Some(name.into()),
-                Some(Box::new(|e| *event == *e.event)),
-                from_state.into(),
-                get_to_state.into(),
-                None
-            )
-        );
-        self
+    /// Returns [StateMachineError::AmbiguousTransition] if more than one Transition would move
+    /// from `state` to a different State, see [StateMachineFactory::strict]
+    fn check_ambiguity(&self, state: &TState, stack: &[TState], history_states: &[Option<TState>], event: &TEvent, emitted: &RefCell<VecDeque<TEvent>>) -> Result<(), StateMachineError<TState, TErr>> {
+        let now = self.now();
+        let mut conflicting = Vec::new();
+        let mut position = 0;
+        while let Some(index) = self.next_candidate(state, event, position) {
+            position = index + 1;
+            let transition = transition_at(&self.transitions, &self.added_transitions, index);
+            if !self.is_candidate(index, state, now) || transition.weight.is_some() {
+                continue;
+            }
+            // Transitions that stay in the State cannot conflict, and unless their result is
+            // calculated that is known without running the Predicate
+            let target = provisional_to_state(transition, index, state, stack, history_states);
+            if matches!(transition.get_to_state, Same | SelfExternal) || (!matches!(transition.get_to_state, Calc(_)) && target == state) {
+                continue;
+            }
+            if let Some(predicate) = &transition.event_predicate {
+                match predicate.evaluate(&self.transition_effect_data(transition, event, state, target, emitted)) {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => return Err(StateMachineError::PredicateError(state.clone(), target.clone(), e))
+                }
+            }
+            if self.to_state(transition, event, state, target).as_ref() == state {
+                continue;
+            }
+            conflicting.push(TransitionId { index, name: transition.name.clone() });
+        }
+        match conflicting.len() {
+            0 | 1 => Ok(()),
+            _ => Err(StateMachineError::AmbiguousTransition { state: state.clone(), transitions: conflicting })
+        }
     }
 
-    /// Adds a named Transition with a side effect to the State Machine definition whose predicate checks
-    /// for equality with a provided Event reference. This is syntactic sugar for
-    /// `.with_predicated_transition(..)` with an equality Predicate.
-    pub fn with_named_event_transition_effect(mut self, name: impl Into<String>, event: &'a TEvent, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, effect: impl Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + Send + 'a) -> Self
-    {
-        self.transitions.push(
-            StateMachineTransition::new(
-                Some(name.into()),
-                Some(Box::new(|e| *event == *e.event)),
-                from_state.into(),
-                get_to_state.into(),
-                Some(Box::new(effect))
-            )
-        );
-        self
+    /// Determines if an Event is deferred in the current State, see
+    /// [StateMachineFactory::with_deferred_event]
+    fn is_deferred(&self, event: &TEvent) -> bool {
+        self.deferrals.iter().any(|deferral| self.matches_from_state(&self.state, &deferral.states) && (deferral.matches)(event))
     }
-    /// Adds an unnamed Transition to the State Machine definition whose predicate checks for
-    /// equality with a provided Event reference. This is syntactic sugar for
-    /// `.with_predicated_transition(..)` with an equality Predicate.
-    pub fn with_event_transition<'b>(mut self, event: &'a TEvent, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>) -> Self
-    {
-        self.transitions.push(
-            StateMachineTransition::new(
+
+    /// Evaluates all Transitions for a single Event, collecting any Events emitted by Effects.
+    fn evaluate_event(&mut self, event: &TEvent, emitted: &RefCell<VecDeque<TEvent>>, effect_errors: &mut Vec<(TState, TState, TErr)>) -> Result<(), StateMachineError<TState, TErr>> {
+        let mut event_matched = false;
+        let mut evaluation_stopped = false;
+        let mut cycles = 0;
+        loop {
+            if self.strict {
+                self.check_ambiguity(&self.state, &self.stack, &self.history_states, event, emitted)?;
+            }
+            let weighted_choice = self.choose_weighted(event, emitted)?;
+            let mut transition_occurred = false;
+            let mut position = 0;
+            while let Some(index) = self.next_candidate(&self.state, event, position) {
+                position = index + 1;
+                let transition = transition_at(&self.transitions, &self.added_transitions, index);
+
+                // If the from_state matches, we need to consider whether this transition should execute
+                if self.matches_from_state(&self.state, &transition.from_state) {
+
+                    // Re-enable a Transition whose circuit breaker cooldown has passed, then skip
+                    // disabled Transitions and weighted Transitions other than the one chosen for
+                    // this pass
+                    let now = self.now();
+                    if let Some(tracker) = &mut self.failure_tracker {
+                        tracker.enable_after_cooldown(index, transition, now);
+                    }
+                    if !self.is_candidate(index, &self.state, now) || (transition.weight.is_some() && weighted_choice != Some(index)) {
+                        continue;
+                    }
+
+                    // Determine the result state. Most Transitions considered are rejected by their
+                    // Predicate, so the result state is borrowed rather than cloned, and a
+                    // calculated result state is not calculated, until the Transition is known to
+                    // execute.
+                    let target = provisional_to_state(transition, index, &self.state, &self.stack, &self.history_states);
+
+                    // If there is a Predicate on this Transition, execute it and if it returns
+                    // false, skip to the next Transition
+                    if let Some(predicate) = &transition.event_predicate {
+                        match predicate.evaluate(&self.transition_effect_data(transition, event, &self.state, target, emitted)) {
+                            Ok(true) => {}
+                            Ok(false) => {
+                                #[cfg(feature = "tracing")]
+                                if let Some(tracer) = &self.tracer {
+                                    tracer.transition(transition, &self.state, target, cycles, "failed", "not run");
+                                }
+                                if let Some(metrics) = &self.metrics {
+                                    metrics.predicate_rejected(transition.name(), &self.state);
+                                }
+                                continue
+                            },
+                            Err(e) => {
+                                #[cfg(feature = "tracing")]
+                                if let Some(tracer) = &self.tracer {
+                                    tracer.transition(transition, &self.state, target, cycles, "error", "not run");
+                                }
+                                return Err(StateMachineError::PredicateError(self.state.clone(), target.clone(), e))
+                            }
+                        }
+                    }
+                    event_matched = true;
+                    let mut to_state = self.to_state(transition, event, &self.state, target).into_owned();
+
+                    if matches!(transition.get_to_state, Pop) && self.stack.is_empty() {
+                        return Err(StateMachineError::EmptyStack(self.state.clone()));
+                    }
+
+                    // If there is an Effect on this Transition, execute it, unless a failure is
+                    // injected into this Transition, which fails as its Effect would
+                    let result = match self.failure_injector.inject(&transition.name) {
+                        Some(e) => Err(e),
+                        None => match &transition.effect {
+                            Some(effect) if !self.pure => effect(self.transition_effect_data(transition, event, &self.state, &to_state, emitted)),
+                            _ => Ok(EffectOutcome::Continue)
+                        }
+                    };
+
+                    // Apply the directive returned by the Effect, if any
+                    let mut stop_evaluation = false;
+                    let result = result.map(|outcome| match outcome {
+                        EffectOutcome::Continue => {}
+                        EffectOutcome::StopEvaluation => stop_evaluation = true,
+                        EffectOutcome::Emit(event) => emitted.borrow_mut().push_back(event),
+                        EffectOutcome::OverrideTarget(state) => to_state = state
+                    });
+                    #[cfg(feature = "tracing")]
+                    if let Some(tracer) = &self.tracer {
+                        let predicate = if transition.event_predicate.is_some() { "passed" } else { "none" };
+                        let effect = match (&result, &transition.effect) {
+                            (Err(_), _) => "failed",
+                            (Ok(_), Some(_)) => "succeeded",
+                            (Ok(_), None) => "none"
+                        };
+                        tracer.transition(transition, &self.state, &to_state, cycles, predicate, effect);
+                    }
+                    if let Some(failure_tracker) = &mut self.failure_tracker {
+                        failure_tracker.track(index, transition, &self.state, &to_state, result.as_ref().err(), clock_now(&self.clock, self.last_tick));
+                    }
+                    if let Some(metrics) = &self.metrics {
+                        match &result {
+                            Ok(_) => metrics.transition_fired(transition.name(), &self.state, &to_state),
+                            Err(_) => metrics.effect_failed(transition.name(), &self.state, &to_state)
+                        }
+                    }
+                    if let Err(e) = result {
+                        match self.effect_error_policy {
+                            // The failed Transition is treated as if it had not matched
+                            EffectErrorPolicy::ContinueCollectingErrors => {
+                                effect_errors.push((self.state.clone(), to_state, e));
+                                continue;
+                            }
+                            _ => return Err(StateMachineError::EffectError {
+                                from: self.state.clone(),
+                                to: to_state,
+                                transition: transition.name.clone(),
+                                event: self.format_event.map(|format_event| format_event(event)),
+                                cycle: cycles,
+                                error: e
+                            })
+                        }
+                    }
+                    self.fired.push(index);
+                    if let Some(history) = &mut self.history {
+                        let event_debug = (history.format_event)(event);
+                        let timestamp = self.epoch.wall_clock_time(clock_now(&self.clock, self.last_tick));
+                        history.push(transition.name.clone(), self.state.clone(), to_state.clone(), event_debug, timestamp);
+                    }
+
+                    // Remember where we came from when pushing, and forget it when popping
+                    match transition.get_to_state {
+                        Push(_) => self.stack.push(self.state.clone()),
+                        Pop => { self.stack.pop(); },
+                        _ => {}
+                    }
+
+                    // If proceed is false or we changed state, mark transition_occurred as true so
+                    // that we evaluate all of the transitions again. An external self-transition
+                    // exits and re-enters the State as if it had changed.
+                    let reentered = self.state == to_state && matches!(transition.get_to_state, SelfExternal);
+                    if self.state != to_state || reentered {
+                        if reentered {
+                            self.reentries += 1;
+                        }
+                        record_departure(&self.transitions, &self.added_transitions, &mut self.history_states, &self.state);
+                        self.state = to_state;
+                        self.state_entered_at = EnteredAt(self.now());
+                        self.enter_state_contexts();
+                        if let Some(recorder) = &self.cause_recorder {
+                            self.last_cause = Some((recorder.clone_event)(event));
+                        }
+                        transition_occurred = true;
+
+                        // Nothing more is evaluated once the State Machine completes
+                        if self.is_complete() {
+                            return Ok(());
+                        }
+                    }
+
+                    // In FirstMatch mode, the first matching transition ends this pass, and an Effect
+                    // that stops evaluation ends evaluation of the Event altogether
+                    if self.evaluation_strategy == EvaluationStrategy::FirstMatch || stop_evaluation {
+                        evaluation_stopped = stop_evaluation;
+                        break;
+                    }
+                }
+            }
+
+            // If no transition occurred, we can end evaluation
+            if !self.cycle || !transition_occurred || evaluation_stopped {
+                break;
+            }
+
+            // Otherwise loop back, unless that would exceed the cycle limit
+            if self.max_cycles.is_some_and(|max_cycles| cycles >= max_cycles) {
+                return Err(StateMachineError::CycleLimitExceeded { state: self.state.clone(), cycles });
+            }
+            cycles += 1;
+        }
+
+        // If no transition matched the event at all, apply the unhandled event policy
+        if !event_matched {
+            match &self.unhandled_event_policy {
+                UnhandledEventPolicy::Ignore => {}
+                UnhandledEventPolicy::Error => return Err(StateMachineError::UnhandledEvent(self.state.clone())),
+                UnhandledEventPolicy::Callback(callback) => callback(event, &self.state, &self.data)
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes every timeout Transition (see [StateMachineFactory::with_timeout_transition]) that has
+    /// fallen due by `now`, earliest deadline first, redelivering deferred Events after each one
+    /// that changes the State. A State entered through a timeout counts as
+    /// entered at the moment that timeout fell due, so chained timeouts fire as they would have
+    /// with a punctual clock even if `tick` is called infrequently. Like a loop back in cycle mode,
+    /// each chained timeout after the first counts towards [StateMachineFactory::max_cycles].
+    pub fn tick(&mut self, now: Instant) -> Result<&TState, StateMachineError<TState, TErr>> {
+        self.last_tick = self.last_tick.max(Some(now));
+        let result = self.evaluate_timeouts(now);
+        self.state_publisher.publish(&self.state);
+        result.map(|_| &self.state)
+    }
+
+    /// Takes every timeout Transition that has fallen due by the current time of the State
+    /// Machine's [Clock], as [StateMachine::tick] does. With a [MockClock], this takes the
+    /// timeouts that fell due while the clock was advanced.
+    pub fn poll(&mut self) -> Result<&TState, StateMachineError<TState, TErr>> {
+        self.tick(self.now())
+    }
+
+    /// Advances the clock of the State Machine to the next timeout deadline (see
+    /// [StateMachine::next_deadline]) and takes the timeouts due at that instant, without waiting.
+    /// Returns the instant the clock was advanced to, or None if no timeout applies to the current
+    /// State. This lets simulations of long, timeout-heavy workflows run in moments:
+    /// `while sm.fast_forward()?.is_some() {}` runs a State Machine until it settles (which it
+    /// never does in a State with a Transition added by [StateMachineFactory::with_every_transition]).
+    pub fn fast_forward(&mut self) -> Result<Option<Instant>, StateMachineError<TState, TErr>> {
+        match self.next_deadline() {
+            Some(deadline) => self.tick(deadline).map(|_| Some(deadline)),
+            None => Ok(None)
+        }
+    }
+
+    /// Returns the current time according to the State Machine: the latest instant passed to
+    /// [StateMachine::tick] (or reached by [StateMachine::fast_forward]), or the time of its
+    /// [Clock] (see [StateMachineFactory::with_clock]) if that is later. States entered while
+    /// handling Events are stamped with this time, so a simulated timeline stays consistent when
+    /// Events and fast-forwarding are interleaved.
+    pub fn now(&self) -> Instant {
+        clock_now(&self.clock, self.last_tick)
+    }
+
+    /// Returns the instant at which the earliest timeout or interval Transition for the current
+    /// State falls due, or None if no such Transition applies to the current State. Drivers can
+    /// use this to decide when to call [StateMachine::tick] next.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.next_timer().map(|(deadline, _)| deadline)
+    }
+
+    /// Finds the deadline of the earliest timeout or interval Transition for the current State.
+    /// Interval Transitions come first, so they fire before a timeout due at the same instant.
+    fn next_timer(&self) -> Option<(Instant, Timer)> {
+        let intervals = self.intervals.iter().enumerate()
+            .filter(|(_, interval)| !interval.period.is_zero() && self.matches_from_state(&self.state, &interval.from_state))
+            .map(|(index, interval)| (self.state_entered_at.0 + interval.period * (self.interval_count(index) + 1), Timer::Interval(index)));
+        let timeouts = self.timeouts.iter().enumerate()
+            .filter(|(_, timeout)| self.matches_from_state(&self.state, &timeout.from_state))
+            .map(|(index, timeout)| (self.state_entered_at.0 + timeout.timeout, Timer::Timeout(index)));
+        intervals.chain(timeouts).min_by_key(|(deadline, _)| *deadline)
+    }
+
+    /// Returns how often an interval Transition has fired since the current State was entered
+    fn interval_count(&self, index: usize) -> u32 {
+        match self.interval_counts.get(index) {
+            Some((entered_at, count)) if *entered_at == self.state_entered_at.0 => *count,
+            _ => 0
+        }
+    }
+
+    /// Takes timeout and interval Transitions until none is due by `now`.
+    fn evaluate_timeouts(&mut self, now: Instant) -> Result<(), StateMachineError<TState, TErr>> {
+        let mut cycles = 0;
+        while let Some((deadline, timer)) = self.next_timer().filter(|(deadline, _)| *deadline <= now && !self.is_complete()) {
+            let index = match timer {
+                Timer::Timeout(index) => index,
+                Timer::Interval(index) => {
+                    let interval_effect_data = StateTimeoutEffectData {
+                        data: &self.data,
+                        from: &self.state,
+                        to: &self.state,
+                        elapsed: deadline - self.state_entered_at.0,
+                        parameters: &self.parameters,
+                    };
+                    let result = (self.intervals[index].effect)(interval_effect_data);
+                    if let Some(metrics) = &self.metrics {
+                        match &result {
+                            Ok(_) => metrics.transition_fired(None, &self.state, &self.state),
+                            Err(_) => metrics.effect_failed(None, &self.state, &self.state)
+                        }
+                    }
+                    result.map_err(|e| StateMachineError::EffectError {
+                        from: self.state.clone(),
+                        to: self.state.clone(),
+                        transition: None,
+                        event: None,
+                        cycle: 0,
+                        error: e
+                    })?;
+                    let count = self.interval_count(index) + 1;
+                    self.interval_counts.resize(self.intervals.len(), (self.state_entered_at.0, 0));
+                    self.interval_counts[index] = (self.state_entered_at.0, count);
+                    continue;
+                }
+            };
+            if self.max_cycles.is_some_and(|max_cycles| cycles > max_cycles) {
+                return Err(StateMachineError::CycleLimitExceeded { state: self.state.clone(), cycles });
+            }
+            let timeout = &self.timeouts[index];
+            if let Some(effect) = &timeout.effect {
+                let timeout_effect_data = StateTimeoutEffectData {
+                    data: &self.data,
+                    from: &self.state,
+                    to: &timeout.to_state,
+                    elapsed: deadline - self.state_entered_at.0,
+                    parameters: &self.parameters,
+                };
+                let result = effect(timeout_effect_data);
+                if let (Some(metrics), Err(_)) = (&self.metrics, &result) {
+                    metrics.effect_failed(None, &self.state, &timeout.to_state);
+                }
+                result.map_err(|e| StateMachineError::EffectError {
+                    from: self.state.clone(),
+                    to: timeout.to_state.clone(),
+                    transition: None,
+                    event: None,
+                    cycle: cycles,
+                    error: e
+                })?;
+            }
+            if let Some(history) = &mut self.history {
+                let timestamp = self.epoch.wall_clock_time(deadline);
+                history.push(None, self.state.clone(), timeout.to_state.clone(), format!("timeout after {:?}", timeout.timeout), timestamp);
+            }
+            if let Some(metrics) = &self.metrics {
+                metrics.transition_fired(None, &self.state, &timeout.to_state);
+            }
+            let state_changed = self.state != timeout.to_state;
+            if state_changed {
+                record_departure(&self.transitions, &self.added_transitions, &mut self.history_states, &self.state);
+                // A timeout has no Event to blame
+                self.last_cause = None;
+            }
+            self.state = timeout.to_state.clone();
+            self.state_entered_at = EnteredAt(deadline);
+            if state_changed {
+                self.enter_state_contexts();
+                if !self.deferred.is_empty() {
+                    let mut effect_errors = Vec::new();
+                    self.redeliver_deferred(&mut effect_errors)?;
+                    if !effect_errors.is_empty() {
+                        return Err(StateMachineError::EffectErrors { state: self.state.clone(), errors: effect_errors });
+                    }
+                }
+            }
+            cycles += 1;
+        }
+        Ok(())
+    }
+
+    /// Returns the context of type `C` of the current State, see
+    /// [StateMachineFactory::with_state_context], or None if the current State has no such context.
+    pub fn context<C: 'static>(&self) -> Option<&C> {
+        self.contexts.iter().find_map(|context| context.value.downcast_ref::<C>())
+    }
+
+    /// Returns the context of type `C` of the current State for modification, see
+    /// [StateMachine::context].
+    pub fn context_mut<C: 'static>(&mut self) -> Option<&mut C> {
+        self.contexts.iter_mut().find_map(|context| context.value.downcast_mut::<C>())
+    }
+
+    /// Replaces the contexts of the previous State with freshly initialized contexts of the
+    /// current State, after entering it
+    fn enter_state_contexts(&mut self) {
+        let contexts = self.context_definitions.iter()
+            .filter(|definition| self.matches_from_state(&self.state, &definition.states))
+            .map(|definition| (definition.init)(&self.state, &self.data))
+            .collect();
+        self.contexts = contexts;
+    }
+
+    /// Calculates the computed view of type `T` registered with [StateMachineFactory::with_computed]
+    /// from the current State and Data, or returns None if no such view was registered.
+    pub fn computed<T: 'static>(&self) -> Option<T> {
+        self.computed_views.iter()
+            .find(|view| view.type_id == TypeId::of::<T>())
+            .and_then(|view| (view.compute)(&self.state, &self.data).downcast::<T>().ok())
+            .map(|value| *value)
+    }
+
+    /// Calculates every registered computed view, returning their `Debug` renderings keyed by the
+    /// name of the view's type.
+    pub fn computed_values(&self) -> BTreeMap<String, String> {
+        self.computed_views.iter()
+            .map(|view| (view.name.to_string(), (view.render)((view.compute)(&self.state, &self.data).as_ref())))
+            .collect()
+    }
+
+    /// Returns true if the State Machine is in one of its final States (see
+    /// [StateMachineFactory::with_final_states]). A complete State Machine does not handle further
+    /// Events or take timeout Transitions.
+    pub fn is_complete(&self) -> bool {
+        self.final_states.contains(&self.state)
+    }
+
+    /// Returns the description of a State for a locale (see [Description::get]), as attached with
+    /// [StateMachineFactory::with_state_description].
+    pub fn state_description(&self, state: &TState, locale: Option<&str>) -> Option<&str> {
+        find_description(&self.state_descriptions, state, locale)
+    }
+
+    /// Returns the description for a locale (see [Description::get]) of the first Transition with
+    /// the given name, as attached with [StateMachineFactory::with_description].
+    pub fn transition_description(&self, name: &str, locale: Option<&str>) -> Option<&str> {
+        self.all_transitions()
+            .find(|transition| transition.name() == Some(name))
+            .and_then(|transition| transition.description(locale))
+    }
+
+    /// Returns the documentation of the first Transition with the given name, as attached with
+    /// [StateMachineFactory::with_doc].
+    pub fn transition_doc(&self, name: &str) -> Option<&str> {
+        self.all_transitions()
+            .find(|transition| transition.name() == Some(name))
+            .and_then(|transition| transition.doc())
+    }
+
+    /// Returns the names (None for unnamed Transitions) of the Transitions executed by the most
+    /// recent call to [StateMachine::handle_event], in the order they executed.
+    pub fn fired_transitions(&self) -> impl Iterator<Item = Option<&str>> + '_ {
+        self.fired.iter().map(|index| self.transition(*index).name.as_deref())
+    }
+
+    /// Adds the Transitions of `transitions` to this State Machine, evaluated after those it
+    /// already has, without rebuilding it or losing its State and Data. Only the Transitions of
+    /// the factory are added; its other settings are ignored, as they are by
+    /// [StateMachineFactory::merge]. The Transitions are owned by this instance, so other State
+    /// Machines built by the same factory are unaffected, and they are not included in
+    /// [StateMachine::transitions].
+    pub fn add_transitions(&mut self, transitions: StateMachineFactory<'a, TEvent, TState, TData, TErr>) {
+        self.added_transitions.extend(transitions.transitions.into_iter().map(Arc::new));
+    }
+
+    /// Disables every Transition with the given name, so it is skipped until it is enabled again
+    /// with [StateMachine::enable_transition]. Returns false if no Transition has the name.
+    pub fn disable_transition(&mut self, name: &str) -> bool {
+        let indices: Vec<usize> = self.transition_indices(name).collect();
+        for index in &indices {
+            if !self.disabled_transitions.contains(index) {
+                self.disabled_transitions.push(*index);
+            }
+        }
+        !indices.is_empty()
+    }
+
+    /// Enables every Transition with the given name that was disabled with
+    /// [StateMachine::disable_transition]. Returns false if no Transition has the name.
+    pub fn enable_transition(&mut self, name: &str) -> bool {
+        let indices: Vec<usize> = self.transition_indices(name).collect();
+        self.disabled_transitions.retain(|index| !indices.contains(index));
+        !indices.is_empty()
+    }
+
+    /// Returns the number of Transitions, including those added with
+    /// [StateMachine::add_transitions].
+    pub fn transition_count(&self) -> usize {
+        self.transitions.len() + self.added_transitions.len()
+    }
+
+    /// Returns the Transition at an index, counting those added with
+    /// [StateMachine::add_transitions] after those the State Machine was built with.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not less than [StateMachine::transition_count].
+    pub fn transition(&self, index: usize) -> &StateMachineTransition<'a, TEvent, TState, TData, TErr> {
+        transition_at(&self.transitions, &self.added_transitions, index)
+    }
+
+    fn all_transitions(&self) -> impl Iterator<Item = &StateMachineTransition<'a, TEvent, TState, TData, TErr>> {
+        self.transitions.iter().chain(self.added_transitions.iter().map(Arc::as_ref))
+    }
+
+    fn transition_indices<'s>(&'s self, name: &'s str) -> impl Iterator<Item = usize> + 's {
+        self.all_transitions().enumerate()
+            .filter(move |(_, transition)| transition.name() == Some(name))
+            .map(|(index, _)| index)
+    }
+
+    /// Returns the Transitions recorded since history was last cleared, oldest first. This is
+    /// empty unless history is enabled with [StateMachineFactory::with_history].
+    pub fn history(&self) -> Vec<&TransitionRecord<TState>> {
+        self.history.iter().flat_map(|history| history.records.iter()).collect()
+    }
+
+    /// Removes all recorded Transitions from the history.
+    pub fn clear_history(&mut self) {
+        if let Some(history) = &mut self.history {
+            history.records.clear();
+        }
+    }
+
+    /// Returns the Events deferred with [StateMachineFactory::with_deferred_event] that are waiting
+    /// to be redelivered, oldest first.
+    pub fn deferred_events(&self) -> impl Iterator<Item = &TEvent> + '_ {
+        self.deferred.iter().map(|deferred| &deferred.event)
+    }
+
+    /// Returns the Event that caused the State Machine to enter its current State, if recording is
+    /// enabled with [StateMachineFactory::with_last_cause]. This is None in the initial State and
+    /// after a timeout Transition, and for a restored State Machine until its State next changes.
+    pub fn last_cause(&self) -> Option<&TEvent> {
+        self.last_cause.as_ref()
+    }
+
+    fn last_cause_debug(&self) -> Option<String> {
+        let recorder = self.cause_recorder.as_ref()?;
+        self.last_cause.as_ref().map(recorder.format_event)
+    }
+
+    fn clone_last_cause(&self) -> Option<TEvent> {
+        let recorder = self.cause_recorder.as_ref()?;
+        self.last_cause.as_ref().map(recorder.clone_event)
+    }
+
+    /// Returns the stack of States saved by [ToState::Push] Transitions, oldest first. The last
+    /// State is the one a [ToState::Pop] Transition returns to.
+    pub fn stack(&self) -> &[TState] {
+        &self.stack
+    }
+
+    /// Measures the bookkeeping this `StateMachine` holds beyond its State and Data, which should
+    /// stay bounded however long the State Machine lives. See [testing::soak].
+    pub fn footprint(&self) -> Footprint {
+        Footprint {
+            history: self.history.as_ref().map_or(0, |history| history.records.len()),
+            stack: self.stack.len(),
+            history_states: self.history_states.len(),
+            deferred: self.deferred.len(),
+        }
+    }
+
+    /// Captures the current State and a copy of the Data of this `StateMachine`. The snapshot can
+    /// later be turned back into a `StateMachine` with [LockedStateMachineFactory::restore].
+    pub fn snapshot(&self) -> Snapshot<TState, TData> where TData: Clone {
+        Snapshot {
+            state: self.state.clone(),
+            data: self.data.clone(),
+            stack: self.stack.clone(),
+            history_states: self.history_states.clone(),
+            last_cause: self.last_cause_debug(),
+            computed: self.computed_values(),
+        }
+    }
+
+    /// Consumes this `StateMachine`, capturing its current State and Data without cloning them.
+    pub fn into_snapshot(self) -> Snapshot<TState, TData> {
+        let computed = self.computed_values();
+        let last_cause = self.last_cause_debug();
+        Snapshot {
+            state: self.state,
+            data: self.data,
+            stack: self.stack,
+            history_states: self.history_states,
+            last_cause,
+            computed,
+        }
+    }
+}
+
+/// The sizes of the collections a [StateMachine] maintains as it runs, see
+/// [StateMachine::footprint]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Footprint {
+    /// The number of Transitions in the history, see [StateMachineFactory::with_history].
+    pub history: usize,
+    /// The number of States on the stack, see [ToState::Push].
+    pub stack: usize,
+    /// The number of States remembered for [ToState::History] Transitions.
+    pub history_states: usize,
+    /// The number of deferred Events, see [StateMachineFactory::with_deferred_event].
+    pub deferred: usize,
+}
+
+impl Footprint {
+    /// Returns the larger of each size in two footprints.
+    pub fn max(self, other: Footprint) -> Footprint {
+        Footprint {
+            history: self.history.max(other.history),
+            stack: self.stack.max(other.stack),
+            history_states: self.history_states.max(other.history_states),
+            deferred: self.deferred.max(other.deferred),
+        }
+    }
+}
+
+/// A timeout or interval Transition of a [StateMachine], by index
+#[derive(Copy, Clone)]
+enum Timer {
+    Timeout(usize),
+    Interval(usize),
+}
+
+/// The instant at which a [StateMachine] entered its current State, defaulting to now
+#[derive(Copy, Clone)]
+struct EnteredAt(Instant);
+
+impl Default for EnteredAt {
+    fn default() -> Self {
+        EnteredAt(Instant::now())
+    }
+}
+
+/// The parts of a [StateMachine] restored by [EffectErrorPolicy::AbortAndRollback] when handling
+/// an Event fails
+struct Checkpoint<TEvent, TState> {
+    state: TState,
+    stack: Vec<TState>,
+    history_states: Vec<Option<TState>>,
+    state_entered_at: EnteredAt,
+    last_cause: Option<TEvent>,
+    contexts: Vec<StateContext>,
+    reentries: usize,
+    history: Option<VecDeque<TransitionRecord<TState>>>,
+    deferred_count: u64,
+}
+
+/// An Event deferred with [StateMachineFactory::with_deferred_event], waiting to be redelivered
+#[derive(Clone)]
+struct DeferredEvent<TEvent> {
+    event: TEvent,
+    /// The order in which the Event was first deferred
+    sequence: u64,
+    /// True if the Event was already enriched when it was redelivered, before being queued again
+    /// by a rollback
+    enriched: bool,
+}
+
+/// An instant of the [Clock] of a [StateMachine] and the wall-clock time it corresponds to,
+/// defaulting to now. The history is timestamped relative to it, so that it follows the clock.
+#[derive(Copy, Clone)]
+struct Epoch {
+    instant: Instant,
+    time: SystemTime,
+}
+
+impl Default for Epoch {
+    fn default() -> Self {
+        Epoch { instant: Instant::now(), time: SystemTime::now() }
+    }
+}
+
+impl Epoch {
+    /// Converts an instant of the clock to wall-clock time
+    fn wall_clock_time(&self, now: Instant) -> SystemTime {
+        match now.checked_duration_since(self.instant) {
+            Some(elapsed) => self.time + elapsed,
+            None => self.time - self.instant.duration_since(now)
+        }
+    }
+}
+
+/// Decides when failures configured with [StateMachineFactory::with_injected_failure] occur for a
+/// single [StateMachine]
+#[derive(Clone)]
+struct FailureInjector<'a, TErr> {
+    failures: Arc<Vec<InjectedFailure<'a, TErr>>>,
+    invocations: Vec<usize>,
+    randoms: Vec<XorShiftRandom>,
+}
+
+impl <'a, TErr> Default for FailureInjector<'a, TErr> {
+    fn default() -> Self {
+        Self::new(Arc::new(Vec::new()))
+    }
+}
+
+impl <'a, TErr> FailureInjector<'a, TErr> {
+    fn new(failures: Arc<Vec<InjectedFailure<'a, TErr>>>) -> Self {
+        let randoms = failures.iter().map(|failure| match failure.injection {
+            FailureInjection::Probability { seed, .. } => XorShiftRandom::new(seed),
+            FailureInjection::OnInvocation(_) => XorShiftRandom::default(),
+        }).collect();
+        Self {
+            invocations: vec![0; failures.len()],
+            randoms,
+            failures,
+        }
+    }
+
+    /// Records an invocation of the named Transition, returning an error if it should fail
+    fn inject(&mut self, name: &Option<String>) -> Option<TErr> {
+        let name = name.as_ref()?;
+        let mut result = None;
+        for (index, failure) in self.failures.iter().enumerate() {
+            if &failure.name != name {
+                continue;
+            }
+            self.invocations[index] += 1;
+            let fail = match failure.injection {
+                FailureInjection::OnInvocation(invocation) => self.invocations[index] == invocation,
+                FailureInjection::Probability { probability, .. } => {
+                    // Reduced to a float in [0, 1)
+                    let random = (self.randoms[index].next_random() >> 11) as f64 / (1u64 << 53) as f64;
+                    random < probability
+                }
+            };
+            if fail && result.is_none() {
+                result = Some((failure.make_error)());
+            }
+        }
+        result
+    }
+}
+
+/// A bounded record of the Transitions executed by a [StateMachine], see
+/// [StateMachineFactory::with_history]
+struct TransitionHistory<TEvent, TState> {
+    records: VecDeque<TransitionRecord<TState>>,
+    capacity: usize,
+    format_event: fn(&TEvent) -> String,
+}
+
+impl <TEvent, TState> TransitionHistory<TEvent, TState> {
+    fn push(&mut self, name: Option<String>, from: TState, to: TState, event_debug: String, timestamp: SystemTime) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(TransitionRecord { timestamp, name, from, to, event_debug });
+    }
+}
+
+impl <TEvent, TState: Clone> Clone for TransitionHistory<TEvent, TState> {
+    fn clone(&self) -> Self {
+        Self {
+            records: self.records.clone(),
+            capacity: self.capacity,
+            format_event: self.format_event,
+        }
+    }
+}
+
+/// Copies the Event that caused a State to be entered, see [StateMachineFactory::with_last_cause]
+struct CauseRecorder<TEvent> {
+    clone_event: fn(&TEvent) -> TEvent,
+    format_event: fn(&TEvent) -> String,
+}
+
+impl <TEvent> Clone for CauseRecorder<TEvent> {
+    fn clone(&self) -> Self {
+        Self {
+            clone_event: self.clone_event,
+            format_event: self.format_event,
+        }
+    }
+}
+
+/// Emits `tracing` spans and events for a [StateMachine], see [StateMachineFactory::with_tracing]
+#[cfg(feature = "tracing")]
+struct Tracer<TEvent, TState> {
+    format_event: fn(&TEvent) -> String,
+    format_state: fn(&TState) -> String,
+}
+
+#[cfg(feature = "tracing")]
+impl <TEvent, TState> Tracer<TEvent, TState> {
+    fn event_span(&self, event: &TEvent, state: &TState) -> tracing::Span {
+        tracing::debug_span!("handle_event", event = %(self.format_event)(event), state = %(self.format_state)(state))
+    }
+
+    /// Reports a Transition whose from_state matched, at TRACE level if it did not execute
+    fn transition<TData, TErr>(&self, transition: &StateMachineTransition<TEvent, TState, TData, TErr>, from: &TState, to: &TState, cycle: usize, predicate: &str, effect: &str)
+    where TState: PartialEq<TState> + Clone + Send
+    {
+        let from = (self.format_state)(from);
+        let to = (self.format_state)(to);
+        match effect {
+            "not run" => tracing::trace!(transition = transition.name(), %from, %to, cycle, predicate, effect, "transition skipped"),
+            _ => tracing::debug!(transition = transition.name(), %from, %to, cycle, predicate, effect, "transition executed")
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl <TEvent, TState> Clone for Tracer<TEvent, TState> {
+    fn clone(&self) -> Self {
+        Self {
+            format_event: self.format_event,
+            format_state: self.format_state,
+        }
+    }
+}
+
+/// Records a State being left for every [ToState::History] Transition whose States include it
+fn record_departure<TEvent, TState: PartialEq<TState> + Clone + Send, TData, TErr>(
+    transitions: &[StateMachineTransition<TEvent, TState, TData, TErr>],
+    added_transitions: &[Arc<StateMachineTransition<TEvent, TState, TData, TErr>>],
+    history_states: &mut Vec<Option<TState>>,
+    state: &TState
+) {
+    for (index, transition) in transitions.iter().chain(added_transitions.iter().map(Arc::as_ref)).enumerate() {
+        if let History(states) = &transition.get_to_state {
+            if states.matches(state) {
+                if history_states.len() <= index {
+                    history_states.resize(index + 1, None);
+                }
+                history_states[index] = Some(state.clone());
+            }
+        }
+    }
+}
+
+/// Determines the State a Transition would move to from `from`, given the stack and the States
+/// remembered for [ToState::History], without running a [ToState::Calc] callback: a calculated
+/// Transition provisionally stays in `from`, and its Predicate sees `from` as the result State.
+fn provisional_to_state<'s, TEvent, TState: PartialEq<TState> + Clone + Send, TData, TErr>(
+    transition: &'s StateMachineTransition<TEvent, TState, TData, TErr>,
+    index: usize,
+    from: &'s TState,
+    stack: &'s [TState],
+    history_states: &'s [Option<TState>]
+) -> &'s TState {
+    match &transition.get_to_state {
+        To(to_state) | Push(to_state) => to_state,
+        Same | SelfExternal | Calc(_) => from,
+        // With an empty stack this stays in the same State; the error is only returned if the
+        // Transition goes on to execute
+        Pop => stack.last().unwrap_or(from),
+        // If no State has been recorded yet, this stays in the same State
+        History(_) => history_states.get(index).and_then(Option::as_ref).unwrap_or(from)
+    }
+}
+
+/// Returns the Transition of a [StateMachine] at an index, counting the Transitions added with
+/// [StateMachine::add_transitions] after those it was built with
+fn transition_at<'t, 'a, TEvent, TState: PartialEq<TState> + Clone + Send, TData, TErr>(
+    transitions: &'t [StateMachineTransition<'a, TEvent, TState, TData, TErr>],
+    added_transitions: &'t [Arc<StateMachineTransition<'a, TEvent, TState, TData, TErr>>],
+    index: usize
+) -> &'t StateMachineTransition<'a, TEvent, TState, TData, TErr> {
+    transitions.get(index).unwrap_or_else(|| &added_transitions[index - transitions.len()])
+}
+
+/// Returns the time of a clock (the system clock if None), or the latest tick of a
+/// [StateMachine] if that is later, see [StateMachine::now]
+fn clock_now(clock: &Option<SharedClock>, last_tick: Option<Instant>) -> Instant {
+    let now = clock.as_ref().map_or_else(Instant::now, |clock| clock.now());
+    last_tick.map_or(now, |last_tick| last_tick.max(now))
+}
+
+/// Counts consecutive Effect failures per Transition of a [StateMachine], see
+/// [StateMachineFactory::with_failure_alert] and [StateMachineFactory::with_circuit_breaker]
+struct FailureTracker<'a, TState, TErr> {
+    alert: Option<(usize, FailureAlertCallback<'a, TState, TErr>)>,
+    breaker: Option<CircuitBreaker<'a>>,
+    consecutive_failures: Vec<usize>,
+    disabled_until: Vec<Option<Instant>>,
+}
+
+/// Disables repeatedly failing Transitions, see [StateMachineFactory::with_circuit_breaker]
+#[derive(Clone)]
+struct CircuitBreaker<'a> {
+    threshold: usize,
+    cooldown: Duration,
+    observer: CircuitBreakerObserver<'a>,
+}
+
+impl <'a, TState, TErr> FailureTracker<'a, TState, TErr> {
+    fn new() -> Self {
+        Self {
+            alert: None,
+            breaker: None,
+            consecutive_failures: Vec::new(),
+            disabled_until: Vec::new(),
+        }
+    }
+
+    fn track<TEvent, TData>(&mut self, index: usize, transition: &StateMachineTransition<TEvent, TState, TData, TErr>, from: &TState, to: &TState, error: Option<&TErr>, now: Instant)
+    where TState: PartialEq<TState> + Clone + Send
+    {
+        if self.consecutive_failures.len() <= index {
+            self.consecutive_failures.resize(index + 1, 0);
+            self.disabled_until.resize(index + 1, None);
+        }
+        let Some(error) = error else {
+            self.consecutive_failures[index] = 0;
+            return;
+        };
+        self.consecutive_failures[index] += 1;
+        let consecutive_failures = self.consecutive_failures[index];
+        if let Some((threshold, alert)) = &self.alert {
+            if consecutive_failures >= *threshold {
+                alert(EffectFailure {
+                    name: transition.name(),
+                    from,
+                    to,
+                    error,
+                    consecutive_failures,
+                });
+            }
+        }
+        if let Some(breaker) = &self.breaker {
+            if consecutive_failures >= breaker.threshold {
+                let until = now + breaker.cooldown;
+                self.disabled_until[index] = Some(until);
+                (breaker.observer)(CircuitBreakerEvent::Disabled { name: transition.name(), until });
+            }
+        }
+    }
+
+    /// Determines whether a Transition is disabled at the given time, without re-enabling it
+    fn is_disabled_at(&self, index: usize, now: Instant) -> bool {
+        self.disabled_until.get(index).copied().flatten().is_some_and(|until| now < until)
+    }
+
+    /// Re-enables a disabled Transition if its cooldown has passed by `now`
+    fn enable_after_cooldown<TEvent, TData>(&mut self, index: usize, transition: &StateMachineTransition<TEvent, TState, TData, TErr>, now: Instant)
+    where TState: PartialEq<TState> + Clone + Send
+    {
+        let Some(Some(until)) = self.disabled_until.get(index).copied() else {
+            return;
+        };
+        if now < until {
+            return;
+        }
+        self.disabled_until[index] = None;
+        if let Some(breaker) = &self.breaker {
+            (breaker.observer)(CircuitBreakerEvent::Enabled { name: transition.name() });
+        }
+    }
+}
+
+impl <TState, TErr> Clone for FailureTracker<'_, TState, TErr> {
+    fn clone(&self) -> Self {
+        Self {
+            alert: self.alert.clone(),
+            breaker: self.breaker.clone(),
+            consecutive_failures: self.consecutive_failures.clone(),
+            disabled_until: self.disabled_until.clone(),
+        }
+    }
+}
+
+/// Lists, in evaluation order, the indexes of the Transitions that may apply to a State
+trait TransitionIndex<TState> {
+    fn candidates(&self, state: &TState) -> &[usize];
+}
+
+/// Transition index built by [StateMachineFactory::lock_indexed]
+struct HashedTransitionIndex<TState> {
+    buckets: HashMap<TState, Vec<usize>>,
+    general: Vec<usize>,
+}
+
+impl <TState: Hash + Eq> TransitionIndex<TState> for HashedTransitionIndex<TState> {
+    fn candidates(&self, state: &TState) -> &[usize] {
+        self.buckets.get(state).unwrap_or(&self.general)
+    }
+}
+
+/// Transition index built by [LockedStateMachineFactory::with_event_index], listing in evaluation
+/// order the indexes of the Transitions that may apply to each kind of Event
+struct EventIndex<TEvent> {
+    buckets: HashMap<std::mem::Discriminant<TEvent>, Vec<usize>>,
+    general: Vec<usize>,
+}
+
+impl <TEvent> EventIndex<TEvent> {
+    fn candidates(&self, event: &TEvent) -> &[usize] {
+        self.buckets.get(&std::mem::discriminant(event)).unwrap_or(&self.general)
+    }
+}
+
+/// Publishes the committed State of a [StateMachine] to its [StateReader]s (and the
+/// [runner::StateWatch]es built on them) and subscribers, if any have been created. Cloning a
+/// `StateMachine` does not share its publisher. Dropping it tells watchers that no newer State
+/// will be committed.
+struct StatePublisher<TState> {
+    shared: Option<Arc<PublishedState<TState>>>,
+    /// The subscribers still listening, see [StateMachine::subscribe]
+    subscribers: Vec<Sender<StateChange<TState>>>,
+}
+
+impl <TState> Default for StatePublisher<TState> {
+    fn default() -> Self {
+        Self { shared: None, subscribers: Vec::new() }
+    }
+}
+
+impl <TState: Clone + PartialEq> StatePublisher<TState> {
+    fn get_or_init(&mut self, state: &TState) -> Arc<PublishedState<TState>> {
+        self.shared.get_or_insert_with(|| Arc::new(PublishedState {
+            committed: Mutex::new(Committed { state: Arc::new(state.clone()), version: 0, closed: false }),
+            changed: Condvar::new(),
+        })).clone()
+    }
+
+    fn subscribe(&mut self, state: &TState) -> Receiver<StateChange<TState>> {
+        self.get_or_init(state);
+        let (sender, receiver) = channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    fn publish(&mut self, state: &TState) {
+        let Some(shared) = &self.shared else {
+            return;
+        };
+        let mut committed = shared.lock();
+        if committed.state.as_ref() == state {
+            return;
+        }
+        let from = std::mem::replace(&mut committed.state, Arc::new(state.clone()));
+        committed.version += 1;
+        shared.changed.notify_all();
+        drop(committed);
+        if !self.subscribers.is_empty() {
+            let change = StateChange { from: from.as_ref().clone(), to: state.clone() };
+            // Subscribers that have dropped their receiver are forgotten
+            self.subscribers.retain(|subscriber| subscriber.send(change.clone()).is_ok());
+        }
+    }
+}
+
+impl <TState> Clone for StatePublisher<TState> {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl <TState> Drop for StatePublisher<TState> {
+    fn drop(&mut self) {
+        if let Some(shared) = &self.shared {
+            shared.lock().closed = true;
+            shared.changed.notify_all();
+        }
+    }
+}
+
+/// The payload extracted by the Predicate of an extracted event Transition, kept for its Effect,
+/// see [StateMachineFactory::with_extracted_event_transition]. Each State Machine has its own.
+#[derive(Default)]
+struct ExtractedPayload(RefCell<Option<Box<dyn Any>>>);
+
+impl ExtractedPayload {
+    fn set<P: 'static>(&self, payload: Option<P>) {
+        *self.0.borrow_mut() = payload.map(|payload| Box::new(payload) as Box<dyn Any>);
+    }
+
+    /// Takes the payload if it is of type `P`
+    fn take<P: 'static>(&self) -> Option<P> {
+        self.0.borrow_mut().take().and_then(|payload| payload.downcast().ok()).map(|payload| *payload)
+    }
+}
+
+impl Clone for ExtractedPayload {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+/// The State last committed by a [StateMachine], shared by its [StatePublisher] and
+/// [StateReader]s
+struct PublishedState<TState> {
+    committed: Mutex<Committed<TState>>,
+    changed: Condvar,
+}
+
+/// The State last committed by a [StateMachine], and how many times it has changed
+struct Committed<TState> {
+    state: Arc<TState>,
+    version: u64,
+    /// True once the State Machine has been dropped
+    closed: bool,
+}
+
+impl <TState> PublishedState<TState> {
+    fn lock(&self) -> MutexGuard<'_, Committed<TState>> {
+        self.committed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A change of the committed State of a [StateMachine], sent to subscribers created with
+/// [StateMachine::subscribe]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StateChange<TState> {
+    /// The State before the change
+    pub from: TState,
+    /// The State after the change
+    pub to: TState,
+}
+
+/// Cloneable handle for reading the State last committed by a [StateMachine], created with
+/// [StateMachine::state_reader]. Readers may live on other threads; reading never waits for the
+/// State Machine's Effects to complete.
+pub struct StateReader<TState> {
+    shared: Arc<PublishedState<TState>>
+}
+
+impl <TState> StateReader<TState> {
+    /// Returns the State last committed by [StateMachine::handle_event].
+    pub fn load(&self) -> Arc<TState> {
+        self.shared.lock().state.clone()
+    }
+
+    /// Returns the number of times the committed State has changed
+    pub(crate) fn version(&self) -> u64 {
+        self.shared.lock().version
+    }
+
+    /// Blocks until the committed State has changed more than `version` times, then returns it
+    /// with the number of changes. Returns None if the State Machine is dropped first.
+    pub(crate) fn wait_for_change(&self, version: u64) -> Option<(Arc<TState>, u64)> {
+        let mut committed = self.shared.lock();
+        while committed.version == version && !committed.closed {
+            committed = self.shared.changed.wait(committed).unwrap_or_else(|e| e.into_inner());
+        }
+        (committed.version != version).then(|| (committed.state.clone(), committed.version))
+    }
+}
+
+impl <TState> Clone for StateReader<TState> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone()
+        }
+    }
+}
+
+/// Locked Factory for StateMachines. This struct is created by calling .lock() on a
+/// StateMachineFactory, usually after defining all transitions needed.
+pub struct LockedStateMachineFactory<'a, TEvent, TState: PartialEq<TState> + Clone + Send + 'a, TData = (), TErr = Box<dyn std::error::Error>> {
+    transitions: Arc<Vec<StateMachineTransition<'a, TEvent, TState, TData, TErr>>>,
+    cycle: bool,
+    max_cycles: Option<usize>,
+    unhandled_event_policy: UnhandledEventPolicy<'a, TEvent, TState, TData>,
+    event_enricher: Option<EventEnricher<'a, TEvent, TData>>,
+    interceptors: Arc<Vec<EventInterceptor<'a, TEvent, TState, TData>>>,
+    evaluation_strategy: EvaluationStrategy,
+    effect_error_policy: EffectErrorPolicy,
+    strict: bool,
+    format_event: Option<fn(&TEvent) -> String>,
+    state_equivalence: Option<StateEquivalence<'a, TState>>,
+    parameters: Arc<Parameters>,
+    final_states: Arc<Vec<TState>>,
+    states: Arc<Vec<TState>>,
+    state_descriptions: Arc<Vec<(TState, Description)>>,
+    computed_views: Arc<Vec<ComputedView<'a, TState, TData>>>,
+    timeouts: Arc<Vec<TimeoutTransition<'a, TState, TData, TErr>>>,
+    intervals: Arc<Vec<IntervalTransition<'a, TState, TData, TErr>>>,
+    deferrals: Arc<Vec<Deferral<'a, TEvent, TState>>>,
+    preconditions: Arc<Vec<Precondition<'a, TEvent, TState, TData>>>,
+    postconditions: Arc<Vec<Postcondition<'a, TState, TData>>>,
+    context_definitions: Arc<Vec<StateContextDefinition<'a, TState, TData>>>,
+    injected_failures: Arc<Vec<InjectedFailure<'a, TErr>>>,
+    history: Option<TransitionHistory<TEvent, TState>>,
+    cause_recorder: Option<CauseRecorder<TEvent>>,
+    #[cfg(feature = "tracing")]
+    tracer: Option<Tracer<TEvent, TState>>,
+    failure_tracker: Option<FailureTracker<'a, TState, TErr>>,
+    progress_observer: Option<ProgressObserver<'a, TState>>,
+    metrics: Option<Metrics<'a, TState>>,
+    clock: Option<SharedClock<'a>>,
+    random: Option<SharedRandom>,
+    transition_index: Option<Arc<dyn TransitionIndex<TState> + 'a>>,
+    event_index: Option<Arc<EventIndex<TEvent>>>,
+}
+
+impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + PartialEq + 'a, TData, TErr> LockedStateMachineFactory<'a, TEvent, TState, TData, TErr> {
+    /// Builds a StateMachine with a specified initial state and initial data.
+    pub fn build(&self, initial_state: TState, initial_data: TData) -> StateMachine<'a, TEvent, TState, TData, TErr> {
+        let mut sm = StateMachine {
+            max_cycles: self.max_cycles,
+            unhandled_event_policy: self.unhandled_event_policy.clone(),
+            event_enricher: self.event_enricher.clone(),
+            interceptors: self.interceptors.clone(),
+            evaluation_strategy: self.evaluation_strategy,
+            effect_error_policy: self.effect_error_policy,
+            strict: self.strict,
+            format_event: self.format_event,
+            state_equivalence: self.state_equivalence.clone(),
+            parameters: self.parameters.clone(),
+            final_states: self.final_states.clone(),
+            state_descriptions: self.state_descriptions.clone(),
+            computed_views: self.computed_views.clone(),
+            timeouts: self.timeouts.clone(),
+            intervals: self.intervals.clone(),
+            clock: self.clock.clone(),
+            random: self.random.clone(),
+            deferrals: self.deferrals.clone(),
+            preconditions: self.preconditions.clone(),
+            postconditions: self.postconditions.clone(),
+            context_definitions: self.context_definitions.clone(),
+            failure_injector: FailureInjector::new(self.injected_failures.clone()),
+            history: self.history.clone(),
+            cause_recorder: self.cause_recorder.clone(),
+            #[cfg(feature = "tracing")]
+            tracer: self.tracer.clone(),
+            failure_tracker: self.failure_tracker.clone(),
+            progress_observer: self.progress_observer.clone(),
+            metrics: self.metrics.clone(),
+            transition_index: self.transition_index.clone(),
+            event_index: self.event_index.clone(),
+            ..StateMachine::new(self.cycle, initial_state, initial_data)
+        }.with_transitions(self.transitions.clone());
+        sm.state_entered_at = EnteredAt(sm.now());
+        sm.epoch = Epoch { instant: sm.now(), time: SystemTime::now() };
+        sm.enter_state_contexts();
+        sm
+    }
+
+    /// Builds a StateMachine like [LockedStateMachineFactory::build], overriding some of the
+    /// parameters declared with [StateMachineFactory::with_parameter]. Parameters that are not
+    /// overridden keep the values declared on the factory.
+    pub fn build_with_parameters(&self, initial_state: TState, initial_data: TData, overrides: Parameters) -> StateMachine<'a, TEvent, TState, TData, TErr> {
+        let mut parameters = self.parameters.deref().clone();
+        parameters.values.extend(overrides.values);
+        StateMachine {
+            parameters: Arc::new(parameters),
+            ..self.build(initial_state, initial_data)
+        }
+    }
+
+    /// Builds a StateMachine from a previously captured [Snapshot], resuming from its State and Data.
+    pub fn restore(&self, snapshot: Snapshot<TState, TData>) -> StateMachine<'a, TEvent, TState, TData, TErr> {
+        StateMachine {
+            stack: snapshot.stack,
+            history_states: snapshot.history_states,
+            ..self.build(snapshot.state, snapshot.data)
+        }
+    }
+
+    /// Indexes the Transitions added with the `with_event_transition` and `with_event_kind_transition`
+    /// families of methods by the kind (enum variant) of their Event. When handling an Event,
+    /// State Machines built from the factory then skip the Transitions that only apply to other
+    /// kinds of Event without running their Predicates, which speeds up State Machines where
+    /// nearly every Transition is triggered by a specific Event. Transitions with other Predicates
+    /// or none are considered for every Event. Evaluation order and results are unchanged, and
+    /// the index can be combined with the State index of [StateMachineFactory::lock_indexed].
+    pub fn with_event_index(self) -> Self {
+        let mut general = Vec::new();
+        let mut buckets: HashMap<std::mem::Discriminant<TEvent>, Vec<usize>> = HashMap::new();
+        for (index, transition) in self.transitions.iter().enumerate() {
+            match &transition.trigger {
+                Trigger::Event(event) => buckets.entry(std::mem::discriminant(*event)).or_default().push(index),
+                Trigger::EventKind(kind) => buckets.entry(*kind).or_default().push(index),
+                Trigger::Auto | Trigger::Predicate => general.push(index),
+            }
+        }
+
+        // Each bucket also holds the Transitions that apply to every Event, in evaluation order
+        for bucket in buckets.values_mut() {
+            bucket.extend(&general);
+            bucket.sort_unstable();
+        }
+        Self {
+            event_index: Some(Arc::new(EventIndex { buckets, general })),
+            ..self
+        }
+    }
+
+    /// Returns the States declared with [StateMachineFactory::with_states].
+    pub fn states(&self) -> &[TState] {
+        &self.states
+    }
+}
+
+impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + PartialEq + 'a, TData, TErr> LockedStateMachineFactory<'a, TEvent, TState, TData, TErr>
+where TEvent: Debug, TState: Debug
+{
+    /// Computes a hash of the definition of this factory, identifying its version. Two factories
+    /// with the same settings, Transitions (names, priorities, from and to States, the Events
+    /// they match, whether they have Predicates and Effects, and metadata), timeout Transitions,
+    /// and final and declared States have the same hash, in every process and on every platform,
+    /// as long as the `Debug` renderings of their Events and States are the same. Kinds of Event
+    /// (see [StateMachineFactory::with_event_kind_transition]) have no stable rendering, so a
+    /// Transition matching a kind is hashed by the position of the first Transition matching
+    /// Events of that kind.
+    ///
+    /// Predicates and Effects are opaque, so changing the body of one does not change the hash.
+    /// The same goes for the other code a factory holds: the unhandled Event policy, deferred
+    /// Events, pre- and post-conditions, Event interceptors and enrichers, and a custom State
+    /// equivalence are only hashed by their presence, and by the names and States they apply to.
+    /// Descriptions, documentation, observers, metrics and clocks do not affect the hash.
+    ///
+    /// Storing the hash with a [Snapshot] allows a snapshot taken with a different definition to
+    /// be detected before it is restored, and comparing hashes detects configuration drift between
+    /// replicas.
+    pub fn definition_hash(&self) -> u64 {
+        let mut definition = format!("cycle {} max_cycles {:?} strategy {:?} effect_errors {:?}\n",
+            self.cycle, self.max_cycles, self.evaluation_strategy, self.effect_error_policy);
+        for (index, transition) in self.transitions.iter().enumerate() {
+            let to_state = match &transition.get_to_state {
+                Same => "same".to_string(),
+                SelfExternal => "self external".to_string(),
+                To(state) => format!("to {:?}", state),
+                Calc(_) => "calc".to_string(),
+                Push(state) => format!("push {:?}", state),
+                Pop => "pop".to_string(),
+                History(states) => format!("history {:?}", states),
+            };
+            let trigger = match &transition.trigger {
+                Trigger::Auto => "auto".to_string(),
+                Trigger::Predicate => "predicate".to_string(),
+                Trigger::Event(event) => format!("event {:?}", event),
+                Trigger::EventKind(kind) => {
+                    // The Debug rendering of a Discriminant is unspecified and may change between
+                    // compilers, so a kind is identified by the first Transition that matches it
+                    let first = self.transitions.iter().position(|other| match &other.trigger {
+                        Trigger::Event(event) => std::mem::discriminant(*event) == *kind,
+                        Trigger::EventKind(other_kind) => other_kind == kind,
+                        Trigger::Auto | Trigger::Predicate => false
+                    }).unwrap_or(index);
+                    format!("kind of transition {}", first)
+                }
+            };
+            definition += &format!("transition {:?} priority {} from {:?} {} trigger {} predicate {} effect {} metadata {:?}\n",
+                transition.name, transition.priority, transition.from_state, to_state, trigger,
+                transition.event_predicate.is_some(), transition.effect.is_some(), transition.metadata);
+            if let Some(weight) = transition.weight {
+                definition += &format!("weight {}\n", weight);
+            }
+        }
+        for timeout in self.timeouts.iter() {
+            definition += &format!("timeout from {:?} after {:?} to {:?} effect {}\n",
+                timeout.from_state, timeout.timeout, timeout.to_state, timeout.effect.is_some());
+        }
+        for interval in self.intervals.iter() {
+            definition += &format!("every {:?} in {:?}\n", interval.period, interval.from_state);
+        }
+        definition += &format!("final {:?}\n", self.final_states);
+        // Options that are not set leave the hash unchanged
+        if !self.states.is_empty() {
+            definition += &format!("states {:?}\n", self.states);
+        }
+        if self.strict {
+            definition += "strict\n";
+        }
+        match self.unhandled_event_policy {
+            UnhandledEventPolicy::Ignore => {}
+            UnhandledEventPolicy::Error => definition += "unhandled error\n",
+            UnhandledEventPolicy::Callback(_) => definition += "unhandled callback\n"
+        }
+        for deferral in self.deferrals.iter() {
+            definition += &format!("defer in {:?}\n", deferral.states);
+        }
+        for precondition in self.preconditions.iter() {
+            definition += &format!("precondition {:?}\n", precondition.name);
+        }
+        for postcondition in self.postconditions.iter() {
+            definition += &format!("postcondition {:?}\n", postcondition.name);
+        }
+        if !self.interceptors.is_empty() {
+            definition += &format!("interceptors {}\n", self.interceptors.len());
+        }
+        if self.event_enricher.is_some() {
+            definition += "enricher\n";
+        }
+        if self.state_equivalence.is_some() {
+            definition += "state equivalence\n";
+        }
+
+        // FNV-1a, which unlike the standard library's hashers is specified and stable
+        definition.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+    }
+}
+
+/// Factory for StateMachines. This struct can be used to define a series of Transitions that
+/// may be subsequently used to create multiple state machine instances with those same
+/// transitions.
+#[derive(Default)]
+pub struct StateMachineFactory<'a, TEvent, TState: PartialEq<TState> + Clone + Send + 'a, TData, TErr = Box<dyn std::error::Error>> {
+    cycle: bool,
+    max_cycles: Option<usize>,
+    transitions: Vec<StateMachineTransition<'a, TEvent, TState, TData, TErr>>,
+    unhandled_event_policy: UnhandledEventPolicy<'a, TEvent, TState, TData>,
+    event_enricher: Option<EventEnricher<'a, TEvent, TData>>,
+    interceptors: Vec<EventInterceptor<'a, TEvent, TState, TData>>,
+    evaluation_strategy: EvaluationStrategy,
+    effect_error_policy: EffectErrorPolicy,
+    strict: bool,
+    format_event: Option<fn(&TEvent) -> String>,
+    parameters: Parameters,
+    final_states: Vec<TState>,
+    states: Vec<TState>,
+    state_descriptions: Vec<(TState, Description)>,
+    computed_views: Vec<ComputedView<'a, TState, TData>>,
+    timeouts: Vec<TimeoutTransition<'a, TState, TData, TErr>>,
+    intervals: Vec<IntervalTransition<'a, TState, TData, TErr>>,
+    deferrals: Vec<Deferral<'a, TEvent, TState>>,
+    preconditions: Vec<Precondition<'a, TEvent, TState, TData>>,
+    postconditions: Vec<Postcondition<'a, TState, TData>>,
+    context_definitions: Vec<StateContextDefinition<'a, TState, TData>>,
+    injected_failures: Vec<InjectedFailure<'a, TErr>>,
+    history: Option<TransitionHistory<TEvent, TState>>,
+    cause_recorder: Option<CauseRecorder<TEvent>>,
+    #[cfg(feature = "tracing")]
+    tracer: Option<Tracer<TEvent, TState>>,
+    failure_tracker: Option<FailureTracker<'a, TState, TErr>>,
+    progress_observer: Option<ProgressObserver<'a, TState>>,
+    metrics: Option<Metrics<'a, TState>>,
+    clock: Option<SharedClock<'a>>,
+    random: Option<SharedRandom>,
+}
+
+impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + PartialEq + 'a, TData, TErr> StateMachineFactory<'a, TEvent, TState, TData, TErr> {
+    /// Creates a new `StateMachineFactory`
+    pub fn new() -> Self {
+        Self {
+            cycle: false,
+            max_cycles: None,
+            transitions: Vec::new(),
+            unhandled_event_policy: UnhandledEventPolicy::Ignore,
+            event_enricher: None,
+            interceptors: Vec::new(),
+            evaluation_strategy: EvaluationStrategy::AllMatches,
+            effect_error_policy: EffectErrorPolicy::AbortAndKeep,
+            strict: false,
+            format_event: None,
+            parameters: Parameters::new(),
+            final_states: Vec::new(),
+            states: Vec::new(),
+            state_descriptions: Vec::new(),
+            computed_views: Vec::new(),
+            timeouts: Vec::new(),
+            intervals: Vec::new(),
+            deferrals: Vec::new(),
+            preconditions: Vec::new(),
+            postconditions: Vec::new(),
+            context_definitions: Vec::new(),
+            injected_failures: Vec::new(),
+            history: None,
+            cause_recorder: None,
+            #[cfg(feature = "tracing")]
+            tracer: None,
+            failure_tracker: None,
+            progress_observer: None,
+            metrics: None,
+            clock: None,
+            random: None,
+        }
+    }
+
+    /// Controls whether a state machine loops back after a transition.
+    pub fn cycle(self, cycle: bool) -> Self {
+        Self {
+            cycle,
+            ..self
+        }
+    }
+
+    /// Controls whether a state machine refuses Events that more than one Transition would handle.
+    /// In strict mode, before each evaluation pass, [StateMachine::handle_event] finds the
+    /// Transitions that match the current State and would move to a different State (running
+    /// their Predicates, which should therefore be free of side effects). If there is more than
+    /// one, it returns [StateMachineError::AmbiguousTransition] instead of executing them in
+    /// definition order. Transitions that match the new State later in the same pass are not
+    /// counted, nor are [EffectOutcome::OverrideTarget] directives.
+    pub fn strict(self, strict: bool) -> Self {
+        Self {
+            strict,
+            ..self
+        }
+    }
+
+    /// Includes the `Debug` rendering of the Event being handled in the
+    /// [StateMachineError::EffectError] errors returned by State Machines built from this
+    /// factory. The Event is only rendered when an Effect fails.
+    pub fn with_error_context(self) -> Self
+    where TEvent: Debug
+    {
+        Self {
+            format_event: Some(|event| format!("{:?}", event)),
+            ..self
+        }
+    }
+
+    /// Limits how many times evaluation may loop back for a single Event when cycle is enabled.
+    /// Exceeding the limit makes [StateMachine::handle_event] return
+    /// [StateMachineError::CycleLimitExceeded], so Transitions that ping-pong forever fail loudly
+    /// instead of hanging.
+    pub fn max_cycles(self, max_cycles: usize) -> Self {
+        Self {
+            max_cycles: Some(max_cycles),
+            ..self
+        }
+    }
+
+    /// Controls whether every matching Transition executes in each evaluation pass
+    /// ([EvaluationStrategy::AllMatches], the default), or only the first one
+    /// ([EvaluationStrategy::FirstMatch]). Transitions are considered in descending priority order
+    /// (see [StateMachineFactory::with_priority]), then in the order they were defined.
+    pub fn evaluation_strategy(self, evaluation_strategy: EvaluationStrategy) -> Self {
+        Self {
+            evaluation_strategy,
+            ..self
+        }
+    }
+
+    /// Controls what happens when an Effect fails while handling an Event: stop and keep any State
+    /// changes made so far ([EffectErrorPolicy::AbortAndKeep], the default), stop and undo them
+    /// ([EffectErrorPolicy::AbortAndRollback]), or skip the failed Transition and carry on
+    /// ([EffectErrorPolicy::ContinueCollectingErrors]).
+    pub fn effect_error_policy(self, effect_error_policy: EffectErrorPolicy) -> Self {
+        Self {
+            effect_error_policy,
+            ..self
+        }
+    }
+
+    /// Sets the priority of the most recently added Transition. Transitions with a higher priority
+    /// are evaluated before those with a lower priority; Transitions with equal priorities (the
+    /// default is 0) are evaluated in the order they were defined. Has no effect if no Transition
+    /// has been added yet.
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        if let Some(transition) = self.transitions.last_mut() {
+            transition.priority = priority;
+        }
+        self
+    }
+
+    /// Gives the most recently added Transition a weight, for simulating behavior that is random
+    /// by nature, such as users choosing between pages. At the start of each pass of evaluation,
+    /// the weighted Transitions whose from_state and Predicate match are found, and one of them is
+    /// chosen at random, in proportion to its weight, using the factory's [RandomSource] (see
+    /// [StateMachineFactory::with_random]). Only the chosen one may execute in that pass; the
+    /// other weighted Transitions are skipped, and Transitions without a weight are evaluated as
+    /// usual. A weight of 0 is never chosen. Has no effect if no Transition has been added yet.
+    ///
+    /// [StateMachine::peek_event] does not draw random numbers, so it skips weighted Transitions,
+    /// and [StateMachineFactory::strict] does not treat them as conflicting.
+    pub fn with_weight(mut self, weight: u32) -> Self {
+        if let Some(transition) = self.transitions.last_mut() {
+            transition.weight = Some(weight);
+        }
+        self
+    }
+
+    /// Replaces the Effect of the most recently added Transition with one that returns an
+    /// [EffectOutcome], directing evaluation once it succeeds: for example, stopping evaluation so
+    /// that the Transition consumes the Event, or choosing the State to move to. Has no effect if
+    /// no Transition has been added yet. [StateMachine::peek_event] does not run Effects, so it
+    /// does not reflect their directives.
+    pub fn with_directed_effect(mut self, effect: impl Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<EffectOutcome<TEvent, TState>, TErr> + 'a) -> Self {
+        if let Some(transition) = self.transitions.last_mut() {
+            transition.effect = Some(Box::new(effect));
+        }
+        self
+    }
+
+    /// Attaches a metadata entry (such as `owner = "payments"`) to the most recently added
+    /// Transition, replacing any previous value for the key. Metadata does not affect evaluation;
+    /// it can be read with [StateMachineTransition::metadata] and is used by exports such as
+    /// [StateMachineFactory::ownership_map]. Has no effect if no Transition has been added yet.
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        if let Some(transition) = self.transitions.last_mut() {
+            transition.metadata.insert(key.into(), value.into());
+        }
+        self
+    }
+
+    /// Attaches a display description (such as "Awaiting payment") to the most recently added
+    /// Transition, used when no description is available for a requested locale. Descriptions do
+    /// not affect evaluation; they can be read with [StateMachineTransition::description] and
+    /// [StateMachine::transition_description] for UI rendering, and are used by exports such as
+    /// [StateMachineFactory::typestate_source]. Has no effect if no Transition has been added yet.
+    pub fn with_description(mut self, description: impl Into<String>) -> Self {
+        if let Some(transition) = self.transitions.last_mut() {
+            transition.description.default = Some(description.into());
+        }
+        self
+    }
+
+    /// Attaches documentation to the most recently added Transition, explaining why it exists
+    /// (such as "Card networks retry declined payments once"). Unlike a description, which is
+    /// display text for users, documentation is meant for the engineers operating the State
+    /// Machine. It does not affect evaluation; it can be read with [StateMachineTransition::doc]
+    /// and [StateMachine::transition_doc], and is included in exports such as
+    /// [StateMachineFactory::typestate_source]. Has no effect if no Transition has been added yet.
+    pub fn with_doc(mut self, doc: impl Into<String>) -> Self {
+        if let Some(transition) = self.transitions.last_mut() {
+            transition.doc = Some(doc.into());
+        }
+        self
+    }
+
+    /// Attaches an example to the Predicate of the most recently added Transition: when the State
+    /// Machine is in `from` with `data` and receives `event`, the Predicate should return
+    /// `expected`. Examples do not affect evaluation; [StateMachineFactory::lock_validated] runs
+    /// them when the factory is locked, so a tricky Predicate is checked every time the definition
+    /// is constructed. A Transition without a Predicate passes for every example. Has no effect if
+    /// no Transition has been added yet.
+    pub fn with_guard_example(mut self, from: TState, event: TEvent, data: TData, expected: bool) -> Self {
+        if let Some(transition) = self.transitions.last_mut() {
+            transition.examples.push(GuardExample { from, event, data, expected });
+        }
+        self
+    }
+
+    /// Attaches a display description for a locale (such as `fr` or `en-GB`) to the most recently
+    /// added Transition, see [StateMachineFactory::with_description].
+    pub fn with_localized_description(mut self, locale: impl Into<String>, description: impl Into<String>) -> Self {
+        if let Some(transition) = self.transitions.last_mut() {
+            transition.description.localized.insert(locale.into(), description.into());
+        }
+        self
+    }
+
+    /// Attaches a display description to a State, used when no description is available for a
+    /// requested locale. Descriptions can be read with [StateMachine::state_description], so
+    /// product-facing surfaces do not need to render internal State names.
+    pub fn with_state_description(mut self, state: TState, description: impl Into<String>) -> Self {
+        self.state_description_mut(state).default = Some(description.into());
+        self
+    }
+
+    /// Attaches a display description for a locale (such as `fr` or `en-GB`) to a State, see
+    /// [StateMachineFactory::with_state_description].
+    pub fn with_localized_state_description(mut self, state: TState, locale: impl Into<String>, description: impl Into<String>) -> Self {
+        self.state_description_mut(state).localized.insert(locale.into(), description.into());
+        self
+    }
+
+    /// Returns the description of a State for a locale, see [Description::get].
+    pub fn state_description(&self, state: &TState, locale: Option<&str>) -> Option<&str> {
+        find_description(&self.state_descriptions, state, locale)
+    }
+
+    fn state_description_mut(&mut self, state: TState) -> &mut Description {
+        let position = match self.state_descriptions.iter().position(|(described, _)| described == &state) {
+            Some(position) => position,
+            None => {
+                self.state_descriptions.push((state, Description::default()));
+                self.state_descriptions.len() - 1
+            }
+        };
+        &mut self.state_descriptions[position].1
+    }
+
+    /// Controls how a state machine reacts to an Event for which no Transition matched, meaning
+    /// that no Transition had both a matching from_state and a passing Predicate. Note that
+    /// Transitions without a Predicate (such as loggers on [FromState::Any]) match every Event.
+    pub fn unhandled_event_policy(self, unhandled_event_policy: UnhandledEventPolicy<'a, TEvent, TState, TData>) -> Self {
+        Self {
+            unhandled_event_policy,
+            ..self
+        }
+    }
+
+    /// Creates a LockedStateMachineFactory which can be used to build StateMachine instances
+    /// with the Transitions defined in this StateMachineFactory.
+    pub fn lock(mut self) -> LockedStateMachineFactory<'a, TEvent, TState, TData, TErr> {
+        // This is a stable sort, so transitions with equal priorities keep their definition order
+        self.transitions.sort_by_key(|transition| std::cmp::Reverse(transition.priority));
+        LockedStateMachineFactory {
+            cycle: self.cycle,
+            max_cycles: self.max_cycles,
+            transitions: Arc::new(self.transitions),
+            unhandled_event_policy: self.unhandled_event_policy,
+            event_enricher: self.event_enricher,
+            interceptors: Arc::new(self.interceptors),
+            evaluation_strategy: self.evaluation_strategy,
+            effect_error_policy: self.effect_error_policy,
+            strict: self.strict,
+            format_event: self.format_event,
+            state_equivalence: None,
+            parameters: Arc::new(self.parameters),
+            final_states: Arc::new(self.final_states),
+            states: Arc::new(self.states),
+            state_descriptions: Arc::new(self.state_descriptions),
+            computed_views: Arc::new(self.computed_views),
+            timeouts: Arc::new(self.timeouts),
+            intervals: Arc::new(self.intervals),
+            deferrals: Arc::new(self.deferrals),
+            preconditions: Arc::new(self.preconditions),
+            postconditions: Arc::new(self.postconditions),
+            context_definitions: Arc::new(self.context_definitions),
+            injected_failures: Arc::new(self.injected_failures),
+            history: self.history,
+            cause_recorder: self.cause_recorder,
+            #[cfg(feature = "tracing")]
+            tracer: self.tracer,
+            failure_tracker: self.failure_tracker,
+            progress_observer: self.progress_observer,
+            metrics: self.metrics,
+            clock: self.clock,
+            random: self.random,
+            transition_index: None,
+            event_index: None,
+        }
+    }
+
+    /// Creates a LockedStateMachineFactory like [StateMachineFactory::lock], using a custom
+    /// equivalence to decide whether the current State matches the from_state of a Transition.
+    /// The equivalence receives the Transition's from_state first and the current State second.
+    /// This is useful for payload-carrying States, e.g. to match `Retrying { attempts: 3 }`
+    /// against `FromState::From(Retrying { attempts: 0 })` regardless of the attempt count. The
+    /// equivalence is only used for from_state matching; a Transition still counts as changing
+    /// State whenever the new State is not equal to the current one.
+    pub fn lock_with_state_equivalence(self, equivalence: impl Fn(&TState, &TState) -> bool + 'a) -> LockedStateMachineFactory<'a, TEvent, TState, TData, TErr> {
+        LockedStateMachineFactory {
+            state_equivalence: Some(Arc::new(equivalence)),
+            ..self.lock()
+        }
+    }
+
+    /// Sets an enrichment step that runs once for every Event, before any Transition is evaluated.
+    /// The enricher receives the Event and the current Data and returns the Event that Predicates
+    /// and Effects will see, so derived values (such as totals computed from Data) can be attached
+    /// to the Event once instead of being recomputed by every Predicate.
+    pub fn with_event_enricher(self, enricher: impl Fn(TEvent, &TData) -> TEvent + 'a) -> Self {
+        Self {
+            event_enricher: Some(Arc::new(enricher)),
+            ..self
+        }
+    }
+
+    /// Adds an interceptor that runs for every Event passed to [StateMachine::handle_event],
+    /// before pre-conditions are checked or any Transition is evaluated. The interceptor sees the
+    /// Event, the current State and the Data, and returns an [EventAction] that passes the Event
+    /// on, replaces it with another Event, or swallows it so that handling ends without error.
+    /// Interceptors run in the order they were added, each seeing the Event returned by the one
+    /// before, which suits cross-cutting concerns such as deduplication and rate limiting.
+    ///
+    /// Events emitted by Effects or redelivered after being deferred are not intercepted again,
+    /// and [StateMachine::peek_event] does not run interceptors.
+    pub fn with_event_interceptor(mut self, interceptor: impl Fn(&TEvent, &TState, &mut TData) -> EventAction<TEvent> + 'a) -> Self {
+        self.interceptors.push(Box::new(interceptor));
+        self
+    }
+
+    /// Declares a named parameter (such as a timeout length or a threshold) that Predicates and
+    /// Effects can read through [StateTransitionEffectData::parameters]. Parameters can be
+    /// overridden per State Machine with [LockedStateMachineFactory::build_with_parameters].
+    pub fn with_parameter<T: Any + Send + Sync>(mut self, name: impl Into<String>, value: T) -> Self {
+        self.parameters = self.parameters.with(name, value);
+        self
+    }
+
+    /// Declares a pre-condition, named `name`, that every Event handled with
+    /// [StateMachine::handle_event] must satisfy in the current State and Data before any
+    /// Transition is evaluated. An Event that fails a pre-condition is refused with
+    /// [StateMachineError::PreconditionFailed] and leaves the State Machine unchanged. Events
+    /// emitted by Effects and redelivered deferred Events are not checked again.
+    pub fn with_precondition(mut self, name: impl Into<String>, check: impl Fn(&TEvent, &TState, &TData) -> bool + 'a) -> Self {
+        self.preconditions.push(Precondition { name: name.into(), check: Box::new(check) });
+        self
+    }
+
+    /// Declares a post-condition, named `name`, that the State and Data must satisfy after every
+    /// Event has been handled successfully, including any Events emitted along the way. A
+    /// violated post-condition is reported with [StateMachineError::PostconditionFailed]; as for
+    /// any other error, the State is restored if the [EffectErrorPolicy] is AbortAndRollback,
+    /// but changes made to the Data by Effects are kept.
+    pub fn with_postcondition(mut self, name: impl Into<String>, check: impl Fn(&TState, &TData) -> bool + 'a) -> Self {
+        self.postconditions.push(Postcondition { name: name.into(), check: Box::new(check) });
+        self
+    }
+
+    /// Declares States in which the State Machine is complete. Once a State Machine enters a final
+    /// State, it stops evaluating Transitions (including for any Events emitted by Effects),
+    /// [StateMachine::is_complete] returns true, and [StateMachine::handle_event] returns
+    /// [StateMachineError::MachineCompleted] for any further Event.
+    pub fn with_final_states(mut self, states: impl IntoIterator<Item = TState>) -> Self {
+        self.final_states.extend(states);
+        self
+    }
+
+    /// Declares the complete set of States the State Machine can be in. This is optional, but
+    /// gives features that need every State, such as [StateMachineFactory::validate],
+    /// [StateMachineFactory::analyze] and [StateMachineFactory::typestate_source], the full set
+    /// through [StateMachineFactory::states] rather than only the States mentioned in Transitions,
+    /// and validation then reports any other State a Transition refers to (see
+    /// [ValidationReport::unknown_states](validation::ValidationReport::unknown_states)).
+    pub fn with_states(mut self, states: impl IntoIterator<Item = TState>) -> Self {
+        for state in states {
+            if !self.states.contains(&state) {
+                self.states.push(state);
+            }
+        }
+        self
+    }
+
+    /// Declares every State of a [StateSet] as the complete set of States, as
+    /// [StateMachineFactory::with_states] does.
+    pub fn with_state_set(self) -> Self
+    where TState: StateSet
+    {
+        self.with_states(TState::states())
+    }
+
+    /// Returns the States declared with [StateMachineFactory::with_states].
+    pub fn states(&self) -> &[TState] {
+        &self.states
+    }
+
+    /// Attaches typed context to the States matching `states`, such as the number of attempts made
+    /// while `Connecting`, so that values only meaningful in some States need not live in `TData`
+    /// as `Option`s. Whenever the State Machine enters a matching State (including the initial
+    /// State, when it is built), `init` creates the context from the new State and the Data; the
+    /// context is dropped when the State Machine leaves the State. Transitions between equal
+    /// States keep the context.
+    ///
+    /// Predicates and Effects read the context of the from_state with
+    /// [StateTransitionEffectData::context], so contexts that Effects update should use interior
+    /// mutability (such as `Cell`), as `TData` does. Outside of event handling, use
+    /// [StateMachine::context] and [StateMachine::context_mut]. Contexts are identified by their
+    /// type. They are restored along with the State by [EffectErrorPolicy::AbortAndRollback],
+    /// but are not part of a [Snapshot]: a restored State Machine starts with fresh contexts.
+    pub fn with_state_context<C: Clone + 'static>(mut self, states: impl Into<FromState<TState>>, init: impl Fn(&TState, &TData) -> C + 'a) -> Self {
+        self.context_definitions.push(StateContextDefinition {
+            states: states.into(),
+            init: Box::new(move |state, data| StateContext {
+                value: Box::new(init(state, data)),
+                clone: |value| Box::new(value.downcast_ref::<C>().expect("state context type mismatch").clone()),
+            }),
+        });
+        self
+    }
+
+    /// Registers a computed view over the State and Data, retrievable with
+    /// [StateMachine::computed] and included (as its `Debug` rendering) in [Snapshot]s. Views are
+    /// identified by their type, so wrap values in a newtype (e.g. `struct IsTerminal(bool)`) to
+    /// register several views of the same underlying type. Registering a second view of the same
+    /// type replaces the first.
+    pub fn with_computed<T: Debug + 'static>(mut self, compute: impl Fn(&TState, &TData) -> T + 'a) -> Self {
+        self.computed_views.retain(|view| view.type_id != TypeId::of::<T>());
+        self.computed_views.push(ComputedView {
+            name: std::any::type_name::<T>(),
+            type_id: TypeId::of::<T>(),
+            compute: Box::new(move |state, data| Box::new(compute(state, data))),
+            render: |value| format!("{:?}", value.downcast_ref::<T>().expect("computed view type mismatch")),
+        });
+        self
+    }
+
+    /// Adds a Transition that is taken automatically once the State Machine has been in a State
+    /// matching from_state for at least `timeout`. No background timer is started; timeouts are
+    /// taken by [StateMachine::tick], which the application calls periodically or at
+    /// [StateMachine::next_deadline].
+    pub fn with_timeout_transition(mut self, from_state: impl Into<FromState<TState>>, timeout: Duration, to_state: TState) -> Self
+    {
+        self.timeouts.push(TimeoutTransition { from_state: from_state.into(), timeout, to_state, effect: None });
+        self
+    }
+
+    /// Adds a timeout Transition like [StateMachineFactory::with_timeout_transition], with an
+    /// Effect that runs when the timeout is taken.
+    pub fn with_timeout_transition_effect(mut self, from_state: impl Into<FromState<TState>>, timeout: Duration, to_state: TState, effect: impl Fn(StateTimeoutEffectData<TState, TData>) -> Result<(), TErr> + 'a) -> Self
+    {
+        self.timeouts.push(TimeoutTransition { from_state: from_state.into(), timeout, to_state, effect: Some(Box::new(effect)) });
+        self
+    }
+
+    /// Adds a Transition from States matching from_state to themselves that runs `effect` every
+    /// `period` for as long as the State Machine stays in the State, counted from when it entered
+    /// the State. Like timeouts, these are taken by [StateMachine::tick]; several periods that fell
+    /// due between two ticks each run the Effect. They do not change the State, so they do not
+    /// restart timeout Transitions, and do not count towards [StateMachineFactory::max_cycles]. A
+    /// zero `period` never falls due.
+    pub fn with_every_transition(mut self, from_state: impl Into<FromState<TState>>, period: Duration, effect: impl Fn(StateTimeoutEffectData<TState, TData>) -> Result<(), TErr> + 'a) -> Self
+    {
+        self.intervals.push(IntervalTransition { from_state: from_state.into(), period, effect: Box::new(effect) });
+        self
+    }
+
+    /// Sets the [Clock] that State Machines built from this factory read the current time from,
+    /// for timeouts, the circuit breaker and [StateMachine::now]. Defaults to the system clock.
+    /// Share a [MockClock] between the factory and a test to control time in the test.
+    pub fn with_clock(self, clock: impl Clock + 'a) -> Self {
+        Self {
+            clock: Some(Arc::new(clock)),
+            ..self
+        }
+    }
+
+    /// Sets the [RandomSource] used to choose between weighted Transitions (see
+    /// [StateMachineFactory::with_weight]). State Machines built from this factory share the
+    /// source, so a seeded source makes a simulation reproducible as long as Events are handled in
+    /// the same order. Without a source, each State Machine uses its own [XorShiftRandom] with a
+    /// fixed seed. With the `rand` feature, any random number generator from the `rand` crate
+    /// (such as a seeded `StdRng`) can be used.
+    pub fn with_random(self, random: impl RandomSource + 'static) -> Self {
+        Self {
+            random: Some(Arc::new(Mutex::new(random))),
+            ..self
+        }
+    }
+
+    /// Instruments State Machines with `tracing` (requires the `tracing` feature). Each Event
+    /// handled, including Events emitted by Effects, gets a `handle_event` span at DEBUG level
+    /// with the Event and the State as `Debug` renderings. Within it, every Transition whose
+    /// from_state matches produces an event with the Transition's name, the from and to States,
+    /// the cycle index, the result of its Predicate (`passed`, `failed`, `error`, or `none`), and
+    /// the outcome of its Effect (`succeeded`, `failed`, `none`, or `not run`). Transitions that do
+    /// not execute are reported at TRACE level, and the others at DEBUG level.
+    #[cfg(feature = "tracing")]
+    pub fn with_tracing(self) -> Self
+    where TEvent: Debug, TState: Debug
+    {
+        Self {
+            tracer: Some(Tracer {
+                format_event: |event| format!("{:?}", event),
+                format_state: |state| format!("{:?}", state),
+            }),
+            ..self
+        }
+    }
+
+    /// Makes State Machines remember the Event that caused them to enter their current State,
+    /// readable with [StateMachine::last_cause] and included in [Snapshot]s, so that error States
+    /// can report what led to them without bookkeeping in `TData`.
+    pub fn with_last_cause(self) -> Self
+    where TEvent: Clone + Debug
+    {
+        Self {
+            cause_recorder: Some(CauseRecorder {
+                clone_event: TEvent::clone,
+                format_event: |event| format!("{:?}", event),
+            }),
+            ..self
+        }
+    }
+
+    /// Enables the built-in history recorder. Each State Machine keeps a [TransitionRecord] for the
+    /// most recent `capacity` Transitions it executed (including timeout Transitions), readable
+    /// with [StateMachine::history]. Older records are discarded as new ones arrive.
+    pub fn with_history(self, capacity: usize) -> Self
+    where TEvent: Debug
+    {
+        Self {
+            history: Some(TransitionHistory {
+                records: VecDeque::with_capacity(capacity),
+                capacity,
+                format_event: |event| format!("{:?}", event),
+            }),
+            ..self
+        }
+    }
+
+    /// Tracks consecutive Effect failures for each Transition, calling `alert` for every failure
+    /// once a Transition has failed `threshold` times in a row, so that State Machines that keep
+    /// retrying a failing Effect surface the problem instead of failing silently. The count for a
+    /// Transition is reset whenever it executes successfully. Counts are kept separately for each
+    /// State Machine.
+    pub fn with_failure_alert(self, threshold: usize, alert: impl Fn(EffectFailure<TState, TErr>) + 'a) -> Self {
+        let failure_tracker = self.failure_tracker.unwrap_or_else(FailureTracker::new);
+        Self {
+            failure_tracker: Some(FailureTracker {
+                alert: Some((threshold, Arc::new(alert))),
+                ..failure_tracker
+            }),
+            ..self
+        }
+    }
+
+    /// Registers an observer for the progress that Effects report with
+    /// [StateTransitionEffectData::progress], so that UIs can show the progress of slow Effects
+    /// (such as uploads or migrations) without a custom channel. The observer is called on the
+    /// thread running the Effect, while the Effect is running.
+    pub fn with_progress_observer(self, observer: impl Fn(EffectProgress<TState>) + 'a) -> Self {
+        Self {
+            progress_observer: Some(Arc::new(observer)),
+            ..self
+        }
+    }
+
+    /// Registers a [MachineMetrics] implementation to be told about the Events handled, Transitions
+    /// executed, Predicate rejections and Effect failures of every State Machine built from this
+    /// factory, as an aggregation point for counters and histograms.
+    pub fn with_metrics(self, metrics: impl MachineMetrics<TState> + 'a) -> Self {
+        Self {
+            metrics: Some(Arc::new(metrics)),
+            ..self
+        }
+    }
+
+    /// Disables a Transition once it has failed `threshold` times in a row, so that a broken
+    /// integration stops being retried and the remaining Transitions are evaluated as if it did
+    /// not exist. A disabled Transition is enabled again the first time it is considered after
+    /// `cooldown` has passed (measured with [StateMachine::now]); if it then fails again, it is
+    /// immediately disabled for another `cooldown`, and a success resets it. `observer` is called
+    /// whenever a Transition is disabled or enabled. Transitions are tracked separately for each
+    /// State Machine.
+    pub fn with_circuit_breaker(self, threshold: usize, cooldown: Duration, observer: impl Fn(CircuitBreakerEvent) + 'a) -> Self {
+        let failure_tracker = self.failure_tracker.unwrap_or_else(FailureTracker::new);
+        Self {
+            failure_tracker: Some(FailureTracker {
+                breaker: Some(CircuitBreaker {
+                    threshold,
+                    cooldown,
+                    observer: Arc::new(observer),
+                }),
+                ..failure_tracker
+            }),
+            ..self
+        }
+    }
+
+    /// Injects failures into the Transitions with the given name, for exercising error handling
+    /// paths (error states, retries, compensation) in tests. When a failure is injected, the
+    /// Transition's Effect is skipped and [StateMachine::handle_event] returns
+    /// [StateMachineError::EffectError] with the error produced by `make_error`, exactly as if the
+    /// Effect had failed. Invocation counts and random sequences are tracked separately for each
+    /// State Machine, so every State Machine built from the factory fails the same way.
+    pub fn with_injected_failure(mut self, transition_name: impl Into<String>, injection: FailureInjection, make_error: impl Fn() -> TErr + 'a) -> Self
+    {
+        self.injected_failures.push(InjectedFailure { name: transition_name.into(), injection, make_error: Arc::new(make_error) });
+        self
+    }
+
+    /// Applies a fragment of Transitions to this `StateMachineFactory`. A fragment is any function
+    /// that adds Transitions to a factory; by writing fragments generically over `TData` with trait
+    /// bounds, the same Transitions can be reused across State Machines whose Data types differ
+    /// but share capabilities. See the crate documentation for an example.
+    pub fn with_fragment(self, fragment: impl FnOnce(Self) -> Self) -> Self
+    {
+        fragment(self)
+    }
+
+    /// Merges the definitions of another `StateMachineFactory` into this one, as if they had been
+    /// added to this factory after its own: Transitions, timeout and interval Transitions,
+    /// deferred Events, pre- and post-conditions, State contexts, final and declared States, State
+    /// descriptions, computed views, parameters, injected failures and Event interceptors, which run
+    /// after those of this factory. This lets reusable bundles of Transitions (standard error
+    /// handling, for example) be built as ordinary factories in separate functions and composed
+    /// into several State Machines. Settings of `other` that apply to the whole State Machine, such
+    /// as [StateMachineFactory::cycle] or observers, are ignored.
+    pub fn merge(mut self, other: StateMachineFactory<'a, TEvent, TState, TData, TErr>) -> Self {
+        self.transitions.extend(other.transitions);
+        self.timeouts.extend(other.timeouts);
+        self.intervals.extend(other.intervals);
+        self.deferrals.extend(other.deferrals);
+        self.preconditions.extend(other.preconditions);
+        self.postconditions.extend(other.postconditions);
+        self.context_definitions.extend(other.context_definitions);
+        for state in other.final_states {
+            if !self.final_states.contains(&state) {
+                self.final_states.push(state);
+            }
+        }
+        self = self.with_states(other.states);
+        for (state, description) in other.state_descriptions {
+            let existing = self.state_description_mut(state);
+            if description.default.is_some() {
+                existing.default = description.default;
+            }
+            existing.localized.extend(description.localized);
+        }
+        for view in other.computed_views {
+            self.computed_views.retain(|existing| existing.type_id != view.type_id);
+            self.computed_views.push(view);
+        }
+        self.parameters.values.extend(other.parameters.values);
+        self.injected_failures.extend(other.injected_failures);
+        self.interceptors.extend(other.interceptors);
+        self
+    }
+
+    /// Adds an externally-created transition to this `StateMachineFactory`
+    pub fn with_custom_transition(mut self, transition: StateMachineTransition<'a, TEvent, TState, TData, TErr>) -> Self
+    {
+        self.transitions.push(transition);
+        self
+    }
+
+    /// Adds a named Transition to the State Machine definition with no predicate and no side
+    /// effects. If this State Machine has cycle enabled, this transition will execute
+    /// automatically, essentially skipping the From state. If Cycle is not enabled, the State
+    /// Machine will transition to the To state with any future event.
+    pub fn with_named_auto_transition(mut self, name: impl Into<String>, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>) -> Self
+    {
+        self.transitions.push(StateMachineTransition::new(Some(name.into()), None, from_state.into(), get_to_state.into(), None));
+        self
+    }
+
+    /// Adds a named Transition to the State Machine definition with a side effect and no predicate.
+    /// If this State Machine has cycle enabled, this transition will execute automatically,
+    /// essentially skipping the From state after executing the side effect. If Cycle is not
+    /// enabled, the State Machine will transition to the To state with any future event.
+    pub fn with_named_transition_effect(mut self, name: impl Into<String>, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, effect: impl Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + 'a) -> Self
+    {
+        self.transitions.push(StateMachineTransition::new(Some(name.into()), None, from_state.into(), get_to_state.into(), Some(plain_effect(effect))));
+        self
+    }
+
+    /// Adds a name Transition to the State Machine definition with a predicate and no Side Effect.
+    /// This transition will test the predicate for any event and move to the To state if the
+    /// Predicate returns true.
+    pub fn with_named_predicated_transition(mut self, name: impl Into<String>, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, event_predicate: impl Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> bool + 'a) -> Self
+    {
+        self.transitions.push(StateMachineTransition::new(Some(name.into()), Some(TransitionPredicate::Infallible(Box::new(event_predicate))), from_state.into(), get_to_state.into(), None));
+        self
+    }
+
+    /// Adds a named Transition to the State Machine definition with a predicate and a Side Effect.
+    /// This transition will test the predicate for any event and execute the Side Effect then move
+    /// to the To state if the Predicate returns true.
+    pub fn with_named_predicated_transition_effect(mut self, name: impl Into<String>, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, event_predicate: impl Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> bool + 'a, effect: impl Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + 'a) -> Self
+    {
+        self.transitions.push(StateMachineTransition::new(Some(name.into()), Some(TransitionPredicate::Infallible(Box::new(event_predicate))), from_state.into(), get_to_state.into(), Some(plain_effect(effect))));
+        self
+    }
+
+    /// Adds a named Transition to the State Machine definition with a fallible predicate and no
+    /// Side Effect. If the Predicate returns an error, [StateMachine::handle_event] stops and
+    /// returns [StateMachineError::PredicateError].
+    pub fn with_named_fallible_predicated_transition(mut self, name: impl Into<String>, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, event_predicate: impl Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> Result<bool, Box<dyn std::error::Error + Send>> + 'a) -> Self
+    {
+        self.transitions.push(StateMachineTransition::new(Some(name.into()), Some(TransitionPredicate::Fallible(Box::new(event_predicate))), from_state.into(), get_to_state.into(), None));
+        self
+    }
+
+    /// Adds a named Transition to the State Machine definition with a fallible predicate and a
+    /// Side Effect. If the Predicate returns an error, [StateMachine::handle_event] stops and
+    /// returns [StateMachineError::PredicateError] without executing the Side Effect.
+    pub fn with_named_fallible_predicated_transition_effect(mut self, name: impl Into<String>, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, event_predicate: impl Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> Result<bool, Box<dyn std::error::Error + Send>> + 'a, effect: impl Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + 'a) -> Self
+    {
+        self.transitions.push(StateMachineTransition::new(Some(name.into()), Some(TransitionPredicate::Fallible(Box::new(event_predicate))), from_state.into(), get_to_state.into(), Some(plain_effect(effect))));
+        self
+    }
+
+    /// Adds an unnamed Transition to the State Machine definition with no predicate and no side
+    /// effects. If this State Machine has cycle enabled, this transition will execute
+    /// automatically, essentially skipping the From state. If Cycle is not enabled, the State
+    /// Machine will transition to the To state with any future event.
+    pub fn with_auto_transition(mut self, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>) -> Self
+    {
+        self.transitions.push(StateMachineTransition::new(None, None, from_state.into(), get_to_state.into(), None));
+        self
+    }
+
+    /// Adds an unnamed Transition to the State Machine definition with a side effect and no
+    /// predicate. If this State Machine has cycle enabled, this transition will execute
+    /// automatically, essentially skipping the From state after executing the side effect. If
+    /// Cycle is not enabled, the State Machine will transition to the To state with any future
+    /// event.
+    pub fn with_transition_effect(mut self, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, effect: impl Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + 'a) -> Self
+    {
+        self.transitions.push(StateMachineTransition::new(None, None, from_state.into(), get_to_state.into(), Some(plain_effect(effect))));
+        self
+    }
+
+    /// Adds an unnamed Transition to the State Machine definition with a predicate and no Side
+    /// Effect. This transition will test the predicate for any event and move to the To state if
+    /// the Predicate returns true.
+    pub fn with_predicated_transition(mut self, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, event_predicate: impl Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> bool + 'a) -> Self
+    {
+        self.transitions.push(StateMachineTransition::new(None, Some(TransitionPredicate::Infallible(Box::new(event_predicate))), from_state.into(), get_to_state.into(), None));
+        self
+    }
+
+    /// Adds an unnamed Transition to the State Machine definition with a predicate and a Side
+    /// Effect. This transition will test the predicate for any event and execute the Side Effect
+    /// then move to the To state if the Predicate returns true.
+    pub fn with_predicated_transition_effect(mut self, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, event_predicate: impl Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> bool + 'a, effect: impl Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + 'a) -> Self
+    {
+        self.transitions.push(StateMachineTransition::new(None, Some(TransitionPredicate::Infallible(Box::new(event_predicate))), from_state.into(), get_to_state.into(), Some(plain_effect(effect))));
+        self
+    }
+
+    /// Adds an unnamed Transition to the State Machine definition with a fallible predicate and no
+    /// Side Effect. If the Predicate returns an error, [StateMachine::handle_event] stops and
+    /// returns [StateMachineError::PredicateError].
+    pub fn with_fallible_predicated_transition(mut self, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, event_predicate: impl Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> Result<bool, Box<dyn std::error::Error + Send>> + 'a) -> Self
+    {
+        self.transitions.push(StateMachineTransition::new(None, Some(TransitionPredicate::Fallible(Box::new(event_predicate))), from_state.into(), get_to_state.into(), None));
+        self
+    }
+
+    /// Adds an unnamed Transition to the State Machine definition with a fallible predicate and a
+    /// Side Effect. If the Predicate returns an error, [StateMachine::handle_event] stops and
+    /// returns [StateMachineError::PredicateError] without executing the Side Effect.
+    pub fn with_fallible_predicated_transition_effect(mut self, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, event_predicate: impl Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> Result<bool, Box<dyn std::error::Error + Send>> + 'a, effect: impl Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + 'a) -> Self
+    {
+        self.transitions.push(StateMachineTransition::new(None, Some(TransitionPredicate::Fallible(Box::new(event_predicate))), from_state.into(), get_to_state.into(), Some(plain_effect(effect))));
+        self
+    }
+
+    /// Adds a named Transition to the State Machine definition that matches any Event of the same
+    /// kind (enum variant) as the provided Event, regardless of its payload. The full Event,
+    /// including its payload, is available to Effects through [StateTransitionEffectData::event].
+    pub fn with_named_event_kind_transition(mut self, name: impl Into<String>, kind: &TEvent, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>) -> Self
+    where TEvent: 'a
+    {
+        let kind = std::mem::discriminant(kind);
+        self.transitions.push(StateMachineTransition::new(Some(name.into()), Some(TransitionPredicate::Infallible(Box::new(move |e| std::mem::discriminant(e.event) == kind))), from_state.into(), get_to_state.into(), None).with_trigger(Trigger::EventKind(kind)));
+        self
+    }
+
+    /// Adds a named Transition with a side effect to the State Machine definition that matches any
+    /// Event of the same kind (enum variant) as the provided Event, regardless of its payload.
+    pub fn with_named_event_kind_transition_effect(mut self, name: impl Into<String>, kind: &TEvent, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, effect: impl Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + 'a) -> Self
+    where TEvent: 'a
+    {
+        let kind = std::mem::discriminant(kind);
+        self.transitions.push(StateMachineTransition::new(Some(name.into()), Some(TransitionPredicate::Infallible(Box::new(move |e| std::mem::discriminant(e.event) == kind))), from_state.into(), get_to_state.into(), Some(plain_effect(effect))).with_trigger(Trigger::EventKind(kind)));
+        self
+    }
+
+    /// Adds an unnamed Transition to the State Machine definition that matches any Event of the
+    /// same kind (enum variant) as the provided Event, regardless of its payload. The full Event,
+    /// including its payload, is available to Effects through [StateTransitionEffectData::event].
+    pub fn with_event_kind_transition(mut self, kind: &TEvent, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>) -> Self
+    where TEvent: 'a
+    {
+        let kind = std::mem::discriminant(kind);
+        self.transitions.push(StateMachineTransition::new(None, Some(TransitionPredicate::Infallible(Box::new(move |e| std::mem::discriminant(e.event) == kind))), from_state.into(), get_to_state.into(), None).with_trigger(Trigger::EventKind(kind)));
+        self
+    }
+
+    /// Adds an unnamed Transition with a side effect to the State Machine definition that matches
+    /// any Event of the same kind (enum variant) as the provided Event, regardless of its payload.
+    pub fn with_event_kind_transition_effect(mut self, kind: &TEvent, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, effect: impl Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + 'a) -> Self
+    where TEvent: 'a
+    {
+        let kind = std::mem::discriminant(kind);
+        self.transitions.push(StateMachineTransition::new(None, Some(TransitionPredicate::Infallible(Box::new(move |e| std::mem::discriminant(e.event) == kind))), from_state.into(), get_to_state.into(), Some(plain_effect(effect))).with_trigger(Trigger::EventKind(kind)));
+        self
+    }
+
+    /// Adds a named Transition with a side effect to the State Machine definition that matches
+    /// the Events from which `extract` extracts a payload, such as the digit of a
+    /// `Digit { digit }` Event. The payload is passed to the Effect, so the Effect does not need
+    /// to match the Event again. `extract` runs once per Event considered, and the payload it
+    /// extracted for the Predicate is kept by the State Machine and passed to the Effect.
+    pub fn with_named_extracted_event_transition<P: 'static>(mut self, name: impl Into<String>, extract: impl Fn(&TEvent) -> Option<P> + 'a, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, effect: impl Fn(P, StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + 'a) -> Self
+    {
+        self.transitions.push(extracted_event_transition(Some(name.into()), extract, from_state.into(), get_to_state.into(), effect));
+        self
+    }
+
+    /// Adds an unnamed Transition with a side effect to the State Machine definition that matches
+    /// the Events from which `extract` extracts a payload, see
+    /// [StateMachineFactory::with_named_extracted_event_transition].
+    pub fn with_extracted_event_transition<P: 'static>(mut self, extract: impl Fn(&TEvent) -> Option<P> + 'a, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, effect: impl Fn(P, StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + 'a) -> Self
+    {
+        self.transitions.push(extracted_event_transition(None, extract, from_state.into(), get_to_state.into(), effect));
+        self
+    }
+}
+
+/// Builds a Transition whose payload extractor doubles as its Predicate, see
+/// [StateMachineFactory::with_named_extracted_event_transition]
+fn extracted_event_transition<'a, TEvent, TState: PartialEq<TState> + Clone + Send + 'a, TData, TErr, P: 'static>(
+    name: Option<String>,
+    extract: impl Fn(&TEvent) -> Option<P> + 'a,
+    from_state: FromState<TState>,
+    get_to_state: ToState<TEvent, TState, TData>,
+    effect: impl Fn(P, StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + 'a
+) -> StateMachineTransition<'a, TEvent, TState, TData, TErr> {
+    let extract = Rc::new(extract);
+    let extract_again = extract.clone();
+    StateMachineTransition::new(
+        name,
+        Some(TransitionPredicate::Infallible(Box::new(move |e| {
+            let payload = extract(e.event);
+            let matched = payload.is_some();
+            e.extracted.set(payload);
+            matched
+        }))),
+        from_state,
+        get_to_state,
+        Some(plain_effect(move |e: StateTransitionEffectData<TEvent, TState, TData>| {
+            // The Predicate stored the payload just before; should it be missing, extract it again
+            match e.extracted.take().or_else(|| extract_again(e.event)) {
+                Some(payload) => effect(payload, e),
+                None => Ok(())
+            }
+        }))
+    )
+}
+
+impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + PartialEq + 'a, TData, TErr> StateMachineFactory<'a, TEvent, TState, TData, TErr>
+where TState: Hash
+{
+    /// Creates a LockedStateMachineFactory like [StateMachineFactory::lock], which also indexes
+    /// Transitions by their from_state. When handling Events, State Machines built from it only
+    /// consider the Transitions that can apply to the current State, rather than every
+    /// Transition, which speeds up State Machines with many Transitions. Evaluation order and
+    /// results are unchanged. The index matches States with `Hash` and `Eq`, so it cannot be
+    /// combined with a custom state equivalence.
+    pub fn lock_indexed(self) -> LockedStateMachineFactory<'a, TEvent, TState, TData, TErr> {
+        let locked = self.lock();
+        let mut general = Vec::new();
+        let mut buckets: HashMap<TState, Vec<usize>> = HashMap::new();
+        for (index, transition) in locked.transitions.iter().enumerate() {
+            match &transition.from_state {
+                FromState::From(state) => buckets.entry(state.clone()).or_default().push(index),
+                FromState::AnyOf(states) => {
+                    for state in states {
+                        let bucket = buckets.entry(state.clone()).or_default();
+                        if bucket.last() != Some(&index) {
+                            bucket.push(index);
+                        }
+                    }
+                }
+                FromState::Any | FromState::NoneOf(_) => general.push(index),
+            }
+        }
+
+        // Each bucket also holds the Transitions valid from any State, in evaluation order
+        for bucket in buckets.values_mut() {
+            bucket.extend(&general);
+            bucket.sort_unstable();
+        }
+        LockedStateMachineFactory {
+            transition_index: Some(Arc::new(HashedTransitionIndex { buckets, general })),
+            ..locked
+        }
+    }
+}
+
+impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + PartialEq + 'a, TData, TErr> StateMachineFactory<'a, TEvent, TState, TData, TErr>
+where TEvent: PartialEq<TEvent> + Sync
+{
+    /// Adds a named Transition to the State Machine definition whose predicate checks for equality with a
+    /// provided Event reference. This is syntactic sugar for `.with_predicated_transition(..)` with
+    /// an equality Predicate.
+    pub fn with_named_event_transition(mut self, name: impl Into<String>, event: &'a TEvent, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>) -> Self
+    {
+        self.transitions.push(
+            StateMachineTransition::new(
+                Some(name.into()),
+                Some(TransitionPredicate::Infallible(Box::new(|e| *event == *e.event))),
+                from_state.into(),
+                get_to_state.into(),
+                None
+            ).with_trigger(Trigger::Event(event))
+        );
+        self
+    }
+
+    /// Adds a named Transition with a side effect to the State Machine definition whose predicate checks
+    /// for equality with a provided Event reference. This is syntactic sugar for
+    /// `.with_predicated_transition(..)` with an equality Predicate.
+    pub fn with_named_event_transition_effect(mut self, name: impl Into<String>, event: &'a TEvent, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, effect: impl Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + 'a) -> Self
+    {
+        self.transitions.push(
+            StateMachineTransition::new(
+                Some(name.into()),
+                Some(TransitionPredicate::Infallible(Box::new(|e| *event == *e.event))),
+                from_state.into(),
+                get_to_state.into(),
+                Some(plain_effect(effect))
+            ).with_trigger(Trigger::Event(event))
+        );
+        self
+    }
+    /// Adds an unnamed Transition to the State Machine definition whose predicate checks for
+    /// equality with a provided Event reference. This is syntactic sugar for
+    /// `.with_predicated_transition(..)` with an equality Predicate.
+    pub fn with_event_transition(mut self, event: &'a TEvent, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>) -> Self
+    {
+        self.transitions.push(
+            StateMachineTransition::new(
                 None,
-                Some(Box::new(|e| *event == *e.event)),
+                Some(TransitionPredicate::Infallible(Box::new(|e| *event == *e.event))),
                 from_state.into(),
                 get_to_state.into(),
                 None
+            ).with_trigger(Trigger::Event(event))
+        );
+        self
+    }
+
+    /// Adds an unnamed Transition with a side effect to the State Machine definition whose
+    /// predicate checks for equality with a provided Event reference. This is syntactic sugar for
+    /// `.with_predicated_transition(..)` with an equality Predicate.
+    pub fn with_event_transition_effect(mut self, event: &'a TEvent, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, effect: impl Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + 'a) -> Self
+    {
+        self.transitions.push(
+            StateMachineTransition::new(
+                None,
+                Some(TransitionPredicate::Infallible(Box::new(|e| *event == *e.event))),
+                from_state.into(),
+                get_to_state.into(),
+                Some(plain_effect(effect))
+            ).with_trigger(Trigger::Event(event))
+        );
+        self
+    }
+
+    /// Defers an Event in States matching `states` (UML's `defer`): while the State Machine is in
+    /// such a State, the Event is not evaluated but kept in a queue, and redelivered once the
+    /// State changes, before any Events emitted by Effects. Events are redelivered in the order
+    /// they arrived, and one that is still deferred in the new State stays queued. This saves
+    /// buffering Events outside the State Machine when they can arrive before it is ready for
+    /// them, such as a `DataReady` Event during initialization.
+    ///
+    /// Deferred Events are not part of a [Snapshot], and see [StateMachine::deferred_events].
+    pub fn with_deferred_event(mut self, event: &'a TEvent, states: impl Into<FromState<TState>>) -> Self
+    {
+        self.deferrals.push(Deferral { matches: Box::new(move |e| *event == *e), states: states.into() });
+        self
+    }
+}
+
+/// Point-in-time copy of the State and Data of a [StateMachine], created with
+/// [StateMachine::snapshot] and restored with [LockedStateMachineFactory::restore]. When the `serde`
+/// feature is enabled, snapshots can be serialized so that long-lived State Machines can be
+/// persisted across process restarts. Transitions are not part of a snapshot; they come from the
+/// factory used to restore it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Snapshot<TState, TData> {
+    /// The State of the State Machine when the snapshot was taken.
+    pub state: TState,
+    /// The Data of the State Machine when the snapshot was taken.
+    pub data: TData,
+    /// The stack of States saved by [ToState::Push] Transitions, see [StateMachine::stack].
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub stack: Vec<TState>,
+    /// The States recorded for [ToState::History] Transitions, by Transition index.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub history_states: Vec<Option<TState>>,
+    /// The `Debug` rendering of the Event that caused the State Machine to enter its State, see
+    /// [StateMachine::last_cause]. This is informational only; it is not restored.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub last_cause: Option<String>,
+    /// `Debug` renderings of the computed views registered with
+    /// [StateMachineFactory::with_computed], keyed by type name. These are informational only;
+    /// they are recalculated rather than restored.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub computed: BTreeMap<String, String>,
+}
+
+/// Display text for a State or Transition, with optional translations, see
+/// [StateMachineFactory::with_state_description] and [StateMachineFactory::with_description]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Description {
+    /// The text used when no text is available for a requested locale.
+    pub default: Option<String>,
+    /// Text keyed by locale, such as `fr` or `en-GB`.
+    pub localized: BTreeMap<String, String>,
+}
+
+impl Description {
+    /// Returns the text for a locale, falling back to the text for its language (`fr` for
+    /// `fr-CA`) and then to the default text. With no locale, returns the default text.
+    pub fn get(&self, locale: Option<&str>) -> Option<&str> {
+        let language = locale.and_then(|locale| locale.split_once('-')).map(|(language, _)| language);
+        locale.into_iter().chain(language)
+            .find_map(|locale| self.localized.get(locale))
+            .or(self.default.as_ref())
+            .map(String::as_str)
+    }
+}
+
+/// Finds the description of a State for a locale
+fn find_description<'d, TState: PartialEq<TState>>(descriptions: &'d [(TState, Description)], state: &TState, locale: Option<&str>) -> Option<&'d str> {
+    descriptions.iter()
+        .find(|(described, _)| described == state)
+        .and_then(|(_, description)| description.get(locale))
+}
+
+/// The outcome of an Event evaluated by [StateMachine::peek_event]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EventPreview<TState> {
+    /// The Transitions that would execute, in the order they would execute. Empty if no
+    /// Transition matches the Event.
+    pub transitions: Vec<TransitionId>,
+    /// The State the State Machine would be in after handling the Event.
+    pub state: TState,
+}
+
+/// A Transition executed by a [StateMachine], recorded when history is enabled with
+/// [StateMachineFactory::with_history]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TransitionRecord<TState> {
+    /// When the Transition executed, by the State Machine's [Clock] (see [StateMachine::now]),
+    /// converted to wall-clock time.
+    pub timestamp: SystemTime,
+    /// The name of the Transition, if any.
+    pub name: Option<String>,
+    /// The State the Transition moved from.
+    pub from: TState,
+    /// The State the Transition moved to.
+    pub to: TState,
+    /// The `Debug` rendering of the Event that caused the Transition, or a description of the
+    /// timeout for timeout Transitions.
+    pub event_debug: String,
+}
+
+/// Basic error type for [StateMachine]
+#[derive(Debug)]
+pub enum StateMachineError<TState: Send + Clone + Eq + PartialEq, TErr = Box<dyn std::error::Error>> {
+    /// Returned by [StateMachine::handle_event] when the Effect of a Transition fails. The error
+    /// returned by the Effect is the [source](std::error::Error::source) of this error if it is
+    /// boxed (as the default `Box<dyn Error>` is).
+    EffectError {
+        /// The state the failing Transition was moving from
+        from: TState,
+        /// The state the failing Transition was moving to
+        to: TState,
+        /// The name of the failing Transition, if any
+        transition: Option<String>,
+        /// The `Debug` rendering of the Event being handled, if enabled with
+        /// [StateMachineFactory::with_error_context], or None for timeout and interval Transitions
+        event: Option<String>,
+        /// The number of times evaluation had looped back (see [StateMachineFactory::cycle]) or
+        /// chained timeouts had been taken before the Effect failed
+        cycle: usize,
+        /// The error returned by the Effect
+        error: TErr
+    },
+    /// Returned by [StateMachine::handle_event] when no Transition matched the Event and the
+    /// [UnhandledEventPolicy] is [UnhandledEventPolicy::Error]
+    UnhandledEvent(TState),
+    /// Returned by [StateMachine::handle_event] when a fallible Predicate fails while deciding
+    /// whether to move from the first state to the second (candidate) state
+    PredicateError(TState, TState, Box<dyn std::error::Error + Send>),
+    /// Returned by [StateMachine::handle_event] when a [ToState::Pop] Transition executes while the
+    /// stack is empty
+    EmptyStack(TState),
+    /// Returned by [StateMachine::handle_event] when the State Machine is already in one of its
+    /// final States, see [StateMachineFactory::with_final_states]
+    MachineCompleted(TState),
+    /// Returned by [StateMachine::handle_event] when evaluation of a single Event would loop back
+    /// more times than allowed by [StateMachineFactory::max_cycles]
+    CycleLimitExceeded {
+        /// The state the State Machine was in when the limit was reached
+        state: TState,
+        /// The number of cycles that were completed
+        cycles: usize
+    },
+    /// Returned by [StateMachine::handle_event] when one or more Effects failed and the
+    /// [EffectErrorPolicy] is ContinueCollectingErrors
+    EffectErrors {
+        /// The state the State Machine ended up in
+        state: TState,
+        /// The state each failing Transition was moving from and to, and the error its Effect
+        /// returned, in the order they failed
+        errors: Vec<(TState, TState, TErr)>
+    },
+    /// Returned by [StateMachine::handle_event] in strict mode when more than one Transition would
+    /// move to a different State, see [StateMachineFactory::strict]
+    AmbiguousTransition {
+        /// The state the State Machine was in
+        state: TState,
+        /// The conflicting Transitions, in evaluation order
+        transitions: Vec<TransitionId>
+    },
+    /// Returned by [StateMachine::handle_event] when the Event fails a pre-condition, see
+    /// [StateMachineFactory::with_precondition]
+    PreconditionFailed {
+        /// The name of the failing pre-condition
+        name: String,
+        /// The state the State Machine is in
+        state: TState
+    },
+    /// Returned by [StateMachine::handle_event] when the resulting State and Data violate a
+    /// post-condition, see [StateMachineFactory::with_postcondition]
+    PostconditionFailed {
+        /// The name of the violated post-condition
+        name: String,
+        /// The state the State Machine ended up in, before any rollback
+        state: TState
+    },
+    /// Returned by [StateMachine::handle_events] when one of the Events fails
+    EventFailed {
+        /// The position of the failing Event in the sequence, starting at 0
+        index: usize,
+        /// The state the State Machine was in after the failing Event
+        state: TState,
+        /// The error returned while handling the failing Event
+        error: Box<StateMachineError<TState, TErr>>
+    }
+}
+
+impl <TState: Send + Clone + Eq + PartialEq + Debug, TErr: Debug> std::fmt::Display for StateMachineError<TState, TErr> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateMachineError::EffectError { from, to, transition, event, cycle, error } => {
+                write!(f, "error running effect moving from state {:?} to {:?}", from, to)?;
+                if let Some(transition) = transition {
+                    write!(f, " in transition {:?}", transition)?;
+                }
+                if let Some(event) = event {
+                    write!(f, " for event {}", event)?;
+                }
+                write!(f, " (cycle {}): {:?}", cycle, error)
+            },
+            StateMachineError::UnhandledEvent(state) => write!(f, "no transition matched the event in state {:?}", state),
+            StateMachineError::PredicateError(from, to, error) => write!(f, "error running predicate moving from state {:?} to {:?}: {:?}", from, to, error),
+            StateMachineError::EmptyStack(state) => write!(f, "cannot pop an empty stack in state {:?}", state),
+            StateMachineError::MachineCompleted(state) => write!(f, "state machine completed in state {:?}", state),
+            StateMachineError::CycleLimitExceeded { state, cycles } => write!(f, "cycle limit exceeded after {} cycles in state {:?}", cycles, state),
+            StateMachineError::EffectErrors { state, errors } => write!(f, "{} effects failed, ending in state {:?}: {:?}", errors.len(), state, errors),
+            StateMachineError::AmbiguousTransition { state, transitions } => write!(f, "ambiguous transitions in state {:?}: {:?}", state, transitions),
+            StateMachineError::PreconditionFailed { name, state } => write!(f, "pre-condition {:?} refused the event in state {:?}", name, state),
+            StateMachineError::PostconditionFailed { name, state } => write!(f, "post-condition {:?} violated in state {:?}", name, state),
+            StateMachineError::EventFailed { index, state, error } => write!(f, "event {} failed in state {:?}: {:?}", index, state, error)
+        }
+    }
+}
+
+impl <TState: Send + Clone + Eq + PartialEq + Debug + 'static, TErr: Debug + 'static> std::error::Error for StateMachineError<TState, TErr> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StateMachineError::EffectError { error, .. } => boxed_error(error),
+            StateMachineError::PredicateError(_, _, error) => Some(error.as_ref()),
+            StateMachineError::EventFailed { error, .. } => Some(error.as_ref()),
+            _ => None
+        }
+    }
+}
+
+/// Returns an Effect error as an error source, if its type is one of the boxed error types. Other
+/// types cannot be told apart from those that do not implement `Error` at all.
+fn boxed_error<TErr: 'static>(error: &TErr) -> Option<&(dyn std::error::Error + 'static)> {
+    let error: &dyn Any = error;
+    if let Some(error) = error.downcast_ref::<Box<dyn std::error::Error>>() {
+        return Some(error.as_ref());
+    }
+    if let Some(error) = error.downcast_ref::<Box<dyn std::error::Error + Send>>() {
+        return Some(error.as_ref());
+    }
+    error.downcast_ref::<Box<dyn std::error::Error + Send + Sync>>().map(|error| error.as_ref() as &(dyn std::error::Error + 'static))
+}
+
+/// Boxed callback calculating a computed view from the State and Data
+type ComputeView<'a, TState, TData> = Box<dyn Fn(&TState, &TData) -> Box<dyn Any> + 'a>;
+
+/// A computed view over the State and Data, see [StateMachineFactory::with_computed]
+struct ComputedView<'a, TState, TData> {
+    name: &'static str,
+    type_id: TypeId,
+    compute: ComputeView<'a, TState, TData>,
+    render: fn(&dyn Any) -> String,
+}
+
+/// Named, typed parameters declared on a [StateMachineFactory] with
+/// [StateMachineFactory::with_parameter], keeping tuning values such as timeouts and thresholds in
+/// one place.
+#[derive(Clone, Default)]
+pub struct Parameters {
+    values: HashMap<String, Arc<dyn Any + Send + Sync>>
+}
+
+impl Parameters {
+    /// Creates an empty set of parameters.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds or replaces a named parameter.
+    pub fn with<T: Any + Send + Sync>(mut self, name: impl Into<String>, value: T) -> Self {
+        self.values.insert(name.into(), Arc::new(value));
+        self
+    }
+
+    /// Returns the parameter with the given name, or None if it is not set or is not a `T`.
+    pub fn get<T: Any>(&self, name: &str) -> Option<&T> {
+        self.values.get(name).and_then(|value| value.downcast_ref::<T>())
+    }
+}
+
+/// Shared custom equivalence between States, see [StateMachineFactory::lock_with_state_equivalence]
+pub type StateEquivalence<'a, TState> = Arc<dyn Fn(&TState, &TState) -> bool + 'a>;
+
+/// Shared callback used to enrich Events before Transitions are evaluated, see
+/// [StateMachineFactory::with_event_enricher]
+pub type EventEnricher<'a, TEvent, TData> = Arc<dyn Fn(TEvent, &TData) -> TEvent + 'a>;
+
+/// Boxed callback run before an Event is handled, see [StateMachineFactory::with_event_interceptor]
+type EventInterceptor<'a, TEvent, TState, TData> = Box<dyn Fn(&TEvent, &TState, &mut TData) -> EventAction<TEvent> + 'a>;
+
+/// Determines what happens to an Event after an interceptor has seen it, see
+/// [StateMachineFactory::with_event_interceptor]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum EventAction<TEvent> {
+    /// Pass the Event on unchanged.
+    Pass,
+    /// Handle the given Event instead.
+    Transform(TEvent),
+    /// Drop the Event, so that [StateMachine::handle_event] returns the current State without
+    /// evaluating any Transition.
+    Swallow,
+}
+
+/// Shared callback used by [UnhandledEventPolicy::Callback]
+type UnhandledEventCallback<'a, TEvent, TState, TData> = Arc<dyn Fn(&TEvent, &TState, &TData) + 'a>;
+
+/// Determines how a [StateMachine] reacts to an Event that matches no Transition
+#[derive(Default)]
+pub enum UnhandledEventPolicy<'a, TEvent, TState, TData> {
+    /// The Event is silently dropped. This is the default.
+    #[default]
+    Ignore,
+    /// [StateMachine::handle_event] returns [StateMachineError::UnhandledEvent]
+    Error,
+    /// The provided callback is invoked with the Event, the current State, and the Data
+    Callback(UnhandledEventCallback<'a, TEvent, TState, TData>)
+}
+
+impl <TEvent, TState, TData> Clone for UnhandledEventPolicy<'_, TEvent, TState, TData> {
+    fn clone(&self) -> Self {
+        match self {
+            UnhandledEventPolicy::Ignore => UnhandledEventPolicy::Ignore,
+            UnhandledEventPolicy::Error => UnhandledEventPolicy::Error,
+            UnhandledEventPolicy::Callback(callback) => UnhandledEventPolicy::Callback(callback.clone())
+        }
+    }
+}
+
+/// Boxed Predicate deciding whether a [StateMachineTransition] applies to an Event
+type EventPredicate<'a, TEvent, TState, TData> = Box<dyn Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> bool + 'a>;
+
+/// Boxed Predicate that may fail while deciding whether a [StateMachineTransition] applies
+type FallibleEventPredicate<'a, TEvent, TState, TData> = Box<dyn Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> Result<bool, Box<dyn std::error::Error + Send>> + 'a>;
+
+/// Predicate of a [StateMachineTransition], which either always succeeds or may return an error
+enum TransitionPredicate<'a, TEvent, TState, TData> {
+    Infallible(EventPredicate<'a, TEvent, TState, TData>),
+    Fallible(FallibleEventPredicate<'a, TEvent, TState, TData>)
+}
+
+impl <TEvent, TState, TData> TransitionPredicate<'_, TEvent, TState, TData> {
+    fn evaluate(&self, data: &StateTransitionEffectData<TEvent, TState, TData>) -> Result<bool, Box<dyn std::error::Error + Send>> {
+        match self {
+            TransitionPredicate::Infallible(predicate) => Ok(predicate(data)),
+            TransitionPredicate::Fallible(predicate) => predicate(data)
+        }
+    }
+}
+
+/// Boxed Effect executed when a [StateMachineTransition] is applied
+type TransitionEffect<'a, TEvent, TState, TData, TErr> = Box<dyn Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<EffectOutcome<TEvent, TState>, TErr> + 'a>;
+
+/// Boxes an Effect that has no directive for evaluation
+pub(crate) fn plain_effect<'a, TEvent, TState, TData, TErr>(effect: impl Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + 'a) -> TransitionEffect<'a, TEvent, TState, TData, TErr> {
+    Box::new(move |d| effect(d).map(|_| EffectOutcome::Continue))
+}
+
+/// Directs evaluation after a successful Effect, see [StateMachineFactory::with_directed_effect]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum EffectOutcome<TEvent, TState> {
+    /// Carry on evaluating Transitions as usual.
+    Continue,
+    /// Complete this Transition, then evaluate no further Transitions for the Event (including
+    /// looping back in cycle mode), so that this Transition consumes the Event.
+    StopEvaluation,
+    /// Complete this Transition and queue an Event, as [StateTransitionEffectData::emit] would.
+    Emit(TEvent),
+    /// Complete this Transition, moving to the given State instead of its to_state.
+    OverrideTarget(TState),
+}
+
+/// Boxed callback used by [ToState::Calc] to determine a result State
+type ToStateCalc<TEvent, TState, TData> = Box<dyn Fn(StateTransitionToStateData<TEvent, TState, TData>) -> TState>;
+
+/// Describes a Transition between States, potentially with a Predicate and/or Effect
+pub struct StateMachineTransition<'a, TEvent, TState: PartialEq<TState> + Clone + Send + 'a, TData, TErr = Box<dyn std::error::Error>>
+{
+    name: Option<String>,
+    priority: i32,
+    weight: Option<u32>,
+    from_state: FromState<TState>,
+    get_to_state: ToState<TEvent, TState, TData>,
+    event_predicate: Option<TransitionPredicate<'a, TEvent, TState, TData>>,
+    effect: Option<TransitionEffect<'a, TEvent, TState, TData, TErr>>,
+    trigger: Trigger<'a, TEvent>,
+    metadata: BTreeMap<String, String>,
+    description: Description,
+    doc: Option<String>,
+    examples: Vec<GuardExample<TEvent, TState, TData>>
+}
+
+/// An example input for the Predicate of a Transition and whether it should pass, see
+/// [StateMachineFactory::with_guard_example]
+struct GuardExample<TEvent, TState, TData> {
+    from: TState,
+    event: TEvent,
+    data: TData,
+    expected: bool,
+}
+
+impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + 'a, TData, TErr> StateMachineTransition<'a, TEvent, TState, TData, TErr> {
+    fn new(
+        name: Option<String>,
+        event_predicate: Option<TransitionPredicate<'a, TEvent, TState, TData>>,
+        from_state: FromState<TState>,
+        get_to_state: ToState<TEvent, TState, TData>,
+        effect: Option<TransitionEffect<'a, TEvent, TState, TData, TErr>>,
+    ) -> Self
+    {
+        Self {
+            name,
+            priority: 0,
+            weight: None,
+            trigger: if event_predicate.is_some() { Trigger::Predicate } else { Trigger::Auto },
+            metadata: BTreeMap::new(),
+            description: Description::default(),
+            doc: None,
+            examples: Vec::new(),
+            event_predicate,
+            from_state,
+            get_to_state,
+            effect
+        }
+    }
+
+    /// Returns the name of this Transition, if any.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns the metadata value for the given key, see [StateMachineFactory::with_metadata].
+    pub fn metadata(&self, key: &str) -> Option<&str> {
+        self.metadata.get(key).map(String::as_str)
+    }
+
+    /// Returns the description of this Transition for a locale (see [Description::get]), as
+    /// attached with [StateMachineFactory::with_description].
+    pub fn description(&self, locale: Option<&str>) -> Option<&str> {
+        self.description.get(locale)
+    }
+
+    /// Returns the documentation of this Transition, as attached with
+    /// [StateMachineFactory::with_doc].
+    pub fn doc(&self) -> Option<&str> {
+        self.doc.as_deref()
+    }
+
+    /// Records what triggers this Transition, for use by static analysis
+    fn with_trigger(self, trigger: Trigger<'a, TEvent>) -> Self {
+        Self {
+            trigger,
+            ..self
+        }
+    }
+}
+
+/// Determines when an injected failure occurs, see [StateMachineFactory::with_injected_failure]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FailureInjection {
+    /// Fails only the Nth time (counting from 1) the Transition executes on a State Machine
+    OnInvocation(usize),
+    /// Fails each time the Transition executes with the given probability (from 0.0 to 1.0),
+    /// using a pseudo-random sequence started from `seed` so that failures are reproducible
+    Probability {
+        /// The probability of each execution failing
+        probability: f64,
+        /// The seed of the pseudo-random sequence
+        seed: u64
+    }
+}
+
+/// Details of a repeatedly failing Effect, passed to the callback registered with
+/// [StateMachineFactory::with_failure_alert]
+pub struct EffectFailure<'a, TState, TErr> {
+    /// The name of the failing Transition, if any.
+    pub name: Option<&'a str>,
+    /// The state the Transition was moving from.
+    pub from: &'a TState,
+    /// The state the Transition was moving to.
+    pub to: &'a TState,
+    /// The error returned by the Effect.
+    pub error: &'a TErr,
+    /// How many times in a row the Transition has failed, including this failure.
+    pub consecutive_failures: usize,
+}
+
+/// Shared callback alerting on repeated Effect failures
+type FailureAlertCallback<'a, TState, TErr> = Arc<dyn Fn(EffectFailure<TState, TErr>) + 'a>;
+
+/// A change to a Transition disabled by the circuit breaker, passed to the observer registered with
+/// [StateMachineFactory::with_circuit_breaker]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CircuitBreakerEvent<'a> {
+    /// The Transition failed too many times in a row and will be skipped until the given time
+    Disabled {
+        /// The name of the Transition, if any.
+        name: Option<&'a str>,
+        /// When the Transition will be enabled again.
+        until: Instant
+    },
+    /// The cooldown of a disabled Transition has passed and it will be evaluated again
+    Enabled {
+        /// The name of the Transition, if any.
+        name: Option<&'a str>
+    }
+}
+
+/// Shared callback observing the circuit breaker
+type CircuitBreakerObserver<'a> = Arc<dyn Fn(CircuitBreakerEvent) + 'a>;
+
+/// Progress of a long-running Effect, passed to the observer registered with
+/// [StateMachineFactory::with_progress_observer]
+pub struct EffectProgress<'a, TState> {
+    /// The name of the Transition running the Effect, if any.
+    pub name: Option<&'a str>,
+    /// The state the Transition is moving from.
+    pub from: &'a TState,
+    /// The state the Transition is moving to.
+    pub to: &'a TState,
+    /// How far the Effect has progressed, between 0.0 and 1.0.
+    pub fraction: f32,
+    /// A short description of the progress.
+    pub note: &'a str,
+}
+
+/// Callback observing the progress of Effects
+type ProgressCallback<'a, TState> = dyn Fn(EffectProgress<TState>) + 'a;
+
+/// Shared callback observing the progress of Effects
+type ProgressObserver<'a, TState> = Arc<ProgressCallback<'a, TState>>;
+
+/// Receives measurements from a [StateMachine], see [StateMachineFactory::with_metrics]. Every
+/// method does nothing by default, so implementations only override what they record. Methods are
+/// called on the thread handling the Event, while it is being handled, so they should be quick
+/// (updating an atomic counter, for example).
+pub trait MachineMetrics<TState> {
+    /// Called once per call to [StateMachine::handle_event], with the time it took (including any
+    /// Events emitted by Effects) and whether it succeeded.
+    fn event_handled(&self, _duration: Duration, _succeeded: bool) {}
+
+    /// Called when a Transition executes, after its Effect succeeds. Timeout and interval
+    /// Transitions have no name.
+    fn transition_fired(&self, _name: Option<&str>, _from: &TState, _to: &TState) {}
+
+    /// Called when the Predicate of a Transition whose from_state matched returns false. This
+    /// includes Event Transitions whose Event does not match.
+    fn predicate_rejected(&self, _name: Option<&str>, _from: &TState) {}
+
+    /// Called when the Effect of a Transition fails, including injected failures and the Effects
+    /// of timeout and interval Transitions.
+    fn effect_failed(&self, _name: Option<&str>, _from: &TState, _to: &TState) {}
+}
+
+/// A State type whose values can all be listed, so that they can be declared as the complete set
+/// of States with [StateMachineFactory::with_state_set]
+pub trait StateSet: Sized {
+    /// Returns every State, in a stable order.
+    fn states() -> Vec<Self>;
+}
+
+/// Shared [MachineMetrics] implementation
+type Metrics<'a, TState> = Arc<dyn MachineMetrics<TState> + 'a>;
+
+/// A source of the current time for State Machines, see [StateMachineFactory::with_clock]
+pub trait Clock {
+    /// Returns the current instant. Successive calls should not go backwards.
+    fn now(&self) -> Instant;
+}
+
+/// The system clock, used by State Machines unless another [Clock] is set
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A [Clock] that only moves when advanced, for testing timeouts without waiting. Clones share
+/// the same time, so a clone can be given to [StateMachineFactory::with_clock] and the original
+/// advanced by the test.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl MockClock {
+    /// Creates a clock stopped at the current system time.
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().expect("mock clock poisoned") += duration;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().expect("mock clock poisoned")
+    }
+}
+
+/// Shared [Clock] implementation
+type SharedClock<'a> = Arc<dyn Clock + 'a>;
+
+/// A source of random numbers for choosing between weighted Transitions, see
+/// [StateMachineFactory::with_random]. With the `rand` feature, every random number generator of
+/// the `rand` crate is a `RandomSource`.
+pub trait RandomSource {
+    /// Returns the next random number, uniformly distributed over every `u64`.
+    fn next_random(&mut self) -> u64;
+}
+
+/// A small seedable xorshift generator, used by State Machines unless another [RandomSource] is
+/// set. The same seed always produces the same numbers; it is not suitable for cryptography.
+#[derive(Clone, Debug)]
+pub struct XorShiftRandom {
+    state: u64,
+}
+
+impl XorShiftRandom {
+    /// Creates a generator from a seed.
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck at zero, so replace a zero seed with an arbitrary constant
+        Self {
+            state: if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed },
+        }
+    }
+}
+
+impl Default for XorShiftRandom {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl RandomSource for XorShiftRandom {
+    fn next_random(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
+
+#[cfg(feature = "rand")]
+impl <R: rand_core::RngCore> RandomSource for R {
+    fn next_random(&mut self) -> u64 {
+        self.next_u64()
+    }
+}
+
+/// Shared [RandomSource] implementation
+type SharedRandom = Arc<Mutex<dyn RandomSource>>;
+
+/// An Event deferred in some States, see [StateMachineFactory::with_deferred_event]
+struct Deferral<'a, TEvent, TState: PartialEq<TState> + Clone> {
+    matches: EventMatcher<'a, TEvent>,
+    states: FromState<TState>,
+}
+
+/// Matches the Events a [Deferral] applies to
+type EventMatcher<'a, TEvent> = Box<dyn Fn(&TEvent) -> bool + 'a>;
+
+/// A named condition on incoming Events, see [StateMachineFactory::with_precondition]
+struct Precondition<'a, TEvent, TState, TData> {
+    name: String,
+    check: EventCondition<'a, TEvent, TState, TData>,
+}
+
+/// Checks an incoming Event against the current State and Data
+type EventCondition<'a, TEvent, TState, TData> = Box<dyn Fn(&TEvent, &TState, &TData) -> bool + 'a>;
+
+/// A named condition on the State and Data after an Event, see
+/// [StateMachineFactory::with_postcondition]
+struct Postcondition<'a, TState, TData> {
+    name: String,
+    check: StateCondition<'a, TState, TData>,
+}
+
+/// Checks the State and Data of a State Machine
+type StateCondition<'a, TState, TData> = Box<dyn Fn(&TState, &TData) -> bool + 'a>;
+
+/// Context attached to some States, see [StateMachineFactory::with_state_context]
+struct StateContextDefinition<'a, TState: PartialEq<TState> + Clone, TData> {
+    states: FromState<TState>,
+    init: InitContext<'a, TState, TData>,
+}
+
+/// Creates the context of a State on entry
+type InitContext<'a, TState, TData> = Box<dyn Fn(&TState, &TData) -> StateContext + 'a>;
+
+/// The context of the current State, with a way of cloning it without knowing its type
+struct StateContext {
+    value: Box<dyn Any>,
+    clone: fn(&dyn Any) -> Box<dyn Any>,
+}
+
+impl Clone for StateContext {
+    fn clone(&self) -> Self {
+        Self {
+            value: (self.clone)(self.value.as_ref()),
+            clone: self.clone,
+        }
+    }
+}
+
+/// Shared callback producing the error of an injected failure
+type MakeError<'a, TErr> = Arc<dyn Fn() -> TErr + 'a>;
+
+/// A failure injected into the Transitions with the given name
+struct InjectedFailure<'a, TErr> {
+    name: String,
+    injection: FailureInjection,
+    make_error: MakeError<'a, TErr>
+}
+
+/// Boxed Effect executed when a timeout Transition is taken
+type TimeoutEffect<'a, TState, TData, TErr> = Box<dyn Fn(StateTimeoutEffectData<TState, TData>) -> Result<(), TErr> + 'a>;
+
+/// A Transition taken once the State Machine has dwelled in a State for too long, see
+/// [StateMachineFactory::with_timeout_transition]
+struct TimeoutTransition<'a, TState: PartialEq<TState> + Clone, TData, TErr> {
+    from_state: FromState<TState>,
+    timeout: Duration,
+    to_state: TState,
+    effect: Option<TimeoutEffect<'a, TState, TData, TErr>>
+}
+
+/// A Transition from a State to itself that runs an Effect periodically, see
+/// [StateMachineFactory::with_every_transition]
+struct IntervalTransition<'a, TState: PartialEq<TState> + Clone, TData, TErr> {
+    from_state: FromState<TState>,
+    period: Duration,
+    effect: TimeoutEffect<'a, TState, TData, TErr>
+}
+
+/// Describes what triggers a [StateMachineTransition], as far as can be known without running its
+/// Predicate
+enum Trigger<'a, TEvent> {
+    /// No Predicate; the Transition applies to every Event
+    Auto,
+    /// An opaque Predicate
+    Predicate,
+    /// Only Events equal to this one
+    Event(&'a TEvent),
+    /// Only Events of this kind (enum variant)
+    EventKind(std::mem::Discriminant<TEvent>)
+}
+
+/// Determines how many matching Transitions execute in each evaluation pass of
+/// [StateMachine::handle_event]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum EvaluationStrategy {
+    /// Every Transition whose from_state and Predicate match executes, in priority then definition
+    /// order. This is the default.
+    #[default]
+    AllMatches,
+    /// Only the first matching Transition (the one with the highest priority, then the earliest
+    /// defined) executes; the remaining Transitions are skipped for this pass. This gives classic
+    /// finite state machine semantics.
+    FirstMatch
+}
+
+/// Determines what [StateMachine::handle_event] does when an Effect fails
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum EffectErrorPolicy {
+    /// Stop handling the Event and return [StateMachineError::EffectError], keeping any State
+    /// changes made by earlier Transitions (for example in an earlier cycle pass). This is the
+    /// default.
+    #[default]
+    AbortAndKeep,
+    /// Stop handling the Event and return the error, restoring the State the State Machine was in
+    /// before the Event, so that the State changes of a call are only committed if it succeeds.
+    /// This also applies to errors other than Effect failures. Along with the State, the stack,
+    /// the States remembered for [ToState::History], state contexts, the last cause, the count of
+    /// external self-transitions, the history and the queue of deferred Events are restored, and
+    /// [StateMachine::fired_transitions] reports no Transitions. Effects that already ran are not
+    /// undone, and consecutive failures still count towards failure alerts and circuit breakers,
+    /// as do invocations towards injected failures.
+    AbortAndRollback,
+    /// Treat the failed Transition as if it had not matched and carry on evaluating, then return
+    /// [StateMachineError::EffectErrors] with every failure once the Event (and any emitted
+    /// Events) have been handled.
+    ContinueCollectingErrors
+}
+
+/// Indicates the State or set of States from which a Transition is valid
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FromState<TState: PartialEq<TState> + Clone> {
+    /// Indicates that a Transition is valid from any State
+    Any,
+    /// Indicates that a Transition is valid from any State in the provided Vector
+    AnyOf(Vec<TState>),
+    /// Indicates that a Transition is valid from any State except those in the provided Vector
+    NoneOf(Vec<TState>),
+    /// Indicates that a Transition is valid only from the specified State
+    From(TState)
+}
+
+impl <TState: PartialEq<TState> + Clone> FromState<TState> {
+    /// Determines whether a State is included, using `PartialEq`
+    fn matches(&self, state: &TState) -> bool {
+        match self {
+            FromState::Any => true,
+            FromState::AnyOf(states) => states.contains(state),
+            FromState::NoneOf(states) => !states.contains(state),
+            FromState::From(from_state) => from_state == state
+        }
+    }
+}
+
+impl <TState: PartialEq<TState> + Clone> From<TState> for FromState<TState> {
+    fn from(value: TState) -> Self {
+        FromState::From(value)
+    }
+}
+
+/// Indicates how a result State is determined after transitioning
+pub enum ToState<TEvent, TState: PartialEq<TState> + Clone + Send, TData> {
+    /// Indicates that a Transition should be applied without changing state (an *internal*
+    /// transition), intended for Transitions that want to execute Effects without causing a state
+    /// change (e.g. Loggers). The current State is not exited or re-entered: its State contexts
+    /// are kept, timeouts and intervals keep running from the original entry, no history is
+    /// recorded, deferred Events are not redelivered, and in cycle mode the Transition does not
+    /// cause evaluation to loop back. A [ToState::To] or [ToState::Calc] Transition whose result
+    /// is the current State behaves the same way.
+    Same,
+    /// Indicates that a Transition should exit the current State and enter it again (an
+    /// *external* self-transition). The State does not change, but the Transition behaves as a
+    /// change of State would: the State is recorded as left for [ToState::History], its State
+    /// contexts are created afresh, timeouts and intervals restart, deferred Events are
+    /// redelivered, and in cycle mode evaluation loops back, so an unconditional external
+    /// self-transition repeats until [StateMachine::max_cycles] is reached.
+    SelfExternal,
+    /// Specifies that a Transition will cause the State Machine to move to the specified State.
+    To(TState),
+    /// Allows a Transition to provide bespoke logic for determining which State to transition into.
+    /// The State is only calculated once the Transition's Predicate has passed, so the Predicate
+    /// sees the from_state as the State being transitioned into.
+    Calc(ToStateCalc<TEvent, TState, TData>),
+    /// Saves the current State on the State Machine's stack, then moves to the specified State.
+    Push(TState),
+    /// Returns to the State most recently saved by [ToState::Push], removing it from the stack.
+    /// Executing this Transition with an empty stack makes [StateMachine::handle_event] return
+    /// [StateMachineError::EmptyStack].
+    Pop,
+    /// Returns to the State most recently left among the given States, such as the State a
+    /// working region was in before the State Machine left it for an `Interrupted` State. The
+    /// State Machine records the States it leaves for every History Transition. If none of the
+    /// given States has been left yet, the State does not change.
+    History(FromState<TState>)
+}
+
+impl <TEvent, TState: PartialEq<TState> + Clone + Send, TData> From<TState> for ToState<TEvent, TState, TData> {
+    fn from(value: TState) -> Self {
+        ToState::<TEvent, TState, TData>::To(value)
+    }
+}
+
+/// Data passed to a Transition Effect callback.
+#[derive(Clone)]
+pub struct StateTransitionEffectData<'a, TEvent, TState, TData> {
+    /// The name of the transition, if any.
+    pub name: &'a Option<String>,
+    /// The event causing this transition to occur.
+    pub event: &'a TEvent,
+    /// The current data associated with the State Machine.
+    pub data: &'a TData,
+    /// The state that is being transitioned from.
+    pub from: &'a TState,
+    /// The state that is being transitioned into. Predicates of [ToState::Calc] Transitions see
+    /// the from_state, as the State is calculated once the Predicate has passed.
+    pub to: &'a TState,
+    /// The parameters of the State Machine, see [StateMachineFactory::with_parameter].
+    pub parameters: &'a Parameters,
+    emitted: &'a RefCell<VecDeque<TEvent>>,
+    contexts: &'a [StateContext],
+    progress_observer: Option<&'a ProgressCallback<'a, TState>>,
+    extracted: &'a ExtractedPayload
+}
+
+impl <TEvent, TState, TData> StateTransitionEffectData<'_, TEvent, TState, TData> {
+    /// Queues an Event to be handled by the State Machine once the current Event has been fully
+    /// evaluated, before [StateMachine::handle_event] returns. Emitted Events are handled in the
+    /// order they were emitted (run-to-completion semantics).
+    pub fn emit(&self, event: TEvent) {
+        self.emitted.borrow_mut().push_back(event);
+    }
+
+    /// Returns the context of type `C` of the from_state, see
+    /// [StateMachineFactory::with_state_context], or None if it has no such context.
+    pub fn context<C: 'static>(&self) -> Option<&C> {
+        self.contexts.iter().find_map(|context| context.value.downcast_ref::<C>())
+    }
+
+    /// Reports the progress of a long-running Effect, as a fraction between 0.0 and 1.0 (values
+    /// outside that range are clamped) with a short note such as "uploaded 3 of 8 files", to the
+    /// observer registered with [StateMachineFactory::with_progress_observer]. Does nothing if no
+    /// observer is registered.
+    pub fn progress(&self, fraction: f32, note: &str) {
+        if let Some(observer) = self.progress_observer {
+            observer(EffectProgress {
+                name: self.name.as_deref(),
+                from: self.from,
+                to: self.to,
+                fraction: fraction.clamp(0.0, 1.0),
+                note,
+            });
+        }
+    }
+}
+
+/// Data passed to the Effect of a timeout Transition.
+#[derive(Clone)]
+pub struct StateTimeoutEffectData<'a, TState, TData> {
+    /// The current data associated with the State Machine.
+    pub data: &'a TData,
+    /// The state that timed out.
+    pub from: &'a TState,
+    /// The state that is being transitioned into.
+    pub to: &'a TState,
+    /// How long the State Machine was in the from state when the timeout fell due.
+    pub elapsed: Duration,
+    /// The parameters of the State Machine, see [StateMachineFactory::with_parameter].
+    pub parameters: &'a Parameters,
+}
+
+/// Data passed to a Transition ToState callback.
+#[derive(Clone)]
+pub struct StateTransitionToStateData<'a, TEvent, TState, TData> {
+    /// The event causing this transition to occur.
+    pub event: &'a TEvent,
+    /// The current data associated with the State Machine.
+    pub data: &'a TData,
+    /// The state that is being transitioned from.
+    pub from: &'a TState,
+    /// The parameters of the State Machine, see [StateMachineFactory::with_parameter].
+    pub parameters: &'a Parameters,
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use std::cell::Cell;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+    use anyhow::{anyhow};
+    use thiserror::Error;
+    use crate::{CircuitBreakerEvent, Clock, EffectErrorPolicy, EffectOutcome, EvaluationStrategy, EventAction, FailureInjection, MachineMetrics, MockClock, Parameters, StateChange, StateMachineFactory, StateMachineError, UnhandledEventPolicy, XorShiftRandom};
+    use crate::FromState;
+    use crate::FromState::From;
+    use crate::ToState::{Calc, Same, SelfExternal, To};
+
+    #[test]
+    fn test_state_machine() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            GoToTwo,
+            GoToThree
+        }
+
+        let go_to_two_happened = AtomicBool::new(false);
+        let go_to_three_happened = AtomicBool::new(false);
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_event_transition_effect(
+                &StateMachineMessage::GoToTwo,
+                1,
+                2,
+                |_| {
+                    go_to_two_happened.store(true, Ordering::SeqCst);
+                    Ok(())
+                }
             )
-        );
-        self
+            .with_event_transition_effect(
+                &StateMachineMessage::GoToThree,
+                2,
+                3,
+                |_| {
+                    go_to_three_happened.store(true, Ordering::SeqCst);
+                    Ok(())
+                }
+            ).lock().build(1, ());
+
+        // Assert our starting state is 1
+        assert_eq!(1, sm.state);
+        // Nothing is going to happen, because no transition is defined for GoToThree from the current state of 1
+        assert_eq!(&1, sm.handle_event(StateMachineMessage::GoToThree).expect("unexpected error"));
+        // This will transition the state to 2
+        assert_eq!(&2, sm.handle_event(StateMachineMessage::GoToTwo).expect("unexpected error"));
+        // Assert that the side effect occurred
+        assert!(go_to_two_happened.load(Ordering::SeqCst), "effect from GoToTwo did not happen when expected");
+        // Assert that we are in state 2
+        assert_eq!(2, sm.state);
+
+        // Nothing is going to happen, because no transition is defined for GoToTwo from the current state of 2
+        assert_eq!(&2, sm.handle_event(StateMachineMessage::GoToTwo).expect("unexpected error"));
+        // This will transition the state to 3
+        assert_eq!(&3, sm.handle_event(StateMachineMessage::GoToThree).expect("unexpected error"));
+        // Assert that the side effect occurred
+        assert!(go_to_three_happened.load(Ordering::SeqCst), "effect from GoToThree did not happen when expected");
+        // Assert that we are in state 3
+        assert_eq!(3, sm.state);
+    }
+
+    #[test]
+    fn test_double_transition() -> anyhow::Result<()> {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            GoToTwo
+        }
+
+        // State here is just an integer
+        let factory = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            // Evaluate all transitions in a loop
+            // until no transition occurs
+            .cycle(true)
+            // When we receive a GoToTwo event
+            // while in state 1, go to state 2
+            .with_event_transition(
+                &StateMachineMessage::GoToTwo,
+                1,
+                2
+            )
+            // When we transition to state 2,
+            // immediately transition to state 3
+            .with_auto_transition(
+                2,
+                3
+            )
+            // Lock the factory object so that
+            // we can build a state machine
+            .lock();
+
+        // Build the state machine, with an empty () as data
+        // (we don't care about data for this example)
+        let mut sm = factory.build(1, ());
+
+        // The StateMachine starts in state 1
+        assert_eq!(1, sm.state);
+
+        // Handling an event tells us what state we end up in
+        match sm.handle_event(StateMachineMessage::GoToTwo) {
+            Ok(state) => {
+                assert_eq!(3, *state);
+            }
+            Err(StateMachineError::EffectError { from, to, error, .. }) => {
+                return Err(anyhow!("error changing state from {} to {}: {}", from, to, error));
+            }
+            Err(e) => {
+                return Err(anyhow!("unexpected error: {}", e));
+            }
+        };
+
+        // Because of the two transitions that we defined,
+        // we end up in state 3
+        assert_eq!(3, sm.state);
+        Ok(())
+    }
+
+    #[test]
+    fn test_effect_error() -> anyhow::Result<()> {
+        #[derive(Eq, PartialEq, Debug)]
+        enum StateMachineMessage {
+            GoToTwo
+        }
+
+        #[derive(Error, Debug, Eq, PartialEq)]
+        enum TestError {
+            #[error("test error")]
+            TestError
+        }
+
+        let mut sm = StateMachineFactory::new()
+            .with_event_transition_effect(
+                &StateMachineMessage::GoToTwo,
+                From(1),
+                To(2),
+                |_| {
+                    Err(TestError::TestError)
+                }
+            ).lock().build(1, ());
+
+        match sm.handle_event(StateMachineMessage::GoToTwo) {
+            Ok(_) => {
+                Err(anyhow!("expected an error"))
+            },
+            Err(StateMachineError::EffectError { from, to, error, .. }) => {
+                assert_eq!(1, from);
+                assert_eq!(2, to);
+                assert_eq!(error, TestError::TestError);
+                Ok(())
+            }
+            Err(e) => {
+                Err(anyhow!("unexpected error: {}", e))
+            }
+        }
+    }
+
+    #[test]
+    fn test_emitted_events() {
+        #[derive(Eq, PartialEq, Debug)]
+        enum StateMachineMessage {
+            Start,
+            Step,
+            Finish
+        }
+
+        // Starting emits two follow-up events, which are handled in order before handle_event returns
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_event_transition_effect(&StateMachineMessage::Start, 1, 2, |d| {
+                d.emit(StateMachineMessage::Step);
+                d.emit(StateMachineMessage::Finish);
+                Ok(())
+            })
+            .with_event_transition(&StateMachineMessage::Step, 2, 3)
+            .with_event_transition(&StateMachineMessage::Finish, 3, 4)
+            .lock().build(1, ());
+
+        assert_eq!(&4, sm.handle_event(StateMachineMessage::Start).expect("unexpected error"));
+    }
+
+    #[test]
+    fn test_predicate_error() -> anyhow::Result<()> {
+        #[derive(Eq, PartialEq, Debug)]
+        enum StateMachineMessage {
+            Charge
+        }
+
+        // The guard fails, e.g. because a balance lookup is unavailable
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_fallible_predicated_transition_effect(
+                1,
+                2,
+                |_| Err(anyhow!("balance lookup unavailable").into()),
+                |_| panic!("effect must not run when the predicate fails")
+            ).lock().build(1, ());
+
+        match sm.handle_event(StateMachineMessage::Charge) {
+            Err(StateMachineError::PredicateError(from, to, cause)) => {
+                assert_eq!(1, from);
+                assert_eq!(2, to);
+                assert_eq!("balance lookup unavailable", cause.to_string());
+            }
+            _ => return Err(anyhow!("expected a predicate error"))
+        }
+        assert_eq!(1, sm.state);
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_reader() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Go
+        }
+
+        let (effect_started, wait_for_effect) = std::sync::mpsc::channel();
+        let (finish_effect, effect_may_finish) = std::sync::mpsc::channel::<()>();
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_event_transition_effect(&StateMachineMessage::Go, 1, 2, move |_| {
+                effect_started.send(()).unwrap();
+                effect_may_finish.recv().unwrap();
+                Ok(())
+            })
+            .lock().build(1, ());
+
+        let reader = sm.state_reader();
+        let thread_reader = reader.clone();
+        std::thread::scope(|scope| {
+            // While the slow effect runs, other threads still read the last committed state
+            scope.spawn(move || {
+                wait_for_effect.recv().unwrap();
+                assert_eq!(1, *thread_reader.load());
+                finish_effect.send(()).unwrap();
+            });
+            sm.handle_event(StateMachineMessage::Go).expect("unexpected error");
+        });
+        assert_eq!(2, *reader.load());
+    }
+
+    #[test]
+    fn test_subscribe() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Go,
+            Stay,
+            Fail,
+            Reset
+        }
+
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_event_transition(&StateMachineMessage::Go, 1, 2)
+            .with_event_transition(&StateMachineMessage::Go, 2, 3)
+            .with_event_transition(&StateMachineMessage::Stay, From(3), Same)
+            .with_event_transition_effect(&StateMachineMessage::Fail, 3, 1, |_| Err("failed".into()))
+            .with_event_transition(&StateMachineMessage::Reset, 3, 1)
+            .effect_error_policy(EffectErrorPolicy::AbortAndRollback)
+            .lock().build(1, ());
+        let changes = sm.subscribe();
+        let dropped = sm.subscribe();
+        drop(dropped);
+
+        // Only the committed State is sent, once per Event that changes it
+        sm.handle_event(StateMachineMessage::Go).expect("unexpected error");
+        sm.handle_event(StateMachineMessage::Stay).expect("unexpected error");
+        sm.handle_event(StateMachineMessage::Fail).expect_err("expected the effect to fail");
+        assert_eq!(vec![StateChange { from: 1, to: 3 }], changes.try_iter().collect::<Vec<_>>());
+
+        // Subscribers may be on other threads
+        std::thread::scope(|scope| {
+            scope.spawn(move || assert_eq!(Ok(StateChange { from: 3, to: 1 }), changes.recv()));
+            sm.handle_event(StateMachineMessage::Reset).expect("unexpected error");
+        });
+    }
+
+    #[test]
+    fn test_state_equivalence() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Fail,
+            Succeed
+        }
+
+        #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+        enum State {
+            Retrying { attempts: u32 },
+            Done
+        }
+
+        // Retrying matches regardless of the number of attempts
+        let mut sm = StateMachineFactory::<StateMachineMessage, State, ()>::new()
+            .with_event_transition(&StateMachineMessage::Succeed, State::Retrying { attempts: 0 }, State::Done)
+            .with_event_transition(&StateMachineMessage::Fail, State::Retrying { attempts: 0 }, State::Retrying { attempts: 1 })
+            .lock_with_state_equivalence(|from, current| std::mem::discriminant(from) == std::mem::discriminant(current))
+            .build(State::Retrying { attempts: 5 }, ());
+
+        assert_eq!(&State::Done, sm.handle_event(StateMachineMessage::Succeed).expect("unexpected error"));
+    }
+
+    #[test]
+    fn test_parameters() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Reading(u32)
+        }
+
+        let factory = StateMachineFactory::<StateMachineMessage, &str, ()>::new()
+            .with_parameter("threshold", 100u32)
+            .with_predicated_transition("normal", "alarm", |d| {
+                let threshold = d.parameters.get::<u32>("threshold").expect("missing threshold");
+                matches!(d.event, StateMachineMessage::Reading(value) if value > threshold)
+            })
+            .lock();
+
+        // The declared threshold applies by default
+        let mut sm = factory.build("normal", ());
+        assert_eq!(&"normal", sm.handle_event(StateMachineMessage::Reading(80)).expect("unexpected error"));
+
+        // A per-build override lowers it for this machine only
+        let mut sm = factory.build_with_parameters("normal", (), Parameters::new().with("threshold", 50u32));
+        assert_eq!(&"alarm", sm.handle_event(StateMachineMessage::Reading(80)).expect("unexpected error"));
+    }
+
+    #[test]
+    fn test_cycle_limit() -> anyhow::Result<()> {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Start
+        }
+
+        // States 2 and 3 ping-pong forever
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .cycle(true)
+            .max_cycles(10)
+            .with_event_transition(&StateMachineMessage::Start, 1, 2)
+            .with_auto_transition(2, 3)
+            .with_auto_transition(3, 2)
+            .lock().build(1, ());
+
+        match sm.handle_event(StateMachineMessage::Start) {
+            Err(StateMachineError::CycleLimitExceeded { cycles, .. }) => assert_eq!(10, cycles),
+            _ => return Err(anyhow!("expected the cycle limit to be exceeded"))
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_first_match_priority() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Go
+        }
+
+        let factory = || StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_event_transition(&StateMachineMessage::Go, 1, 2)
+            .with_event_transition(&StateMachineMessage::Go, 1, 3)
+            .with_priority(10)
+            .with_event_transition(&StateMachineMessage::Go, 3, 4);
+
+        // With AllMatches, the higher priority transition runs first, and the next one matches too
+        let mut sm = factory().lock().build(1, ());
+        assert_eq!(&4, sm.handle_event(StateMachineMessage::Go).expect("unexpected error"));
+
+        // With FirstMatch, only the highest priority transition runs
+        let mut sm = factory().evaluation_strategy(EvaluationStrategy::FirstMatch).lock().build(1, ());
+        assert_eq!(&3, sm.handle_event(StateMachineMessage::Go).expect("unexpected error"));
+    }
+
+    #[test]
+    fn test_computed_views() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Finish
+        }
+
+        #[derive(Debug, Eq, PartialEq)]
+        struct IsTerminal(bool);
+
+        #[derive(Debug, Eq, PartialEq)]
+        struct DisplayStatus(String);
+
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, &str>::new()
+            .with_event_transition(&StateMachineMessage::Finish, 1, 2)
+            .with_computed(|state, _| IsTerminal(*state == 2))
+            .with_computed(|state, owner| DisplayStatus(format!("{} is in step {}", owner, state)))
+            .lock().build(1, "order-42");
+
+        assert_eq!(Some(IsTerminal(false)), sm.computed::<IsTerminal>());
+        sm.handle_event(StateMachineMessage::Finish).expect("unexpected error");
+        assert_eq!(Some(IsTerminal(true)), sm.computed::<IsTerminal>());
+        assert_eq!(Some(DisplayStatus("order-42 is in step 2".to_string())), sm.computed::<DisplayStatus>());
+        assert_eq!(None, sm.computed::<u8>());
+
+        // Snapshots include the rendered views
+        let snapshot = sm.snapshot();
+        assert_eq!(Some(&"IsTerminal(true)".to_string()), snapshot.computed.values().find(|v| v.starts_with("IsTerminal")));
+    }
+
+    #[test]
+    fn test_event_enricher() {
+        #[derive(Eq, PartialEq, Debug)]
+        enum StateMachineMessage {
+            AddToCart { price: u32, cart_total: u32 }
+        }
+
+        // The enricher computes the cart total once, so the predicate only reads it
+        let mut sm = StateMachineFactory::<StateMachineMessage, &str, AtomicUsize>::new()
+            .with_event_enricher(|event, data| match event {
+                StateMachineMessage::AddToCart { price, .. } => StateMachineMessage::AddToCart {
+                    price,
+                    cart_total: data.fetch_add(price as usize, Ordering::SeqCst) as u32 + price
+                }
+            })
+            .with_predicated_transition("shopping", "free_shipping", |d| {
+                matches!(d.event, StateMachineMessage::AddToCart { cart_total, .. } if *cart_total >= 50)
+            })
+            .lock().build("shopping", AtomicUsize::new(0));
+
+        assert_eq!(&"shopping", sm.handle_event(StateMachineMessage::AddToCart { price: 30, cart_total: 0 }).expect("unexpected error"));
+        assert_eq!(&"free_shipping", sm.handle_event(StateMachineMessage::AddToCart { price: 30, cart_total: 0 }).expect("unexpected error"));
+    }
+
+    #[test]
+    fn test_event_interceptor() {
+        #[derive(Clone, Eq, PartialEq, Debug)]
+        enum StateMachineMessage {
+            Request(u32),
+            Cancel
+        }
+
+        // Duplicate requests are dropped, and cancellations are handled as a request for 0
+        let mut sm = StateMachineFactory::<StateMachineMessage, &str, Vec<u32>>::new()
+            .with_event_interceptor(|event, _, seen| match event {
+                StateMachineMessage::Request(id) if seen.contains(id) => EventAction::Swallow,
+                StateMachineMessage::Request(id) => {
+                    seen.push(*id);
+                    EventAction::Pass
+                },
+                StateMachineMessage::Cancel => EventAction::Transform(StateMachineMessage::Request(0))
+            })
+            .with_event_interceptor(|event, state, _| match (event, *state) {
+                (StateMachineMessage::Request(0), "idle") => EventAction::Swallow,
+                _ => EventAction::Pass
+            })
+            .with_event_transition(&StateMachineMessage::Request(1), "busy", "overloaded")
+            .with_event_transition(&StateMachineMessage::Request(1), "idle", "busy")
+            .with_event_transition(&StateMachineMessage::Request(0), "busy", "idle")
+            .unhandled_event_policy(UnhandledEventPolicy::Error)
+            .lock().build("idle", Vec::new());
+
+        assert_eq!(&"idle", sm.handle_event(StateMachineMessage::Cancel).expect("unexpected error"));
+        assert_eq!(&"busy", sm.handle_event(StateMachineMessage::Request(1)).expect("unexpected error"));
+
+        // Peeking does not run interceptors, so it predicts a Transition for the duplicate
+        assert_eq!("overloaded", sm.peek_event(StateMachineMessage::Request(1)).expect("unexpected error").state);
+        assert_eq!(&"busy", sm.handle_event(StateMachineMessage::Request(1)).expect("unexpected error"));
+        assert_eq!(&"idle", sm.handle_event(StateMachineMessage::Cancel).expect("unexpected error"));
+        assert_eq!(vec![1], sm.data);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_snapshot_round_trip() -> anyhow::Result<()> {
+        use crate::Snapshot;
+
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            GoToTwo,
+            GoToThree
+        }
+
+        let factory = StateMachineFactory::<StateMachineMessage, u32, Vec<String>>::new()
+            .with_event_transition(&StateMachineMessage::GoToTwo, 1, 2)
+            .with_event_transition(&StateMachineMessage::GoToThree, 2, 3)
+            .lock();
+
+        let mut sm = factory.build(1, vec!["order-42".to_string()]);
+        sm.handle_event(StateMachineMessage::GoToTwo).expect("unexpected error");
+
+        // Persist the machine, then restore it as if after a process restart
+        let json = serde_json::to_string(&sm.snapshot())?;
+        let snapshot: Snapshot<u32, Vec<String>> = serde_json::from_str(&json)?;
+        let mut restored = factory.restore(snapshot);
+
+        assert_eq!(2, restored.state);
+        assert_eq!(vec!["order-42".to_string()], restored.data);
+        assert_eq!(&3, restored.handle_event(StateMachineMessage::GoToThree).expect("unexpected error"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_events() -> anyhow::Result<()> {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Next,
+            Fail
+        }
+
+        let factory = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_event_transition(&StateMachineMessage::Next, FromState::Any, Calc(Box::new(|d| d.from + 1)))
+            .with_event_transition_effect(&StateMachineMessage::Fail, FromState::Any, Same, |_| Err("failed".into()))
+            .lock();
+
+        let mut sm = factory.build(0, ());
+        assert_eq!(&3, sm.handle_events([StateMachineMessage::Next, StateMachineMessage::Next, StateMachineMessage::Next]).expect("unexpected error"));
+
+        let mut sm = factory.build(0, ());
+        match sm.handle_events([StateMachineMessage::Next, StateMachineMessage::Fail, StateMachineMessage::Next]) {
+            Err(StateMachineError::EventFailed { index, state, error }) => {
+                assert_eq!(1, index);
+                assert_eq!(1, state);
+                assert!(matches!(*error, StateMachineError::EffectError { from: 1, to: 1, .. }));
+            }
+            _ => return Err(anyhow!("expected a failed event error"))
+        }
+        assert_eq!(1, sm.state);
+        Ok(())
+    }
+
+    #[test]
+    fn test_peek_event() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Submit,
+            Approve
+        }
+
+        let effects = AtomicUsize::new(0);
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, bool>::new()
+            .with_named_event_transition_effect("submit", &StateMachineMessage::Submit, From(1), To(2), |_| {
+                effects.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+            .with_named_predicated_transition("auto_approve", From(2), To(3), |d| *d.data)
+            .with_predicated_transition(From(2), To(4), |d| d.event == &StateMachineMessage::Approve)
+            .cycle(true)
+            .lock().build(1, true);
+
+        let preview = sm.peek_event(StateMachineMessage::Submit).expect("unexpected error");
+        assert_eq!(3, preview.state);
+        assert_eq!(vec![Some("submit".to_string()), Some("auto_approve".to_string())], preview.transitions.into_iter().map(|t| t.name).collect::<Vec<_>>());
+        assert_eq!(0, effects.load(Ordering::SeqCst));
+        assert_eq!(1, sm.state);
+
+        // An Event that matches no Transition is reported as having no Transitions
+        assert!(sm.peek_event(StateMachineMessage::Approve).expect("unexpected error").transitions.is_empty());
+
+        sm.data = false;
+        assert_eq!(2, sm.peek_event(StateMachineMessage::Submit).expect("unexpected error").state);
+        assert_eq!(&2, sm.handle_event(StateMachineMessage::Submit).expect("unexpected error"));
+        assert_eq!(1, effects.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_descriptions() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Pay
+        }
+
+        let sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_named_event_transition("pay", &StateMachineMessage::Pay, From(1), To(2))
+            .with_description("Pay now")
+            .with_localized_description("en-GB", "Pay now, please")
+            .with_state_description(1, "Awaiting payment")
+            .with_localized_state_description(1, "fr", "En attente de paiement")
+            .lock().build(1, ());
+
+        assert_eq!(Some("Awaiting payment"), sm.state_description(&1, None));
+        assert_eq!(Some("En attente de paiement"), sm.state_description(&1, Some("fr-FR")));
+        assert_eq!(Some("Awaiting payment"), sm.state_description(&1, Some("de")));
+        assert_eq!(None, sm.state_description(&2, None));
+        assert_eq!(Some("Pay now, please"), sm.transition_description("pay", Some("en-GB")));
+        assert_eq!(Some("Pay now"), sm.transition_description("pay", Some("en-US")));
+    }
+
+    #[test]
+    fn test_effect_error_policy() -> anyhow::Result<()> {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Next
+        }
+
+        let factory = || StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_event_transition(&StateMachineMessage::Next, From(1), To(2))
+            .with_transition_effect(From(2), To(3), |_| Err("failed".into()))
+            .with_transition_effect(From(2), To(4), |_| Err("also failed".into()))
+            .with_auto_transition(From(2), To(5))
+            .cycle(true);
+
+        let mut sm = factory().lock().build(1, ());
+        assert!(matches!(sm.handle_event(StateMachineMessage::Next), Err(StateMachineError::EffectError { from: 2, to: 3, .. })));
+        assert_eq!(2, sm.state);
+
+        let mut sm = factory().effect_error_policy(EffectErrorPolicy::AbortAndRollback).lock().build(1, ());
+        assert!(matches!(sm.handle_event(StateMachineMessage::Next), Err(StateMachineError::EffectError { from: 2, to: 3, .. })));
+        assert_eq!(1, sm.state);
+
+        let mut sm = factory().effect_error_policy(EffectErrorPolicy::ContinueCollectingErrors).lock().build(1, ());
+        match sm.handle_event(StateMachineMessage::Next) {
+            Err(StateMachineError::EffectErrors { state, errors }) => {
+                assert_eq!(5, state);
+                assert_eq!(vec![(2, 3), (2, 4)], errors.into_iter().map(|(from, to, _)| (from, to)).collect::<Vec<_>>());
+            }
+            _ => return Err(anyhow!("expected effect errors"))
+        }
+        assert_eq!(5, sm.state);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollback_history() {
+        #[derive(Eq, PartialEq, Debug)]
+        enum StateMachineMessage {
+            Next,
+            Fail
+        }
+
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_named_event_transition("next", &StateMachineMessage::Next, From(1), To(2))
+            .with_named_event_transition("leave", &StateMachineMessage::Fail, From(2), To(3))
+            .with_named_transition_effect("fail", From(3), To(4), |_| Err("failed".into()))
+            .cycle(true)
+            .with_history(10)
+            .effect_error_policy(EffectErrorPolicy::AbortAndRollback)
+            .lock().build(1, ());
+
+        sm.handle_event(StateMachineMessage::Next).expect("unexpected error");
+        sm.handle_event(StateMachineMessage::Fail).expect_err("expected the effect to fail");
+
+        // The Transition from 2 to 3 was rolled back along with the State
+        assert_eq!(2, sm.state);
+        assert_eq!(0, sm.fired_transitions().count());
+        let history = sm.history();
+        assert_eq!(1, history.len());
+        assert_eq!(Some("next".to_string()), history[0].name);
+    }
+
+    #[test]
+    fn test_effect_progress() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Upload
+        }
+
+        let reports = std::sync::Mutex::new(Vec::new());
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_named_event_transition_effect("upload", &StateMachineMessage::Upload, From(1), To(2), |d| {
+                for file in 1..=2 {
+                    d.progress(file as f32 / 2.0, &format!("uploaded {} of 2 files", file));
+                }
+                Ok(())
+            })
+            .with_progress_observer(|progress| {
+                assert_eq!(Some("upload"), progress.name);
+                assert_eq!((&1, &2), (progress.from, progress.to));
+                reports.lock().unwrap().push((progress.fraction, progress.note.to_string()));
+            })
+            .lock().build(1, ());
+
+        assert_eq!(&2, sm.handle_event(StateMachineMessage::Upload).expect("unexpected error"));
+        assert_eq!(vec![(0.5, "uploaded 1 of 2 files".to_string()), (1.0, "uploaded 2 of 2 files".to_string())], *reports.lock().unwrap());
+    }
+
+    #[test]
+    fn test_merge() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Fail,
+            Retry,
+            Finish
+        }
+
+        // A reusable bundle of error handling Transitions
+        fn error_handling<'a>() -> StateMachineFactory<'a, StateMachineMessage, u32, ()> {
+            StateMachineFactory::new()
+                .with_event_transition(&StateMachineMessage::Fail, FromState::Any, To(99))
+                .with_event_transition(&StateMachineMessage::Retry, From(99), To(1))
+                .with_state_description(99, "Failed")
+                .with_final_states([100])
+                .with_event_interceptor(|event, state, _| match (event, state) {
+                    (StateMachineMessage::Finish, 99) => EventAction::Transform(StateMachineMessage::Retry),
+                    _ => EventAction::Pass
+                })
+        }
+
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_event_transition(&StateMachineMessage::Finish, From(1), To(100))
+            .with_state_description(1, "Started")
+            .with_final_states([100])
+            .merge(error_handling())
+            .lock().build(1, ());
+
+        assert_eq!(Some("Failed"), sm.state_description(&99, None));
+        assert_eq!(Some("Started"), sm.state_description(&1, None));
+        assert_eq!(&99, sm.handle_event(StateMachineMessage::Fail).expect("unexpected error"));
+        // Finishing a failed machine retries it instead
+        assert_eq!(&1, sm.handle_event(StateMachineMessage::Finish).expect("unexpected error"));
+        assert_eq!(&100, sm.handle_event(StateMachineMessage::Finish).expect("unexpected error"));
+        assert!(sm.is_complete());
+        assert_eq!(&[100], sm.final_states.as_slice());
+    }
+
+    #[test]
+    fn test_deferred_events() {
+        #[derive(Clone, Eq, PartialEq, Debug)]
+        enum StateMachineMessage {
+            DataReady(u32),
+            Initialized,
+            Suspend,
+            Resume
+        }
+
+        #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+        enum States {
+            Initializing,
+            Ready,
+            Suspended
+        }
+
+        let received = std::sync::Mutex::new(Vec::new());
+        let mut sm = StateMachineFactory::<StateMachineMessage, States, ()>::new()
+            .with_event_transition(&StateMachineMessage::Initialized, States::Initializing, States::Ready)
+            .with_event_kind_transition_effect(&StateMachineMessage::DataReady(0), States::Ready, Same, |d| {
+                received.lock().unwrap().push(d.event.clone());
+                Ok(())
+            })
+            .with_event_transition(&StateMachineMessage::Suspend, States::Initializing, States::Suspended)
+            .with_event_transition(&StateMachineMessage::Resume, States::Suspended, States::Ready)
+            .with_deferred_event(&StateMachineMessage::DataReady(1), FromState::AnyOf(vec![States::Initializing, States::Suspended]))
+            .with_deferred_event(&StateMachineMessage::DataReady(2), States::Initializing)
+            .unhandled_event_policy(UnhandledEventPolicy::Error)
+            .lock().build(States::Initializing, ());
+
+        sm.handle_event(StateMachineMessage::DataReady(1)).expect("unexpected error");
+        sm.handle_event(StateMachineMessage::DataReady(2)).expect("unexpected error");
+        assert_eq!(vec![&StateMachineMessage::DataReady(1), &StateMachineMessage::DataReady(2)], sm.deferred_events().collect::<Vec<_>>());
+        assert!(sm.peek_event(StateMachineMessage::DataReady(1)).expect("unexpected error").transitions.is_empty());
+
+        // DataReady(2) is not deferred when suspended, and nothing handles it there
+        sm.handle_event(StateMachineMessage::Suspend).expect_err("expected DataReady(2) to be unhandled");
+        assert_eq!(vec![&StateMachineMessage::DataReady(1)], sm.deferred_events().collect::<Vec<_>>());
+        assert_eq!(1, sm.footprint().deferred);
+
+        sm.handle_event(StateMachineMessage::Resume).expect("unexpected error");
+        assert_eq!(States::Ready, sm.state);
+        assert_eq!(vec![StateMachineMessage::DataReady(1)], *received.lock().unwrap());
+        assert_eq!(0, sm.deferred_events().count());
+    }
+
+    #[test]
+    fn test_deferred_events_rollback() {
+        #[derive(Clone, Eq, PartialEq, Debug)]
+        enum StateMachineMessage {
+            DataReady(u32),
+            Initialized
+        }
+
+        #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+        enum States {
+            Initializing,
+            Ready
+        }
+
+        let fail = std::cell::Cell::new(true);
+        let mut sm = StateMachineFactory::<StateMachineMessage, States, ()>::new()
+            .with_event_transition(&StateMachineMessage::Initialized, States::Initializing, States::Ready)
+            .with_event_kind_transition_effect(&StateMachineMessage::DataReady(0), States::Ready, Same, |_| {
+                match fail.get() {
+                    true => Err("failed".into()),
+                    false => Ok(())
+                }
+            })
+            .with_deferred_event(&StateMachineMessage::DataReady(1), States::Initializing)
+            .with_deferred_event(&StateMachineMessage::DataReady(2), States::Initializing)
+            .effect_error_policy(EffectErrorPolicy::AbortAndRollback)
+            .lock().build(States::Initializing, ());
+
+        sm.handle_event(StateMachineMessage::DataReady(1)).expect("unexpected error");
+        sm.handle_event(StateMachineMessage::DataReady(2)).expect("unexpected error");
+
+        // The redelivered DataReady(1) fails, so both Events are deferred again in their order
+        sm.handle_event(StateMachineMessage::Initialized).expect_err("expected the effect to fail");
+        assert_eq!(States::Initializing, sm.state);
+        assert_eq!(vec![&StateMachineMessage::DataReady(1), &StateMachineMessage::DataReady(2)], sm.deferred_events().collect::<Vec<_>>());
+
+        fail.set(false);
+        sm.handle_event(StateMachineMessage::Initialized).expect("unexpected error");
+        assert_eq!(States::Ready, sm.state);
+        assert_eq!(0, sm.deferred_events().count());
+    }
+
+    #[test]
+    fn test_deferred_events_timeout() {
+        #[derive(Clone, Eq, PartialEq, Debug)]
+        enum StateMachineMessage {
+            DataReady
+        }
+
+        #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+        enum States {
+            Initializing,
+            Ready,
+            Done
+        }
+
+        let mut sm = StateMachineFactory::<StateMachineMessage, States, ()>::new()
+            .with_event_transition(&StateMachineMessage::DataReady, States::Ready, States::Done)
+            .with_deferred_event(&StateMachineMessage::DataReady, States::Initializing)
+            .with_timeout_transition(States::Initializing, Duration::from_secs(5), States::Ready)
+            .with_clock(MockClock::new())
+            .lock().build(States::Initializing, ());
+
+        sm.handle_event(StateMachineMessage::DataReady).expect("unexpected error");
+        assert_eq!(1, sm.deferred_events().count());
+
+        // The timeout changes the State, so DataReady is redelivered
+        sm.fast_forward().expect("unexpected error");
+        assert_eq!(States::Done, sm.state);
+        assert_eq!(0, sm.deferred_events().count());
+    }
+
+    #[test]
+    fn test_last_cause() {
+        #[derive(Clone, Eq, PartialEq, Debug)]
+        enum StateMachineMessage {
+            Fail { code: u32 },
+            Retry
+        }
+
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_event_kind_transition(&StateMachineMessage::Fail { code: 0 }, From(1), To(2))
+            .with_event_transition(&StateMachineMessage::Retry, From(2), Same)
+            .with_timeout_transition(2, Duration::from_secs(5), 1)
+            .with_last_cause()
+            .lock().build(1, ());
+
+        assert_eq!(None, sm.last_cause());
+        sm.handle_event(StateMachineMessage::Fail { code: 503 }).expect("unexpected error");
+        // Transitions that stay in the same State do not replace the cause
+        sm.handle_event(StateMachineMessage::Retry).expect("unexpected error");
+        assert_eq!(Some(&StateMachineMessage::Fail { code: 503 }), sm.last_cause());
+        assert_eq!(Some("Fail { code: 503 }".to_string()), sm.snapshot().last_cause);
+
+        sm.fast_forward().expect("unexpected error");
+        assert_eq!(1, sm.state);
+        assert_eq!(None, sm.last_cause());
+    }
+
+    #[test]
+    fn test_directed_effects() {
+        #[derive(Eq, PartialEq, Debug)]
+        enum StateMachineMessage {
+            Click,
+            Route(u32),
+            Log
+        }
+
+        let logged = AtomicUsize::new(0);
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .cycle(true)
+            // The first Click consumes the Event, so cycling does not carry it on to 3
+            .with_event_transition(&StateMachineMessage::Click, From(1), To(2))
+            .with_directed_effect(|_| Ok(EffectOutcome::StopEvaluation))
+            .with_event_transition(&StateMachineMessage::Click, From(2), To(3))
+            .with_event_kind_transition(&StateMachineMessage::Route(0), FromState::AnyOf(vec![2, 4]), Same)
+            .with_directed_effect(|d| match d.event {
+                StateMachineMessage::Route(target) => Ok(EffectOutcome::OverrideTarget(*target)),
+                _ => Ok(EffectOutcome::Continue)
+            })
+            .with_event_transition(&StateMachineMessage::Route(5), From(5), To(6))
+            .with_directed_effect(|_| Ok(EffectOutcome::Emit(StateMachineMessage::Log)))
+            .with_event_transition_effect(&StateMachineMessage::Log, FromState::Any, Same, |_| {
+                logged.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+            .lock().build(1, ());
+
+        assert_eq!(&2, sm.handle_event(StateMachineMessage::Click).expect("unexpected error"));
+        assert_eq!(&4, sm.handle_event(StateMachineMessage::Route(4)).expect("unexpected error"));
+        assert_eq!(&6, sm.handle_event(StateMachineMessage::Route(5)).expect("unexpected error"));
+        assert_eq!(1, logged.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_single_threaded() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Click
+        }
+
+        // Neither the Data nor the captured label is Send
+        let label = Rc::new(RefCell::new(String::new()));
+        let clicks = Rc::new(RefCell::new(0));
+        let effect_label = label.clone();
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, Rc<RefCell<u32>>>::new()
+            .with_event_transition_effect(&StateMachineMessage::Click, From(1), To(2), move |d| {
+                *d.data.borrow_mut() += 1;
+                *effect_label.borrow_mut() = "clicked".to_string();
+                Ok(())
+            })
+            .lock().build(1, clicks.clone());
+
+        sm.handle_event(StateMachineMessage::Click).expect("unexpected error");
+        assert_eq!(1, *clicks.borrow());
+        assert_eq!("clicked", label.borrow().as_str());
+    }
+
+    #[test]
+    fn test_definition_hash() {
+        #[derive(Eq, PartialEq, Debug)]
+        enum StateMachineMessage {
+            Start,
+            Stop
+        }
+
+        let factory = |to: u32| StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_named_event_transition("start", &StateMachineMessage::Start, From(1), To(to))
+            .with_metadata("owner", "payments")
+            .with_event_transition_effect(&StateMachineMessage::Stop, FromState::Any, To(1), |_| Ok(()))
+            .with_timeout_transition(2, Duration::from_secs(30), 1);
+
+        let hash = factory(2).lock().definition_hash();
+        assert_eq!(hash, factory(2).with_description("Stops the machine").lock().definition_hash());
+        assert_ne!(hash, factory(3).lock().definition_hash());
+        assert_ne!(hash, factory(2).with_priority(1).lock().definition_hash());
+        assert_ne!(hash, factory(2).cycle(true).lock().definition_hash());
+        assert_ne!(hash, factory(2).unhandled_event_policy(UnhandledEventPolicy::Error).lock().definition_hash());
+        assert_ne!(hash, factory(2).with_deferred_event(&StateMachineMessage::Start, 2).lock().definition_hash());
+        assert_ne!(hash, factory(2).with_precondition("ready", |_, _, _| true).lock().definition_hash());
+        assert_ne!(hash, factory(2).with_postcondition("valid", |_, _| true).lock().definition_hash());
+        assert_ne!(hash, factory(2).with_event_interceptor(|_, _, _| EventAction::Pass).lock().definition_hash());
+        assert_ne!(hash, factory(2).lock_with_state_equivalence(|a, b| a == b).definition_hash());
+        // The hash is the same in every process
+        assert_eq!(0xaa30_7cff_bc60_44a5, hash);
+
+        // A kind of Event is hashed by the first Transition matching it
+        let kinds = |kind: &StateMachineMessage| StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_event_transition(&StateMachineMessage::Start, From(1), To(2))
+            .with_event_kind_transition(kind, From(2), To(1))
+            .lock().definition_hash();
+        assert_ne!(kinds(&StateMachineMessage::Start), kinds(&StateMachineMessage::Stop));
+        assert_eq!(0xa516_13e9_904b_ea21, kinds(&StateMachineMessage::Stop));
+    }
+
+    #[test]
+    fn test_metrics() {
+        use std::sync::Mutex;
+
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Pay,
+            Settle,
+            Cancel
+        }
+
+        #[derive(Default)]
+        struct Counters(Mutex<Vec<String>>);
+
+        impl MachineMetrics<u32> for &Counters {
+            fn event_handled(&self, _duration: Duration, succeeded: bool) {
+                self.0.lock().unwrap().push(format!("handled {}", succeeded));
+            }
+
+            fn transition_fired(&self, name: Option<&str>, from: &u32, to: &u32) {
+                self.0.lock().unwrap().push(format!("fired {:?} {} -> {}", name, from, to));
+            }
+
+            fn predicate_rejected(&self, name: Option<&str>, _from: &u32) {
+                self.0.lock().unwrap().push(format!("rejected {:?}", name));
+            }
+
+            fn effect_failed(&self, name: Option<&str>, _from: &u32, _to: &u32) {
+                self.0.lock().unwrap().push(format!("failed {:?}", name));
+            }
+        }
+
+        let counters = Counters::default();
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_named_predicated_transition("refund", From(1), To(0), |_| false)
+            .with_named_event_transition("pay", &StateMachineMessage::Pay, From(1), To(2))
+            .with_named_event_transition_effect("settle", &StateMachineMessage::Settle, From(2), To(3), |_| Err("declined".into()))
+            .with_timeout_transition(From(2), Duration::from_secs(10), 4)
+            .with_timeout_transition_effect(From(4), Duration::from_secs(10), 5, |_| Err("expired".into()))
+            .with_precondition("not cancelled", |event, _, _| event != &StateMachineMessage::Cancel)
+            .with_final_states([5])
+            .with_metrics(&counters)
+            .with_clock(MockClock::new())
+            .lock().build(1, ());
+
+        sm.handle_event(StateMachineMessage::Pay).expect("unexpected error");
+        sm.handle_event(StateMachineMessage::Settle).expect_err("expected the settle effect to fail");
+        sm.handle_event(StateMachineMessage::Cancel).expect_err("expected the precondition to fail");
+        sm.fast_forward().expect("unexpected error");
+        sm.fast_forward().expect_err("expected the timeout effect to fail");
+        assert_eq!(vec![
+            "rejected Some(\"refund\")",
+            "fired Some(\"pay\") 1 -> 2",
+            // Event Transitions match their Event with a Predicate
+            "rejected Some(\"settle\")",
+            "handled true",
+            "failed Some(\"settle\")",
+            "handled false",
+            "handled false",
+            "fired None 2 -> 4",
+            "failed None",
+        ], *counters.0.lock().unwrap());
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing() {
+        use std::sync::Mutex;
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        /// Records the fields of every span and event as `name=value` lines
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+
+        struct Fields(Vec<String>);
+
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                self.0.push(format!("{}={:?}", field.name(), value));
+            }
+        }
+
+        impl Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool { true }
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut fields = Fields(vec![span.metadata().name().to_string()]);
+                span.record(&mut fields);
+                self.0.lock().unwrap().push(fields.0.join(" "));
+                Id::from_u64(1)
+            }
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = Fields(Vec::new());
+                event.record(&mut fields);
+                self.0.lock().unwrap().push(fields.0.join(" "));
+            }
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        #[derive(Eq, PartialEq, Debug)]
+        enum StateMachineMessage {
+            Next
+        }
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_named_predicated_transition("never", From(1), To(3), |_| false)
+            .with_named_event_transition_effect("next", &StateMachineMessage::Next, From(1), To(2), |_| Ok(()))
+            .with_tracing()
+            .lock().build(1, ());
+        tracing::subscriber::with_default(Recorder(lines.clone()), || {
+            sm.handle_event(StateMachineMessage::Next).expect("unexpected error");
+        });
+
+        assert_eq!(vec![
+            "handle_event event=Next state=1",
+            "message=transition skipped transition=\"never\" from=1 to=3 cycle=0 predicate=\"failed\" effect=\"not run\"",
+            "message=transition executed transition=\"next\" from=1 to=2 cycle=0 predicate=\"passed\" effect=\"succeeded\""
+        ], *lines.lock().unwrap());
+    }
+
+    #[test]
+    fn test_unhandled_event_policy() -> anyhow::Result<()> {
+        #[derive(Eq, PartialEq, Debug)]
+        enum StateMachineMessage {
+            GoToTwo,
+            GoToThree
+        }
+
+        let unhandled_count = AtomicUsize::new(0);
+        let factory = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_event_transition(&StateMachineMessage::GoToTwo, 1, 2);
+
+        // With the Error policy, an event that matches no transition is reported
+        let mut sm = factory.unhandled_event_policy(UnhandledEventPolicy::Error).lock().build(1, ());
+        match sm.handle_event(StateMachineMessage::GoToThree) {
+            Err(StateMachineError::UnhandledEvent(state)) => assert_eq!(1, state),
+            _ => return Err(anyhow!("expected an unhandled event error"))
+        }
+        assert_eq!(&2, sm.handle_event(StateMachineMessage::GoToTwo).expect("unexpected error"));
+
+        // With the Callback policy, the callback sees the event and the current state
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_event_transition(&StateMachineMessage::GoToTwo, 1, 2)
+            .unhandled_event_policy(UnhandledEventPolicy::Callback(Arc::new(|event, state, _| {
+                assert_eq!(&StateMachineMessage::GoToThree, event);
+                assert_eq!(&1, state);
+                unhandled_count.fetch_add(1, Ordering::SeqCst);
+            })))
+            .lock().build(1, ());
+        assert_eq!(&1, sm.handle_event(StateMachineMessage::GoToThree).expect("unexpected error"));
+        assert_eq!(1, unhandled_count.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[test]
+    fn test_timeout_transitions() {
+        #[derive(Eq, PartialEq)]
+        enum SessionEvent {
+            Activity
+        }
+        #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+        enum SessionState {
+            Connected,
+            Idle,
+            Closed
+        }
+
+        let closed_after = AtomicUsize::new(0);
+        let mut sm = StateMachineFactory::<SessionEvent, SessionState, ()>::new()
+            .with_event_transition(&SessionEvent::Activity, SessionState::Idle, SessionState::Connected)
+            .with_timeout_transition(SessionState::Connected, Duration::from_secs(30), SessionState::Idle)
+            .with_timeout_transition_effect(SessionState::Idle, Duration::from_secs(60), SessionState::Closed, |d| {
+                closed_after.store(d.elapsed.as_secs() as usize, Ordering::SeqCst);
+                Ok(())
+            })
+            .lock().build(SessionState::Connected, ());
+
+        // Nothing happens before the deadline
+        let idle_deadline = sm.next_deadline().expect("missing deadline");
+        assert_eq!(&SessionState::Connected, sm.tick(idle_deadline - Duration::from_millis(1)).expect("unexpected error"));
+        assert_eq!(&SessionState::Idle, sm.tick(idle_deadline).expect("unexpected error"));
+
+        // The Idle timer starts when the Connected timeout fell due, and activity resets it
+        assert_eq!(Some(idle_deadline + Duration::from_secs(60)), sm.next_deadline());
+        sm.handle_event(SessionEvent::Activity).expect("unexpected error");
+        assert_eq!(SessionState::Connected, sm.state);
+
+        // Chained timeouts are all taken by a single late tick
+        let late = sm.next_deadline().expect("missing deadline") + Duration::from_secs(600);
+        assert_eq!(&SessionState::Closed, sm.tick(late).expect("unexpected error"));
+        assert_eq!(60, closed_after.load(Ordering::SeqCst));
+        assert_eq!(None, sm.next_deadline());
+    }
+
+    #[test]
+    fn test_injected_failures() -> anyhow::Result<()> {
+        #[derive(Eq, PartialEq, Debug)]
+        enum StateMachineMessage {
+            Charge
+        }
+
+        #[derive(Error, Debug, Eq, PartialEq)]
+        enum TestError {
+            #[error("injected error")]
+            Injected
+        }
+
+        let factory = StateMachineFactory::<StateMachineMessage, u32, (), TestError>::new()
+            .with_named_event_transition("charge", &StateMachineMessage::Charge, From(1), To(1))
+            .with_injected_failure("charge", FailureInjection::OnInvocation(2), || TestError::Injected)
+            .lock();
+
+        // Only the second invocation fails, and each State Machine counts separately
+        for _ in 0..2 {
+            let mut sm = factory.build(1, ());
+            assert!(sm.handle_event(StateMachineMessage::Charge).is_ok());
+            match sm.handle_event(StateMachineMessage::Charge) {
+                Err(StateMachineError::EffectError { from: 1, to: 1, error: TestError::Injected, .. }) => {}
+                Ok(_) => return Err(anyhow!("expected an error")),
+                Err(e) => return Err(anyhow!("unexpected error: {}", e))
+            }
+            assert!(sm.handle_event(StateMachineMessage::Charge).is_ok());
+        }
+
+        // Probabilistic failures are reproducible for a given seed
+        let factory = StateMachineFactory::<StateMachineMessage, u32, (), TestError>::new()
+            .with_named_event_transition("charge", &StateMachineMessage::Charge, From(1), To(1))
+            .with_injected_failure("charge", FailureInjection::Probability { probability: 0.5, seed: 42 }, || TestError::Injected)
+            .lock();
+        let failures = |mut sm: crate::StateMachine<StateMachineMessage, u32, (), TestError>| -> Vec<bool> {
+            (0..64).map(|_| sm.handle_event(StateMachineMessage::Charge).is_err()).collect()
+        };
+        let first_run = failures(factory.build(1, ()));
+        assert_eq!(first_run, failures(factory.build(1, ())));
+        assert!(first_run.contains(&true) && first_run.contains(&false));
+        Ok(())
+    }
+
+    #[test]
+    fn test_none_of_transition() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Tick,
+            Terminate
+        }
+
+        let logged = AtomicUsize::new(0);
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_event_transition(&StateMachineMessage::Tick, From(1), To(2))
+            .with_event_transition(&StateMachineMessage::Terminate, FromState::Any, To(0))
+            .with_transition_effect(FromState::NoneOf(vec![0]), Same, |_| {
+                logged.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+            .lock().build(1, ());
+
+        sm.handle_event(StateMachineMessage::Tick).expect("unexpected error");
+        sm.handle_event(StateMachineMessage::Terminate).expect("unexpected error");
+        sm.handle_event(StateMachineMessage::Tick).expect("unexpected error");
+        assert_eq!(0, sm.state);
+        assert_eq!(1, logged.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_fast_forward() {
+        #[derive(Eq, PartialEq)]
+        enum InvoiceEvent {
+            Dispute
+        }
+        #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+        enum InvoiceState {
+            Overdue,
+            Reminded,
+            Disputed,
+            Collections
+        }
+        const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+        let factory = StateMachineFactory::<InvoiceEvent, InvoiceState, ()>::new()
+            .with_event_transition(&InvoiceEvent::Dispute, InvoiceState::Reminded, InvoiceState::Disputed)
+            .with_timeout_transition(InvoiceState::Overdue, 7 * DAY, InvoiceState::Reminded)
+            .with_timeout_transition(InvoiceState::Reminded, 23 * DAY, InvoiceState::Collections)
+            .with_timeout_transition(InvoiceState::Disputed, 14 * DAY, InvoiceState::Reminded)
+            .lock();
+
+        // Thirty days of timeouts run without waiting
+        let start = Instant::now();
+        let mut sm = factory.build(InvoiceState::Overdue, ());
+        while sm.fast_forward().expect("unexpected error").is_some() {}
+        assert_eq!(InvoiceState::Collections, sm.state);
+        assert!(sm.now() - start >= 30 * DAY);
+
+        // States entered through Events are stamped with the simulated time
+        let mut sm = factory.build(InvoiceState::Overdue, ());
+        let reminded_at = sm.fast_forward().expect("unexpected error").expect("missing deadline");
+        sm.handle_event(InvoiceEvent::Dispute).expect("unexpected error");
+        assert_eq!(Some(reminded_at + 14 * DAY), sm.next_deadline());
+    }
+
+    #[test]
+    fn test_every_transition() {
+        #[derive(Eq, PartialEq)]
+        enum LinkEvent {
+            Connected
+        }
+        #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+        enum LinkState {
+            Connecting,
+            Online,
+            Failed
+        }
+        const SECOND: Duration = Duration::from_secs(1);
+
+        let clock = MockClock::new();
+        let attempts = Cell::new(0);
+        let factory = StateMachineFactory::<LinkEvent, LinkState, ()>::new()
+            .with_event_transition(&LinkEvent::Connected, LinkState::Connecting, LinkState::Online)
+            .with_every_transition(LinkState::Connecting, 10 * SECOND, |d| {
+                assert_eq!(d.from, d.to);
+                attempts.set(attempts.get() + 1);
+                Ok(())
+            })
+            .with_timeout_transition(LinkState::Connecting, 35 * SECOND, LinkState::Failed)
+            .with_clock(clock.clone())
+            .lock();
+
+        let start = clock.now();
+        let mut sm = factory.build(LinkState::Connecting, ());
+        assert_eq!(Some(start + 10 * SECOND), sm.next_deadline());
+        assert_eq!(&LinkState::Connecting, sm.poll().expect("unexpected error"));
+        assert_eq!(0, attempts.get());
+
+        // Periods that fell due while the clock was advanced each run the Effect
+        clock.advance(25 * SECOND);
+        assert_eq!(&LinkState::Connecting, sm.poll().expect("unexpected error"));
+        assert_eq!(2, attempts.get());
+        assert_eq!(Some(start + 30 * SECOND), sm.next_deadline());
+
+        // Intervals do not restart the timeout
+        clock.advance(10 * SECOND);
+        assert_eq!(&LinkState::Failed, sm.poll().expect("unexpected error"));
+        assert_eq!(3, attempts.get());
+        assert_eq!(None, sm.next_deadline());
+        assert_eq!(clock.now(), sm.now());
+
+        let mut sm = factory.build(LinkState::Connecting, ());
+        clock.advance(15 * SECOND);
+        sm.handle_event(LinkEvent::Connected).expect("unexpected error");
+        assert_eq!(&LinkState::Online, sm.poll().expect("unexpected error"));
+        assert_eq!(3, attempts.get());
+    }
+
+    #[test]
+    fn test_history_state() {
+        use crate::ToState::History;
+
+        #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+        enum Job {
+            Downloading,
+            Extracting,
+            Interrupted,
+            Confirming
+        }
+
+        #[derive(Eq, PartialEq)]
+        enum JobEvent {
+            Next,
+            Interrupt,
+            Resume
+        }
+
+        let working = || FromState::AnyOf(vec![Job::Downloading, Job::Extracting]);
+        let mut sm = StateMachineFactory::<JobEvent, Job, ()>::new()
+            .with_event_transition(&JobEvent::Next, Job::Downloading, Job::Extracting)
+            .with_event_transition(&JobEvent::Interrupt, working(), Job::Interrupted)
+            .with_event_transition(&JobEvent::Next, Job::Interrupted, Job::Confirming)
+            .with_event_transition(&JobEvent::Resume, Job::Confirming, History(working()))
+            .lock().build(Job::Downloading, ());
+
+        // Nothing has been left yet, so the State does not change
+        sm.state = Job::Confirming;
+        assert_eq!(&Job::Confirming, sm.handle_event(JobEvent::Resume).expect("unexpected error"));
+
+        sm.state = Job::Downloading;
+        sm.handle_event(JobEvent::Next).expect("unexpected error");
+        sm.handle_event(JobEvent::Interrupt).expect("unexpected error");
+        sm.handle_event(JobEvent::Next).expect("unexpected error");
+        assert_eq!(Job::Extracting, sm.peek_event(JobEvent::Resume).expect("unexpected error").state);
+        assert_eq!(&Job::Extracting, sm.handle_event(JobEvent::Resume).expect("unexpected error"));
+    }
+
+    #[test]
+    fn test_push_and_pop() -> anyhow::Result<()> {
+        use crate::ToState::{Pop, Push};
+
+        #[derive(Eq, PartialEq)]
+        enum Token {
+            Open,
+            Close
+        }
+
+        let mut sm = StateMachineFactory::<Token, u32, ()>::new()
+            .with_event_transition(&Token::Open, FromState::Any, Push(2))
+            .with_event_transition(&Token::Close, FromState::Any, Pop)
+            .lock().build(1, ());
+
+        sm.handle_event(Token::Open).expect("unexpected error");
+        sm.handle_event(Token::Open).expect("unexpected error");
+        assert_eq!(&[1, 2], sm.stack());
+        assert_eq!(&2, sm.handle_event(Token::Close).expect("unexpected error"));
+        assert_eq!(&1, sm.handle_event(Token::Close).expect("unexpected error"));
+        assert!(sm.stack().is_empty());
+
+        match sm.handle_event(Token::Close) {
+            Err(StateMachineError::EmptyStack(1)) => Ok(()),
+            Ok(_) => Err(anyhow!("expected an error")),
+            Err(e) => Err(anyhow!("unexpected error: {}", e))
+        }
+    }
+
+    #[test]
+    fn test_final_states() -> anyhow::Result<()> {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Next
+        }
+
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .cycle(true)
+            .with_event_transition(&StateMachineMessage::Next, From(1), To(2))
+            .with_auto_transition(From(2), To(3))
+            .with_auto_transition(From(3), To(4))
+            .with_final_states([3])
+            .lock().build(1, ());
+
+        assert!(!sm.is_complete());
+        assert_eq!(&3, sm.handle_event(StateMachineMessage::Next).expect("unexpected error"));
+        assert!(sm.is_complete());
+        match sm.handle_event(StateMachineMessage::Next) {
+            Err(StateMachineError::MachineCompleted(3)) => Ok(()),
+            Ok(_) => Err(anyhow!("expected an error")),
+            Err(e) => Err(anyhow!("unexpected error: {}", e))
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        #[derive(Eq, PartialEq, Debug)]
+        enum StateMachineMessage {
+            Next
+        }
+
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_named_predicated_transition("increment", FromState::Any, Calc(Box::new(|d| d.from + 1)), |_| true)
+            .with_history(2)
+            .lock().build(1, ());
+
+        for _ in 0..3 {
+            sm.handle_event(StateMachineMessage::Next).expect("unexpected error");
+        }
+
+        // Only the two most recent transitions are kept
+        let history = sm.history();
+        assert_eq!(2, history.len());
+        assert_eq!((2, 3), (history[0].from, history[0].to));
+        assert_eq!((3, 4), (history[1].from, history[1].to));
+        assert_eq!(Some("increment".to_string()), history[1].name);
+        assert_eq!("Next", history[1].event_debug);
+
+        sm.clear_history();
+        assert!(sm.history().is_empty());
+    }
+
+    #[test]
+    fn test_history_timestamps() {
+        #[derive(Eq, PartialEq, Debug)]
+        enum StateMachineMessage {
+            Next
+        }
+        const SECOND: Duration = Duration::from_secs(1);
+
+        let clock = MockClock::new();
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_event_transition(&StateMachineMessage::Next, From(1), To(2))
+            .with_timeout_transition(2, 30 * SECOND, 3)
+            .with_history(10)
+            .with_clock(clock.clone())
+            .lock().build(1, ());
+
+        // Timestamps follow the clock of the State Machine rather than the system clock
+        clock.advance(3600 * SECOND);
+        sm.handle_event(StateMachineMessage::Next).expect("unexpected error");
+        clock.advance(45 * SECOND);
+        sm.poll().expect("unexpected error");
+        let history = sm.history();
+        assert_eq!(2, history.len());
+        assert!(history[0].timestamp > std::time::SystemTime::now() + 3000 * SECOND);
+        assert_eq!(history[0].timestamp + 30 * SECOND, history[1].timestamp);
+    }
+
+    #[test]
+    fn test_failure_alert() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Retry
+        }
+
+        let healthy = AtomicBool::new(false);
+        let alerts = std::sync::Mutex::new(Vec::new());
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_named_event_transition_effect("sync", &StateMachineMessage::Retry, From(1), To(2), |_| {
+                match healthy.load(Ordering::SeqCst) {
+                    true => Ok(()),
+                    false => Err("upstream unavailable".into())
+                }
+            })
+            .with_failure_alert(3, |failure| {
+                assert_eq!(Some("sync"), failure.name);
+                alerts.lock().unwrap().push(failure.consecutive_failures);
+            })
+            .lock().build(1, ());
+
+        for _ in 0..4 {
+            assert!(sm.handle_event(StateMachineMessage::Retry).is_err());
+        }
+        assert_eq!(vec![3, 4], *alerts.lock().unwrap());
+
+        // A success resets the count
+        healthy.store(true, Ordering::SeqCst);
+        assert_eq!(&2, sm.handle_event(StateMachineMessage::Retry).expect("unexpected error"));
+        assert_eq!(2, alerts.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Retry
+        }
+
+        let healthy = AtomicBool::new(false);
+        let fallbacks = AtomicUsize::new(0);
+        let changes = std::sync::Mutex::new(Vec::new());
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_named_event_transition_effect("sync", &StateMachineMessage::Retry, From(1), To(2), |_| {
+                match healthy.load(Ordering::SeqCst) {
+                    true => Ok(()),
+                    false => Err("upstream unavailable".into())
+                }
+            })
+            .with_event_transition_effect(&StateMachineMessage::Retry, From(1), Same, |_| {
+                fallbacks.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+            .with_circuit_breaker(2, Duration::from_secs(60), |event| {
+                changes.lock().unwrap().push(match event {
+                    CircuitBreakerEvent::Disabled { name, .. } => format!("disabled {:?}", name),
+                    CircuitBreakerEvent::Enabled { name } => format!("enabled {:?}", name)
+                });
+            })
+            .lock().build(1, ());
+
+        for _ in 0..2 {
+            assert!(sm.handle_event(StateMachineMessage::Retry).is_err());
+        }
+        assert_eq!(vec!["disabled Some(\"sync\")"], *changes.lock().unwrap());
+
+        // While disabled, the remaining Transitions are evaluated instead
+        assert_eq!(&1, sm.handle_event(StateMachineMessage::Retry).expect("unexpected error"));
+        assert_eq!(1, fallbacks.load(Ordering::SeqCst));
+
+        // After the cooldown, the Transition is enabled and evaluated again
+        healthy.store(true, Ordering::SeqCst);
+        let later = sm.now() + Duration::from_secs(61);
+        sm.tick(later).expect("unexpected error");
+        assert_eq!(&2, sm.handle_event(StateMachineMessage::Retry).expect("unexpected error"));
+        assert_eq!(vec!["disabled Some(\"sync\")", "enabled Some(\"sync\")"], *changes.lock().unwrap());
+    }
+
+    #[test]
+    fn test_indexed_dispatch() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Next
+        }
+
+        // Transitions from the new State later in evaluation order still execute in the same pass
+        let visited = std::sync::Mutex::new(Vec::new());
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_event_transition(&StateMachineMessage::Next, From(1), To(2))
+            .with_transition_effect(FromState::NoneOf(vec![1]), Same, |d| {
+                visited.lock().unwrap().push(*d.from);
+                Ok(())
+            })
+            .with_event_transition(&StateMachineMessage::Next, FromState::AnyOf(vec![2, 3]), To(3))
+            .with_event_transition(&StateMachineMessage::Next, From(4), To(1))
+            .lock_indexed().build(1, ());
+
+        assert_eq!(&3, sm.handle_event(StateMachineMessage::Next).expect("unexpected error"));
+        assert_eq!(vec![2], *visited.lock().unwrap());
+        assert_eq!(&3, sm.handle_event(StateMachineMessage::Next).expect("unexpected error"));
+        assert_eq!(vec![2, 3], *visited.lock().unwrap());
+    }
+
+    #[test]
+    fn test_event_index() {
+        enum StateMachineMessage {
+            Ping,
+            Route(u32),
+            Stop
+        }
+        static PING_COMPARED: AtomicUsize = AtomicUsize::new(0);
+
+        // Counts how often the Predicate of the Ping Transition compares an Event with Ping
+        impl PartialEq for StateMachineMessage {
+            fn eq(&self, other: &Self) -> bool {
+                if matches!(self, StateMachineMessage::Ping) {
+                    PING_COMPARED.fetch_add(1, Ordering::SeqCst);
+                }
+                match (self, other) {
+                    (StateMachineMessage::Route(a), StateMachineMessage::Route(b)) => a == b,
+                    _ => std::mem::discriminant(self) == std::mem::discriminant(other)
+                }
+            }
+        }
+        impl Eq for StateMachineMessage {}
+
+        let factory = || StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_event_transition(&StateMachineMessage::Ping, From(1), To(1))
+            .with_event_kind_transition(&StateMachineMessage::Route(0), From(1), To(2))
+            .with_event_transition(&StateMachineMessage::Stop, FromState::Any, To(3))
+            .with_auto_transition(From(3), To(4));
+
+        // Without an index, the Predicate of the Ping Transition runs for every Event
+        let mut sm = factory().lock().build(1, ());
+        assert_eq!(&2, sm.handle_event(StateMachineMessage::Route(5)).expect("unexpected error"));
+        assert_eq!(1, PING_COMPARED.swap(0, Ordering::SeqCst));
+
+        for locked in [factory().lock().with_event_index(), factory().lock_indexed().with_event_index()] {
+            let mut sm = locked.build(1, ());
+            assert_eq!(&2, sm.handle_event(StateMachineMessage::Route(5)).expect("unexpected error"));
+            assert_eq!(0, PING_COMPARED.load(Ordering::SeqCst));
+
+            // Transitions without an Event, and Transitions added later, apply to every Event
+            sm.add_transitions(StateMachineFactory::new().with_auto_transition(From(4), To(1)));
+            assert_eq!(&1, sm.handle_event(StateMachineMessage::Stop).expect("unexpected error"));
+            assert_eq!(&1, sm.handle_event(StateMachineMessage::Ping).expect("unexpected error"));
+            assert_eq!(1, PING_COMPARED.swap(0, Ordering::SeqCst));
+        }
+    }
+    #[test]
+    fn test_pre_and_postconditions() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Withdraw(i64)
+        }
+
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, std::cell::Cell<i64>>::new()
+            .with_predicated_transition_effect(From(1), To(2), |_| true, |d| {
+                let StateMachineMessage::Withdraw(amount) = d.event;
+                d.data.set(d.data.get() - amount);
+                Ok(())
+            })
+            .with_precondition("positive amount", |event, _, _| matches!(event, StateMachineMessage::Withdraw(amount) if *amount > 0))
+            .with_postcondition("not overdrawn", |_, balance| balance.get() >= 0)
+            .effect_error_policy(EffectErrorPolicy::AbortAndRollback)
+            .lock().build(1, std::cell::Cell::new(10));
+
+        // A refused Event is not evaluated at all
+        match sm.handle_event(StateMachineMessage::Withdraw(0)) {
+            Err(StateMachineError::PreconditionFailed { name, state }) => {
+                assert_eq!("positive amount", name);
+                assert_eq!(1, state);
+            },
+            _ => panic!("expected the pre-condition to fail")
+        }
+        assert!(matches!(sm.peek_event(StateMachineMessage::Withdraw(0)), Err(StateMachineError::PreconditionFailed { .. })));
+
+        // The State is rolled back after a violated post-condition, but the Data is not
+        match sm.handle_event(StateMachineMessage::Withdraw(15)) {
+            Err(StateMachineError::PostconditionFailed { name, state }) => {
+                assert_eq!("not overdrawn", name);
+                assert_eq!(2, state);
+            },
+            _ => panic!("expected the post-condition to fail")
+        }
+        assert_eq!(1, sm.state);
+        assert_eq!(-5, sm.data.get());
+    }
+    #[test]
+    fn test_state_context() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Fail,
+            Succeed
+        }
+
+        #[derive(Clone, Default)]
+        struct Attempts(std::cell::Cell<u32>);
+
+        // 1 is Connecting, 2 is Connected and 3 is Failed
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, u32>::new()
+            .with_state_context(From(1), |_, max_attempts| Attempts(std::cell::Cell::new(*max_attempts)))
+            .with_predicated_transition(From(1), To(3), |d| d.event == &StateMachineMessage::Fail && d.context::<Attempts>().is_some_and(|a| a.0.get() == 1))
+            .with_event_transition_effect(&StateMachineMessage::Fail, From(1), Same, |d| {
+                let attempts = d.context::<Attempts>().expect("expected a context in Connecting");
+                attempts.0.set(attempts.0.get() - 1);
+                Ok(())
+            })
+            .with_event_transition(&StateMachineMessage::Succeed, From(1), To(2))
+            .with_event_transition(&StateMachineMessage::Fail, From(2), To(1))
+            .lock().build(1, 3);
+
+        // The context is created for the initial State, and kept across Same Transitions
+        assert_eq!(3, sm.context::<Attempts>().expect("expected a context").0.get());
+        sm.handle_event(StateMachineMessage::Fail).expect("unexpected error");
+        assert_eq!(2, sm.context::<Attempts>().expect("expected a context").0.get());
+
+        // It is dropped on exit and created afresh on entry
+        sm.handle_event(StateMachineMessage::Succeed).expect("unexpected error");
+        assert!(sm.context::<Attempts>().is_none());
+        sm.handle_event(StateMachineMessage::Fail).expect("unexpected error");
+        assert_eq!(3, sm.context::<Attempts>().expect("expected a context").0.get());
+
+        sm.context_mut::<Attempts>().expect("expected a context").0.set(1);
+        assert_eq!(&3, sm.handle_event(StateMachineMessage::Fail).expect("unexpected error"));
+    }
+    #[test]
+    fn test_self_external() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Ping,
+            Retry
+        }
+        const SECOND: Duration = Duration::from_secs(1);
+
+        // The context numbers each entry into "waiting"
+        let clock = MockClock::new();
+        let factory = StateMachineFactory::<StateMachineMessage, &str, Cell<u32>>::new()
+            .with_state_context(From("waiting"), |_, entries: &Cell<u32>| {
+                entries.set(entries.get() + 1);
+                entries.get()
+            })
+            .with_event_transition(&StateMachineMessage::Ping, From("waiting"), Same)
+            .with_event_transition(&StateMachineMessage::Retry, From("waiting"), SelfExternal)
+            .with_timeout_transition("waiting", 10 * SECOND, "expired")
+            .with_clock(clock.clone())
+            .lock();
+
+        let start = clock.now();
+        let mut sm = factory.build("waiting", Cell::new(0));
+        assert_eq!(Some(&1), sm.context::<u32>());
+
+        // An internal Transition neither re-enters the State nor restarts its timeout
+        clock.advance(6 * SECOND);
+        sm.handle_event(StateMachineMessage::Ping).expect("unexpected error");
+        assert_eq!(Some(&1), sm.context::<u32>());
+        assert_eq!(Some(start + 10 * SECOND), sm.next_deadline());
+
+        // An external self-transition does both
+        sm.handle_event(StateMachineMessage::Retry).expect("unexpected error");
+        assert_eq!(Some(&2), sm.context::<u32>());
+        assert_eq!(Some(start + 16 * SECOND), sm.next_deadline());
+
+        // In cycle mode, an external self-transition loops back like any change of State
+        let mut sm = StateMachineFactory::<StateMachineMessage, &str, ()>::new()
+            .with_event_transition(&StateMachineMessage::Ping, From("waiting"), Same)
+            .with_auto_transition(From("polling"), SelfExternal)
+            .cycle(true)
+            .max_cycles(3)
+            .lock().build("waiting", ());
+        assert_eq!(&"waiting", sm.handle_event(StateMachineMessage::Ping).expect("unexpected error"));
+        sm.state = "polling";
+        match sm.handle_event(StateMachineMessage::Ping) {
+            Err(StateMachineError::CycleLimitExceeded { state, cycles }) => assert_eq!(("polling", 3), (state, cycles)),
+            _ => panic!("expected the cycle limit to be exceeded")
+        }
     }
 
-    /// Adds an unnamed Transition with a side effect to the State Machine definition whose
-    /// predicate checks for equality with a provided Event reference. This is syntactic sugar for
-    /// `.with_predicated_transition(..)` with an equality Predicate.
-    pub fn with_event_transition_effect(mut self, event: &'a TEvent, from_state: impl Into<FromState<TState>>, get_to_state: impl Into<ToState<TEvent, TState, TData>>, effect: impl Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + Send + 'a) -> Self
-    {
-        self.transitions.push(
-            StateMachineTransition::new(
-                None,
-                Some(Box::new(|e| *event == *e.event)),
-                from_state.into(),
-                get_to_state.into(),
-                Some(Box::new(effect))
-            )
-        );
-        self
+    #[test]
+    fn test_weighted_transitions() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Tick
+        }
+
+        let factory = |seed| StateMachineFactory::<StateMachineMessage, &str, ()>::new()
+            .with_event_transition(&StateMachineMessage::Tick, "cart", "browsing")
+            .with_event_transition(&StateMachineMessage::Tick, "browsing", "cart")
+            .with_weight(3)
+            .with_event_transition(&StateMachineMessage::Tick, "browsing", "exit")
+            .with_weight(1)
+            .with_event_transition(&StateMachineMessage::Tick, "browsing", "never")
+            .with_weight(0)
+            .with_random(XorShiftRandom::new(seed))
+            .lock();
+        let simulate = |seed| {
+            let factory = factory(seed);
+            (0..1000).map(|_| {
+                let mut sm = factory.build("browsing", ());
+                *sm.handle_event(StateMachineMessage::Tick).expect("unexpected error")
+            }).collect::<Vec<_>>()
+        };
+
+        // Exactly one weighted Transition is chosen each time, in proportion to its weight
+        let outcomes = simulate(7);
+        let carts = outcomes.iter().filter(|state| **state == "cart").count();
+        let exits = outcomes.iter().filter(|state| **state == "exit").count();
+        assert_eq!(1000, carts + exits);
+        assert!((650..850).contains(&carts), "{} of 1000 chose the cart", carts);
+
+        // The same seed gives the same simulation
+        assert_eq!(outcomes, simulate(7));
+        assert_ne!(outcomes, simulate(8));
+
+        // Previews skip weighted Transitions
+        let sm = factory(7).build("browsing", ());
+        assert_eq!("browsing", sm.peek_event(StateMachineMessage::Tick).expect("unexpected error").state);
     }
-}
 
-/// Basic error type for [StateMachine]
-#[derive(Error, Debug)]
-pub enum StateMachineError<TState: Send + Clone + Eq + PartialEq, TErr = Box<dyn std::error::Error>> {
-    /// Basic error type for [StateMachine::handle_event]
-    #[error("error running effect moving from state {0:?} to {1:?}: {2:?}")]
-    EffectError(TState, TState, TErr)
-}
+    #[cfg(feature = "rand")]
+    #[test]
+    fn test_weighted_transitions_rand() {
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Tick
+        }
 
-/// Describes a Transition between States, potentially with a Predicate and/or Effect
-pub struct StateMachineTransition<'a, TEvent, TState: PartialEq<TState> + Clone + Send + 'a, TData, TErr = Box<dyn std::error::Error>>
-{
-    name: Option<String>,
-    from_state: FromState<TState>,
-    get_to_state: ToState<TEvent, TState, TData>,
-    event_predicate: Option<Box<dyn Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> bool + Send + 'a>>,
-    effect: Option<Box<dyn Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + Send + 'a>>
-}
+        /// Returns the same number every time
+        struct Constant(u64);
 
-impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + 'a, TData, TErr> StateMachineTransition<'a, TEvent, TState, TData, TErr> {
-    fn new(
-        name: Option<String>,
-        event_predicate: Option<Box<dyn Fn(&StateTransitionEffectData<TEvent, TState, TData>) -> bool + Send + 'a>>,
-        from_state: FromState<TState>,
-        get_to_state: ToState<TEvent, TState, TData>,
-        effect: Option<Box<dyn Fn(StateTransitionEffectData<TEvent, TState, TData>) -> Result<(), TErr> + Send + 'a>>,
-    ) -> Self
-    {
-        Self {
-            name,
-            event_predicate,
-            from_state,
-            get_to_state,
-            effect
+        impl rand_core::RngCore for Constant {
+            fn next_u32(&mut self) -> u32 {
+                self.0 as u32
+            }
+
+            fn next_u64(&mut self) -> u64 {
+                self.0
+            }
+
+            fn fill_bytes(&mut self, dst: &mut [u8]) {
+                dst.fill(0);
+            }
+        }
+
+        // With weights of 3 and 1, draws of 0 to 2 choose the first Transition and 3 the second
+        for (draw, expected) in [(0, "cart"), (2, "cart"), (3, "exit"), (7, "exit")] {
+            let mut sm = StateMachineFactory::<StateMachineMessage, &str, ()>::new()
+                .with_event_transition(&StateMachineMessage::Tick, "browsing", "cart")
+                .with_weight(3)
+                .with_event_transition(&StateMachineMessage::Tick, "browsing", "exit")
+                .with_weight(1)
+                .with_random(Constant(draw))
+                .lock().build("browsing", ());
+            assert_eq!(&expected, sm.handle_event(StateMachineMessage::Tick).expect("unexpected error"));
         }
     }
-}
 
-/// Indicates the State or set of States from which a Transition is valid
-#[derive(Clone, Eq, PartialEq)]
-pub enum FromState<TState: PartialEq<TState> + Clone> {
-    /// Indicates that a Transition is valid from any State
-    Any,
-    /// Indicates that a Transition is valid from any State in the provided Vector
-    AnyOf(Vec<TState>),
-    /// Indicates that a Transition is valid only from the specified State
-    From(TState)
-}
+    #[test]
+    fn test_strict() {
+        #[derive(Eq, PartialEq, Debug)]
+        enum StateMachineMessage {
+            Stop,
+            Next
+        }
 
-impl <TState: PartialEq<TState> + Clone> From<TState> for FromState<TState> {
-    fn from(value: TState) -> Self {
-        FromState::From(value)
-    }
-}
+        let factory = |strict| StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_named_event_transition("stop", &StateMachineMessage::Stop, From(1), To(2))
+            .with_named_event_transition("abort", &StateMachineMessage::Stop, FromState::Any, To(3))
+            .with_event_transition(&StateMachineMessage::Stop, From(1), Same)
+            .with_event_transition(&StateMachineMessage::Next, From(1), To(2))
+            .with_event_transition(&StateMachineMessage::Next, From(2), To(3))
+            .strict(strict)
+            .lock();
 
-/// Indicates how a result State is determined after transitioning
-pub enum ToState<TEvent, TState: PartialEq<TState> + Clone + Send, TData> {
-    /// Indicates that a Transition should be applied without changing state.
-    /// This is a special case, intended for Transitions that want to execute Effects
-    /// without causing a state change (e.g. Loggers). Count As Transition should be
-    /// set to true for Transitions that should stop execution, or false for Transactions
-    /// that should not stop execution.
-    Same,
-    /// Specifies that a Transition will cause the State Machine to move to the specified State.
-    To(TState),
-    /// Allows a Transition to provide bespoke logic for determining which State to transition into.
-    Calc(Box<dyn Fn(StateTransitionToStateData<TEvent, TState, TData>) -> TState>)
-}
+        // Without strict mode, both Transitions execute in definition order
+        assert_eq!(&3, factory(false).build(1, ()).handle_event(StateMachineMessage::Stop).expect("unexpected error"));
 
-impl <TEvent, TState: PartialEq<TState> + Clone + Send, TData> From<TState> for ToState<TEvent, TState, TData> {
-    fn from(value: TState) -> Self {
-        ToState::<TEvent, TState, TData>::To(value)
+        let mut sm = factory(true).build(1, ());
+        match sm.peek_event(StateMachineMessage::Stop) {
+            Err(StateMachineError::AmbiguousTransition { state, transitions }) => {
+                assert_eq!(1, state);
+                assert_eq!(vec![Some("stop"), Some("abort")], transitions.iter().map(|t| t.name.as_deref()).collect::<Vec<_>>());
+            },
+            _ => panic!("expected the transitions to be ambiguous")
+        }
+        assert!(matches!(sm.handle_event(StateMachineMessage::Stop), Err(StateMachineError::AmbiguousTransition { .. })));
+        assert_eq!(1, sm.state);
+
+        // Transitions that match the new State later in the same pass are not ambiguous
+        assert_eq!(&3, sm.handle_event(StateMachineMessage::Next).expect("unexpected error"));
+        assert_ne!(factory(false).definition_hash(), factory(true).definition_hash());
     }
-}
+    #[test]
+    fn test_rejected_transitions_do_not_clone() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
 
-/// Data passed to a Transition Effect callback.
-#[derive(Clone)]
-pub struct StateTransitionEffectData<'a, TEvent, TState, TData> {
-    /// The name of the transition, if any.
-    pub name: &'a Option<String>,
-    /// The event causing this transition to occur.
-    pub event: &'a TEvent,
-    /// The current data associated with the State Machine.
-    pub data: &'a TData,
-    /// The state that is being transitioned from.
-    pub from: &'a TState,
-    /// The state that is being transitioned into.
-    pub to: &'a TState
-}
+        static CLONES: AtomicUsize = AtomicUsize::new(0);
 
-/// Data passed to a Transition ToState callback.
-#[derive(Clone)]
-pub struct StateTransitionToStateData<'a, TEvent, TState, TData> {
-    /// The event causing this transition to occur.
-    pub event: &'a TEvent,
-    /// The current data associated with the State Machine.
-    pub data: &'a TData,
-    /// The state that is being transitioned from.
-    pub from: &'a TState,
-}
+        #[derive(Eq, PartialEq)]
+        enum StateMachineMessage {
+            Succeed,
+            Fail
+        }
 
-#[cfg(test)]
-mod unit_tests {
-    use std::sync::atomic::{AtomicBool, Ordering};
-    use anyhow::{anyhow};
-    use thiserror::Error;
-    use crate::{StateMachineFactory, StateMachineError};
-    use crate::FromState::From;
-    use crate::ToState::To;
+        #[derive(Eq, PartialEq, Debug)]
+        struct Large(u32);
+
+        impl Clone for Large {
+            fn clone(&self) -> Self {
+                CLONES.fetch_add(1, Ordering::SeqCst);
+                Large(self.0)
+            }
+        }
+
+        let sm = |rejected| (0..rejected).fold(StateMachineFactory::<StateMachineMessage, Large, ()>::new(), |factory, _| {
+                factory.with_predicated_transition(Large(1), Large(2), |_| false)
+            })
+            .with_event_transition(&StateMachineMessage::Fail, Large(1), Large(2))
+            .lock()
+            .build(Large(1), ());
+
+        let clones = |rejected| {
+            let mut sm = sm(rejected);
+            let before = CLONES.load(Ordering::SeqCst);
+            sm.handle_event(StateMachineMessage::Succeed).expect("unexpected error");
+            sm.handle_event(StateMachineMessage::Fail).expect("unexpected error");
+            assert_eq!(Large(2), sm.state);
+            CLONES.load(Ordering::SeqCst) - before
+        };
+        assert_eq!(clones(0), clones(10));
+    }
 
     #[test]
-    fn test_state_machine() {
+    fn test_extracted_event_transition() {
         #[derive(Eq, PartialEq)]
         enum StateMachineMessage {
-            GoToTwo,
-            GoToThree
+            Digit(u32),
+            Clear
         }
 
-        let go_to_two_happened = AtomicBool::new(false);
-        let go_to_three_happened = AtomicBool::new(false);
+        let extractions = Cell::new(0);
+        let total = Cell::new(0);
         let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
-            .with_event_transition_effect(
-                &StateMachineMessage::GoToTwo,
-                1,
-                2,
-                |_| {
-                    go_to_two_happened.store(true, Ordering::SeqCst);
-                    Ok(())
-                }
-            )
-            .with_event_transition_effect(
-                &StateMachineMessage::GoToThree,
-                2,
-                3,
-                |_| {
-                    go_to_three_happened.store(true, Ordering::SeqCst);
-                    Ok(())
+            .with_extracted_event_transition(|event| {
+                extractions.set(extractions.get() + 1);
+                match event {
+                    StateMachineMessage::Digit(digit) => Some(*digit),
+                    StateMachineMessage::Clear => None
                 }
-            ).lock().build(1, ());
-
-        // Assert our starting state is 1
-        assert_eq!(1, sm.state);
-        // Nothing is going to happen, because no transition is defined for GoToThree from the current state of 1
-        assert_eq!(&1, sm.handle_event(StateMachineMessage::GoToThree).expect("unexpected error"));
-        // This will transition the state to 2
-        assert_eq!(&2, sm.handle_event(StateMachineMessage::GoToTwo).expect("unexpected error"));
-        // Assert that the side effect occurred
-        assert!(go_to_two_happened.load(Ordering::SeqCst), "effect from GoToTwo did not happen when expected");
-        // Assert that we are in state 2
-        assert_eq!(2, sm.state);
+            }, From(1), Same, |digit, _| {
+                total.set(total.get() + digit);
+                Ok(())
+            })
+            .lock()
+            .build(1, ());
 
-        // Nothing is going to happen, because no transition is defined for GoToTwo from the current state of 2
-        assert_eq!(&2, sm.handle_event(StateMachineMessage::GoToTwo).expect("unexpected error"));
-        // This will transition the state to 3
-        assert_eq!(&3, sm.handle_event(StateMachineMessage::GoToThree).expect("unexpected error"));
-        // Assert that the side effect occurred
-        assert!(go_to_three_happened.load(Ordering::SeqCst), "effect from GoToThree did not happen when expected");
-        // Assert that we are in state 3
-        assert_eq!(3, sm.state);
+        // The payload is extracted once per Event, and passed on to the Effect
+        sm.handle_event(StateMachineMessage::Digit(4)).expect("unexpected error");
+        sm.handle_event(StateMachineMessage::Clear).expect("unexpected error");
+        sm.handle_event(StateMachineMessage::Digit(5)).expect("unexpected error");
+        assert_eq!(3, extractions.get());
+        assert_eq!(9, total.get());
     }
 
     #[test]
-    fn test_double_transition<'a>() -> anyhow::Result<()> {
+    fn test_calc_runs_after_predicate() {
         #[derive(Eq, PartialEq)]
         enum StateMachineMessage {
-            GoToTwo
+            Skip,
+            Go
         }
+        static CALCULATED: AtomicUsize = AtomicUsize::new(0);
 
-        // State here is just an integer
-        let factory = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
-            // Evaluate all transitions in a loop
-            // until no transition occurs
-            .cycle(true)
-            // When we receive a GoToTwo event
-            // while in state 1, go to state 2
-            .with_event_transition(
-                &StateMachineMessage::GoToTwo,
-                1,
-                2
-            )
-            // When we transition to state 2,
-            // immediately transition to state 3
-            .with_auto_transition(
-                2,
-                3
-            )
-            // Lock the factory object so that
-            // we can build a state machine
-            .lock();
+        let mut sm = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+            .with_predicated_transition(From(1), Calc(Box::new(|d| {
+                CALCULATED.fetch_add(1, Ordering::SeqCst);
+                d.from + 1
+            })), |d| {
+                // The Predicate sees the from_state in place of the calculated State
+                assert_eq!(d.from, d.to);
+                d.event == &StateMachineMessage::Go
+            })
+            .lock()
+            .build(1, ());
 
-        // Build the state machine, with an empty () as data
-        // (we don't care about data for this example)
-        let mut sm = factory.build(1, ());
+        sm.handle_event(StateMachineMessage::Skip).expect("unexpected error");
+        assert_eq!(0, CALCULATED.load(Ordering::SeqCst));
+        sm.handle_event(StateMachineMessage::Go).expect("unexpected error");
+        assert_eq!(2, sm.state);
+        assert_eq!(1, CALCULATED.load(Ordering::SeqCst));
+    }
 
-        // The StateMachine starts in state 1
-        assert_eq!(1, sm.state);
+    #[test]
+    fn test_effect_error_context() {
+        #[derive(Eq, PartialEq, Debug)]
+        enum StateMachineMessage {
+            Charge { amount: u32 }
+        }
 
-        // Handling an event tells us what state we end up in
-        match sm.handle_event(StateMachineMessage::GoToTwo) {
-            Ok(state) => {
-                assert_eq!(3, *state);
-            }
-            Err(StateMachineError::EffectError(from, to, e)) => {
-                return Err(anyhow!("error changing state from {} to {}: {}", from, to, e));
+        let charge = StateMachineMessage::Charge { amount: 5 };
+        let factory = |context: bool| {
+            let factory = StateMachineFactory::<StateMachineMessage, u32, ()>::new()
+                .with_named_predicated_transition_effect("capture", From(2), To(3), |_| true, |_| Err("card declined".into()))
+                .with_event_transition(&charge, From(1), To(2))
+                .cycle(true);
+            match context {
+                true => factory.with_error_context().lock(),
+                false => factory.lock()
             }
         };
 
-        // Because of the two transitions that we defined,
-        // we end up in state 3
-        assert_eq!(3, sm.state);
-        Ok(())
+        let error = factory(false).build(1, ()).handle_event(StateMachineMessage::Charge { amount: 5 }).map(|_| ()).expect_err("expected an effect error");
+        assert_eq!("card declined", std::error::Error::source(&error).expect("missing source").to_string());
+        assert_eq!("error running effect moving from state 2 to 3 in transition \"capture\" (cycle 1): \"card declined\"", error.to_string());
+        match error {
+            StateMachineError::EffectError { from, to, transition, event, cycle, .. } => {
+                assert_eq!((2, 3, Some("capture"), None, 1), (from, to, transition.as_deref(), event.as_deref(), cycle));
+            },
+            _ => panic!("expected an effect error")
+        }
+
+        match factory(true).build(1, ()).handle_event(StateMachineMessage::Charge { amount: 5 }) {
+            Err(StateMachineError::EffectError { event, .. }) => assert_eq!(Some("Charge { amount: 5 }".to_string()), event),
+            _ => panic!("expected an effect error")
+        };
     }
 
     #[test]
-    fn test_effect_error() -> anyhow::Result<()> {
-        #[derive(Eq, PartialEq, Debug)]
-        enum StateMachineMessage {
-            GoToTwo
+    fn test_runtime_transitions() {
+        #[derive(Clone, Eq, PartialEq, Debug)]
+        enum PluginEvent {
+            Start,
+            Pause,
+            Stop
         }
-
-        #[derive(Error, Debug, Eq, PartialEq)]
-        enum TestError {
-            #[error("test error")]
-            TestError
+        #[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+        enum PluginState {
+            Idle,
+            Running,
+            Paused
         }
 
-        let mut sm = StateMachineFactory::new()
-            .with_event_transition_effect(
-                &StateMachineMessage::GoToTwo,
-                From(1),
-                To(2),
-                |_| {
-                    Err(TestError::TestError)
-                }
-            ).lock().build(1, ());
+        for indexed in [false, true] {
+            let factory = StateMachineFactory::<PluginEvent, PluginState, u32>::new()
+                .with_named_event_transition("start", &PluginEvent::Start, PluginState::Idle, PluginState::Running)
+                .with_named_event_transition("stop", &PluginEvent::Stop, PluginState::Running, PluginState::Idle);
+            let factory = if indexed { factory.lock_indexed() } else { factory.lock() };
 
-        match sm.handle_event(StateMachineMessage::GoToTwo) {
-            Ok(_) => {
-                Err(anyhow!("expected an error"))
-            },
-            Err(StateMachineError::EffectError(from, to, cause)) => {
-                assert_eq!(1, from);
-                assert_eq!(2, to);
-                assert_eq!(cause, TestError::TestError);
-                Ok(())
-            }
+            let mut sm = factory.build(PluginState::Idle, 7);
+            sm.handle_event(PluginEvent::Start).expect("unexpected error");
+            assert_eq!(&PluginState::Running, sm.handle_event(PluginEvent::Pause).expect("unexpected error"));
+
+            // Added Transitions apply to this instance only, keeping its State and Data
+            sm.add_transitions(StateMachineFactory::new()
+                .with_named_event_transition("pause", &PluginEvent::Pause, PluginState::Running, PluginState::Paused)
+                .with_named_event_transition("resume", &PluginEvent::Start, PluginState::Paused, PluginState::Running));
+            assert_eq!(4, sm.transition_count());
+            assert_eq!(2, sm.transitions.len());
+            assert_eq!(&PluginState::Paused, sm.handle_event(PluginEvent::Pause).expect("unexpected error"));
+            assert_eq!(vec![Some("pause")], sm.fired_transitions().collect::<Vec<_>>());
+            assert_eq!(&PluginState::Running, sm.handle_event(PluginEvent::Start).expect("unexpected error"));
+            assert_eq!(7, sm.data);
+            assert_eq!(2, factory.build(PluginState::Idle, 0).transition_count());
+
+            // Disabled Transitions are skipped until enabled again
+            assert!(sm.disable_transition("stop"));
+            assert!(!sm.disable_transition("missing"));
+            assert_eq!(&PluginState::Running, sm.handle_event(PluginEvent::Stop).expect("unexpected error"));
+            assert!(sm.enable_transition("stop"));
+            assert_eq!(&PluginState::Idle, sm.handle_event(PluginEvent::Stop).expect("unexpected error"));
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,112 @@
+//! The [state_machine!](crate::state_machine) macro.
+
+/// Builds a [StateMachineFactory](crate::StateMachineFactory) from a table of Event Transitions,
+/// which is easier to review than a long chain of builder calls.
+///
+/// The table starts with the Event, State and Data types (and optionally the Effect error type),
+/// followed by one row per Transition of the form `From + Event => To`. States and Events are
+/// unit variants of the Event and State enums, which must be in scope by name; every variant is
+/// checked by the compiler, so a misspelled State or Event does not compile. `_` as the from
+/// State matches any State ([FromState::Any](crate::FromState::Any)). A row may end with
+/// `as "name"` to name the Transition, and with `with effect` to attach an Effect.
+///
+/// Each row expands to [with_event_transition](crate::StateMachineFactory::with_event_transition)
+/// or one of its named and Effect variants, in the order of the table, so the result can be
+/// configured further like any other factory.
+///
+/// ```
+/// use statement::state_machine;
+///
+/// #[derive(Eq, PartialEq)]
+/// enum Event { Start, Pause, Stop }
+///
+/// #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+/// enum State { Idle, Running, Paused }
+///
+/// let mut sm = state_machine! {
+///     Event, State, u32;
+///     Idle    + Start => Running as "start",
+///     Running + Pause => Paused with |d| { println!("paused after {}", d.data); Ok(()) },
+///     Paused  + Start => Running,
+///     _       + Stop  => Idle,
+/// }.lock().build(State::Idle, 0);
+///
+/// sm.handle_event(Event::Start).unwrap();
+/// sm.handle_event(Event::Pause).unwrap();
+/// assert_eq!(State::Paused, sm.state);
+/// sm.handle_event(Event::Stop).unwrap();
+/// assert_eq!(State::Idle, sm.state);
+/// ```
+#[macro_export]
+macro_rules! state_machine {
+    (
+        $event:ident, $state:ident, $data:ty $(, $err:ty)?;
+        $($from:tt + $on:ident => $to:ident $(as $name:literal)? $(with $effect:expr)?),* $(,)?
+    ) => {{
+        let factory = $crate::StateMachineFactory::<$event, $state, $data $(, $err)?>::new();
+        $(
+            let factory = $crate::state_machine!(@transition factory, $event, $state, $from, $on, $to, [$($name)?], [$($effect)?]);
+        )*
+        factory
+    }};
+    (@transition $factory:ident, $event:ident, $state:ident, $from:tt, $on:ident, $to:ident, [], []) => {
+        $factory.with_event_transition(&$event::$on, $crate::state_machine!(@from $state, $from), $state::$to)
+    };
+    (@transition $factory:ident, $event:ident, $state:ident, $from:tt, $on:ident, $to:ident, [$name:literal], []) => {
+        $factory.with_named_event_transition($name, &$event::$on, $crate::state_machine!(@from $state, $from), $state::$to)
+    };
+    (@transition $factory:ident, $event:ident, $state:ident, $from:tt, $on:ident, $to:ident, [], [$effect:expr]) => {
+        $factory.with_event_transition_effect(&$event::$on, $crate::state_machine!(@from $state, $from), $state::$to, $effect)
+    };
+    (@transition $factory:ident, $event:ident, $state:ident, $from:tt, $on:ident, $to:ident, [$name:literal], [$effect:expr]) => {
+        $factory.with_named_event_transition_effect($name, &$event::$on, $crate::state_machine!(@from $state, $from), $state::$to, $effect)
+    };
+    (@from $state:ident, _) => {
+        $crate::FromState::<$state>::Any
+    };
+    (@from $state:ident, $from:ident) => {
+        $crate::FromState::From($state::$from)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    #[derive(Eq, PartialEq, Debug)]
+    enum Events {
+        Insert,
+        Push,
+        Refund
+    }
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum States {
+        Locked,
+        Unlocked
+    }
+
+    #[test]
+    fn test_state_machine_macro() {
+        let refunds = Cell::new(0);
+        let mut sm = state_machine! {
+            Events, States, (), String;
+            Locked   + Insert => Unlocked as "insert",
+            Locked   + Push   => Locked with |_| Err("locked".to_string()),
+            Unlocked + Push   => Locked,
+            _        + Refund => Locked as "refund" with |_| {
+                refunds.set(refunds.get() + 1);
+                Ok(())
+            }
+        }.lock().build(States::Locked, ());
+
+        assert_eq!(&States::Unlocked, sm.handle_event(Events::Insert).expect("unexpected error"));
+        assert_eq!(vec![Some("insert")], sm.fired_transitions().collect::<Vec<_>>());
+        assert_eq!(&States::Locked, sm.handle_event(Events::Push).expect("unexpected error"));
+        sm.handle_event(Events::Insert).expect("unexpected error");
+        assert_eq!(&States::Locked, sm.handle_event(Events::Refund).expect("unexpected error"));
+        assert_eq!(&States::Locked, sm.handle_event(Events::Refund).expect("unexpected error"));
+        assert_eq!(2, refunds.get());
+        assert!(sm.handle_event(Events::Push).is_err());
+    }
+}
@@ -0,0 +1,204 @@
+//! Event-sourced persistence of State Machines.
+//!
+//! Instead of serializing a State Machine, its Events can be appended to an [EventStore] as they
+//! are handled, and [LockedStateMachineFactory::rehydrate] rebuilds the State Machine later by
+//! replaying them from the initial State. Effects that reach outside the State Machine (sending
+//! emails, charging cards) have already happened, so they can be suppressed during the replay.
+//!
+//! ```
+//! use statement::StateMachineFactory;
+//! use statement::persist::{EventStore, MemoryEventStore};
+//!
+//! #[derive(Clone, Eq, PartialEq)]
+//! enum Event { Pay, Ship }
+//!
+//! #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+//! enum State { Pending, Paid, Shipped }
+//!
+//! let factory = StateMachineFactory::<Event, State, ()>::new()
+//!     .with_event_transition_effect(&Event::Pay, State::Pending, State::Paid, |_| Ok(()))
+//!     .with_event_transition(&Event::Ship, State::Paid, State::Shipped)
+//!     .lock();
+//!
+//! let mut store = MemoryEventStore::new();
+//! let mut sm = factory.build(State::Pending, ());
+//! for event in [Event::Pay, Event::Ship] {
+//!     sm.handle_event(event.clone()).unwrap();
+//!     store.append(&event).unwrap();
+//! }
+//!
+//! let sm = factory.rehydrate(State::Pending, (), &store, true).unwrap();
+//! assert_eq!(State::Shipped, sm.state);
+//! ```
+
+use std::convert::Infallible;
+use thiserror::Error;
+use crate::{LockedStateMachineFactory, StateMachine, StateMachineError};
+
+/// An append-only log of the Events handled by a State Machine, see the
+/// [persist](crate::persist) module.
+pub trait EventStore<TEvent> {
+    /// The error returned when the store cannot be written or read
+    type Error;
+
+    /// Appends an Event to the end of the log.
+    fn append(&mut self, event: &TEvent) -> Result<(), Self::Error>;
+
+    /// Loads every Event in the log, oldest first.
+    fn load(&self) -> Result<Vec<TEvent>, Self::Error>;
+}
+
+/// An [EventStore] held in memory, mostly useful for tests
+#[derive(Clone, Debug)]
+pub struct MemoryEventStore<TEvent> {
+    events: Vec<TEvent>,
+}
+
+impl <TEvent> MemoryEventStore<TEvent> {
+    /// Creates an empty store.
+    pub fn new() -> Self {
+        Self {
+            events: Vec::new(),
+        }
+    }
+
+    /// Returns the Events in the store, oldest first.
+    pub fn events(&self) -> &[TEvent] {
+        &self.events
+    }
+}
+
+impl <TEvent> Default for MemoryEventStore<TEvent> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl <TEvent: Clone> EventStore<TEvent> for MemoryEventStore<TEvent> {
+    type Error = Infallible;
+
+    fn append(&mut self, event: &TEvent) -> Result<(), Self::Error> {
+        self.events.push(event.clone());
+        Ok(())
+    }
+
+    fn load(&self) -> Result<Vec<TEvent>, Self::Error> {
+        Ok(self.events.clone())
+    }
+}
+
+/// An error returned by [LockedStateMachineFactory::rehydrate]
+#[derive(Error, Debug)]
+pub enum RehydrateError<TStoreErr, TState: Send + Clone + Eq + PartialEq, TErr = Box<dyn std::error::Error>> {
+    /// The Events could not be loaded from the store
+    #[error("error loading events: {0:?}")]
+    Load(TStoreErr),
+    /// A stored Event failed when it was replayed, see [StateMachineError::EventFailed]
+    #[error("error replaying events: {0:?}")]
+    Replay(StateMachineError<TState, TErr>),
+}
+
+/// The result of [LockedStateMachineFactory::rehydrate]
+type Rehydrated<'a, TEvent, TState, TData, TErr, TStoreErr> = Result<StateMachine<'a, TEvent, TState, TData, TErr>, RehydrateError<TStoreErr, TState, TErr>>;
+
+impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + PartialEq + 'a, TData, TErr> LockedStateMachineFactory<'a, TEvent, TState, TData, TErr> {
+    /// Builds a State Machine in `initial_state` with `initial_data`, as
+    /// [LockedStateMachineFactory::build] would, and replays every Event in `store` through it.
+    ///
+    /// With `suppress_effects` set, Effects are not run during the replay (they behave as if they
+    /// had succeeded with [EffectOutcome::Continue](crate::EffectOutcome::Continue)) and are run
+    /// normally for Events handled afterwards. Data changed only by Effects is then not restored,
+    /// so Predicates that depend on it may choose different Transitions than they originally did.
+    pub fn rehydrate<TStore: EventStore<TEvent>>(&self, initial_state: TState, initial_data: TData, store: &TStore, suppress_effects: bool) -> Rehydrated<'a, TEvent, TState, TData, TErr, TStore::Error> {
+        let events = store.load().map_err(RehydrateError::Load)?;
+        let mut sm = StateMachine { pure: suppress_effects, ..self.build(initial_state, initial_data) };
+        let replayed = sm.handle_events(events).map(|_| ()).map_err(RehydrateError::Replay);
+        sm.pure = false;
+        replayed.map(|_| sm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use crate::persist::{EventStore, MemoryEventStore, RehydrateError};
+    use crate::{StateMachineError, StateMachineFactory, UnhandledEventPolicy};
+
+    #[derive(Clone, Eq, PartialEq, Debug)]
+    enum Events {
+        Pay,
+        Ship,
+        Refund
+    }
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum States {
+        Pending,
+        Paid,
+        Shipped
+    }
+
+    /// A store that cannot be read
+    struct Unavailable;
+
+    impl EventStore<Events> for Unavailable {
+        type Error = &'static str;
+
+        fn append(&mut self, _event: &Events) -> Result<(), Self::Error> {
+            Err("unavailable")
+        }
+
+        fn load(&self) -> Result<Vec<Events>, Self::Error> {
+            Err("unavailable")
+        }
+    }
+
+    #[test]
+    fn test_rehydrate() {
+        let charges = Cell::new(0);
+        let factory = StateMachineFactory::<Events, States, ()>::new()
+            .with_event_transition_effect(&Events::Pay, States::Pending, States::Paid, |_| {
+                charges.set(charges.get() + 1);
+                Ok(())
+            })
+            .with_event_transition(&Events::Ship, States::Paid, States::Shipped)
+            .with_event_transition_effect(&Events::Refund, States::Shipped, States::Pending, |_| {
+                charges.set(charges.get() - 1);
+                Ok(())
+            })
+            .unhandled_event_policy(UnhandledEventPolicy::Error)
+            .lock();
+
+        let mut store = MemoryEventStore::new();
+        let mut sm = factory.build(States::Pending, ());
+        for event in [Events::Pay, Events::Ship] {
+            sm.handle_event(event.clone()).expect("unexpected error");
+            store.append(&event).expect("unexpected error");
+        }
+        assert_eq!(1, charges.get());
+
+        // Replaying with Effects suppressed does not charge again, but later Events run Effects
+        let mut sm = factory.rehydrate(States::Pending, (), &store, true).expect("unexpected error");
+        assert_eq!(States::Shipped, sm.state);
+        assert_eq!(1, charges.get());
+        sm.handle_event(Events::Refund).expect("unexpected error");
+        assert_eq!(0, charges.get());
+
+        // Replaying with Effects runs them again
+        let sm = factory.rehydrate(States::Pending, (), &store, false).expect("unexpected error");
+        assert_eq!(States::Shipped, sm.state);
+        assert_eq!(1, charges.get());
+
+        // An Event that no longer applies fails the replay
+        store.append(&Events::Ship).expect("unexpected error");
+        match factory.rehydrate(States::Pending, (), &store, true) {
+            Err(RehydrateError::Replay(StateMachineError::EventFailed { index, state, .. })) => {
+                assert_eq!(2, index);
+                assert_eq!(States::Shipped, state);
+            },
+            _ => panic!("expected the replay to fail")
+        }
+
+        assert!(matches!(factory.rehydrate(States::Pending, (), &Unavailable, true), Err(RehydrateError::Load("unavailable"))));
+    }
+}
@@ -0,0 +1,301 @@
+//! Orthogonal regions: several independent sets of Transitions sharing one set of Data.
+//!
+//! A [ParallelStateMachine] holds a number of named regions, each with its own State and
+//! Transitions, over a single `TData` shared by all of them. Every Event is dispatched to every
+//! region in the order the regions were added, and each region's Predicates and Effects see the
+//! shared Data. This models systems whose parts evolve independently, such as the power state and
+//! the connectivity state of a device, without composing separate State Machines by hand.
+//!
+//! The regions run inside one [StateMachine], built from the factory of the first region, which
+//! owns the Data. The factories of later regions only contribute their Transitions; every other
+//! option (policies, Event hooks, timeouts, history, metrics) comes from the first factory and
+//! applies to all regions.
+//!
+//! ```
+//! use statement::StateMachineFactory;
+//! use statement::regions::ParallelStateMachine;
+//!
+//! #[derive(Clone, Eq, PartialEq)]
+//! enum Event { PowerOn, Connect }
+//!
+//! #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+//! enum State { Off, On, Disconnected, Connected }
+//!
+//! let power = StateMachineFactory::<Event, State, bool>::new()
+//!     .with_event_transition(&Event::PowerOn, State::Off, State::On)
+//!     .lock();
+//! let connectivity = StateMachineFactory::<Event, State, bool>::new()
+//!     .with_predicated_transition(State::Disconnected, State::Connected, |d| d.event == &Event::Connect && *d.data)
+//!     .lock();
+//!
+//! let mut sm = ParallelStateMachine::new("power", &power, State::Off, true)
+//!     .with_region("connectivity", &connectivity, State::Disconnected);
+//!
+//! sm.handle_event(Event::PowerOn).unwrap();
+//! sm.handle_event(Event::Connect).unwrap();
+//! assert_eq!(Some(&State::On), sm.state("power"));
+//! assert_eq!(Some(&State::Connected), sm.state("connectivity"));
+//! ```
+
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::Instant;
+use thiserror::Error;
+use crate::{DeferredEvent, EnteredAt, EventIndex, FailureTracker, LockedStateMachineFactory, StateContext, StateMachine, StateMachineError, StateMachineTransition, TransitionIndex};
+
+/// Independent regions sharing one set of Data, see the [regions](crate::regions) module.
+pub struct ParallelStateMachine<'a, TEvent, TState: PartialEq<TState> + Clone + Send + 'a, TData, TErr = Box<dyn std::error::Error>> {
+    /// The State Machine of the first region, which owns the shared Data
+    machine: StateMachine<'a, TEvent, TState, TData, TErr>,
+    name: String,
+    regions: Vec<Region<'a, TEvent, TState, TData, TErr>>,
+}
+
+/// An error returned by one region of a [ParallelStateMachine]
+#[derive(Error, Debug)]
+#[error("error in region {region:?}: {error:?}")]
+pub struct RegionError<TState: Send + Clone + Eq + PartialEq, TErr = Box<dyn std::error::Error>> {
+    /// The name of the region
+    pub region: String,
+    /// The error returned by the region
+    pub error: StateMachineError<TState, TErr>,
+}
+
+impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + PartialEq + 'a, TData, TErr> ParallelStateMachine<'a, TEvent, TState, TData, TErr> {
+    /// Creates a `ParallelStateMachine` with one region, named `name`, with the Transitions of a
+    /// factory, starting in `initial_state`. The State Machine built from the factory owns the
+    /// shared Data and supplies the options of every region.
+    pub fn new(name: impl Into<String>, factory: &LockedStateMachineFactory<'a, TEvent, TState, TData, TErr>, initial_state: TState, data: TData) -> Self {
+        Self {
+            machine: factory.build(initial_state, data),
+            name: name.into(),
+            regions: Vec::new(),
+        }
+    }
+
+    /// Adds a region, named `name`, with the Transitions of a factory, starting in
+    /// `initial_state`. Only the Transitions of the factory are used.
+    pub fn with_region(mut self, name: impl Into<String>, factory: &LockedStateMachineFactory<'a, TEvent, TState, TData, TErr>, initial_state: TState) -> Self {
+        let mut region = Region {
+            name: name.into(),
+            state: initial_state,
+            transitions: factory.transitions.clone(),
+            disabled_transitions: Vec::new(),
+            transition_index: factory.transition_index.clone(),
+            event_index: factory.event_index.clone(),
+            failure_tracker: factory.failure_tracker.clone(),
+            fired: Vec::new(),
+            stack: Vec::new(),
+            history_states: Vec::new(),
+            reentries: 0,
+            state_entered_at: EnteredAt(self.machine.now()),
+            interval_counts: Vec::new(),
+            contexts: Vec::new(),
+            deferred: VecDeque::new(),
+            deferred_count: 0,
+        };
+        region.enter(&mut self.machine).enter_state_contexts();
+        self.regions.push(region);
+        self
+    }
+
+    /// Returns the shared Data.
+    pub fn data(&self) -> &TData {
+        &self.machine.data
+    }
+
+    /// Returns the shared Data for modification.
+    pub fn data_mut(&mut self) -> &mut TData {
+        &mut self.machine.data
+    }
+
+    /// Returns the current State of the named region, or None if there is no such region.
+    pub fn state(&self, region: &str) -> Option<&TState> {
+        self.states().find(|(name, _)| *name == region).map(|(_, state)| state)
+    }
+
+    /// Returns the name and current State of every region, in the order the regions were added.
+    pub fn states(&self) -> impl Iterator<Item = (&str, &TState)> + '_ {
+        std::iter::once((self.name.as_str(), &self.machine.state))
+            .chain(self.regions.iter().map(|region| (region.name.as_str(), &region.state)))
+    }
+
+    /// Handles an Event in every region, in the order the regions were added, as
+    /// [StateMachine::handle_event] would. Events emitted by Effects are handled by the region
+    /// that emitted them. A region that fails does not stop the Event from reaching the remaining
+    /// regions; the errors of all failing regions are returned together.
+    pub fn handle_event(&mut self, event: TEvent) -> Result<(), Vec<RegionError<TState, TErr>>>
+    where TEvent: Clone
+    {
+        let mut errors = Vec::new();
+        if let Err(error) = self.machine.handle_event(event.clone()) {
+            errors.push(RegionError { region: self.name.clone(), error });
+        }
+        for region in &mut self.regions {
+            let result = region.enter(&mut self.machine).handle_event(event.clone()).map(|_| ());
+            if let Err(error) = result {
+                errors.push(RegionError { region: region.name.clone(), error });
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors)
+        }
+    }
+}
+
+/// The State, Transitions and bookkeeping of a region after the first, which are swapped into the
+/// State Machine of the first region while the region handles an Event
+struct Region<'a, TEvent, TState: PartialEq<TState> + Clone + Send + 'a, TData, TErr> {
+    name: String,
+    state: TState,
+    transitions: Arc<Vec<StateMachineTransition<'a, TEvent, TState, TData, TErr>>>,
+    disabled_transitions: Vec<usize>,
+    transition_index: Option<Arc<dyn TransitionIndex<TState> + 'a>>,
+    event_index: Option<Arc<EventIndex<TEvent>>>,
+    failure_tracker: Option<FailureTracker<'a, TState, TErr>>,
+    fired: Vec<usize>,
+    stack: Vec<TState>,
+    history_states: Vec<Option<TState>>,
+    reentries: usize,
+    state_entered_at: EnteredAt,
+    interval_counts: Vec<(Instant, u32)>,
+    contexts: Vec<StateContext>,
+    deferred: VecDeque<DeferredEvent<TEvent>>,
+    deferred_count: u64,
+}
+
+impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + 'a, TData, TErr> Region<'a, TEvent, TState, TData, TErr> {
+    /// Swaps the region into `machine` until the returned guard is dropped, even by a panic
+    fn enter<'r>(&'r mut self, machine: &'r mut StateMachine<'a, TEvent, TState, TData, TErr>) -> EnteredRegion<'r, 'a, TEvent, TState, TData, TErr> {
+        self.swap(machine);
+        EnteredRegion { region: self, machine }
+    }
+
+    fn swap(&mut self, machine: &mut StateMachine<'a, TEvent, TState, TData, TErr>) {
+        std::mem::swap(&mut self.state, &mut machine.state);
+        std::mem::swap(&mut self.transitions, &mut machine.transitions);
+        std::mem::swap(&mut self.disabled_transitions, &mut machine.disabled_transitions);
+        std::mem::swap(&mut self.transition_index, &mut machine.transition_index);
+        std::mem::swap(&mut self.event_index, &mut machine.event_index);
+        std::mem::swap(&mut self.failure_tracker, &mut machine.failure_tracker);
+        std::mem::swap(&mut self.fired, &mut machine.fired);
+        std::mem::swap(&mut self.stack, &mut machine.stack);
+        std::mem::swap(&mut self.history_states, &mut machine.history_states);
+        std::mem::swap(&mut self.reentries, &mut machine.reentries);
+        std::mem::swap(&mut self.state_entered_at, &mut machine.state_entered_at);
+        std::mem::swap(&mut self.interval_counts, &mut machine.interval_counts);
+        std::mem::swap(&mut self.contexts, &mut machine.contexts);
+        std::mem::swap(&mut self.deferred, &mut machine.deferred);
+        std::mem::swap(&mut self.deferred_count, &mut machine.deferred_count);
+    }
+}
+
+/// A region swapped into the State Machine of the first region, see [Region::enter]
+struct EnteredRegion<'r, 'a, TEvent, TState: PartialEq<TState> + Clone + Send + 'a, TData, TErr> {
+    region: &'r mut Region<'a, TEvent, TState, TData, TErr>,
+    machine: &'r mut StateMachine<'a, TEvent, TState, TData, TErr>,
+}
+
+impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + 'a, TData, TErr> Deref for EnteredRegion<'_, 'a, TEvent, TState, TData, TErr> {
+    type Target = StateMachine<'a, TEvent, TState, TData, TErr>;
+
+    fn deref(&self) -> &Self::Target {
+        self.machine
+    }
+}
+
+impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + 'a, TData, TErr> DerefMut for EnteredRegion<'_, 'a, TEvent, TState, TData, TErr> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.machine
+    }
+}
+
+impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + 'a, TData, TErr> Drop for EnteredRegion<'_, 'a, TEvent, TState, TData, TErr> {
+    fn drop(&mut self) {
+        self.region.swap(self.machine);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use crate::StateMachineFactory;
+    use crate::regions::ParallelStateMachine;
+
+    #[derive(Clone, Eq, PartialEq, Debug)]
+    enum Events {
+        PowerOn,
+        PowerOff,
+        Connect
+    }
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum States {
+        Off,
+        On,
+        Disconnected,
+        Connected
+    }
+
+    struct Device {
+        signal: Cell<u32>
+    }
+
+    #[test]
+    fn test_regions() {
+        let power = StateMachineFactory::<Events, States, Device>::new()
+            .with_event_transition_effect(&Events::PowerOn, States::Off, States::On, |d| {
+                d.data.signal.set(3);
+                Ok(())
+            })
+            .with_event_transition(&Events::PowerOff, States::On, States::Off)
+            .with_event_transition_effect(&Events::Connect, States::Off, States::Off, |_| Err("powered off".into()))
+            .lock();
+        let connectivity = StateMachineFactory::<Events, States, Device>::new()
+            .with_predicated_transition(States::Disconnected, States::Connected, |d| d.event == &Events::Connect && d.data.signal.get() > 0)
+            .with_event_transition(&Events::PowerOff, States::Connected, States::Disconnected)
+            .lock();
+
+        let mut sm = ParallelStateMachine::new("power", &power, States::Off, Device { signal: Cell::new(0) })
+            .with_region("connectivity", &connectivity, States::Disconnected);
+
+        // A failing region does not stop the Event reaching the others
+        let errors = sm.handle_event(Events::Connect).expect_err("expected the power region to fail");
+        assert_eq!(vec!["power"], errors.iter().map(|e| e.region.as_str()).collect::<Vec<_>>());
+        assert_eq!(Some(&States::Disconnected), sm.state("connectivity"));
+
+        // Data changed by one region is seen by the others
+        sm.handle_event(Events::PowerOn).expect("unexpected error");
+        assert_eq!(3, sm.data().signal.get());
+        sm.handle_event(Events::Connect).expect("unexpected error");
+        assert_eq!(vec![("power", &States::On), ("connectivity", &States::Connected)], sm.states().collect::<Vec<_>>());
+
+        sm.handle_event(Events::PowerOff).expect("unexpected error");
+        assert_eq!(vec![("power", &States::Off), ("connectivity", &States::Disconnected)], sm.states().collect::<Vec<_>>());
+        assert_eq!(None, sm.state("battery"));
+    }
+
+    #[test]
+    fn test_region_panic() {
+        let power = StateMachineFactory::<Events, States, Device>::new()
+            .with_event_transition(&Events::PowerOn, States::Off, States::On)
+            .lock();
+        let connectivity = StateMachineFactory::<Events, States, Device>::new()
+            .with_event_transition_effect(&Events::Connect, States::Disconnected, States::Connected, |_| panic!("radio failure"))
+            .lock();
+
+        let mut sm = ParallelStateMachine::new("power", &power, States::Off, Device { signal: Cell::new(2) })
+            .with_region("connectivity", &connectivity, States::Disconnected);
+
+        // The shared Data and the State of each region survive a panicking region
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| sm.handle_event(Events::Connect)));
+        assert!(result.is_err());
+        assert_eq!(2, sm.data().signal.get());
+        assert_eq!(vec![("power", &States::Off), ("connectivity", &States::Disconnected)], sm.states().collect::<Vec<_>>());
+
+        sm.handle_event(Events::PowerOn).expect("unexpected error");
+        assert_eq!(Some(&States::On), sm.state("power"));
+    }
+}
@@ -0,0 +1,208 @@
+//! Runs a State Machine on a dedicated thread, fed with Events from a channel.
+//!
+//! A [StateMachineRunner] owns a [StateMachine] on its own thread and handles the Events sent
+//! through its [EventSender] handles one at a time, in the order they arrive. The current State
+//! can be observed from any thread through a [StateWatch], which waits on the State Machine's
+//! [StateReader].
+//!
+//! State Machines are not `Send`, so the runner is given a function that builds the State Machine
+//! on the runner thread rather than the State Machine itself.
+//!
+//! ```
+//! use statement::StateMachineFactory;
+//! use statement::runner::StateMachineRunner;
+//!
+//! #[derive(Eq, PartialEq)]
+//! enum Event { Connect, Disconnect }
+//!
+//! #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+//! enum State { Offline, Online }
+//!
+//! let runner = StateMachineRunner::spawn(|| {
+//!     StateMachineFactory::<Event, State, ()>::new()
+//!         .with_event_transition(&Event::Connect, State::Offline, State::Online)
+//!         .with_event_transition(&Event::Disconnect, State::Online, State::Offline)
+//!         .lock()
+//!         .build(State::Offline, ())
+//! }, |_| {});
+//!
+//! let mut watch = runner.watch();
+//! runner.sender().send(Event::Connect).unwrap();
+//! assert_eq!(Some(State::Online), watch.changed());
+//! assert_eq!(State::Online, runner.join().unwrap());
+//! ```
+
+use std::sync::mpsc::{channel, SendError, Sender};
+use std::thread::JoinHandle;
+use crate::{StateMachine, StateMachineError, StateReader};
+
+/// Owns a [StateMachine] running on a dedicated thread, see the [runner](crate::runner) module.
+pub struct StateMachineRunner<TEvent, TState> {
+    sender: EventSender<TEvent>,
+    reader: StateReader<TState>,
+    thread: JoinHandle<TState>,
+}
+
+impl <TEvent: Send + 'static, TState: PartialEq<TState> + Clone + Send + Sync + Eq + 'static> StateMachineRunner<TEvent, TState> {
+    /// Starts a thread that builds a State Machine with `build` and handles every Event sent to
+    /// the runner. Errors returned by [StateMachine::handle_event] are passed to `on_error` on
+    /// the runner thread; the runner keeps handling Events afterwards. If `build` panics, the
+    /// panic is resumed on the calling thread.
+    pub fn spawn<TData: 'static, TErr: 'static>(
+        build: impl FnOnce() -> StateMachine<'static, TEvent, TState, TData, TErr> + Send + 'static,
+        mut on_error: impl FnMut(StateMachineError<TState, TErr>) + Send + 'static
+    ) -> Self {
+        let (sender, receiver) = channel();
+        let (reader_sender, ready) = channel();
+        let thread = std::thread::spawn(move || {
+            let mut sm = build();
+            // The spawning thread is waiting for the reader, so this cannot fail
+            let _ = reader_sender.send(sm.state_reader());
+            for event in receiver {
+                if let Err(e) = sm.handle_event(event) {
+                    on_error(e);
+                }
+            }
+            // Dropping the State Machine wakes any waiting watches
+            sm.state
+        });
+        let reader = match ready.recv() {
+            Ok(reader) => reader,
+            // The thread only drops the sender without sending if build panicked
+            Err(_) => match thread.join() {
+                Err(payload) => std::panic::resume_unwind(payload),
+                Ok(_) => unreachable!("the state machine runner stopped without sending its reader")
+            }
+        };
+        Self {
+            sender: EventSender { sender },
+            reader,
+            thread,
+        }
+    }
+
+    /// Returns a handle for sending Events to the State Machine.
+    pub fn sender(&self) -> EventSender<TEvent> {
+        self.sender.clone()
+    }
+
+    /// Returns a handle for observing the State of the State Machine.
+    pub fn watch(&self) -> StateWatch<TState> {
+        StateWatch {
+            seen_version: self.reader.version(),
+            reader: self.reader.clone(),
+        }
+    }
+
+    /// Stops accepting Events through this runner's own sender, waits for the runner thread to
+    /// handle every Event already sent, and returns the final State. The thread only finishes
+    /// once every [EventSender] has been dropped, so drop any handles obtained with
+    /// [StateMachineRunner::sender] first.
+    pub fn join(self) -> std::thread::Result<TState> {
+        drop(self.sender);
+        self.thread.join()
+    }
+}
+
+/// Cloneable handle for sending Events to a [StateMachineRunner]
+pub struct EventSender<TEvent> {
+    sender: Sender<TEvent>,
+}
+
+impl <TEvent> EventSender<TEvent> {
+    /// Queues an Event for the State Machine. Fails, returning the Event, if the runner thread has
+    /// stopped.
+    pub fn send(&self, event: TEvent) -> Result<(), SendError<TEvent>> {
+        self.sender.send(event)
+    }
+}
+
+impl <TEvent> Clone for EventSender<TEvent> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+/// Watch-style handle for observing the State of a [StateMachineRunner]. Each handle remembers
+/// the last State it has seen, so [StateWatch::changed] only returns newer States.
+pub struct StateWatch<TState> {
+    reader: StateReader<TState>,
+    seen_version: u64,
+}
+
+impl <TState: PartialEq<TState> + Clone> StateWatch<TState> {
+    /// Returns the current State.
+    pub fn get(&self) -> TState {
+        self.reader.load().as_ref().clone()
+    }
+
+    /// Blocks until the State differs from the last State this handle returned (or the State
+    /// when the handle was created), then returns it. Returns None once the runner has stopped
+    /// and there is no newer State.
+    pub fn changed(&mut self) -> Option<TState> {
+        let (state, version) = self.reader.wait_for_change(self.seen_version)?;
+        self.seen_version = version;
+        Some(state.as_ref().clone())
+    }
+}
+
+impl <TState> Clone for StateWatch<TState> {
+    fn clone(&self) -> Self {
+        Self {
+            reader: self.reader.clone(),
+            seen_version: self.seen_version,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use crate::runner::StateMachineRunner;
+    use crate::StateMachineFactory;
+    use crate::FromState::Any;
+    use crate::ToState::{Calc, Same};
+
+    #[derive(Eq, PartialEq)]
+    enum Events {
+        Increment,
+        Fail
+    }
+
+    #[test]
+    fn test_runner() {
+        let (error_sender, errors) = channel();
+        let runner = StateMachineRunner::spawn(|| {
+            StateMachineFactory::<Events, u32, ()>::new()
+                .with_predicated_transition(Any, Calc(Box::new(|d| d.from + 1)), |d| d.event == &Events::Increment)
+                .with_event_transition_effect(&Events::Fail, Any, Same, |_| Err("failed".into()))
+                .lock()
+                .build(0, ())
+        }, move |e| error_sender.send(e.to_string()).expect("test receiver dropped"));
+
+        let mut watch = runner.watch();
+        let senders: Vec<_> = (0..4).map(|_| runner.sender()).collect();
+        std::thread::scope(|scope| {
+            for sender in senders {
+                scope.spawn(move || sender.send(Events::Increment).expect("runner stopped"));
+            }
+        });
+        runner.sender().send(Events::Fail).expect("runner stopped");
+
+        assert!(watch.changed().is_some());
+        assert_eq!(4, runner.join().expect("runner panicked"));
+        assert_eq!(4, watch.get());
+        assert_eq!(1, errors.iter().count());
+    }
+
+    #[test]
+    fn test_runner_build_panic() {
+        let result = std::panic::catch_unwind(|| {
+            StateMachineRunner::<Events, u32>::spawn::<(), Box<dyn std::error::Error>>(|| panic!("no configuration"), |_| {})
+        });
+        let payload = result.err().expect("expected the build to panic");
+        assert_eq!(Some(&"no configuration"), payload.downcast_ref::<&str>());
+    }
+}
@@ -0,0 +1,414 @@
+//! Import and export of State Machine definitions in SCXML.
+//!
+//! [ScxmlDefinition::parse] reads a subset of [SCXML](https://www.w3.org/TR/scxml/) into a
+//! [MachineDefinition]: top-level `<state>` and `<final>` elements, and `<transition>` elements
+//! with an `event`, a `cond` and a single `target`. Events, guards (`cond`) and States are referred
+//! to by name, and [HandlerRegistry::scxml_factory] maps the names to values and code just as it
+//! does for the declarative definitions of the [config](crate::config) module. Statecharts
+//! maintained in SCXML tooling can then be used without translating them into builder calls.
+//!
+//! [LockedStateMachineFactory::to_scxml] renders a factory back to SCXML.
+//!
+//! ```
+//! use statement::config::HandlerRegistry;
+//! use statement::scxml::ScxmlDefinition;
+//!
+//! #[derive(Clone, Eq, PartialEq, Debug)]
+//! enum State { Pending, Paid }
+//!
+//! #[derive(Clone, Eq, PartialEq, Debug)]
+//! enum Event { Pay }
+//!
+//! let scxml = ScxmlDefinition::parse(r#"
+//!     <scxml xmlns="http://www.w3.org/2005/07/scxml" version="1.0" initial="Pending">
+//!         <state id="Pending">
+//!             <transition event="Pay" cond="in_stock" target="Paid"/>
+//!         </state>
+//!         <final id="Paid"/>
+//!     </scxml>
+//! "#).unwrap();
+//!
+//! let registry = HandlerRegistry::<Event, State, ()>::new()
+//!     .with_state("Pending", State::Pending)
+//!     .with_state("Paid", State::Paid)
+//!     .with_event("Pay", Event::Pay)
+//!     .with_predicate("in_stock", |_| true);
+//!
+//! let (factory, initial_state) = registry.scxml_factory(&scxml).unwrap();
+//! let factory = factory.lock();
+//! let mut sm = factory.build(initial_state.clone(), ());
+//! sm.handle_event(Event::Pay).unwrap();
+//! assert!(sm.is_complete());
+//!
+//! let exported = factory.to_scxml(&initial_state, &[State::Pending, State::Paid]);
+//! assert!(exported.contains(r#"<transition event="Pay" cond="in_stock" target="Paid"/>"#));
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Write};
+use thiserror::Error;
+use crate::{EvaluationStrategy, LockedStateMachineFactory, StateMachineFactory, ToState, Trigger};
+use crate::config::{ConfigError, FromDefinition, HandlerRegistry, MachineDefinition, TransitionDefinition};
+
+/// The SCXML namespace
+const NAMESPACE: &str = "http://www.w3.org/2005/07/scxml";
+
+/// A State Machine definition read from SCXML, see the [scxml](crate::scxml) module.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ScxmlDefinition {
+    /// The name of the initial State: the `initial` attribute of the document, or the first State.
+    pub initial: String,
+    /// The names of the States declared with `<final>`.
+    pub final_states: Vec<String>,
+    /// The Transitions of the State Machine, in document order. The `event` and `cond` of each
+    /// Transition are also kept as metadata under those keys, so that they survive
+    /// [LockedStateMachineFactory::to_scxml].
+    pub definition: MachineDefinition,
+}
+
+/// Error type for reading SCXML, see [ScxmlDefinition::parse]
+#[derive(Error, Debug)]
+pub enum ScxmlError {
+    /// The document is not well-formed XML
+    #[error("invalid SCXML document: {0}")]
+    Xml(#[from] roxmltree::Error),
+    /// The document is not an `<scxml>` document
+    #[error("the root element is not <scxml>")]
+    NotScxml,
+    /// The document uses a part of SCXML outside the supported subset
+    #[error("unsupported SCXML: {0}")]
+    Unsupported(String),
+    /// The document has no States
+    #[error("the document has no states")]
+    NoStates,
+}
+
+impl ScxmlDefinition {
+    /// Parses the supported subset of SCXML. Each event descriptor in the space-separated `event`
+    /// attribute of a `<transition>` becomes a separate Transition that matches Events registered
+    /// under exactly that name; the descriptor `*`, like a missing `event`, matches every Event. A
+    /// `<transition>` without a `target` stays in the same State. Nested States, `<parallel>`,
+    /// `<history>`, executable content and data models are not supported and are reported as
+    /// [ScxmlError::Unsupported].
+    pub fn parse(xml: &str) -> Result<Self, ScxmlError> {
+        let document = roxmltree::Document::parse(xml)?;
+        let root = document.root_element();
+        if root.tag_name().name() != "scxml" || root.tag_name().namespace().is_some_and(|namespace| namespace != NAMESPACE) {
+            return Err(ScxmlError::NotScxml);
+        }
+
+        let mut states = Vec::new();
+        let mut final_states = Vec::new();
+        let mut transitions = Vec::new();
+        for element in root.children().filter(roxmltree::Node::is_element) {
+            let id = match element.tag_name().name() {
+                "state" => {
+                    let id = id(element)?;
+                    for child in element.children().filter(roxmltree::Node::is_element) {
+                        match child.tag_name().name() {
+                            "transition" => transitions.extend(transition_definitions(id, child)?),
+                            name => return Err(ScxmlError::Unsupported(format!("<{}> in state {:?}", name, id)))
+                        }
+                    }
+                    id
+                }
+                "final" => {
+                    let id = id(element)?;
+                    if let Some(child) = element.children().find(roxmltree::Node::is_element) {
+                        return Err(ScxmlError::Unsupported(format!("<{}> in final state {:?}", child.tag_name().name(), id)));
+                    }
+                    final_states.push(id.to_string());
+                    id
+                }
+                name => return Err(ScxmlError::Unsupported(format!("<{}>", name)))
+            };
+            states.push(id.to_string());
+        }
+
+        let initial = match root.attribute("initial") {
+            Some(initial) if initial.split_whitespace().count() > 1 => return Err(ScxmlError::Unsupported(format!("initial states {:?}", initial))),
+            Some(initial) => initial.to_string(),
+            None => states.first().cloned().ok_or(ScxmlError::NoStates)?
+        };
+
+        Ok(Self {
+            initial,
+            final_states,
+            definition: MachineDefinition {
+                transitions,
+                ..MachineDefinition::default()
+            },
+        })
+    }
+}
+
+/// Returns the `id` of a `<state>` or `<final>` element
+fn id<'i>(element: roxmltree::Node<'i, '_>) -> Result<&'i str, ScxmlError> {
+    element.attribute("id").ok_or_else(|| ScxmlError::Unsupported(format!("<{}> without an id", element.tag_name().name())))
+}
+
+/// Converts a `<transition>` element into one Transition per event descriptor
+fn transition_definitions(from: &str, transition: roxmltree::Node) -> Result<Vec<TransitionDefinition>, ScxmlError> {
+    if let Some(child) = transition.children().find(roxmltree::Node::is_element) {
+        return Err(ScxmlError::Unsupported(format!("<{}> in a transition from {:?}", child.tag_name().name(), from)));
+    }
+    let to = match transition.attribute("target") {
+        Some(target) if target.split_whitespace().count() > 1 => return Err(ScxmlError::Unsupported(format!("transition targets {:?}", target))),
+        target => target.map(str::to_string)
+    };
+    let cond = transition.attribute("cond").map(str::to_string);
+    let events: Vec<Option<String>> = match transition.attribute("event") {
+        Some(events) => events.split_whitespace().map(|event| (event != "*").then(|| event.to_string())).collect(),
+        None => vec![None]
+    };
+
+    Ok(events.into_iter().map(|event| {
+        let mut metadata = BTreeMap::new();
+        if let Some(event) = &event {
+            metadata.insert("event".to_string(), event.clone());
+        }
+        if let Some(cond) = &cond {
+            metadata.insert("cond".to_string(), cond.clone());
+        }
+        TransitionDefinition {
+            name: None,
+            from: FromDefinition::One(from.to_string()),
+            to: to.clone(),
+            event,
+            predicate: cond.clone(),
+            effect: None,
+            priority: 0,
+            metadata,
+            description: None,
+            descriptions: BTreeMap::new(),
+            doc: None,
+            examples: Vec::new(),
+        }
+    }).collect())
+}
+
+/// A factory created from an SCXML definition, with its initial State
+type ScxmlFactory<'a, TEvent, TState, TData, TErr> = (StateMachineFactory<'a, TEvent, TState, TData, TErr>, TState);
+
+impl <'a, TEvent, TState, TData, TErr> HandlerRegistry<'a, TEvent, TState, TData, TErr>
+where
+    TEvent: PartialEq + Clone + Send + 'a,
+    TState: PartialEq<TState> + Clone + Send + Eq + 'a,
+    TData: 'a,
+    TErr: 'a,
+{
+    /// Creates a [StateMachineFactory] with the Transitions and final States of an SCXML
+    /// definition, and returns it with the initial State. Event names and `cond` guards are looked
+    /// up among the registered Events and Predicates. Like SCXML, the factory only takes the first
+    /// enabled Transition for each Event ([EvaluationStrategy::FirstMatch]).
+    pub fn scxml_factory(&self, scxml: &ScxmlDefinition) -> Result<ScxmlFactory<'a, TEvent, TState, TData, TErr>, ConfigError> {
+        let final_states = scxml.final_states.iter().map(|name| self.state(name)).collect::<Result<Vec<_>, _>>()?;
+        let factory = self.factory(&scxml.definition)?
+            .with_final_states(final_states)
+            .evaluation_strategy(EvaluationStrategy::FirstMatch);
+        Ok((factory, self.state(&scxml.initial)?))
+    }
+}
+
+impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + PartialEq + 'a, TData, TErr> LockedStateMachineFactory<'a, TEvent, TState, TData, TErr>
+where TEvent: Debug, TState: Debug
+{
+    /// Renders the Transitions over the known States as SCXML, with a `<state>` (or `<final>`)
+    /// element per State, named after its `Debug` rendering. States and Events should therefore
+    /// render as identifiers, as unit enum variants do.
+    ///
+    /// A Transition's `event` comes from the Event it was defined with, or from its `event`
+    /// metadata (as kept for Transitions read with [ScxmlDefinition::parse]). Its `cond` comes
+    /// from its `cond` metadata; Predicates are opaque, so other predicated Transitions use their
+    /// name, or `predicate` if they have none. Transitions whose to_state is calculated
+    /// ([ToState::Calc]), popped ([ToState::Pop]) or restored from history, and timeout
+    /// Transitions, cannot be expressed and are skipped. Transitions are listed in evaluation
+    /// order, but SCXML always takes the first enabled Transition, so the rendering only has the
+    /// same meaning under [EvaluationStrategy::FirstMatch], as used by
+    /// [HandlerRegistry::scxml_factory].
+    pub fn to_scxml(&self, initial_state: &TState, states: &[TState]) -> String {
+        let mut scxml = String::new();
+        // Writing to a String cannot fail
+        let _ = self.write_scxml(&mut scxml, initial_state, states);
+        scxml
+    }
+
+    fn write_scxml(&self, scxml: &mut String, initial_state: &TState, states: &[TState]) -> std::fmt::Result {
+        writeln!(scxml, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(scxml, r#"<scxml xmlns="{}" version="1.0" initial="{}">"#, NAMESPACE, escape(&format!("{:?}", initial_state)))?;
+        for state in states {
+            let id = escape(&format!("{:?}", state));
+            if self.final_states.contains(state) {
+                writeln!(scxml, r#"    <final id="{}"/>"#, id)?;
+                continue;
+            }
+            let mut body = String::new();
+            for transition in self.transitions.iter().filter(|transition| transition.from_state.matches(state)) {
+                let mut attributes = String::new();
+                let event = match &transition.trigger {
+                    Trigger::Event(event) => Some(format!("{:?}", event)),
+                    _ => transition.metadata.get("event").cloned()
+                };
+                if let Some(event) = event {
+                    write!(attributes, r#" event="{}""#, escape(&event))?;
+                }
+                let cond = match (transition.metadata.get("cond"), &transition.trigger) {
+                    (Some(cond), _) => Some(cond.as_str()),
+                    (None, Trigger::Predicate | Trigger::EventKind(_)) => Some(transition.name().unwrap_or("predicate")),
+                    (None, _) => None
+                };
+                if let Some(cond) = cond {
+                    write!(attributes, r#" cond="{}""#, escape(cond))?;
+                }
+                match &transition.get_to_state {
+                    ToState::To(to_state) | ToState::Push(to_state) => write!(attributes, r#" target="{}""#, escape(&format!("{:?}", to_state)))?,
+                    // An external self-transition exits and re-enters its State, as a targeted
+                    // transition does in SCXML
+                    ToState::SelfExternal => write!(attributes, r#" target="{}""#, escape(&format!("{:?}", state)))?,
+                    ToState::Same => {}
+                    ToState::Calc(_) | ToState::Pop | ToState::History(_) => continue
+                }
+                writeln!(body, r#"        <transition{}/>"#, attributes)?;
+            }
+            match body.is_empty() {
+                true => writeln!(scxml, r#"    <state id="{}"/>"#, id)?,
+                false => {
+                    writeln!(scxml, r#"    <state id="{}">"#, id)?;
+                    write!(scxml, "{}", body)?;
+                    writeln!(scxml, "    </state>")?;
+                }
+            }
+        }
+        writeln!(scxml, "</scxml>")
+    }
+}
+
+/// Escapes text for use in an XML attribute value
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::HandlerRegistry;
+    use crate::scxml::{ScxmlDefinition, ScxmlError};
+    use crate::{FromState, StateMachineFactory};
+    use crate::ToState::Pop;
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum States {
+        Idle,
+        Running,
+        Stopped
+    }
+
+    #[derive(Clone, Eq, PartialEq, Debug)]
+    enum Events {
+        Start,
+        Stop,
+        Pause
+    }
+
+    const STATES: [States; 3] = [States::Idle, States::Running, States::Stopped];
+
+    #[test]
+    fn test_import() {
+        let scxml = ScxmlDefinition::parse(r#"<?xml version="1.0"?>
+            <scxml xmlns="http://www.w3.org/2005/07/scxml" version="1.0">
+                <!-- Without an initial attribute, the first state is initial -->
+                <state id="Idle">
+                    <transition event="Start" target="Running"/>
+                </state>
+                <state id="Running">
+                    <transition event="Pause"/>
+                    <transition event="Stop Start" cond="is_safe" target="Stopped"/>
+                </state>
+                <final id="Stopped"/>
+            </scxml>
+        "#).expect("invalid document");
+        assert_eq!("Idle", scxml.initial);
+        assert_eq!(vec!["Stopped"], scxml.final_states);
+        assert_eq!(4, scxml.definition.transitions.len());
+
+        let registry = HandlerRegistry::<Events, States, bool>::new()
+            .with_state("Idle", States::Idle)
+            .with_state("Running", States::Running)
+            .with_state("Stopped", States::Stopped)
+            .with_event("Start", Events::Start)
+            .with_event("Stop", Events::Stop)
+            .with_event("Pause", Events::Pause)
+            .with_predicate("is_safe", |d| *d.data);
+        let (factory, initial_state) = registry.scxml_factory(&scxml).expect("invalid definition");
+
+        let mut sm = factory.lock().build(initial_state, false);
+        assert_eq!(&States::Running, sm.handle_event(Events::Start).expect("unexpected error"));
+        assert_eq!(&States::Running, sm.handle_event(Events::Stop).expect("unexpected error"));
+        sm.data = true;
+        assert_eq!(&States::Stopped, sm.handle_event(Events::Start).expect("unexpected error"));
+        assert!(sm.is_complete());
+    }
+
+    #[test]
+    fn test_import_first_match() {
+        let scxml = ScxmlDefinition::parse(r#"<scxml xmlns="http://www.w3.org/2005/07/scxml" version="1.0">
+                <state id="Idle">
+                    <transition event="Start" cond="is_safe"/>
+                    <transition event="Start" target="Running"/>
+                </state>
+                <state id="Running"/>
+            </scxml>
+        "#).expect("invalid document");
+
+        let registry = HandlerRegistry::<Events, States, bool>::new()
+            .with_state("Idle", States::Idle)
+            .with_state("Running", States::Running)
+            .with_event("Start", Events::Start)
+            .with_predicate("is_safe", |d| *d.data);
+        let (factory, initial_state) = registry.scxml_factory(&scxml).expect("invalid definition");
+        let factory = factory.lock();
+
+        // The enabled targetless Transition is the only one taken
+        let mut sm = factory.build(initial_state, true);
+        assert_eq!(&States::Idle, sm.handle_event(Events::Start).expect("unexpected error"));
+        let mut sm = factory.build(initial_state, false);
+        assert_eq!(&States::Running, sm.handle_event(Events::Start).expect("unexpected error"));
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let parse = |body: &str| ScxmlDefinition::parse(&format!(r#"<scxml xmlns="http://www.w3.org/2005/07/scxml">{}</scxml>"#, body));
+        assert!(matches!(parse(r#"<parallel id="Both"/>"#), Err(ScxmlError::Unsupported(_))));
+        assert!(matches!(parse(r#"<state id="Outer"><state id="Inner"/></state>"#), Err(ScxmlError::Unsupported(_))));
+        assert!(matches!(parse(r#"<state id="A"><transition target="B C"/></state>"#), Err(ScxmlError::Unsupported(_))));
+        assert!(matches!(parse(r#"<state id="A"><transition target="B"><log expr="'hi'"/></transition></state>"#), Err(ScxmlError::Unsupported(_))));
+        assert!(matches!(parse(""), Err(ScxmlError::NoStates)));
+        assert!(matches!(ScxmlDefinition::parse("<machine/>"), Err(ScxmlError::NotScxml)));
+        assert!(matches!(ScxmlDefinition::parse("<scxml>"), Err(ScxmlError::Xml(_))));
+    }
+
+    #[test]
+    fn test_export() {
+        let scxml = StateMachineFactory::<Events, States, ()>::new()
+            .with_event_transition(&Events::Start, States::Idle, States::Running)
+            .with_named_predicated_transition("stop", States::Running, States::Stopped, |d| d.event == &Events::Stop)
+            .with_event_transition(&Events::Pause, FromState::NoneOf(vec![States::Idle]), States::Running)
+            .with_event_transition(&Events::Stop, FromState::Any, Pop)
+            .with_final_states([States::Stopped])
+            .lock()
+            .to_scxml(&States::Idle, &STATES);
+
+        assert_eq!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<scxml xmlns="http://www.w3.org/2005/07/scxml" version="1.0" initial="Idle">
+    <state id="Idle">
+        <transition event="Start" target="Running"/>
+    </state>
+    <state id="Running">
+        <transition cond="stop" target="Stopped"/>
+        <transition event="Pause" target="Running"/>
+    </state>
+    <final id="Stopped"/>
+</scxml>
+"#, scxml);
+        assert!(ScxmlDefinition::parse(&scxml).is_ok());
+    }
+}
@@ -0,0 +1,756 @@
+//! Helpers for testing State Machines.
+//!
+//! A [Scenario] describes a starting State, a sequence of Events, and what is expected to happen
+//! after each of them. Running it against a [LockedStateMachineFactory] builds a State Machine,
+//! drives it, and panics with a descriptive message if any expectation is not met, so each
+//! integration test can be written as a single expression.
+//!
+//! [soak] drives a State Machine with a long stream of random Events and reports how its
+//! [Footprint] changed, so that State Machines expected to live for months can be checked for
+//! unbounded growth before they are deployed.
+//!
+//! [replay_diff] replays a recorded Event log against two versions of a definition and reports
+//! where they first disagree, as a safety check before rolling out a changed definition.
+//!
+//! A [MachineTestHarness] wraps a State Machine, drives it through scripted Event sequences, and
+//! records which Transitions executed along the way, so that a test suite can check that it
+//! exercises every Transition of a definition.
+//!
+//! [explore] and [random_walk] drive fresh State Machines with a set of sample Events to find the
+//! States they can reach, the States they can get stuck in, and the Events whose Effects panic.
+//!
+//! ```
+//! use statement::StateMachineFactory;
+//! use statement::testing::scenario;
+//!
+//! #[derive(Debug, Eq, PartialEq)]
+//! enum Event { Pay }
+//!
+//! #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+//! enum State { Pending, Paid }
+//!
+//! let factory = StateMachineFactory::<Event, State, ()>::new()
+//!     .with_named_event_transition_effect("charge_card", &Event::Pay, State::Pending, State::Paid, |_| Ok(()))
+//!     .lock();
+//!
+//! scenario()
+//!     .given_state(State::Pending)
+//!     .when(Event::Pay)
+//!     .expect_state(State::Paid)
+//!     .expect_effect("charge_card")
+//!     .run(&factory);
+//! ```
+
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use crate::{Footprint, LockedStateMachineFactory, RandomSource, StateMachine, StateMachineError, XorShiftRandom};
+use crate::validation::TransitionId;
+
+/// Starts describing a [Scenario].
+pub fn scenario<TEvent, TState, TData>() -> Scenario<TEvent, TState, TData> {
+    Scenario {
+        state: None,
+        data: None,
+        steps: vec![Step::new(None)],
+    }
+}
+
+/// A sequence of Events and expectations to run against a State Machine, created with
+/// [scenario].
+pub struct Scenario<TEvent, TState, TData> {
+    state: Option<TState>,
+    data: Option<TData>,
+    steps: Vec<Step<TEvent, TState>>,
+}
+
+/// An Event (or, for the first step, no Event) and the expectations checked after handling it
+struct Step<TEvent, TState> {
+    event: Option<TEvent>,
+    expected_state: Option<TState>,
+    expected_effects: Vec<String>,
+    expect_error: bool,
+}
+
+impl <TEvent, TState> Step<TEvent, TState> {
+    fn new(event: Option<TEvent>) -> Self {
+        Self {
+            event,
+            expected_state: None,
+            expected_effects: Vec::new(),
+            expect_error: false,
+        }
+    }
+}
+
+impl <TEvent, TState, TData> Scenario<TEvent, TState, TData> {
+    /// Sets the State the State Machine is built with. This is required.
+    pub fn given_state(self, state: TState) -> Self {
+        Self {
+            state: Some(state),
+            ..self
+        }
+    }
+
+    /// Sets the Data the State Machine is built with. Defaults to `TData::default()`.
+    pub fn given_data(self, data: TData) -> Self {
+        Self {
+            data: Some(data),
+            ..self
+        }
+    }
+
+    /// Adds an Event to handle. The expectations that follow apply to the result of this Event.
+    pub fn when(mut self, event: TEvent) -> Self {
+        self.steps.push(Step::new(Some(event)));
+        self
+    }
+
+    /// Expects the State Machine to be in the given State after the most recent Event.
+    pub fn expect_state(mut self, state: TState) -> Self {
+        self.last_step().expected_state = Some(state);
+        self
+    }
+
+    /// Expects the Transition with the given name to have executed (including its Effect, if
+    /// any) while handling the most recent Event.
+    pub fn expect_effect(mut self, transition_name: impl Into<String>) -> Self {
+        self.last_step().expected_effects.push(transition_name.into());
+        self
+    }
+
+    /// Expects handling the most recent Event to return an error.
+    pub fn expect_error(mut self) -> Self {
+        self.last_step().expect_error = true;
+        self
+    }
+
+    fn last_step(&mut self) -> &mut Step<TEvent, TState> {
+        self.steps.last_mut().expect("a scenario always has an initial step")
+    }
+
+    /// Builds a State Machine from the factory, handles each Event in turn, and checks the
+    /// expectations, panicking on the first one that is not met. Returns the State Machine for
+    /// any further assertions.
+    pub fn run<'a, TErr: Debug>(self, factory: &LockedStateMachineFactory<'a, TEvent, TState, TData, TErr>) -> StateMachine<'a, TEvent, TState, TData, TErr>
+    where TEvent: Debug, TState: PartialEq<TState> + Clone + Send + Eq + Debug + 'a, TData: Default
+    {
+        let state = self.state.expect("scenario has no given_state");
+        let mut sm = factory.build(state, self.data.unwrap_or_default());
+        for (number, step) in self.steps.into_iter().enumerate() {
+            if let Some(event) = step.event {
+                let description = format!("{:?}", event);
+                match sm.handle_event(event) {
+                    Ok(_) if step.expect_error => panic!("step {} ({}): expected an error", number, description),
+                    Err(e) if !step.expect_error => panic!("step {} ({}): unexpected error: {:?}", number, description, e),
+                    _ => {}
+                }
+                for effect in &step.expected_effects {
+                    let fired: Vec<_> = sm.fired_transitions().collect();
+                    if !fired.contains(&Some(effect.as_str())) {
+                        panic!("step {} ({}): expected transition {:?} to execute, but executed {:?}", number, description, effect, fired);
+                    }
+                }
+            }
+            if let Some(expected_state) = step.expected_state {
+                assert_eq!(expected_state, sm.state, "step {}: unexpected state", number);
+            }
+        }
+        sm
+    }
+}
+
+/// Handles `events` Events, produced by `next_event` from a pseudo-random number seeded with
+/// `seed`, recording the [Footprint] of the State Machine as it goes. Errors returned by
+/// [StateMachine::handle_event] are counted rather than treated as failures, since random Events
+/// are often not valid in the current State. The soak stops early if the State Machine completes.
+///
+/// Call [SoakReport::assert_bounded] on the result to check that nothing kept growing. Bounded
+/// collections (such as a history with a capacity) take some Events to fill up, so use enough
+/// Events for them to fill within the first half of the soak.
+pub fn soak<'a, TEvent, TState, TData, TErr>(sm: &mut StateMachine<'a, TEvent, TState, TData, TErr>, events: usize, seed: u64, mut next_event: impl FnMut(u64) -> TEvent) -> SoakReport
+where TState: PartialEq<TState> + Clone + Send + Eq + 'a
+{
+    let mut random = XorShiftRandom::new(seed);
+    let mut report = SoakReport::default();
+    for handled in 0..events {
+        if sm.is_complete() {
+            break;
+        }
+        if sm.handle_event(next_event(random.next_random())).is_err() {
+            report.errors += 1;
+        }
+        report.events += 1;
+        let footprint = sm.footprint();
+        match handled < events / 2 {
+            true => report.first_half_peak = report.first_half_peak.max(footprint),
+            false => report.second_half_peak = report.second_half_peak.max(footprint)
+        }
+        report.last = footprint;
+    }
+    report
+}
+
+/// Replays a recorded Event log against State Machines built from an `old` and a `new` factory,
+/// both starting in `initial_state` with a copy of `initial_data`, and compares the State,
+/// executed Transitions and success of every Event. Returns the first Event for which they
+/// differ, or None if the definitions agree on the whole log.
+///
+/// With `pure` set, Effects are not run (they behave as if they had succeeded with
+/// [EffectOutcome::Continue](crate::EffectOutcome::Continue)), so replaying a production log
+/// sends no emails and charges no cards. Predicates still run, so they should not depend on
+/// Data that only Effects change.
+pub fn replay_diff<'a, TEvent, TState, TData, TErr>(
+    old: &LockedStateMachineFactory<'a, TEvent, TState, TData, TErr>,
+    new: &LockedStateMachineFactory<'a, TEvent, TState, TData, TErr>,
+    initial_state: TState,
+    initial_data: TData,
+    events: impl IntoIterator<Item = TEvent>,
+    pure: bool
+) -> Option<Divergence<TState>>
+where TEvent: Clone, TState: PartialEq<TState> + Clone + Send + Eq + 'a, TData: Clone
+{
+    let mut old = StateMachine { pure, ..old.build(initial_state.clone(), initial_data.clone()) };
+    let mut new = StateMachine { pure, ..new.build(initial_state, initial_data) };
+    for (index, event) in events.into_iter().enumerate() {
+        let old_step = ReplayStep::of(&mut old, event.clone());
+        let new_step = ReplayStep::of(&mut new, event);
+        if old_step != new_step {
+            return Some(Divergence { index, old: old_step, new: new_step });
+        }
+    }
+    None
+}
+
+/// The first Event for which two definitions disagree, see [replay_diff]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Divergence<TState> {
+    /// The position of the Event in the log, starting at 0
+    pub index: usize,
+    /// What the State Machine built from the old definition did
+    pub old: ReplayStep<TState>,
+    /// What the State Machine built from the new definition did
+    pub new: ReplayStep<TState>,
+}
+
+/// What a State Machine did with one Event of a replayed log, see [replay_diff]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReplayStep<TState> {
+    /// The State after handling the Event
+    pub state: TState,
+    /// The names (None for unnamed Transitions) of the Transitions executed, in order
+    pub fired: Vec<Option<String>>,
+    /// True if handling the Event returned an error
+    pub failed: bool,
+}
+
+impl <TState: PartialEq<TState> + Clone + Send + Eq> ReplayStep<TState> {
+    fn of<'a, TEvent, TData, TErr>(sm: &mut StateMachine<'a, TEvent, TState, TData, TErr>, event: TEvent) -> Self
+    where TState: 'a
+    {
+        let failed = sm.handle_event(event).is_err();
+        Self {
+            state: sm.state.clone(),
+            fired: sm.fired_transitions().map(|name| name.map(str::to_string)).collect(),
+            failed,
+        }
+    }
+}
+
+/// Explores the States reachable from the State Machine built with the State and Data returned by
+/// `initial`, breadth first, by handling every one of the sample `events` in every State found
+/// within `max_depth` Events of the initial State.
+///
+/// Each State is expanded once, from the first (shortest) sequence of Events found to reach it,
+/// by building a fresh State Machine and replaying that sequence. States reached with different
+/// Data along other sequences are not explored again, so Predicates that depend on Data may hide
+/// some States; [random_walk] samples more sequences.
+pub fn explore<'a, TEvent, TState, TData, TErr>(factory: &LockedStateMachineFactory<'a, TEvent, TState, TData, TErr>, initial: impl Fn() -> (TState, TData), events: &[TEvent], max_depth: usize) -> ExplorationReport<TEvent, TState>
+where TEvent: Clone, TState: PartialEq<TState> + Clone + Send + Eq + 'a
+{
+    let build = || {
+        let (state, data) = initial();
+        factory.build(state, data)
+    };
+    let mut explorer = Explorer::new(events.len());
+    explorer.reach(&build());
+    let mut paths: VecDeque<Vec<TEvent>> = VecDeque::from([Vec::new()]);
+    while let Some(path) = paths.pop_front() {
+        if path.len() >= max_depth {
+            continue;
+        }
+        for (index, event) in events.iter().enumerate() {
+            let mut sm = build();
+            for event in &path {
+                let _ = sm.handle_event(event.clone());
+            }
+            if sm.is_complete() {
+                break;
+            }
+            if let Some(true) = explorer.step(&mut sm, &path, index, event) {
+                let mut path = path.clone();
+                path.push(event.clone());
+                paths.push_back(path);
+            }
+        }
+    }
+    explorer.report()
+}
+
+/// Performs `walks` random walks of up to `steps` Events each, every one starting from a fresh
+/// State Machine built with the State and Data returned by `initial` and handling sample `events`
+/// chosen with a pseudo-random number seeded with `seed`. A walk ends early if the State Machine
+/// completes or an Effect panics.
+///
+/// Unlike [explore], the report only lists a State as a dead end once every sample Event has been
+/// tried in it, so short or few walks may miss dead ends.
+pub fn random_walk<'a, TEvent, TState, TData, TErr>(factory: &LockedStateMachineFactory<'a, TEvent, TState, TData, TErr>, initial: impl Fn() -> (TState, TData), events: &[TEvent], walks: usize, steps: usize, seed: u64) -> ExplorationReport<TEvent, TState>
+where TEvent: Clone, TState: PartialEq<TState> + Clone + Send + Eq + 'a
+{
+    let mut random = XorShiftRandom::new(seed);
+    let mut explorer = Explorer::new(events.len());
+    for _ in 0..walks {
+        let (state, data) = initial();
+        let mut sm = factory.build(state, data);
+        explorer.reach(&sm);
+        let mut path = Vec::new();
+        for _ in 0..steps {
+            if events.is_empty() || sm.is_complete() {
+                break;
+            }
+            let index = (random.next_random() % events.len() as u64) as usize;
+            if explorer.step(&mut sm, &path, index, &events[index]).is_none() {
+                break;
+            }
+            path.push(events[index].clone());
+        }
+    }
+    explorer.report()
+}
+
+/// The States found by [explore] or [random_walk]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ExplorationReport<TEvent, TState> {
+    /// The States reached, in the order they were first reached, starting with the initial State.
+    pub reachable: Vec<TState>,
+    /// Reached States, other than final States, that none of the sample Events leads out of. States
+    /// in which an Event panicked are not included.
+    pub dead_ends: Vec<TState>,
+    /// The Events that panicked, with how they were reached.
+    pub panics: Vec<ExplorationPanic<TEvent, TState>>,
+}
+
+/// An Event that panicked during [explore] or [random_walk]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ExplorationPanic<TEvent, TState> {
+    /// The Events handled from the initial State, ending with the one that panicked.
+    pub events: Vec<TEvent>,
+    /// The State the panicking Event was handled in.
+    pub state: TState,
+    /// The panic message, if it was a string.
+    pub message: String,
+}
+
+/// The States reached so far by [explore] or [random_walk], and which sample Events have been
+/// tried in each of them
+struct Explorer<TEvent, TState> {
+    events: usize,
+    reachable: Vec<TState>,
+    tried: Vec<Vec<bool>>,
+    left: Vec<bool>,
+    complete: Vec<bool>,
+    panics: Vec<ExplorationPanic<TEvent, TState>>,
+}
+
+impl <TEvent: Clone, TState: PartialEq<TState> + Clone + Send + Eq> Explorer<TEvent, TState> {
+    fn new(events: usize) -> Self {
+        Self {
+            events,
+            reachable: Vec::new(),
+            tried: Vec::new(),
+            left: Vec::new(),
+            complete: Vec::new(),
+            panics: Vec::new(),
+        }
+    }
+
+    /// Records the State of a State Machine as reached, returning true if it had not been before
+    fn reach<'a, TData, TErr>(&mut self, sm: &StateMachine<'a, TEvent, TState, TData, TErr>) -> bool
+    where TState: 'a
+    {
+        if self.reachable.contains(&sm.state) {
+            return false;
+        }
+        self.reachable.push(sm.state.clone());
+        self.tried.push(vec![false; self.events]);
+        self.left.push(false);
+        self.complete.push(sm.is_complete());
+        true
+    }
+
+    /// Handles the sample Event at `index` in a State Machine reached with `path`, returning
+    /// whether this reached a new State, or None if it panicked
+    fn step<'a, TData, TErr>(&mut self, sm: &mut StateMachine<'a, TEvent, TState, TData, TErr>, path: &[TEvent], index: usize, event: &TEvent) -> Option<bool>
+    where TState: 'a
+    {
+        let from = self.reachable.iter().position(|state| state == &sm.state).expect("the State has been reached");
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            let _ = sm.handle_event(event.clone());
+        }));
+        if let Err(payload) = result {
+            let message = payload.downcast_ref::<&str>().map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            let mut events = path.to_vec();
+            events.push(event.clone());
+            self.panics.push(ExplorationPanic { events, state: self.reachable[from].clone(), message });
+            return None;
+        }
+        self.tried[from][index] = true;
+        if sm.state != self.reachable[from] {
+            self.left[from] = true;
+        }
+        Some(self.reach(sm))
+    }
+
+    fn report(self) -> ExplorationReport<TEvent, TState> {
+        let dead_ends = self.reachable.iter().enumerate()
+            .filter(|(index, _)| !self.left[*index] && !self.complete[*index] && self.tried[*index].iter().all(|tried| *tried))
+            .map(|(_, state)| state.clone())
+            .collect();
+        ExplorationReport {
+            reachable: self.reachable,
+            dead_ends,
+            panics: self.panics,
+        }
+    }
+}
+
+/// A State Machine wrapped to drive scripted Event sequences and record which Transitions they
+/// exercise, see the [testing](crate::testing) module.
+pub struct MachineTestHarness<'a, TEvent, TState: PartialEq<TState> + Clone + Send + 'a, TData, TErr = Box<dyn std::error::Error>> {
+    sm: StateMachine<'a, TEvent, TState, TData, TErr>,
+    executions: Vec<usize>,
+}
+
+impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + 'a, TData, TErr> MachineTestHarness<'a, TEvent, TState, TData, TErr> {
+    /// Wraps a State Machine. No Transitions are recorded as exercised yet.
+    pub fn new(sm: StateMachine<'a, TEvent, TState, TData, TErr>) -> Self {
+        Self {
+            executions: vec![0; sm.transition_count()],
+            sm,
+        }
+    }
+
+    /// Returns the wrapped State Machine.
+    pub fn machine(&self) -> &StateMachine<'a, TEvent, TState, TData, TErr> {
+        &self.sm
+    }
+
+    /// Returns the wrapped State Machine mutably. Events handled through it directly are not
+    /// recorded.
+    pub fn machine_mut(&mut self) -> &mut StateMachine<'a, TEvent, TState, TData, TErr> {
+        &mut self.sm
+    }
+
+    /// Replaces the wrapped State Machine, keeping the Transitions recorded so far, and returns
+    /// the previous one. This lets several scripts, each starting from a fresh State Machine built
+    /// from the same factory, add up to one coverage report.
+    pub fn replace(&mut self, sm: StateMachine<'a, TEvent, TState, TData, TErr>) -> StateMachine<'a, TEvent, TState, TData, TErr> {
+        assert_eq!(self.executions.len(), sm.transition_count(), "the replacement State Machine has different Transitions");
+        std::mem::replace(&mut self.sm, sm)
+    }
+
+    /// Handles an Event as [StateMachine::handle_event] does, recording the Transitions it
+    /// executed.
+    pub fn handle_event(&mut self, event: TEvent) -> Result<&TState, StateMachineError<TState, TErr>> {
+        let result = self.sm.handle_event(event).map(|_| ());
+        for index in &self.sm.fired {
+            self.executions[*index] += 1;
+        }
+        result.map(|_| &self.sm.state)
+    }
+
+    /// Handles each Event in turn and returns the State after each of them, panicking if any
+    /// Event returns an error.
+    pub fn drive(&mut self, events: impl IntoIterator<Item = TEvent>) -> Vec<TState>
+    where TEvent: Debug, TErr: Debug, TState: Debug
+    {
+        events.into_iter().enumerate()
+            .map(|(number, event)| {
+                let description = format!("{:?}", event);
+                match self.handle_event(event) {
+                    Ok(state) => state.clone(),
+                    Err(e) => panic!("event {} ({}): unexpected error: {:?}", number, description, e)
+                }
+            })
+            .collect()
+    }
+
+    /// Handles each Event in turn, as [MachineTestHarness::drive] does, and panics unless the
+    /// States after each of them are `expected`.
+    pub fn expect_trajectory(&mut self, events: impl IntoIterator<Item = TEvent>, expected: &[TState]) -> &mut Self
+    where TEvent: Debug, TErr: Debug, TState: Debug
+    {
+        let trajectory = self.drive(events);
+        assert_eq!(expected, trajectory.as_slice(), "unexpected trajectory");
+        self
+    }
+
+    /// Returns how often each Transition has executed so far.
+    pub fn coverage(&self) -> CoverageReport {
+        CoverageReport {
+            transitions: self.executions.iter().enumerate()
+                .map(|(index, executions)| (TransitionId { index, name: self.sm.transition(index).name.clone() }, *executions))
+                .collect(),
+        }
+    }
+}
+
+/// How often each Transition of a definition executed, see [MachineTestHarness::coverage]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CoverageReport {
+    /// Every Transition, in definition order, with the number of times it executed.
+    pub transitions: Vec<(TransitionId, usize)>,
+}
+
+impl CoverageReport {
+    /// Returns the Transitions that never executed.
+    pub fn uncovered(&self) -> Vec<&TransitionId> {
+        self.transitions.iter()
+            .filter(|(_, executions)| *executions == 0)
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    /// Returns the fraction of Transitions that executed at least once, or 1 if there are no
+    /// Transitions.
+    pub fn ratio(&self) -> f64 {
+        match self.transitions.len() {
+            0 => 1.0,
+            total => (total - self.uncovered().len()) as f64 / total as f64
+        }
+    }
+
+    /// Panics if any Transition never executed, listing them.
+    pub fn assert_covered(&self) {
+        let uncovered = self.uncovered();
+        if !uncovered.is_empty() {
+            panic!("{} of {} transitions never executed: {:?}", uncovered.len(), self.transitions.len(), uncovered);
+        }
+    }
+}
+
+/// The outcome of a [soak]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SoakReport {
+    /// The number of Events handled.
+    pub events: usize,
+    /// The number of Events for which [StateMachine::handle_event] returned an error.
+    pub errors: usize,
+    /// The largest size of each collection during the first half of the soak.
+    pub first_half_peak: Footprint,
+    /// The largest size of each collection during the second half of the soak.
+    pub second_half_peak: Footprint,
+    /// The size of each collection once the soak finished.
+    pub last: Footprint,
+}
+
+impl SoakReport {
+    /// Returns the names of the collections that grew beyond their first-half peak during the
+    /// second half of the soak, which suggests they grow without bound.
+    pub fn growing(&self) -> Vec<&'static str> {
+        let (first, second) = (&self.first_half_peak, &self.second_half_peak);
+        [
+            ("history", first.history, second.history),
+            ("stack", first.stack, second.stack),
+            ("history_states", first.history_states, second.history_states),
+            ("deferred", first.deferred, second.deferred),
+        ].into_iter()
+            .filter(|(_, first, second)| second > first)
+            .map(|(name, _, _)| name)
+            .collect()
+    }
+
+    /// Panics if any collection grew during the second half of the soak, see
+    /// [SoakReport::growing].
+    pub fn assert_bounded(&self) {
+        let growing = self.growing();
+        if !growing.is_empty() {
+            panic!("unbounded growth in {:?} over {} events: {:?}", growing, self.events, self);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::StateMachineFactory;
+    use crate::testing::{explore, random_walk, replay_diff, scenario, soak, MachineTestHarness};
+    use crate::validation::TransitionId;
+    use crate::FromState::Any;
+    use crate::ToState::{Pop, Push};
+
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    enum Events {
+        Pay,
+        Refund
+    }
+
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    enum States {
+        Pending,
+        Paid,
+        Refunded
+    }
+
+    fn factory<'a>() -> crate::LockedStateMachineFactory<'a, Events, States, ()> {
+        StateMachineFactory::new()
+            .with_named_event_transition("charge_card", &Events::Pay, States::Pending, States::Paid)
+            .with_named_event_transition_effect("refund_card", &Events::Refund, States::Paid, States::Refunded, |_| Err("refund failed".into()))
+            .lock()
+    }
+
+    #[test]
+    fn test_scenario() {
+        let sm = scenario()
+            .given_state(States::Pending)
+            .expect_state(States::Pending)
+            .when(Events::Pay)
+            .expect_state(States::Paid)
+            .expect_effect("charge_card")
+            .when(Events::Refund)
+            .expect_error()
+            .expect_state(States::Paid)
+            .run(&factory());
+        assert_eq!(States::Paid, sm.state);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected transition \"refund_card\" to execute")]
+    fn test_failed_expectation() {
+        scenario()
+            .given_state(States::Pending)
+            .when(Events::Pay)
+            .expect_effect("refund_card")
+            .run(&factory());
+    }
+
+    #[test]
+    fn test_soak() {
+        let bounded = StateMachineFactory::<Events, States, ()>::new()
+            .with_event_transition(&Events::Pay, States::Pending, States::Paid)
+            .with_event_transition(&Events::Refund, States::Paid, States::Pending)
+            .with_history(100)
+            .lock();
+        let mut sm = bounded.build(States::Pending, ());
+        let report = soak(&mut sm, 10_000, 7, |random| if random % 2 == 0 { Events::Pay } else { Events::Refund });
+        report.assert_bounded();
+        assert_eq!(10_000, report.events);
+        assert!(report.errors == 0 && report.last.history == 100);
+
+        // Pushing without ever popping leaks a State per Event
+        let leaking = StateMachineFactory::<Events, States, ()>::new()
+            .with_event_transition(&Events::Pay, Any, Push(States::Paid))
+            .with_event_transition(&Events::Refund, States::Refunded, Pop)
+            .lock();
+        let mut sm = leaking.build(States::Pending, ());
+        let report = soak(&mut sm, 1_000, 7, |_| Events::Pay);
+        assert_eq!(vec!["stack"], report.growing());
+    }
+
+    #[test]
+    fn test_replay_diff() {
+        let old = factory();
+        let new = StateMachineFactory::new()
+            .with_named_event_transition("charge_card", &Events::Pay, States::Pending, States::Paid)
+            .with_named_event_transition("refund_card", &Events::Refund, States::Paid, States::Refunded)
+            .lock();
+        let log = [Events::Pay, Events::Pay, Events::Refund];
+
+        // Pure replay skips the failing refund Effect, so both definitions agree
+        assert_eq!(None, replay_diff(&old, &new, States::Pending, (), log.clone(), true));
+
+        let divergence = replay_diff(&old, &new, States::Pending, (), log, false).expect("expected a divergence");
+        assert_eq!(2, divergence.index);
+        assert_eq!((States::Paid, true), (divergence.old.state, divergence.old.failed));
+        assert_eq!((States::Refunded, vec![Some("refund_card".to_string())]), (divergence.new.state, divergence.new.fired));
+    }
+
+    #[test]
+    fn test_harness_coverage() {
+        let factory = StateMachineFactory::<Events, States, ()>::new()
+            .with_named_event_transition("pay", &Events::Pay, States::Pending, States::Paid)
+            .with_named_event_transition("refund", &Events::Refund, States::Paid, States::Refunded)
+            .with_event_transition(&Events::Refund, States::Pending, States::Refunded)
+            .lock();
+
+        let mut harness = MachineTestHarness::new(factory.build(States::Pending, ()));
+        harness.expect_trajectory([Events::Pay, Events::Pay, Events::Refund], &[States::Paid, States::Paid, States::Refunded]);
+        let coverage = harness.coverage();
+        assert_eq!(vec![&TransitionId { index: 2, name: None }], coverage.uncovered());
+        assert_eq!(1, coverage.transitions[1].1);
+
+        // Coverage adds up across State Machines
+        harness.replace(factory.build(States::Pending, ()));
+        assert_eq!(vec![States::Refunded], harness.drive([Events::Refund]));
+        harness.coverage().assert_covered();
+        assert_eq!(1.0, harness.coverage().ratio());
+    }
+
+    #[test]
+    #[should_panic(expected = "1 of 2 transitions never executed")]
+    fn test_harness_uncovered() {
+        let mut harness = MachineTestHarness::new(factory().build(States::Pending, ()));
+        harness.drive([Events::Pay]);
+        harness.coverage().assert_covered();
+    }
+
+    #[test]
+    fn test_explore() {
+        #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+        enum Steps {
+            Submit,
+            Fail,
+            Retry,
+            Approve,
+            Audit
+        }
+
+        #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+        enum Stages {
+            Draft,
+            Submitted,
+            AwaitingRetry,
+            Approved,
+            Audited
+        }
+
+        let factory = StateMachineFactory::<Steps, Stages, u32>::new()
+            .with_event_transition(&Steps::Submit, Stages::Draft, Stages::Submitted)
+            .with_event_transition(&Steps::Fail, Stages::Submitted, Stages::AwaitingRetry)
+            .with_predicated_transition(Stages::AwaitingRetry, Stages::Submitted, |d| d.event == &Steps::Retry && *d.data > 0)
+            .with_event_transition(&Steps::Approve, Stages::Submitted, Stages::Approved)
+            .with_event_transition_effect(&Steps::Audit, Stages::Approved, Stages::Audited, |_| panic!("audit log unavailable"))
+            .with_final_states(vec![Stages::Audited])
+            .lock();
+        let events = [Steps::Submit, Steps::Fail, Steps::Retry, Steps::Approve, Steps::Audit];
+
+        // Without retries left, the machine gets stuck awaiting one
+        let report = explore(&factory, || (Stages::Draft, 0), &events, 10);
+        assert_eq!(vec![Stages::Draft, Stages::Submitted, Stages::AwaitingRetry, Stages::Approved], report.reachable);
+        assert_eq!(vec![Stages::AwaitingRetry], report.dead_ends);
+        assert_eq!(1, report.panics.len());
+        assert_eq!(vec![Steps::Submit, Steps::Approve, Steps::Audit], report.panics[0].events);
+        assert_eq!((Stages::Approved, "audit log unavailable"), (report.panics[0].state, report.panics[0].message.as_str()));
+
+        // A depth limit stops the exploration, without reporting unexplored States as dead ends
+        let report = explore(&factory, || (Stages::Draft, 0), &events, 2);
+        assert_eq!(vec![Stages::Draft, Stages::Submitted, Stages::AwaitingRetry, Stages::Approved], report.reachable);
+        assert!(report.dead_ends.is_empty() && report.panics.is_empty());
+
+        let report = random_walk(&factory, || (Stages::Draft, 1), &events, 20, 20, 7);
+        assert!(report.dead_ends.is_empty());
+        assert!(report.reachable.contains(&Stages::AwaitingRetry));
+        assert!(!report.panics.is_empty());
+    }
+}
@@ -0,0 +1,415 @@
+//! Static validation of State Machine definitions.
+//!
+//! [StateMachineFactory::validate] inspects the Transitions of a factory, without running any
+//! Predicates or Effects, and produces a [ValidationReport] describing States and Transitions that
+//! are likely to be mistakes. Running it at startup (or in a test) catches these problems before a
+//! State Machine gets stuck in production.
+//!
+//! ```
+//! use statement::StateMachineFactory;
+//!
+//! #[derive(Eq, PartialEq)]
+//! enum Event { Start, Stop }
+//!
+//! #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+//! enum State { Idle, Running, Stopped, Orphaned }
+//!
+//! let factory = StateMachineFactory::<Event, State, ()>::new()
+//!     .with_event_transition(&Event::Start, State::Idle, State::Running)
+//!     .with_event_transition(&Event::Stop, State::Running, State::Stopped)
+//!     .with_event_transition(&Event::Stop, State::Orphaned, State::Stopped);
+//!
+//! let report = factory.validate(&State::Idle, &[State::Idle, State::Running, State::Stopped, State::Orphaned]);
+//! assert_eq!(vec![State::Orphaned], report.unreachable_states);
+//! assert_eq!(vec![State::Stopped], report.dead_end_states);
+//! assert_eq!(2, report.unreachable_transitions[0].index);
+//! ```
+//!
+//! Predicates can also carry examples of the inputs they should accept and reject, attached with
+//! [StateMachineFactory::with_guard_example]. [StateMachineFactory::lock_validated] runs them as
+//! the factory is locked, and refuses to lock a factory whose Predicates disagree with them.
+//!
+//! ```
+//! use statement::StateMachineFactory;
+//!
+//! #[derive(Eq, PartialEq)]
+//! enum Event { Withdraw(u32) }
+//!
+//! #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+//! enum State { Open, Overdrawn }
+//!
+//! let failures = StateMachineFactory::<Event, State, u32>::new()
+//!     .with_predicated_transition(State::Open, State::Overdrawn, |d| matches!(d.event, Event::Withdraw(amount) if amount > d.data))
+//!     .with_guard_example(State::Open, Event::Withdraw(20), 10, true)
+//!     .with_guard_example(State::Open, Event::Withdraw(10), 10, true)
+//!     .lock_validated()
+//!     .err()
+//!     .unwrap();
+//! assert_eq!(1, failures[0].example);
+//! assert_eq!(Ok(false), failures[0].actual);
+//! ```
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use crate::{ExtractedPayload, FromState, GuardExample, LockedStateMachineFactory, StateMachineFactory, StateMachineTransition, StateTransitionEffectData, StateTransitionToStateData, ToState, Trigger};
+
+/// Identifies a Transition in a [ValidationReport]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TransitionId {
+    /// The position of the Transition in definition order, starting at 0
+    pub index: usize,
+    /// The name of the Transition, if any
+    pub name: Option<String>,
+}
+
+/// Problems found by [StateMachineFactory::validate]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ValidationReport<TState> {
+    /// Known States that no sequence of Transitions leads to from the initial State.
+    pub unreachable_states: Vec<TState>,
+    /// Reachable known States that no Transition leads out of, other than the final States
+    /// declared with [StateMachineFactory::with_final_states].
+    pub dead_end_states: Vec<TState>,
+    /// Transitions that can never execute, because none of their from_states is reachable.
+    pub unreachable_transitions: Vec<TransitionId>,
+    /// Pairs of Transitions to different States that can both execute for the same State and
+    /// Event, with no Predicate to tell them apart.
+    pub ambiguous_transitions: Vec<(TransitionId, TransitionId)>,
+    /// States that are not among the known States, but are the initial State or are named by a
+    /// Transition, timeout Transition or final State, in the order they are first named.
+    pub unknown_states: Vec<TState>,
+}
+
+impl <TState> ValidationReport<TState> {
+    /// Returns true if no problems were found.
+    pub fn is_empty(&self) -> bool {
+        self.unreachable_states.is_empty()
+            && self.dead_end_states.is_empty()
+            && self.unreachable_transitions.is_empty()
+            && self.ambiguous_transitions.is_empty()
+            && self.unknown_states.is_empty()
+    }
+}
+
+/// An example attached with [StateMachineFactory::with_guard_example] that the Predicate of its
+/// Transition disagreed with, returned by [StateMachineFactory::lock_validated]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GuardExampleFailure {
+    /// The Transition whose Predicate was run
+    pub transition: TransitionId,
+    /// The position of the example among those attached to the Transition, starting at 0
+    pub example: usize,
+    /// The result the example expected
+    pub expected: bool,
+    /// The result of the Predicate, or the error returned by a fallible Predicate
+    pub actual: Result<bool, String>,
+}
+
+impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + PartialEq + 'a, TData, TErr> StateMachineFactory<'a, TEvent, TState, TData, TErr> {
+    /// Runs the examples attached to Predicates with [StateMachineFactory::with_guard_example] and,
+    /// if every Predicate agrees with its examples, locks the factory as
+    /// [StateMachineFactory::lock] would. Otherwise returns every example that failed. The
+    /// to_state seen by a Predicate is calculated as it would be at runtime, except that popped
+    /// ([ToState::Pop]) and [ToState::History] Transitions see the example's from State.
+    pub fn lock_validated(mut self) -> Result<LockedStateMachineFactory<'a, TEvent, TState, TData, TErr>, Vec<GuardExampleFailure>> {
+        let mut failures = Vec::new();
+        for (index, transition) in self.transitions.iter_mut().enumerate() {
+            let examples = std::mem::take(&mut transition.examples);
+            for (example, GuardExample { from, event, data, expected }) in examples.into_iter().enumerate() {
+                let to = match &transition.get_to_state {
+                    ToState::To(to_state) | ToState::Push(to_state) => to_state.clone(),
+                    ToState::Calc(get_to_state) => get_to_state(StateTransitionToStateData {
+                        event: &event,
+                        data: &data,
+                        from: &from,
+                        parameters: &self.parameters,
+                    }),
+                    ToState::Same | ToState::SelfExternal | ToState::Pop | ToState::History(_) => from.clone()
+                };
+                let emitted = RefCell::new(VecDeque::new());
+                let transition_effect_data = StateTransitionEffectData {
+                    name: &transition.name,
+                    event: &event,
+                    data: &data,
+                    from: &from,
+                    to: &to,
+                    parameters: &self.parameters,
+                    emitted: &emitted,
+                    contexts: &[],
+                    progress_observer: None,
+                    extracted: &ExtractedPayload::default()
+                };
+                let actual = match &transition.event_predicate {
+                    Some(predicate) => predicate.evaluate(&transition_effect_data).map_err(|e| e.to_string()),
+                    None => Ok(true)
+                };
+                if actual != Ok(expected) {
+                    failures.push(GuardExampleFailure {
+                        transition: transition_id(index, transition),
+                        example,
+                        expected,
+                        actual,
+                    });
+                }
+            }
+        }
+        match failures.is_empty() {
+            true => Ok(self.lock()),
+            false => Err(failures)
+        }
+    }
+}
+
+impl <'a, TEvent, TState: PartialEq<TState> + Clone + Send + Eq + PartialEq + 'a, TData, TErr> StateMachineFactory<'a, TEvent, TState, TData, TErr>
+where TEvent: PartialEq<TEvent>
+{
+    /// Checks the Transitions defined so far against the known set of States, starting from
+    /// `initial_state`. Predicates are never run, so any Transition is assumed to be able to
+    /// execute, and Transitions whose to_state is calculated ([ToState::Calc]) or popped
+    /// ([ToState::Pop]) are assumed to be able to reach every known State. The States declared
+    /// with [StateMachineFactory::with_states] can be passed as the known States with
+    /// [StateMachineFactory::states]. See the [validation](crate::validation) module.
+    pub fn validate(&self, initial_state: &TState, states: &[TState]) -> ValidationReport<TState> {
+        let reachable = self.reachable_states(initial_state, states);
+
+        let unreachable_states = states.iter()
+            .filter(|state| !reachable.contains(state))
+            .cloned()
+            .collect();
+
+        let dead_end_states = states.iter()
+            .filter(|state| reachable.contains(state) && !self.final_states.contains(state) && !self.leaves(state))
+            .cloned()
+            .collect();
+
+        let unreachable_transitions = self.transitions.iter().enumerate()
+            .filter(|(_, transition)| !reachable.iter().any(|state| transition.from_state.matches(state)))
+            .map(|(index, transition)| transition_id(index, transition))
+            .collect();
+
+        let mut ambiguous_transitions = Vec::new();
+        for (index, first) in self.transitions.iter().enumerate() {
+            for (other_index, second) in self.transitions.iter().enumerate().skip(index + 1) {
+                if is_ambiguous(first, second, states) {
+                    ambiguous_transitions.push((transition_id(index, first), transition_id(other_index, second)));
+                }
+            }
+        }
+
+        let mut unknown_states: Vec<TState> = Vec::new();
+        for state in self.named_states(initial_state) {
+            if !states.contains(state) && !unknown_states.contains(state) {
+                unknown_states.push(state.clone());
+            }
+        }
+
+        ValidationReport {
+            unreachable_states,
+            dead_end_states,
+            unreachable_transitions,
+            ambiguous_transitions,
+            unknown_states,
+        }
+    }
+
+    /// Lists the States named by the initial State, Transitions, timeout Transitions and final
+    /// States, with repeats
+    fn named_states<'s>(&'s self, initial_state: &'s TState) -> Vec<&'s TState> {
+        fn from_states<TState: PartialEq<TState> + Clone>(from_state: &FromState<TState>) -> &[TState] {
+            match from_state {
+                FromState::Any => &[],
+                FromState::AnyOf(states) | FromState::NoneOf(states) => states,
+                FromState::From(state) => std::slice::from_ref(state)
+            }
+        }
+
+        let mut named = vec![initial_state];
+        for transition in self.transitions.iter() {
+            named.extend(from_states(&transition.from_state));
+            if let ToState::To(to_state) | ToState::Push(to_state) = &transition.get_to_state {
+                named.push(to_state);
+            }
+        }
+        for timeout in self.timeouts.iter() {
+            named.extend(from_states(&timeout.from_state));
+            named.push(&timeout.to_state);
+        }
+        named.extend(self.final_states.iter());
+        named
+    }
+
+    /// Finds every State reachable from the initial State
+    fn reachable_states(&self, initial_state: &TState, states: &[TState]) -> Vec<TState> {
+        let mut reachable = vec![initial_state.clone()];
+        let mut next = 0;
+        while next < reachable.len() {
+            let state = reachable[next].clone();
+            next += 1;
+            let mut targets = Vec::new();
+            for transition in self.transitions.iter().filter(|transition| transition.from_state.matches(&state)) {
+                match &transition.get_to_state {
+                    ToState::To(to_state) | ToState::Push(to_state) => targets.push(to_state.clone()),
+                    ToState::Calc(_) | ToState::Pop => targets.extend(states.iter().cloned()),
+                    // A History Transition only returns to States that were already reached
+                    ToState::Same | ToState::SelfExternal | ToState::History(_) => {}
+                }
+            }
+            for timeout in self.timeouts.iter().filter(|timeout| timeout.from_state.matches(&state)) {
+                targets.push(timeout.to_state.clone());
+            }
+            for target in targets {
+                if !reachable.contains(&target) {
+                    reachable.push(target);
+                }
+            }
+        }
+        reachable
+    }
+
+    /// Determines whether any Transition leads out of a State
+    fn leaves(&self, state: &TState) -> bool {
+        self.transitions.iter()
+            .filter(|transition| transition.from_state.matches(state))
+            .any(|transition| match &transition.get_to_state {
+                ToState::To(to_state) | ToState::Push(to_state) => to_state != state,
+                ToState::Calc(_) | ToState::Pop | ToState::History(_) => true,
+                ToState::Same | ToState::SelfExternal => false
+            })
+        || self.timeouts.iter().any(|timeout| timeout.from_state.matches(state) && &timeout.to_state != state)
+    }
+}
+
+/// Identifies the Transition at the given index
+fn transition_id<TEvent, TState: PartialEq<TState> + Clone + Send, TData, TErr>(index: usize, transition: &StateMachineTransition<TEvent, TState, TData, TErr>) -> TransitionId {
+    TransitionId {
+        index,
+        name: transition.name.clone(),
+    }
+}
+
+/// Determines whether two Transitions can both execute for the same State and Event, with
+/// different results
+fn is_ambiguous<TEvent: PartialEq<TEvent>, TState: PartialEq<TState> + Clone + Send, TData, TErr>(
+    first: &StateMachineTransition<TEvent, TState, TData, TErr>,
+    second: &StateMachineTransition<TEvent, TState, TData, TErr>,
+    states: &[TState]
+) -> bool {
+    let same_result = match (&first.get_to_state, &second.get_to_state) {
+        (ToState::Same, _) | (_, ToState::Same) => return false,
+        (ToState::To(first), ToState::To(second)) | (ToState::Push(first), ToState::Push(second)) => first == second,
+        (ToState::Pop, ToState::Pop) => true,
+        _ => false
+    };
+    let same_event = match (&first.trigger, &second.trigger) {
+        (Trigger::Predicate, _) | (_, Trigger::Predicate) => false,
+        (Trigger::Auto, _) | (_, Trigger::Auto) => true,
+        (Trigger::Event(first), Trigger::Event(second)) => first == second,
+        (Trigger::Event(event), Trigger::EventKind(kind)) | (Trigger::EventKind(kind), Trigger::Event(event)) => std::mem::discriminant(*event) == *kind,
+        (Trigger::EventKind(first), Trigger::EventKind(second)) => first == second
+    };
+    let same_state = match (&first.from_state, &second.from_state) {
+        (FromState::Any, _) | (_, FromState::Any) => true,
+        _ => states.iter().any(|state| first.from_state.matches(state) && second.from_state.matches(state))
+    };
+    !same_result && same_event && same_state
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{FromState, StateMachineFactory, StateSet};
+    use crate::ToState::Calc;
+    use crate::validation::{GuardExampleFailure, TransitionId};
+
+    #[derive(Clone, Eq, PartialEq, Debug)]
+    enum Events {
+        Start,
+        Stop { force: bool }
+    }
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum States {
+        Idle,
+        Running,
+        Stopped,
+        Crashed
+    }
+
+    const STATES: [States; 4] = [States::Idle, States::Running, States::Stopped, States::Crashed];
+
+    impl StateSet for States {
+        fn states() -> Vec<Self> {
+            STATES.to_vec()
+        }
+    }
+
+    #[test]
+    fn test_valid_definition() {
+        let report = StateMachineFactory::<Events, States, ()>::new()
+            .with_event_transition(&Events::Start, States::Idle, States::Running)
+            .with_event_kind_transition(&Events::Stop { force: false }, States::Running, States::Stopped)
+            .with_predicated_transition(States::Running, States::Crashed, |d| d.event == &Events::Stop { force: true })
+            .with_event_transition(&Events::Start, FromState::AnyOf(vec![States::Stopped, States::Crashed]), States::Idle)
+            .validate(&States::Idle, &STATES);
+        assert!(report.is_empty(), "{:?}", report);
+    }
+
+    #[test]
+    fn test_ambiguous_transitions() {
+        let report = StateMachineFactory::<Events, States, ()>::new()
+            .with_named_event_transition("start", &Events::Start, States::Idle, States::Running)
+            .with_named_event_kind_transition("stop", &Events::Stop { force: false }, States::Running, States::Stopped)
+            .with_named_event_transition("crash", &Events::Stop { force: true }, States::Running, States::Crashed)
+            .validate(&States::Idle, &STATES);
+        assert_eq!(vec![(
+            TransitionId { index: 1, name: Some("stop".into()) },
+            TransitionId { index: 2, name: Some("crash".into()) }
+        )], report.ambiguous_transitions);
+        assert_eq!(vec![States::Stopped, States::Crashed], report.dead_end_states);
+    }
+    #[test]
+    fn test_guard_examples() {
+        let factory = || StateMachineFactory::<Events, States, u32>::new()
+            .with_named_predicated_transition("crash", States::Running, States::Crashed, |d| d.event == &Events::Stop { force: true } && *d.data > 2)
+            .with_guard_example(States::Running, Events::Stop { force: true }, 3, true)
+            .with_guard_example(States::Running, Events::Stop { force: false }, 3, false)
+            .with_event_transition(&Events::Start, States::Idle, States::Running)
+            .with_guard_example(States::Idle, Events::Start, 0, true)
+            .with_fallible_predicated_transition(FromState::Any, Calc(Box::new(|d| if *d.data > 5 { States::Crashed } else { States::Idle })), |d| match d.to {
+                States::Crashed => Err(anyhow::anyhow!("crashed").into()),
+                _ => Ok(true)
+            });
+
+        assert!(factory().lock_validated().is_ok());
+
+        // Examples that the Predicates disagree with are all reported, and the to_state is
+        // calculated from the example
+        let failures = factory()
+            .with_guard_example(States::Stopped, Events::Start, 1, false)
+            .with_guard_example(States::Stopped, Events::Start, 6, true)
+            .lock_validated()
+            .err()
+            .expect("expected the examples to fail");
+        assert_eq!(vec![
+            GuardExampleFailure { transition: TransitionId { index: 2, name: None }, example: 0, expected: false, actual: Ok(true) },
+            GuardExampleFailure { transition: TransitionId { index: 2, name: None }, example: 1, expected: true, actual: Err("crashed".into()) },
+        ], failures);
+    }
+
+    #[test]
+    fn test_unknown_states() {
+        let factory = StateMachineFactory::<Events, States, ()>::new()
+            .with_event_transition(&Events::Start, States::Idle, States::Running)
+            .with_event_kind_transition(&Events::Stop { force: false }, FromState::AnyOf(vec![States::Running, States::Crashed]), States::Stopped)
+            .with_final_states(vec![States::Stopped])
+            .with_states([States::Idle, States::Running, States::Stopped, States::Idle]);
+        assert_eq!(&[States::Idle, States::Running, States::Stopped], factory.states());
+        assert_eq!(vec![States::Crashed], factory.validate(&States::Idle, factory.states()).unknown_states);
+
+        let factory = factory.with_state_set();
+        assert_eq!(&STATES, factory.states());
+        let report = factory.validate(&States::Idle, factory.states());
+        assert!(report.unknown_states.is_empty());
+        assert_eq!(vec![States::Crashed], report.unreachable_states);
+        assert_eq!(&STATES, factory.lock().states());
+    }
+}
@@ -32,13 +32,7 @@ mod calculator_tests {
             Equals
         }
 
-        impl Events {
-            fn is_digit(&self) -> bool {
-                if let Events::Digit { digit: _ } = self { true } else { false }
-            }
-        }
-
-        let mut init_data = CalcData {
+        let init_data = CalcData {
             input_value: AtomicF64::new(0f64),
             stored_value: AtomicF64::new(0f64)
         };
@@ -53,24 +47,22 @@ mod calculator_tests {
                     print!("user sent {:?} event", d.event);
                     Ok(())
                 })
-            .with_predicated_transition_effect(
+            .with_extracted_event_transition(
+                |e| match e {
+                    Events::Digit { digit } => Some(*digit),
+                    _ => None
+                },
                 Any,
                 Same,
-                |d| d.event.is_digit(),
-                |d| {
-                    if let Events::Digit { digit } = d.event {
-                        append_digit(d.data, digit.clone());
-                    }
+                |digit, d| {
+                    append_digit(d.data, digit);
                     Ok(())
                 })
             .with_predicated_transition_effect(
                 AnyOf(vec![States::Adding, States::Subtracting, States::Multiplying, States::Dividing]),
                 States::Idle,
                 |d| {
-                    match d.event {
-                        Events::Add | Events::Subtract | Events::Multiply | Events::Divide | Events::Equals => true,
-                        _ => false
-                    }
+                    matches!(d.event, Events::Add | Events::Subtract | Events::Multiply | Events::Divide | Events::Equals)
                 },
                 |d| {
                     apply_function(d);
@@ -102,7 +94,7 @@ mod calculator_tests {
                     println!(", input value is {}, stored value is {}", d.data.input_value.load(SeqCst), d.data.stored_value.load(SeqCst));
                     Ok(())
                 })
-            .lock().build(States::Idle, &mut init_data);
+            .lock().build(States::Idle, &init_data);
 
         sm.handle_event(Events::Digit {digit: 2})?;
         sm.handle_event(Events::Add)?;